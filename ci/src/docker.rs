@@ -5,6 +5,9 @@ use crate::*;
 // Latest postgres version: https://hub.docker.com/_/postgres
 const POSTGRES_IMAGE: &str = "postgres:17-alpine";
 
+// Latest redis version: https://hub.docker.com/_/redis
+const REDIS_IMAGE: &str = "redis:8-alpine";
+
 pub struct Docker {
     pub context: Rc<Context>,
 }
@@ -41,6 +44,7 @@ impl Docker {
             ExecutionContext::Build => args.push(format!("wiki-ci:build-{}", self.context.id)),
             ExecutionContext::E2E => args.push(format!("wiki-ci:e2e-{}", self.context.id)),
             ExecutionContext::Postgres => args.push(POSTGRES_IMAGE.into()),
+            ExecutionContext::Redis => args.push(REDIS_IMAGE.into()),
         }
 
         if !script.is_empty() {
@@ -73,7 +77,7 @@ impl Docker {
         shell::spawn_result_to_result(cmd.spawn(), finished_print)
     }
 
-    fn pull_postgres_image(&self, image: &str) -> Result<(), Error> {
+    fn pull_image(&self, image: &str) -> Result<(), Error> {
         let mut prog = Command::new("docker");
         let cmd = prog.args([
             "pull",
@@ -118,7 +122,8 @@ impl Runner for Docker {
                     "ln -s /ci/ui/node_modules ui/node_modules || true",
                 )
             },
-            ExecutionContext::Postgres => self.pull_postgres_image(POSTGRES_IMAGE),
+            ExecutionContext::Postgres => self.pull_image(POSTGRES_IMAGE),
+            ExecutionContext::Redis => self.pull_image(REDIS_IMAGE),
         }
     }
 
@@ -163,6 +163,7 @@ pub enum ExecutionContext {
     Build,
     E2E,
     Postgres,
+    Redis,
 }
 
 pub trait Stage {
@@ -22,6 +22,10 @@ static POSTGRES_BIN_REQUIRED: [&str; 2] = [
     "pg_ctl",
 ];
 
+static REDIS_BIN_REQUIRED: [&str; 1] = [
+    "redis-server",
+];
+
 pub struct Shell {
     pub context: Rc<Context>,
     job_id: RefCell<u32>,
@@ -183,12 +187,16 @@ impl Runner for Shell {
             ExecutionContext::Postgres => {
                 self.check_build_prereqs(&POSTGRES_BIN_REQUIRED)
             },
+            ExecutionContext::Redis => {
+                self.check_build_prereqs(&REDIS_BIN_REQUIRED)
+            },
         }
     }
 
     fn run(&self, context: ExecutionContext, env: Vec<&str>, _include_source: bool, script: &str) -> Result<(), Error> {
         match context {
             ExecutionContext::Postgres => Err(error!("postgres cannot be run in foreground")),
+            ExecutionContext::Redis => Err(error!("redis cannot be run in foreground")),
             _ => self.run_shell(env, script),
         }
     }
@@ -196,6 +204,8 @@ impl Runner for Shell {
     fn run_background(&self, context: ExecutionContext, env: Vec<&str>, _include_source: bool, script: &str) -> Result<Box<dyn BackgroundServer>, Error> {
         match context {
             ExecutionContext::Postgres => self.run_postgres_background(env),
+            // the cache need not persist
+            ExecutionContext::Redis => self.run_shell_background(env, "redis-server --save '' --appendonly no"),
             _ => self.run_shell_background(env, script),
         }
     }
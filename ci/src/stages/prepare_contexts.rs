@@ -9,10 +9,11 @@ impl Stage for PrepareContexts {
 
     // run builds test and build images
     fn run(&self, context: &Context, runner: &dyn Runner) -> Result<(), Error> {
-        let exec_contexts: [ExecutionContext; 3] = [
+        let exec_contexts: [ExecutionContext; 4] = [
             ExecutionContext::Build,
             ExecutionContext::E2E,
             ExecutionContext::Postgres,
+            ExecutionContext::Redis,
         ];
 
         for exec_context in exec_contexts {
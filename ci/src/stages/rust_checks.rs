@@ -15,10 +15,19 @@ impl Stage for RustChecks {
             false,
             "",
         )?;
+        let cache = runner.run_background(
+            ExecutionContext::Redis,
+            vec![],
+            false,
+            "",
+        )?;
 
         runner.run(
             ExecutionContext::Build,
-            vec![&format!("WIKI_CI_TEST_POSTGRES_HOST={}", &db.addr())],
+            vec![
+                &format!("WIKI_CI_TEST_POSTGRES_HOST={}", &db.addr()),
+                &format!("WIKI_CI_TEST_REDIS_URL=redis://{}", &cache.addr()),
+            ],
             true,
             r"
                 set -xe
@@ -6,13 +6,13 @@ import { user } from "../../store/user";
 export const subject = {
     list() {
         return axios.get(config.apiBaseUrl + "/subjects/").then((response) => {
-            return response.data.split("\n").map((v) => decodeURIComponent(v));
+            return response.data.split("\n");
         });
     },
 
     get(subject) {
         return axios
-            .get(config.apiBaseUrl + "/subject/" + subject)
+            .get(config.apiBaseUrl + "/subject/" + encodeURIComponent(subject))
             .then((response) => response.data)
             .catch((err) => {
                 if (err.response.status == 404) {
@@ -23,38 +23,58 @@ export const subject = {
             });
     },
 
-    put(subject, content) {
-        let url = config.apiBaseUrl + "/subject/" + subject;
-        return doAuthRequest(axios.patch, url, content).catch((err) => {
+    // edit optionally says why: { summary, minor }
+    put(subject, content, edit = {}) {
+        let url = config.apiBaseUrl + "/subject/" + encodeURIComponent(subject);
+        // the same key on retries, so that the server applies the edit once
+        let key = crypto.randomUUID();
+        return doAuthRequest(axios.patch, url, content, key, edit).catch((err) => {
             if (!err.response || err.response.status != 401) {
                 throw err;
             }
             return user.signIn(user.username(), "", user.refresh()).then(() => {
                 console.log("signed back in " + user.username());
-                return doAuthRequest(axios.patch, url, content);
+                return doAuthRequest(axios.patch, url, content, key, edit);
             });
         });
     },
 
-    post(subject, content) {
-        let url = config.apiBaseUrl + "/subject/" + subject;
-        return doAuthRequest(axios.post, url, content).catch((err) => {
-            if (!err.response || err.response.status != 401) {
+    post(subject, content, edit = {}) {
+        let url = config.apiBaseUrl + "/subject/" + encodeURIComponent(subject);
+        let key = crypto.randomUUID();
+        return doAuthRequest(axios.post, url, content, key, edit)
+            .catch((err) => {
+                if (!err.response || err.response.status != 401) {
+                    throw err;
+                }
+                return user
+                    .signIn(user.username(), "", user.refresh())
+                    .then(() => {
+                        console.log("signed back in " + user.username());
+                        return doAuthRequest(axios.post, url, content, key, edit);
+                    });
+            })
+            .catch((err) => {
+                if (err.response && err.response.status == 409) {
+                    throw new Error("subject already exists");
+                }
                 throw err;
-            }
-            return user.signIn(user.username(), "", user.refresh()).then(() => {
-                console.log("signed back in " + user.username());
-                return doAuthRequest(axios.post, url, content);
             });
-        });
     },
 };
 
-function doAuthRequest(axiosHelper, url, content) {
-    return axiosHelper(url, content, {
-        headers: {
-            Authorization: "Bearer " + user.token(),
-            "Content-Type": "text/plain",
-        },
-    });
+function doAuthRequest(axiosHelper, url, content, idempotencyKey, edit) {
+    let headers = {
+        Authorization: "Bearer " + user.token(),
+        "Content-Type": "text/plain",
+        "Idempotency-Key": idempotencyKey,
+    };
+    // header values are ASCII, the server percent-decodes the summary
+    if (edit.summary) {
+        headers["X-Edit-Summary"] = encodeURIComponent(edit.summary);
+    }
+    if (edit.minor) {
+        headers["X-Minor-Edit"] = "true";
+    }
+    return axiosHelper(url, content, { headers });
 }
@@ -1,19 +1,75 @@
-/* eslint no-unused-vars: "off" */
+import axios from "axios";
+
+import config from "../../config.json";
 
 export const user = {
     signIn(username, password, refresh) {
-        return new Promise((resolve, reject) => {
-            reject(new Error("unimplemented"));
-        });
+        let request =
+            refresh != ""
+                ? axios.post(config.authBaseUrl + "/refresh", { refresh })
+                : axios.post(config.authBaseUrl + "/sign-in", {
+                      username,
+                      password,
+                  });
+        return request
+            .then((response) => {
+                current = response.data;
+                return response.data;
+            })
+            .catch(unauthorized);
     },
 
     signOut() {
-        throw new Error("unimplemented");
+        if (!current) {
+            return;
+        }
+        let tokens = current;
+        current = null;
+        axios
+            .post(
+                config.authBaseUrl + "/sign-out",
+                { refresh: tokens.refresh },
+                {
+                    headers: {
+                        Authorization: "Bearer " + tokens.token,
+                    },
+                },
+            )
+            .catch((err) => {
+                console.error(err);
+            });
+    },
+
+    signInWithCode(code) {
+        return axios
+            .post(config.authBaseUrl + "/oidc/token", { code })
+            .then((response) => {
+                current = response.data;
+                return response.data;
+            })
+            .catch(unauthorized);
+    },
+
+    providerUrl() {
+        return config.authBaseUrl + "/oidc/login";
     },
 
     signUp(username, password) {
-        return new Promise((resolve, reject) => {
-            reject(new Error("unimplemented"));
-        });
+        return axios
+            .post(config.authBaseUrl + "/sign-up", { username, password })
+            .then((response) => {
+                current = response.data;
+                return response.data;
+            })
+            .catch(unauthorized);
     },
 };
+
+let current = null;
+
+function unauthorized(err) {
+    if (err.response && err.response.status == 401) {
+        throw new Error("unauthorized");
+    }
+    throw err;
+}
@@ -84,6 +84,7 @@ export class SignedOutUser extends HTMLElement {
             <input id="password" type="password" autocomplete="on" />
             <button onclick="signIn()">Sign In</button>
             <button onclick="signUp()">Sign Up</button>
+            <a href="${user.providerUrl()}">Sign In With Provider</a>
             <span style="color: red;display: none">Unauthorized</span>
         `,
             {
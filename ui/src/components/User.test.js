@@ -27,6 +27,14 @@ describe("User component", () => {
         assert(wikiUser.querySelector("wiki-signed-out-user"));
     });
 
+    test("signed out links to provider's sign in", () => {
+        let wikiUser = document.createElement("wiki-user");
+        document.body.appendChild(wikiUser);
+
+        let link = wikiUser.querySelector("a");
+        assert(link.getAttribute("href").startsWith("#oidc-code="));
+    });
+
     test("signed in shows signed in component", async () => {
         window.user.signIn("alice", "somepass");
 
@@ -1,3 +1,5 @@
+// the user store takes a provider's code before the app routes its location
+import "./store/user";
 import "./components/App";
 import "./components/EditSubject";
 import "./components/ListSubjects";
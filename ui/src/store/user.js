@@ -6,6 +6,9 @@ export const signal = "user";
 
 const localStorageKey = "user";
 
+// codeParameter holds the code the provider's sign in returns to the UI with
+const codeParameter = "oidc-code";
+
 const userStore = store(
     {
         username: "",
@@ -50,6 +53,23 @@ export const user = {
         userStore.signOut();
     },
 
+    signInWithCode(code) {
+        return auth
+            .signInWithCode(code)
+            .then((v) => {
+                setPersistentUser(v.username, v.refresh);
+                userStore.signIn(v.username, v.token, v.refresh);
+            })
+            .catch((err) => {
+                this.signOut();
+                throw err;
+            });
+    },
+
+    providerUrl() {
+        return auth.providerUrl();
+    },
+
     signUp(username, password) {
         return auth.signUp(username, password).then((v) => {
             setPersistentUser(username, v.refresh);
@@ -88,8 +108,34 @@ function clearPersistentUser() {
     localStorage.removeItem(localStorageKey);
 }
 
-// Check for existing user when app starts
+// takeCode removes the provider's code from the location, so that it is
+// neither used again nor routed
+function takeCode() {
+    let params = new URLSearchParams(location.hash.substring(1));
+    let code = params.get(codeParameter);
+    if (code) {
+        history.replaceState(null, "", location.pathname + location.search);
+    }
+    return code;
+}
+
+function signInWithTakenCode() {
+    let code = takeCode();
+    if (code) {
+        user.signInWithCode(code).catch((err) => {
+            console.error(err);
+        });
+    }
+    return code;
+}
+
+addEventListener("hashchange", signInWithTakenCode);
+
+// Check for a returning provider sign in or existing user when app starts
 (() => {
+    if (signInWithTakenCode()) {
+        return;
+    }
     let u = getPersistentUser();
     if (u) {
         user.signIn(u.username, "", u.refresh).catch((err) => {
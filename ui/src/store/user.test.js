@@ -2,6 +2,7 @@ import assert from "node:assert";
 import { describe, beforeEach, test } from "node:test";
 
 import { DOM } from "../test-helpers/dom.js";
+import { providerCode } from "../test-helpers/mock/auth.js";
 import { waitFor } from "../test-helpers/waitFor.js";
 
 describe("user store", () => {
@@ -85,6 +86,48 @@ describe("user store", () => {
         await waitFor(() => passed, document);
     });
 
+    test("sign in with provider's code", async () => {
+        let passed = false;
+        window.user.signInWithCode(providerCode("bob", "bobpass")).then(() => {
+            passed = true;
+        });
+
+        await waitFor(() => passed, document);
+
+        assert(window.user.username() == "bob");
+        assert(window.localStorage.getItem("user"));
+    });
+
+    test("sign in with expired provider's code fails", async () => {
+        let err;
+        window.user
+            .signInWithCode(providerCode("bob", "bobpass", Date.now() - 1))
+            .catch((e) => {
+                err = e;
+            });
+
+        await waitFor(() => {
+            return err !== undefined && err.message == "unauthorized";
+        }, document);
+    });
+
+    test("sign in with provider's code in location", async () => {
+        let code = providerCode("alice", "somepass");
+        let dom = new DOM(`http://localhost/#oidc-code=${code}`);
+        dom.addScript(`
+            import { user } from "./src/store/user";
+
+            window.user = user;
+        `);
+
+        await waitFor(
+            () => dom.window.user.username() == "alice",
+            dom.window.document,
+        );
+
+        assert(dom.window.location.hash == "");
+    });
+
     test("sign up with invalid password fails", async () => {
         let err;
         window.user.signUp("newUser", "badpass").catch((e) => {
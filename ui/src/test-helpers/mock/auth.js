@@ -7,6 +7,11 @@ export function newUser() {
     };
 }
 
+// providerCode is a code the mock provider returns to the UI with
+export function providerCode(name, pass, expiration = Date.now() + 60000) {
+    return btoa(JSON.stringify({ username: name, password: pass, expiration }));
+}
+
 export function existingUser() {
     return {
         name: "bob",
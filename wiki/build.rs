@@ -1,5 +1,9 @@
 use std::{collections::HashMap, ffi::OsStr, fs::{self, File}, io::Write, path::Path, process::Command};
 
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
 const UI_SRC: &str = "../ui";
 const DIST_DIR: &str = "../ui/dist";
 const DIST_FILE: &str = "src/dist.rs";
@@ -28,6 +32,7 @@ fn main() {
     build_ui(&build_options);
 
     let assets = build_assets(Path::new(DIST_DIR));
+    let script_hashes = inline_script_hashes(&assets);
     let mut m = phf_codegen::Map::new();
     for asset in assets {
         m.entry(
@@ -46,6 +51,31 @@ fn main() {
         "pub static DIST: phf::Map<&'static str, crate::spa_server::Asset> = \n{};\n",
         m.build(),
     ).unwrap();
+    writeln!(
+        &mut out_file,
+        "/// CSP sources of the inline scripts in DIST\npub static SCRIPT_HASHES: &[&str] = &{:?};",
+        script_hashes,
+    ).unwrap();
+}
+
+// inline_script_hashes returns the CSP hash source of each inline script in
+// the HTML assets, so that the Content-Security-Policy allows them and no
+// others
+fn inline_script_hashes(assets: &[Asset]) -> Vec<String> {
+    let re = Regex::new(r"(?is)<script([^>]*)>(.*?)</script>").unwrap();
+    let mut hashes: Vec<String> = assets.iter()
+        .filter(|a| a.content_type == "text/html")
+        .flat_map(|a| {
+            let html = String::from_utf8_lossy(&a.content);
+            re.captures_iter(&html)
+                .filter(|c| !c[1].to_lowercase().contains("src="))
+                .map(|c| format!("'sha256-{}'", STANDARD.encode(Sha256::digest(c[2].as_bytes()))))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    hashes.sort();
+    hashes.dedup();
+    hashes
 }
 
 fn walk_dir(dir: &Path, f: &mut dyn FnMut(&Path)) {
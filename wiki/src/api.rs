@@ -1,8 +1,142 @@
-use warp::{reject::Rejection, Filter};
+use std::sync::Arc;
 
+use warp::{filters::{path::Peek, BoxedFilter}, http::{header::{HeaderName, HeaderValue, LINK}, HeaderMap}, reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::user::Users, error::Error};
+
+use self::{draft::Drafts, idempotency::{Idempotency, Keys}, link::Links, profile::Profiles, stats::{Stats, Views}, subject::Subjects, transclusion::Renders};
+
+pub mod batch;
+pub mod draft;
+pub mod edit;
+pub mod idempotency;
+pub mod link;
+pub mod openapi;
+pub mod profile;
+pub mod section;
+pub mod stats;
 pub mod subject;
+pub mod title;
+pub mod transclusion;
+pub mod v2;
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// When v2 superseded v1, in seconds since the unix epoch
+const V1_DEPRECATED_AT: u64 = 1792368000;
 
 pub fn filter() -> impl Filter<Extract = (), Error = Rejection> + Clone
 {
     warp::path!("api" / "v1" / ..)
 }
+
+/// Matches the path after the next segment, such as a title or username,
+/// without consuming it. Routes check it before endpoints, which recover every
+/// rejection, including those of a path that does not match.
+pub fn with_tail(tail: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone
+{
+    warp::path::peek()
+        .and_then(move |peek: Peek| async move {
+            match peek.as_str().split_once('/') {
+                Some((_, t)) if t == tail => Ok(()),
+                None if tail.is_empty() => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+}
+
+/// Stores of the v1 routes. The wiki keeps them all in its database, tests
+/// keep each in memory.
+pub struct Stores<S, D, R, P, T, L> {
+    pub subjects: Arc<S>,
+    pub drafts: Arc<D>,
+    pub renders: Arc<R>,
+    pub profiles: Arc<P>,
+    pub stats: Arc<T>,
+    pub links: Arc<L>,
+}
+
+/// Settings of the routes of both versions
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub max_title_length: usize,
+    pub max_transclusion_depth: usize,
+    /// Whether the OpenAPI document is served with a viewer at /docs
+    pub openapi_viewer: bool,
+}
+
+/// Routes of every v1 module, under /api/v1, and its OpenAPI document. Boxed,
+/// or their type nests too deep for the compiler.
+pub fn routes<S, D, R, P, T, L, U, K>(stores: Stores<S, D, R, P, T, L>, views: Arc<Views>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, settings: Settings) -> BoxedFilter<(Box<dyn Reply>,)>
+where
+    S: Subjects + Send + Sync + 'static,
+    D: Drafts + Send + Sync + 'static,
+    R: Renders + Send + Sync + 'static,
+    P: Profiles + Send + Sync + 'static,
+    T: Stats + Send + Sync + 'static,
+    L: Links + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+    K: Keys + Send + Sync + 'static,
+{
+    let Stores { subjects, drafts, renders, profiles, stats, links } = stores;
+    let max_title_length = settings.max_title_length;
+    subject::filter(subjects.clone(), views, users.clone(), idempotency.clone(), max_title_length)
+        .or(batch::filter(subjects.clone(), users.clone(), idempotency.clone(), max_title_length))
+        .or(draft::filter(drafts, subjects.clone(), users.clone(), idempotency.clone(), max_title_length))
+        .or(section::filter(subjects.clone(), users.clone(), idempotency, max_title_length))
+        .or(transclusion::filter(subjects, renders, settings.max_transclusion_depth, max_title_length))
+        .or(profile::filter(profiles, users))
+        .or(stats::filter(stats, max_title_length))
+        .or(link::filter(links))
+        .or(openapi::filter(Arc::new(openapi::document(&operations(), "/api/v1", true)), settings.openapi_viewer))
+        .map(|r| Box::new(r) as Box<dyn Reply>)
+        .boxed()
+}
+
+/// Operations of every API module, which the OpenAPI document describes
+pub fn operations() -> Vec<openapi::Operation> {
+    subject::operations().into_iter()
+        .chain(batch::operations())
+        .chain(draft::operations())
+        .chain(section::operations())
+        .chain(transclusion::operations())
+        .chain(profile::operations())
+        .chain(stats::operations())
+        .chain(link::operations())
+        .chain(openapi::operations())
+        .collect()
+}
+
+/// Headers of every v1 reply, which announce its deprecation (RFC 9745) and
+/// its successor, and with sunset the HTTP-date it is removed (RFC 8594).
+pub fn deprecation(sunset: Option<&str>) -> Result<HeaderMap, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(DEPRECATION, HeaderValue::from_str(&format!("@{}", V1_DEPRECATED_AT)).unwrap());
+    headers.insert(LINK, HeaderValue::from_static("</api/v2>; rel=\"successor-version\""));
+    if let Some(sunset) = sunset {
+        headers.insert(SUNSET, HeaderValue::from_str(sunset)
+            .map_err(|e| Error::BadRequest(format!("bad sunset {}: {}", sunset, e)))?);
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deprecation() {
+        let headers = deprecation(None).unwrap();
+        assert_eq!(headers[DEPRECATION], "@1792368000");
+        assert_eq!(headers[LINK], "</api/v2>; rel=\"successor-version\"");
+        assert!(!headers.contains_key(SUNSET));
+
+        let headers = deprecation(Some("Wed, 01 Dec 2027 00:00:00 GMT")).unwrap();
+        assert_eq!(headers[SUNSET], "Wed, 01 Dec 2027 00:00:00 GMT");
+
+        let r = deprecation(Some("bad\ndate"));
+        assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+    }
+}
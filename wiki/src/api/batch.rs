@@ -0,0 +1,360 @@
+// batch applies many changes to subjects at once, all or nothing. Changes are
+// applied in order in one unit of work, and the first that fails rolls back
+// those before it and skips those after it. Rolled back batches reply 409
+// whatever failed, so that their status tells their body from a problem's.
+
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::{http::Method, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{edit::Edit, idempotency::{self, Idempotency, Keys}, openapi::{Body, Operation, Response}, subject::Subjects}, auth::user::{Scope, Users}, error::{self, Problem}};
+
+/// Most changes a batch may hold
+pub const MAX_CHANGES: usize = 500;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Batch {
+    pub operations: Vec<Change>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Change {
+    Create {
+        title: String,
+        content: String,
+        #[serde(flatten)]
+        edit: Edit,
+    },
+    Update {
+        title: String,
+        content: String,
+        #[serde(flatten)]
+        edit: Edit,
+    },
+    Delete { title: String },
+}
+
+impl Change {
+    fn title(&self) -> &str {
+        match self {
+            Change::Create { title, .. } | Change::Update { title, .. } | Change::Delete { title } => title,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct BatchResult {
+    /// Whether the changes were applied, all of them or none
+    pub committed: bool,
+    /// Results in the order of the changes
+    pub results: Vec<ChangeResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ChangeResult {
+    /// Title as it was sent
+    pub title: String,
+    /// Status the change would have replied on its own, 424 for changes
+    /// that were rolled back or skipped because another failed
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<Problem>,
+}
+
+pub fn filter<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    S: Subjects + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+    K: Keys + Send + Sync + 'static,
+{
+    warp::path!("batch")
+        .and(
+            warp::post().and(endpoints::apply(subjects, users, idempotency, max_title_length))
+            .or(error::method_not_allowed(&[Method::POST]))
+        )
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::POST,
+            path: "/batch",
+            id: "applyBatch",
+            summary: "Creates, updates and deletes subjects in order, all or nothing",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: idempotency::HEADERS,
+            request: Some(Body::json::<Batch>()),
+            responses: vec![
+                Response::ok(200, "Committed, with the result of each change", Some(Body::json::<BatchResult>())),
+                Response::ok(409, "Rolled back, with the status of each change in its result, such as 400, 404 or 409 of the failed change", Some(Body::json::<BatchResult>())),
+                Response::problem(400, "No changes"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(413, "Too many changes"),
+            ],
+        },
+    ]
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{api::{idempotency::{self, Idempotency, Keys}, subject::Subjects}, auth::user::{with_authorization, Scope, Users}, error};
+
+    use super::handlers;
+
+    pub fn apply<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+        K: Keys + Send + Sync + 'static,
+    {
+        warp::any().map(move || subjects.clone())
+            .and(with_authorization(users, Scope::Write))
+            .and(warp::body::json())
+            .and(warp::any().map(move || max_title_length))
+            .and(idempotency::attempt(idempotency))
+            .and_then(handlers::apply)
+            .recover(error::recover)
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{api::{idempotency::{Attempt, Keys}, subject::{Subjects, UnitOfWork}, title::Title}, auth::user::User, error::{Error, Problem}};
+
+    use super::{Batch, BatchResult, Change, ChangeResult, MAX_CHANGES};
+
+    pub async fn apply<S: Subjects, K: Keys>(subjects: Arc<S>, user: User, batch: Batch, max_title_length: usize, attempt: Attempt<K>) -> Result<impl Reply, Rejection> {
+        let body = serde_json::to_vec(&batch).unwrap_or_default();
+        attempt.run(&user.name, &body, async {
+            if batch.operations.is_empty() {
+                return Err(Error::BadRequest("no operations".into()));
+            }
+            if batch.operations.len() > MAX_CHANGES {
+                return Err(Error::PayloadTooLarge(format!(
+                    "{} operations, the most allowed is {}", batch.operations.len(), MAX_CHANGES,
+                )));
+            }
+
+            let mut uow = subjects.begin().await?;
+            let mut statuses = Vec::with_capacity(batch.operations.len());
+            for change in &batch.operations {
+                match apply_change(&mut uow, &user.name, change, max_title_length).await {
+                    Ok(status) => statuses.push(status),
+                    // dropping the unit of work rolls it back
+                    Err(err) => {
+                        let result = rolled_back(&batch, statuses.len(), err.problem());
+                        return Ok(warp::reply::with_status(warp::reply::json(&result), StatusCode::CONFLICT));
+                    },
+                }
+            }
+            uow.commit().await?;
+
+            let result = BatchResult {
+                committed: true,
+                results: batch.operations.iter().zip(statuses)
+                    .map(|(change, status)| ChangeResult {
+                        title: change.title().to_string(),
+                        status: status.as_u16(),
+                        problem: None,
+                    })
+                    .collect(),
+            };
+            Ok(warp::reply::with_status(warp::reply::json(&result), StatusCode::OK))
+        }).await
+    }
+
+    async fn apply_change<W: UnitOfWork>(uow: &mut W, user: &str, change: &Change, max_title_length: usize) -> Result<StatusCode, Error> {
+        let title = Title::parse(change.title(), max_title_length)?;
+        match change {
+            Change::Create { content, edit, .. } => {
+                require_content(content)?;
+                edit.validate()?;
+                uow.create(user, &title, content, edit).await?;
+                Ok(StatusCode::CREATED)
+            },
+            Change::Update { content, edit, .. } => {
+                require_content(content)?;
+                edit.validate()?;
+                uow.update(user, &title, content, edit).await?;
+                Ok(StatusCode::OK)
+            },
+            Change::Delete { .. } => {
+                uow.delete(&title).await?;
+                Ok(StatusCode::NO_CONTENT)
+            },
+        }
+    }
+
+    fn require_content(content: &str) -> Result<(), Error> {
+        if content.is_empty() {
+            Err(Error::BadRequest("no content".into()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn rolled_back(batch: &Batch, failed: usize, problem: Problem) -> BatchResult {
+        BatchResult {
+            committed: false,
+            results: batch.operations.iter().enumerate()
+                .map(|(i, change)| {
+                    let problem = if i == failed {
+                        problem.clone()
+                    } else {
+                        let detail = if i < failed { "rolled back" } else { "not applied" };
+                        Problem::new(StatusCode::FAILED_DEPENDENCY, "failed_dependency", Some(format!("{}, operation {} failed", detail, failed)))
+                    };
+                    ChangeResult {
+                        title: change.title().to_string(),
+                        status: problem.status,
+                        problem: Some(problem),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules, with subjects in memory.
+///
+/// Test plan:
+/// 1. Good batches apply every change and reply their results
+/// 2. A failing change rolls back the batch and replies 409, as documented,
+///    with its problem
+/// 3. Bad batches reply with problems and change nothing
+/// 4. Reject bad methods with 405 and the allowed methods
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use crate::{api::{idempotency::tests::new_idempotency, subject::tests::MemorySubjects, title::MAX_LENGTH}, auth::mock_user};
+
+    use super::*;
+
+    fn new_filter(subjects: Arc<MemorySubjects>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        filter(subjects, Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH)
+    }
+
+    fn post(body: serde_json::Value) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method("POST")
+            .path("/batch")
+            .header("Authorization", "Basic bob:pass")
+            .json(&body)
+    }
+
+    fn statuses(result: &BatchResult) -> Vec<u16> {
+        result.results.iter().map(|r| r.status).collect()
+    }
+
+    #[tokio::test]
+    async fn test_good_batches() {
+        let subjects = Arc::new(MemorySubjects::with(&[("Old", "old"), ("Gone", "soon")]));
+        let f = new_filter(subjects.clone());
+
+        let res = post(json!({"operations": [
+            {"op": "create", "title": "New_subject", "content": "new"},
+            {"op": "update", "title": "old", "content": "renewed", "summary": "Renew", "minor": true},
+            {"op": "delete", "title": "Gone"},
+            {"op": "update", "title": "New subject", "content": "newer"},
+        ]})).reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let result: BatchResult = serde_json::from_slice(res.body()).unwrap();
+        assert!(result.committed);
+        assert_eq!(statuses(&result), vec![201, 200, 204, 200]);
+        assert_eq!(result.results[0].title, "New_subject");
+        assert!(result.results.iter().all(|r| r.problem.is_none()));
+
+        assert_eq!(subjects.content("New subject").as_deref(), Some("newer"));
+        assert_eq!(subjects.content("Old").as_deref(), Some("renewed"));
+        assert_eq!(subjects.content("Gone"), None);
+        assert_eq!(subjects.edits()[1], ("Old".into(), Edit { summary: "Renew".into(), minor: true }));
+    }
+
+    #[tokio::test]
+    async fn test_failures_roll_back() {
+        let subjects = Arc::new(MemorySubjects::with(&[("Old", "old")]));
+        let f = new_filter(subjects.clone());
+
+        for (operations, status, code) in [
+            (json!([
+                {"op": "update", "title": "Old", "content": "changed"},
+                {"op": "create", "title": "OLD", "content": "again"},
+                {"op": "create", "title": "Other", "content": "other"},
+            ]), StatusCode::CONFLICT, "conflict"),
+            (json!([
+                {"op": "update", "title": "Old", "content": "changed"},
+                {"op": "delete", "title": "Missing"},
+                {"op": "create", "title": "Other", "content": "other"},
+            ]), StatusCode::NOT_FOUND, "not_found"),
+            (json!([
+                {"op": "update", "title": "Old", "content": "changed"},
+                {"op": "create", "title": "a/b", "content": "bad title"},
+                {"op": "create", "title": "Other", "content": "other"},
+            ]), StatusCode::BAD_REQUEST, "bad_request"),
+        ] {
+            let res = post(json!({"operations": operations})).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+            let result: BatchResult = serde_json::from_slice(res.body()).unwrap();
+            assert!(!result.committed);
+            assert_eq!(statuses(&result), vec![424, status.as_u16(), 424]);
+            assert_eq!(result.results[1].problem.as_ref().unwrap().code, code);
+            assert_eq!(result.results[0].problem.as_ref().unwrap().detail.as_deref(), Some("rolled back, operation 1 failed"));
+            assert_eq!(result.results[2].problem.as_ref().unwrap().detail.as_deref(), Some("not applied, operation 1 failed"));
+
+            assert_eq!(subjects.content("Old").as_deref(), Some("old"));
+            assert_eq!(subjects.content("Other"), None);
+        }
+
+        let documented: Vec<u16> = operations()[0].responses.iter()
+            .filter(|r| r.body.is_some() && r.status != 200)
+            .map(|r| r.status)
+            .collect();
+        assert_eq!(documented, vec![StatusCode::CONFLICT.as_u16()]);
+    }
+
+    #[tokio::test]
+    async fn test_bad_batches() {
+        let subjects = Arc::new(MemorySubjects::default());
+        let f = new_filter(subjects.clone());
+
+        let too_many: Vec<_> = (0..=MAX_CHANGES)
+            .map(|i| json!({"op": "create", "title": format!("S{}", i), "content": "x"}))
+            .collect();
+        for (req, status, code) in [
+            (post(json!({"operations": []})), StatusCode::BAD_REQUEST, "bad_request"),
+            (post(json!({"operations": too_many})), StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+            (post(json!({"operations": [{"op": "rename", "title": "A"}]})), StatusCode::BAD_REQUEST, "invalid_body"),
+            (post(json!({"operations": [{"op": "create", "title": "A"}]})), StatusCode::BAD_REQUEST, "invalid_body"),
+            (warp::test::request().method("POST").path("/batch").json(&json!({"operations": []})), StatusCode::UNAUTHORIZED, "unauthorized"),
+        ] {
+            let res = req.reply(&f).await;
+            assert_eq!(res.status(), status);
+            assert_eq!(res.headers()["content-type"], "application/problem+json");
+            let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(problem.code, code);
+        }
+        assert!(subjects.subjects.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reject_bad_methods() {
+        let f = new_filter(Arc::new(MemorySubjects::default()));
+        for m in ["GET", "PUT", "DELETE"] {
+            let res = warp::test::request().method(m).path("/batch").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", m);
+            assert_eq!(res.headers()["allow"], "POST");
+        }
+    }
+}
@@ -0,0 +1,567 @@
+// draft keeps unpublished content of subjects, one draft per user and subject.
+// Drafts are private to their author until published, which turns the draft
+// into the content of the subject, creating the subject if need be. Drafts
+// remember the content they started from, and are not published over changes
+// others made since.
+
+use std::{future::Future, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::{http::Method, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{edit, idempotency::{Idempotency, Keys}, openapi::{Body, Operation, Response}, section, subject::Subjects, title::{self, Title}, with_tail}, auth::user::{Scope, Users}, error::{self, Error}};
+
+/// Drafts are keyed by username and canonical title, compared
+/// case-insensitively like subjects.
+pub trait Drafts {
+    /// Lists the drafts of username, most recently saved first
+    fn list_drafts(&self, username: &str) -> impl Future<Output = Result<Vec<Draft>, Error>> + Send;
+    fn read_draft(&self, username: &str, title: &Title) -> impl Future<Output = Result<Draft, Error>> + Send;
+    /// Saves the draft, replacing any draft username has of the subject. The
+    /// base of a draft is that of its first save.
+    fn save_draft(&self, username: &str, title: &Title, content: &str, base: Option<&str>) -> impl Future<Output = Result<(), Error>> + Send;
+    fn delete_draft(&self, username: &str, title: &Title) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Draft {
+    pub title: String,
+    pub content: String,
+    pub saved_at: SystemTime,
+    /// ETag of the content of the subject when the draft was started, None
+    /// if the subject did not exist
+    pub base: Option<String>,
+}
+
+/// Base of a draft of a subject with content, None if it does not exist
+fn base(content: Option<&str>) -> Option<String> {
+    content.map(section::etag)
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DraftInfo {
+    pub title: String,
+    /// Path segment of the subject
+    pub slug: String,
+    /// Seconds since the unix epoch
+    pub saved_at: u64,
+}
+
+impl From<&Draft> for DraftInfo {
+    fn from(d: &Draft) -> Self {
+        DraftInfo {
+            title: d.title.clone(),
+            slug: title::slug(&d.title),
+            saved_at: d.saved_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }
+    }
+}
+
+pub fn filter<D, S, U, K>(drafts: Arc<D>, subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    D: Drafts + Send + Sync + 'static,
+    S: Subjects + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+    K: Keys + Send + Sync + 'static,
+{
+    warp::path!("subject" / ..)
+        .and(
+            with_tail("draft").and(warp::get()).and(endpoints::read(drafts.clone(), users.clone(), max_title_length))
+            .or(with_tail("draft").and(warp::put()).and(endpoints::save(drafts.clone(), subjects.clone(), users.clone(), max_title_length)))
+            .or(with_tail("draft").and(warp::delete()).and(endpoints::delete(drafts.clone(), users.clone(), max_title_length)))
+            .or(with_tail("draft/publish").and(warp::post()).and(endpoints::publish(drafts.clone(), subjects, users.clone(), idempotency, max_title_length)))
+            .or(with_tail("draft").and(error::method_not_allowed(&[Method::GET, Method::PUT, Method::DELETE])))
+            .or(with_tail("draft/publish").and(error::method_not_allowed(&[Method::POST])))
+        )
+        .or(
+            warp::path!("drafts")
+                .and(
+                    warp::get().and(endpoints::list(drafts, users))
+                    .or(error::method_not_allowed(&[Method::GET]))
+                )
+        )
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::GET,
+            path: "/drafts",
+            id: "listDrafts",
+            summary: "Lists the drafts of the signed in user, most recently saved first",
+            scope: Some(Scope::Read),
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Drafts", Some(Body::json::<Vec<DraftInfo>>())),
+                Response::problem(401, "Not signed in"),
+            ],
+        },
+        Operation {
+            method: Method::GET,
+            path: "/subject/{title}/draft",
+            id: "readDraft",
+            summary: "Reads the draft of a subject by the signed in user",
+            scope: Some(Scope::Read),
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Content", Some(Body::Text)),
+                Response::problem(401, "Not signed in"),
+                Response::problem(404, "No such draft"),
+            ],
+        },
+        Operation {
+            method: Method::PUT,
+            path: "/subject/{title}/draft",
+            id: "saveDraft",
+            summary: "Saves a draft of a subject, which need not exist yet",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: &[],
+            request: Some(Body::Text),
+            responses: vec![
+                Response::ok(204, "Saved", None),
+                Response::problem(400, "Bad title or no content"),
+                Response::problem(401, "Not signed in"),
+            ],
+        },
+        Operation {
+            method: Method::DELETE,
+            path: "/subject/{title}/draft",
+            id: "deleteDraft",
+            summary: "Discards a draft",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(204, "Deleted", None),
+                Response::problem(401, "Not signed in"),
+                Response::problem(404, "No such draft"),
+            ],
+        },
+        Operation {
+            method: Method::POST,
+            path: "/subject/{title}/draft/publish",
+            id: "publishDraft",
+            summary: "Makes a draft the content of its subject, creating the subject if need be, and discards the draft",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: edit::HEADERS,
+            request: None,
+            responses: vec![
+                Response::ok(200, "Subject updated", None),
+                Response::ok(201, "Subject created", None),
+                Response::problem(400, "Bad edit"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(404, "No such draft"),
+                Response::problem(412, "The subject changed since the draft was started"),
+            ],
+        },
+    ]
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use bytes::Bytes;
+    use warp::{reply::Reply, Filter};
+
+    use crate::{api::{edit, idempotency::{self, Idempotency, Keys}, subject::Subjects, title}, auth::user::{with_authorization, Scope, Users}, error};
+
+    use super::{handlers, Drafts};
+
+    pub fn list<D, U>(drafts: Arc<D>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        D: Drafts + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_drafts(drafts)
+            .and(with_authorization(users, Scope::Read))
+            .and_then(handlers::list)
+            .recover(error::recover)
+    }
+
+    pub fn read<D, U>(drafts: Arc<D>, users: Arc<U>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        D: Drafts + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        title::param(max_title_length)
+            .and(warp::path!("draft"))
+            .and(with_drafts(drafts))
+            .and(with_authorization(users, Scope::Read))
+            .and_then(handlers::read)
+            .recover(error::recover)
+    }
+
+    pub fn save<D, S, U>(drafts: Arc<D>, subjects: Arc<S>, users: Arc<U>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        D: Drafts + Send + Sync + 'static,
+        S: Subjects + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        title::param(max_title_length)
+            .and(warp::path!("draft"))
+            .and(with_drafts(drafts))
+            .and(warp::any().map(move || subjects.clone()))
+            .and(with_authorization(users, Scope::Write))
+            .and(warp::body::bytes().map(|body: Bytes| {
+                String::from_utf8_lossy(&body).to_string()
+            }))
+            .and_then(handlers::save)
+            .recover(error::recover)
+    }
+
+    pub fn delete<D, U>(drafts: Arc<D>, users: Arc<U>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        D: Drafts + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        title::param(max_title_length)
+            .and(warp::path!("draft"))
+            .and(with_drafts(drafts))
+            .and(with_authorization(users, Scope::Write))
+            .and_then(handlers::delete)
+            .recover(error::recover)
+    }
+
+    pub fn publish<D, S, U, K>(drafts: Arc<D>, subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        D: Drafts + Send + Sync + 'static,
+        S: Subjects + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+        K: Keys + Send + Sync + 'static,
+    {
+        title::param(max_title_length)
+            .and(warp::path!("draft" / "publish"))
+            .and(with_drafts(drafts))
+            .and(warp::any().map(move || subjects.clone()))
+            .and(with_authorization(users, Scope::Write))
+            .and(edit::headers())
+            .and(idempotency::attempt(idempotency))
+            .and_then(handlers::publish)
+            .recover(error::recover)
+    }
+
+    fn with_drafts<D>(drafts: Arc<D>) -> impl Filter<Extract = (Arc<D>,), Error = Infallible> + Clone
+    where
+        D: Drafts + Send + Sync + 'static
+    {
+        warp::any().map(move || drafts.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{api::{edit::Edit, idempotency::{Attempt, Keys}, subject::Subjects, title::Title}, auth::user::User, error::Error};
+
+    use super::{base, DraftInfo, Drafts};
+
+    pub async fn list<D: Drafts>(drafts: Arc<D>, user: User) -> Result<impl Reply, Rejection> {
+        match drafts.list_drafts(&user.name).await {
+            Ok(drafts) => Ok(warp::reply::json(&drafts.iter().map(DraftInfo::from).collect::<Vec<_>>())),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn read<D: Drafts>(title: Title, drafts: Arc<D>, user: User) -> Result<impl Reply, Rejection> {
+        match drafts.read_draft(&user.name, &title).await {
+            Ok(draft) => Ok(warp::reply::with_header(
+                draft.content,
+                "Content-Type",
+                "text/plain")),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn save<D: Drafts, S: Subjects>(title: Title, drafts: Arc<D>, subjects: Arc<S>, user: User, content: String) -> Result<impl Reply, Rejection> {
+        let r = async {
+            if content.is_empty() {
+                return Err(Error::BadRequest("no body".into()));
+            }
+            let current = match subjects.read(&title).await {
+                Ok(current) => Some(current),
+                Err(Error::NotFound(_)) => None,
+                Err(err) => return Err(err),
+            };
+            drafts.save_draft(&user.name, &title, &content, base(current.as_deref()).as_deref()).await
+        }.await;
+
+        match r {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn delete<D: Drafts>(title: Title, drafts: Arc<D>, user: User) -> Result<impl Reply, Rejection> {
+        match drafts.delete_draft(&user.name, &title).await {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn publish<D: Drafts, S: Subjects, K: Keys>(title: Title, drafts: Arc<D>, subjects: Arc<S>, user: User, edit: Edit, attempt: Attempt<K>) -> Result<impl Reply, Rejection> {
+        let body = serde_json::to_vec(&edit).unwrap_or_default();
+        attempt.run(&user.name, &body, async {
+            let draft = drafts.read_draft(&user.name, &title).await?;
+            let stale = || Error::PreconditionFailed(format!("{} changed since the draft was started", title));
+            let status = match &draft.base {
+                Some(_) => {
+                    let current = match subjects.read(&title).await {
+                        Ok(current) => current,
+                        Err(Error::NotFound(_)) => return Err(stale()),
+                        Err(err) => return Err(err),
+                    };
+                    if base(Some(&current)) != draft.base {
+                        return Err(stale());
+                    }
+                    // fails if another edit landed since the read
+                    subjects.update_if(&user.name, &title, &current, &draft.content, &edit).await?;
+                    StatusCode::OK
+                },
+                None => match subjects.create(&user.name, &title, &draft.content, &edit).await {
+                    Ok(()) => StatusCode::CREATED,
+                    Err(Error::Conflict(_)) => return Err(stale()),
+                    Err(err) => return Err(err),
+                },
+            };
+            // the subject holds the content now, so a draft left behind would
+            // only be stale
+            if let Err(err) = drafts.delete_draft(&user.name, &title).await {
+                log::warn!(target: "wiki::api", "published draft of {} by {} was not deleted: {:?}", title, user.name, err);
+            }
+            Ok(status)
+        }).await
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules, with drafts and subjects
+/// in memory. Exposes drafts in memory to test modules.
+///
+/// Test plan:
+/// 1. Drafts are saved, read, listed and deleted by their author
+/// 2. Drafts are private to their author
+/// 3. Publishing updates or creates the subject and discards the draft, unless
+///    the subject changed since the draft was started
+/// 4. Bad requests reply with problems
+/// 5. Reject bad methods with 405 and the allowed methods
+#[cfg(test)]
+pub mod tests {
+    use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+    use warp::http::StatusCode;
+
+    use crate::{api::{idempotency::tests::new_idempotency, subject::tests::MemorySubjects, title::MAX_LENGTH}, auth::mock_user, error::Problem};
+
+    use super::*;
+
+    /// MemoryDrafts keeps drafts by username and lowercase title
+    #[derive(Default)]
+    pub struct MemoryDrafts {
+        drafts: Mutex<BTreeMap<(String, String), Draft>>,
+    }
+
+    fn key(username: &str, title: &Title) -> (String, String) {
+        (username.to_string(), title.as_str().to_lowercase())
+    }
+
+    impl Drafts for MemoryDrafts {
+        async fn list_drafts(&self, username: &str) -> Result<Vec<Draft>, Error> {
+            let mut drafts: Vec<Draft> = self.drafts.lock().unwrap().iter()
+                .filter(|((u, _), _)| u == username)
+                .map(|(_, d)| d.clone())
+                .collect();
+            drafts.sort_by_key(|d| std::cmp::Reverse(d.saved_at));
+            Ok(drafts)
+        }
+
+        async fn read_draft(&self, username: &str, title: &Title) -> Result<Draft, Error> {
+            self.drafts.lock().unwrap().get(&key(username, title))
+                .cloned()
+                .ok_or_else(|| Error::NotFound(format!("draft of {} by {}", title, username)))
+        }
+
+        async fn save_draft(&self, username: &str, title: &Title, content: &str, base: Option<&str>) -> Result<(), Error> {
+            // saves are apart in time, so that they list in order
+            let mut drafts = self.drafts.lock().unwrap();
+            let saved_at = drafts.values()
+                .map(|d| d.saved_at + Duration::from_secs(1))
+                .max()
+                .unwrap_or(UNIX_EPOCH);
+            let base = match drafts.get(&key(username, title)) {
+                Some(draft) => draft.base.clone(),
+                None => base.map(String::from),
+            };
+            drafts.insert(key(username, title), Draft {
+                title: title.to_string(),
+                content: content.to_string(),
+                saved_at,
+                base,
+            });
+            Ok(())
+        }
+
+        async fn delete_draft(&self, username: &str, title: &Title) -> Result<(), Error> {
+            match self.drafts.lock().unwrap().remove(&key(username, title)) {
+                Some(_) => Ok(()),
+                None => Err(Error::NotFound(format!("draft of {} by {}", title, username))),
+            }
+        }
+    }
+
+    fn new_filter(subjects: Arc<MemorySubjects>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        filter(Arc::new(MemoryDrafts::default()), subjects, Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH)
+    }
+
+    fn request(method: &str, path: &str, user: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", format!("Basic {}:pass", user))
+    }
+
+    #[tokio::test]
+    async fn test_drafts() {
+        let f = new_filter(Arc::new(MemorySubjects::default()));
+
+        for (title, content) in [("First_subject", "one"), ("Second_subject", "two"), ("first_subject", "one again")] {
+            let res = request("PUT", &format!("/subject/{}/draft", title), "bob").body(content).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT, "{:?}", res.body());
+        }
+
+        let res = request("GET", "/subject/First_Subject/draft", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "one again");
+
+        let res = request("GET", "/drafts", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let drafts: Vec<DraftInfo> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            drafts.iter().map(|d| (d.title.as_str(), d.slug.as_str())).collect::<Vec<_>>(),
+            vec![("first subject", "first_subject"), ("Second subject", "Second_subject")],
+        );
+
+        let res = request("DELETE", "/subject/Second_subject/draft", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = request("GET", "/subject/Second_subject/draft", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = request("DELETE", "/subject/Second_subject/draft", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_drafts_are_private() {
+        let f = new_filter(Arc::new(MemorySubjects::default()));
+        request("PUT", "/subject/Secret/draft", "bob").body("half-finished").reply(&f).await;
+
+        let res = request("GET", "/drafts", "alice").reply(&f).await;
+        assert_eq!(res.body(), "[]");
+        for m in ["GET", "DELETE"] {
+            let res = request(m, "/subject/Secret/draft", "alice").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", m);
+        }
+        let res = request("POST", "/subject/Secret/draft/publish", "alice").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = request("GET", "/subject/Secret/draft", "bob").reply(&f).await;
+        assert_eq!(res.body(), "half-finished");
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let subjects = Arc::new(MemorySubjects::with(&[("Old", "old")]));
+        let f = new_filter(subjects.clone());
+
+        for (title, status) in [("old", StatusCode::OK), ("New", StatusCode::CREATED)] {
+            let path = format!("/subject/{}/draft", title);
+            request("PUT", &path, "bob").body(format!("{} draft", title)).reply(&f).await;
+            let res = request("POST", &format!("{}/publish", path), "bob")
+                .header(edit::EDIT_SUMMARY, "Publish")
+                .reply(&f).await;
+            assert_eq!(res.status(), status, "{}: {:?}", title, res.body());
+            assert_eq!(subjects.edits().last().unwrap().1.summary, "Publish");
+            assert_eq!(subjects.content(title), Some(format!("{} draft", title)));
+
+            let res = request("GET", &path, "bob").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", title);
+        }
+
+        let res = request("POST", "/subject/Missing/draft/publish", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(subjects.content("Missing"), None);
+    }
+
+    #[tokio::test]
+    async fn test_publish_stale_drafts() {
+        let subjects = Arc::new(MemorySubjects::with(&[("Old", "old")]));
+        let f = new_filter(subjects.clone());
+        let edit = edit::Edit::default();
+
+        // alice edits, and creates, while bob drafts
+        request("PUT", "/subject/Old/draft", "bob").body("bob's").reply(&f).await;
+        request("PUT", "/subject/New/draft", "bob").body("bob's").reply(&f).await;
+        subjects.update("alice", &Title::parse("Old", MAX_LENGTH).unwrap(), "alice's", &edit).await.unwrap();
+        subjects.create("alice", &Title::parse("New", MAX_LENGTH).unwrap(), "alice's", &edit).await.unwrap();
+        // saving again keeps the base
+        request("PUT", "/subject/Old/draft", "bob").body("bob's again").reply(&f).await;
+
+        for title in ["Old", "New"] {
+            let res = request("POST", &format!("/subject/{}/draft/publish", title), "bob").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED, "{}: {:?}", title, res.body());
+            assert_eq!(subjects.content(title).as_deref(), Some("alice's"));
+            let res = request("GET", &format!("/subject/{}/draft", title), "bob").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", title);
+        }
+
+        // drafts started over from the current content publish
+        request("DELETE", "/subject/Old/draft", "bob").reply(&f).await;
+        request("PUT", "/subject/Old/draft", "bob").body("bob's").reply(&f).await;
+        let res = request("POST", "/subject/Old/draft/publish", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(subjects.content("Old").as_deref(), Some("bob's"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let f = new_filter(Arc::new(MemorySubjects::default()));
+        for (req, status, code) in [
+            (request("PUT", "/subject/A/draft", "bob"), StatusCode::BAD_REQUEST, "bad_request"),
+            (request("PUT", "/subject/a%2Fb/draft", "bob").body("x"), StatusCode::BAD_REQUEST, "bad_request"),
+            (warp::test::request().path("/drafts"), StatusCode::UNAUTHORIZED, "unauthorized"),
+            (warp::test::request().path("/subject/A/draft"), StatusCode::UNAUTHORIZED, "unauthorized"),
+            (warp::test::request().method("PUT").path("/subject/A/draft").body("x"), StatusCode::UNAUTHORIZED, "unauthorized"),
+            (warp::test::request().method("POST").path("/subject/A/draft/publish"), StatusCode::UNAUTHORIZED, "unauthorized"),
+        ] {
+            let res = req.reply(&f).await;
+            assert_eq!(res.status(), status, "{:?}", res.body());
+            let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(problem.code, code);
+        }
+
+        // other paths below subjects are left to other filters
+        let res = request("GET", "/subject/A", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_reject_bad_methods() {
+        let f = new_filter(Arc::new(MemorySubjects::default()));
+        for (path, method, allow) in [
+            ("/drafts", "POST", "GET"),
+            ("/subject/A/draft", "POST", "GET, PUT, DELETE"),
+            ("/subject/A/draft/publish", "GET", "POST"),
+        ] {
+            let res = request(method, path, "bob").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            assert_eq!(res.headers()["allow"], allow);
+        }
+    }
+}
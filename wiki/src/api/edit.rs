@@ -0,0 +1,253 @@
+// edit says why content changed: a summary, and whether the change is minor.
+// Plain text requests carry them in X-Edit-Summary and X-Minor-Edit headers,
+// JSON requests in fields next to the content. Both are stored with the change.
+// Subjects may be configured to need a summary on every edit.
+
+use std::sync::Arc;
+
+use percent_encoding::percent_decode_str;
+use regex::{RegexSet, RegexSetBuilder};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::{reject::Rejection, Filter};
+
+use crate::{api::{idempotency, subject::{Subjects, UnitOfWork}, title::Title}, error::Error};
+
+pub const EDIT_SUMMARY: &str = "x-edit-summary";
+pub const MINOR_EDIT: &str = "x-minor-edit";
+/// Request headers of operations that change content from plain text, for
+/// OpenAPI. Those operations take idempotency keys as well.
+pub const HEADERS: &[(&str, &str)] = &[
+    idempotency::HEADERS[0],
+    ("X-Edit-Summary", "string"),
+    ("X-Minor-Edit", "boolean"),
+];
+pub const MAX_SUMMARY_LENGTH: usize = 500;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Edit {
+    /// Why the content changed, a single line of at most 500 characters
+    #[serde(default)]
+    pub summary: String,
+    /// Minor edits, such as typo fixes, need no review
+    #[serde(default)]
+    pub minor: bool,
+}
+
+impl Edit {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.summary.chars().count() > MAX_SUMMARY_LENGTH {
+            return Err(Error::BadRequest(format!(
+                "edit summary must be at most {} characters", MAX_SUMMARY_LENGTH,
+            )));
+        }
+        if self.summary.chars().any(char::is_control) {
+            return Err(Error::BadRequest("edit summary must be a single line".into()));
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the edit of a plain text request, rejecting malformed headers
+/// with Error::BadRequest. X-Edit-Summary is percent-decoded, since header
+/// values are ASCII.
+pub fn headers() -> impl Filter<Extract = (Edit,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(EDIT_SUMMARY)
+        .and(warp::header::optional::<String>(MINOR_EDIT))
+        .and_then(|summary: Option<String>, minor: Option<String>| async move {
+            from_headers(summary.as_deref(), minor.as_deref()).map_err(warp::reject::custom)
+        })
+}
+
+fn from_headers(summary: Option<&str>, minor: Option<&str>) -> Result<Edit, Error> {
+    let summary = match summary {
+        Some(summary) => percent_decode_str(summary).decode_utf8()
+            .map_err(|_| Error::BadRequest("edit summary must be percent-encoded UTF-8".into()))?
+            .into_owned(),
+        None => String::new(),
+    };
+    let minor = match minor {
+        None => false,
+        Some(minor) if minor.eq_ignore_ascii_case("true") => true,
+        Some(minor) if minor.eq_ignore_ascii_case("false") => false,
+        Some(minor) => return Err(Error::BadRequest(format!("minor edit must be true or false, not {}", minor))),
+    };
+    let edit = Edit { summary, minor };
+    edit.validate()?;
+    Ok(edit)
+}
+
+/// Summarized requires a summary of edits to subjects whose titles match any
+/// of its patterns, failing others with Error::BadRequest. Every change of
+/// content goes through Subjects or its units of work, so that wrapping them
+/// covers the API of both versions, batches, sections and drafts alike.
+pub struct Summarized<S> {
+    subjects: Arc<S>,
+    required: RegexSet,
+}
+
+impl<S> Summarized<S> {
+    /// Patterns are regular expressions matched against titles
+    /// case-insensitively, like titles are compared
+    pub fn new(subjects: Arc<S>, patterns: &[String]) -> Result<Self, regex::Error> {
+        let required = RegexSetBuilder::new(patterns).case_insensitive(true).build()?;
+        Ok(Summarized { subjects, required })
+    }
+}
+
+fn require(required: &RegexSet, title: &Title, edit: &Edit) -> Result<(), Error> {
+    if edit.summary.trim().is_empty() && required.is_match(title.as_str()) {
+        return Err(Error::BadRequest(format!("edits of {} must have a summary", title)));
+    }
+    Ok(())
+}
+
+impl<S: Subjects + Send + Sync> Subjects for Summarized<S> {
+    type UnitOfWork = SummarizedUnitOfWork<S::UnitOfWork>;
+
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        self.subjects.list().await
+    }
+
+    async fn list_after(&self, after: Option<&str>, limit: i64) -> Result<Vec<String>, Error> {
+        self.subjects.list_after(after, limit).await
+    }
+
+    async fn create(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        require(&self.required, title, edit)?;
+        self.subjects.create(user, title, content, edit).await
+    }
+
+    async fn read(&self, title: &Title) -> Result<String, Error> {
+        self.subjects.read(title).await
+    }
+
+    async fn update(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        require(&self.required, title, edit)?;
+        self.subjects.update(user, title, content, edit).await
+    }
+
+    async fn update_if(&self, user: &str, title: &Title, previous: &str, content: &str, edit: &Edit) -> Result<(), Error> {
+        require(&self.required, title, edit)?;
+        self.subjects.update_if(user, title, previous, content, edit).await
+    }
+
+    async fn begin(&self) -> Result<Self::UnitOfWork, Error> {
+        Ok(SummarizedUnitOfWork {
+            work: self.subjects.begin().await?,
+            required: self.required.clone(),
+        })
+    }
+}
+
+pub struct SummarizedUnitOfWork<W> {
+    work: W,
+    required: RegexSet,
+}
+
+impl<W: UnitOfWork + Send> UnitOfWork for SummarizedUnitOfWork<W> {
+    async fn create(&mut self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        require(&self.required, title, edit)?;
+        self.work.create(user, title, content, edit).await
+    }
+
+    async fn update(&mut self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        require(&self.required, title, edit)?;
+        self.work.update(user, title, content, edit).await
+    }
+
+    async fn delete(&mut self, title: &Title) -> Result<(), Error> {
+        self.work.delete(title).await
+    }
+
+    async fn commit(self) -> Result<(), Error> {
+        self.work.commit().await
+    }
+}
+
+/// Tests headers and Summarized.
+///
+/// Test plan:
+/// 1. Requests without headers make major edits without summary
+/// 2. Headers are decoded
+/// 3. Bad headers are rejected with Error::BadRequest
+/// 4. Edits of subjects with matching titles need a summary, in units of work
+///    too, while edits of others do not
+#[cfg(test)]
+mod tests {
+    use crate::api::{subject::tests::MemorySubjects, title::MAX_LENGTH};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_headers() {
+        let edit = warp::test::request().filter(&headers()).await.unwrap();
+        assert_eq!(edit, Edit::default());
+
+        let edit = warp::test::request()
+            .header(EDIT_SUMMARY, "Fix%20typo%20in%20%C2%A73")
+            .header(MINOR_EDIT, "True")
+            .filter(&headers())
+            .await
+            .unwrap();
+        assert_eq!(edit, Edit { summary: "Fix typo in §3".into(), minor: true });
+    }
+
+    #[tokio::test]
+    async fn test_bad_headers() {
+        let long = "a".repeat(MAX_SUMMARY_LENGTH + 1);
+        for (summary, minor) in [
+            (Some("%FF"), None),
+            (Some("two%0Alines"), None),
+            (Some(long.as_str()), None),
+            (None, Some("yes")),
+        ] {
+            let mut req = warp::test::request();
+            if let Some(summary) = summary {
+                req = req.header(EDIT_SUMMARY, summary);
+            }
+            if let Some(minor) = minor {
+                req = req.header(MINOR_EDIT, minor);
+            }
+            let rejection = req.filter(&headers()).await.unwrap_err();
+            assert!(matches!(rejection.find::<Error>(), Some(Error::BadRequest(_))), "{:?} {:?}", summary, minor);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summarized() {
+        let memory = Arc::new(MemorySubjects::with(&[("Policy: Leave", "old"), ("Lunch", "old")]));
+        let subjects = Summarized::new(memory.clone(), &["^policy:".to_string()]).unwrap();
+        let title = |t| Title::parse(t, MAX_LENGTH).unwrap();
+        let none = Edit::default();
+        let blank = Edit { summary: "  ".into(), minor: true };
+        let some = Edit { summary: "Agreed on review".into(), minor: false };
+
+        for edit in [&none, &blank] {
+            let r = subjects.create("alice", &title("Policy: Travel"), "new", edit).await;
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+            let r = subjects.update("alice", &title("POLICY: Leave"), "new", edit).await;
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+            let r = subjects.update_if("alice", &title("Policy: Leave"), "old", "new", edit).await;
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+            let mut uow = subjects.begin().await.unwrap();
+            let r = uow.update("alice", &title("Policy: Leave"), "new", edit).await;
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+            let r = uow.create("alice", &title("Policy: Travel"), "new", edit).await;
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+        }
+        assert_eq!(memory.content("Policy: Leave").as_deref(), Some("old"));
+        assert_eq!(memory.content("Policy: Travel"), None);
+
+        subjects.update("alice", &title("Lunch"), "new", &none).await.unwrap();
+        subjects.create("alice", &title("Dinner"), "new", &none).await.unwrap();
+        subjects.update_if("alice", &title("Policy: Leave"), "old", "new", &some).await.unwrap();
+        let mut uow = subjects.begin().await.unwrap();
+        uow.create("alice", &title("Policy: Travel"), "new", &some).await.unwrap();
+        uow.update("alice", &title("Lunch"), "newer", &none).await.unwrap();
+        uow.commit().await.unwrap();
+        for t in ["Lunch", "Dinner", "Policy: Leave", "Policy: Travel"] {
+            assert!(memory.content(t).is_some_and(|c| c.starts_with("new")), "{}", t);
+        }
+    }
+}
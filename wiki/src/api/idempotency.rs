@@ -0,0 +1,473 @@
+// idempotency lets clients retry mutating requests without repeating their
+// effects. A request carrying an Idempotency-Key header claims the key for its
+// user, and its response is stored with a fingerprint of the request for a
+// window. Retries within the window get the stored response, and requests
+// reusing the key for something else are rejected. Keys are kept in memory, or
+// in Redis so that replicas share them.
+
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::{http::{header::{HeaderName, HeaderValue}, Method, StatusCode}, path::FullPath, reject::Rejection, reply::{Reply, Response}, Filter};
+
+use crate::error::{self, Error};
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Header of replayed responses
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+/// Request headers of operations that take idempotency keys, for OpenAPI
+pub const HEADERS: &[(&str, &str)] = &[("Idempotency-Key", "string")];
+const MAX_KEY_LENGTH: usize = 255;
+// a request that never completes, such as one whose replica died, holds its
+// key this long
+const PENDING_TTL: Duration = Duration::from_secs(60);
+// memory keys are pruned of expired keys past this many
+const MAX_MEMORY_KEYS: usize = 100_000;
+
+/// Entry is what holds a key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Entry {
+    /// The request that claimed the key is in progress
+    Pending { fingerprint: String },
+    Done { fingerprint: String, response: Stored },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> &str {
+        match self {
+            Entry::Pending { fingerprint } | Entry::Done { fingerprint, .. } => fingerprint,
+        }
+    }
+}
+
+/// Stored is a response as it was replied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stored {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+pub trait Keys {
+    /// Claims key with a pending entry for ttl, or returns the entry that
+    /// holds it
+    fn claim(&self, key: &str, pending: &Entry, ttl: Duration) -> impl Future<Output = Result<Option<Entry>, Error>> + Send;
+    /// Replaces the entry of a claimed key, holding it for ttl
+    fn store(&self, key: &str, entry: &Entry, ttl: Duration) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Releases a claimed key, so that the request may be retried
+    fn release(&self, key: &str) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+pub struct Idempotency<K> {
+    keys: K,
+    window: Duration,
+}
+
+impl<K: Keys> Idempotency<K> {
+    /// Keeps responses for window
+    pub fn new(keys: K, window: Duration) -> Self {
+        Idempotency { keys, window }
+    }
+}
+
+/// Attempt is a request that may carry an idempotency key
+pub struct Attempt<K> {
+    idempotency: Arc<Idempotency<K>>,
+    key: Option<String>,
+    method: Method,
+    path: FullPath,
+}
+
+impl<K: Keys> Attempt<K> {
+    /// Runs the request of user with body, unless a request with the same key
+    /// ran. Responses are stored unless they are server errors, which may go
+    /// away on retry.
+    pub async fn run<R, F>(self, user: &str, body: &[u8], run: F) -> Result<Response, Rejection>
+    where
+        R: Reply,
+        F: Future<Output = Result<R, Error>>,
+    {
+        let Some(key) = &self.key else {
+            return run.await
+                .map(Reply::into_response)
+                .map_err(warp::reject::custom);
+        };
+        let key = format!("{}:{}", user, key);
+        let fingerprint = self.fingerprint(body);
+
+        let pending = Entry::Pending { fingerprint: fingerprint.clone() };
+        let claimed = self.idempotency.keys.claim(&key, &pending, PENDING_TTL.min(self.idempotency.window)).await
+            .map_err(warp::reject::custom)?;
+        match claimed {
+            Some(entry) if entry.fingerprint() != fingerprint => Err(warp::reject::custom(Error::UnprocessableEntity(
+                "idempotency key was used for another request".into(),
+            ))),
+            Some(Entry::Pending { .. }) => Err(warp::reject::custom(Error::Conflict(
+                "a request with this idempotency key is in progress".into(),
+            ))),
+            Some(Entry::Done { response, .. }) => Ok(replay(response)),
+            None => {
+                let response = match run.await {
+                    Ok(reply) => reply.into_response(),
+                    Err(err) => match error::recover(warp::reject::custom(err)).await {
+                        Ok(reply) => reply.into_response(),
+                        Err(never) => match never {},
+                    },
+                };
+                if response.status().is_server_error() {
+                    if let Err(err) = self.idempotency.keys.release(&key).await {
+                        log::warn!(target: "wiki::api", "idempotency: {:?}", err);
+                    }
+                    return Ok(response);
+                }
+
+                let (parts, body) = response.into_parts();
+                let body = warp::hyper::body::to_bytes(body).await
+                    .map_err(|e| warp::reject::custom(Error::Internal(e.to_string())))?;
+                let stored = Stored {
+                    status: parts.status.as_u16(),
+                    headers: parts.headers.iter()
+                        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                        .collect(),
+                    body: String::from_utf8_lossy(&body).to_string(),
+                };
+                let done = Entry::Done { fingerprint, response: stored };
+                if let Err(err) = self.idempotency.keys.store(&key, &done, self.idempotency.window).await {
+                    log::warn!(target: "wiki::api", "idempotency: {:?}", err);
+                }
+                Ok(Response::from_parts(parts, body.into()))
+            },
+        }
+    }
+
+    fn fingerprint(&self, body: &[u8]) -> String {
+        let mut hash = Sha256::new();
+        hash.update(self.method.as_str());
+        hash.update(b"\n");
+        hash.update(self.path.as_str());
+        hash.update(b"\n");
+        hash.update(body);
+        hash.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+fn replay(stored: Stored) -> Response {
+    let mut response = Response::new(stored.body.into());
+    *response.status_mut() = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    for (name, value) in stored.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::from_str(&value)) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response.headers_mut().insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    response
+}
+
+/// Extracts the attempt of a request, rejecting malformed keys with
+/// Error::BadRequest
+pub fn attempt<K>(idempotency: Arc<Idempotency<K>>) -> impl Filter<Extract = (Attempt<K>,), Error = Rejection> + Clone
+where
+    K: Keys + Send + Sync + 'static
+{
+    warp::header::optional::<String>(IDEMPOTENCY_KEY)
+        .and(warp::method())
+        .and(warp::path::full())
+        .and_then(move |key: Option<String>, method: Method, path: FullPath| {
+            let idempotency = idempotency.clone();
+            async move {
+                if let Some(key) = &key
+                    && (key.is_empty() || key.len() > MAX_KEY_LENGTH || !key.bytes().all(|b| b.is_ascii_graphic()))
+                {
+                    return Err(warp::reject::custom(Error::BadRequest(format!(
+                        "idempotency key must be 1 to {} visible ASCII characters", MAX_KEY_LENGTH,
+                    ))));
+                }
+                Ok(Attempt { idempotency, key, method, path })
+            }
+        })
+}
+
+/// MemoryKeys keeps keys for a single replica.
+#[derive(Default)]
+pub struct MemoryKeys {
+    keys: Mutex<HashMap<String, (Entry, SystemTime)>>,
+}
+
+impl Keys for MemoryKeys {
+    async fn claim(&self, key: &str, pending: &Entry, ttl: Duration) -> Result<Option<Entry>, Error> {
+        let now = SystemTime::now();
+        let mut keys = self.keys.lock().unwrap();
+        if keys.len() >= MAX_MEMORY_KEYS {
+            keys.retain(|_, (_, expires)| *expires > now);
+        }
+        match keys.get(key) {
+            Some((entry, expires)) if *expires > now => Ok(Some(entry.clone())),
+            _ => {
+                keys.insert(key.to_string(), (pending.clone(), now + ttl));
+                Ok(None)
+            },
+        }
+    }
+
+    async fn store(&self, key: &str, entry: &Entry, ttl: Duration) -> Result<(), Error> {
+        self.keys.lock().unwrap().insert(key.to_string(), (entry.clone(), SystemTime::now() + ttl));
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<(), Error> {
+        self.keys.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// RedisKeys shares keys between replicas, which expire with their entries.
+pub struct RedisKeys {
+    redis: ConnectionManager,
+    script: redis::Script,
+}
+
+const CLAIM_SCRIPT: &str = r"
+local entry = redis.call('GET', KEYS[1])
+if entry then
+    return entry
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+return false
+";
+
+const KEY_PREFIX: &str = "wiki:idempotency:";
+
+impl RedisKeys {
+    pub fn new(redis: ConnectionManager) -> Self {
+        RedisKeys { redis, script: redis::Script::new(CLAIM_SCRIPT) }
+    }
+}
+
+fn to_json(entry: &Entry) -> Result<String, Error> {
+    serde_json::to_string(entry).map_err(|e| Error::Internal(e.to_string()))
+}
+
+impl Keys for RedisKeys {
+    async fn claim(&self, key: &str, pending: &Entry, ttl: Duration) -> Result<Option<Entry>, Error> {
+        let mut redis = self.redis.clone();
+        let r: redis::RedisResult<Option<String>> = self.script
+            .key(format!("{}{}", KEY_PREFIX, key))
+            .arg(to_json(pending)?)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut redis)
+            .await;
+
+        match r.map_err(|e| Error::Internal(e.to_string()))? {
+            Some(entry) => serde_json::from_str(&entry)
+                .map(Some)
+                .map_err(|e| Error::Internal(e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    async fn store(&self, key: &str, entry: &Entry, ttl: Duration) -> Result<(), Error> {
+        let mut redis = self.redis.clone();
+        let r: redis::RedisResult<()> = redis::cmd("SET")
+            .arg(format!("{}{}", KEY_PREFIX, key))
+            .arg(to_json(entry)?)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut redis)
+            .await;
+        r.map_err(|e| Error::Internal(e.to_string()))
+    }
+
+    async fn release(&self, key: &str) -> Result<(), Error> {
+        let mut redis = self.redis.clone();
+        let r: redis::RedisResult<()> = redis::cmd("DEL")
+            .arg(format!("{}{}", KEY_PREFIX, key))
+            .query_async(&mut redis)
+            .await;
+        r.map_err(|e| Error::Internal(e.to_string()))
+    }
+}
+
+pub enum Store {
+    Memory(MemoryKeys),
+    Redis(RedisKeys),
+}
+
+impl Keys for Store {
+    async fn claim(&self, key: &str, pending: &Entry, ttl: Duration) -> Result<Option<Entry>, Error> {
+        match self {
+            Store::Memory(keys) => keys.claim(key, pending, ttl).await,
+            Store::Redis(keys) => keys.claim(key, pending, ttl).await,
+        }
+    }
+
+    async fn store(&self, key: &str, entry: &Entry, ttl: Duration) -> Result<(), Error> {
+        match self {
+            Store::Memory(keys) => keys.store(key, entry, ttl).await,
+            Store::Redis(keys) => keys.store(key, entry, ttl).await,
+        }
+    }
+
+    async fn release(&self, key: &str) -> Result<(), Error> {
+        match self {
+            Store::Memory(keys) => keys.release(key).await,
+            Store::Redis(keys) => keys.release(key).await,
+        }
+    }
+}
+
+/// Tests keys and attempts. Exposes idempotency in memory to test modules.
+///
+/// Test plan:
+/// 1. Keys are claimed once, hold their entries for the ttl and are released
+/// 2. Redis keys behave like memory keys
+/// 3. Retries replay the response, other requests with the key are rejected
+/// 4. Server errors are not stored, malformed keys are rejected
+#[cfg(test)]
+pub mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::error::Problem;
+
+    use super::*;
+
+    pub fn new_idempotency() -> Arc<Idempotency<MemoryKeys>> {
+        Arc::new(Idempotency::new(MemoryKeys::default(), Duration::from_secs(60)))
+    }
+
+    async fn test_keys(keys: &impl Keys, key: &str) {
+        let pending = Entry::Pending { fingerprint: "a".into() };
+        let done = Entry::Done {
+            fingerprint: "a".into(),
+            response: Stored { status: 201, headers: vec![("location".into(), "A".into())], body: "{}".into() },
+        };
+
+        assert_eq!(keys.claim(key, &pending, Duration::from_secs(60)).await.unwrap(), None);
+        assert_eq!(keys.claim(key, &pending, Duration::from_secs(60)).await.unwrap(), Some(pending.clone()));
+        keys.store(key, &done, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(keys.claim(key, &pending, Duration::from_secs(60)).await.unwrap(), Some(done));
+        keys.release(key).await.unwrap();
+        assert_eq!(keys.claim(key, &pending, Duration::from_millis(10)).await.unwrap(), None);
+
+        // expired keys are claimed again
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(keys.claim(key, &pending, Duration::from_secs(60)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_keys_in_memory() {
+        test_keys(&MemoryKeys::default(), "test").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_keys_with_redis() {
+        let url = std::env::var("WIKI_CI_TEST_REDIS_URL").unwrap_or("redis://localhost".into());
+        let redis = redis::Client::open(url).unwrap().get_connection_manager().await.unwrap();
+        let key = format!("test-{}", SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos());
+        test_keys(&RedisKeys::new(redis), &key).await;
+    }
+
+    /// Replies 201 with a count of runs, or fails with what the body says
+    fn counting(idempotency: Arc<Idempotency<MemoryKeys>>, runs: Arc<AtomicUsize>) -> impl Filter<Extract = (Response,), Error = std::convert::Infallible> + Clone {
+        attempt(idempotency)
+            .and(warp::header::<String>("user"))
+            .and(warp::body::bytes())
+            .and_then(move |attempt: Attempt<MemoryKeys>, user: String, body: bytes::Bytes| {
+                let runs = runs.clone();
+                async move {
+                    attempt.run(&user, &body, async {
+                        let n = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                        match body.as_ref() {
+                            b"conflict" => Err(Error::Conflict("exists".into())),
+                            b"internal" => Err(Error::Internal("down".into())),
+                            _ => Ok(warp::reply::with_status(n.to_string(), StatusCode::CREATED)),
+                        }
+                    }).await
+                }
+            })
+            .recover(error::recover)
+            .map(Reply::into_response)
+    }
+
+    fn request(key: Option<&str>, user: &str, body: &str) -> warp::test::RequestBuilder {
+        let mut req = warp::test::request().method("POST").path("/subjects").header("user", user).body(body);
+        if let Some(key) = key {
+            req = req.header(IDEMPOTENCY_KEY, key);
+        }
+        req
+    }
+
+    #[tokio::test]
+    async fn test_attempts() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let f = counting(new_idempotency(), runs.clone());
+
+        // without keys requests run every time
+        for n in ["1", "2"] {
+            let res = request(None, "bob", "A").reply(&f).await;
+            assert_eq!(res.body(), n);
+        }
+
+        // retries replay
+        let res = request(Some("k1"), "bob", "A").reply(&f).await;
+        assert_eq!((res.status(), res.body().as_ref()), (StatusCode::CREATED, b"3".as_ref()));
+        assert!(!res.headers().contains_key(IDEMPOTENT_REPLAYED));
+        let res = request(Some("k1"), "bob", "A").reply(&f).await;
+        assert_eq!((res.status(), res.body().as_ref()), (StatusCode::CREATED, b"3".as_ref()));
+        assert_eq!(res.headers()[IDEMPOTENT_REPLAYED], "true");
+
+        // reusing the key for another body is rejected, other users have their own keys
+        let res = request(Some("k1"), "bob", "B").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(problem.code, "unprocessable_entity");
+        let res = request(Some("k1"), "alice", "B").reply(&f).await;
+        assert_eq!(res.body(), "4");
+
+        // client errors replay too
+        for _ in 0..2 {
+            let res = request(Some("k2"), "bob", "conflict").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+            assert_eq!(res.headers()["content-type"], "application/problem+json");
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_bad_attempts() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let idempotency = new_idempotency();
+        let f = counting(idempotency.clone(), runs.clone());
+
+        // server errors run again
+        for _ in 0..2 {
+            let res = request(Some("k"), "bob", "internal").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        // requests in progress hold their keys
+        let pending = Entry::Pending { fingerprint: "other".into() };
+        idempotency.keys.claim("bob:busy", &pending, PENDING_TTL).await.unwrap();
+        let res = request(Some("busy"), "bob", "A").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let fingerprint = Attempt {
+            idempotency: idempotency.clone(),
+            key: None,
+            method: Method::POST,
+            path: warp::test::request().path("/subjects").filter(&warp::path::full()).await.unwrap(),
+        }.fingerprint(b"A");
+        idempotency.keys.store("bob:busy", &Entry::Pending { fingerprint }, PENDING_TTL).await.unwrap();
+        let res = request(Some("busy"), "bob", "A").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        let long = "k".repeat(MAX_KEY_LENGTH + 1);
+        for key in ["", "has space", long.as_str()] {
+            let res = request(Some(key), "bob", "A").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{:?}", key);
+        }
+    }
+}
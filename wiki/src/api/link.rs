@@ -0,0 +1,337 @@
+// link finds which subjects link to which. Links are the Markdown links of
+// content to /wiki/{title}, the paths the UI shows subjects at, extracted when
+// subjects are created or updated. Reports over them find subjects nobody
+// links to, titles linked to that have no subject yet, and subjects that link
+// nowhere.
+
+use std::{collections::HashSet, future::Future, sync::Arc};
+
+use pulldown_cmark::{Event, Parser, Tag};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::{filters::path::Peek, http::Method, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{openapi::{Body, Operation, Response}, title::{self, Title}}, error::{self, Error}};
+
+/// Path the UI shows subjects at
+const PREFIX: &str = "/wiki/";
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+pub trait Links {
+    /// Lists the subjects or titles of a report, ordered by title, after the
+    /// title after, if any
+    fn report_links(&self, report: Report, after: Option<&str>, limit: i64) -> impl Future<Output = Result<Vec<Linked>, Error>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    /// Subjects no other subject links to
+    Orphans,
+    /// Titles linked to that have no subject
+    Wanted,
+    /// Subjects that link to no title but their own
+    DeadEnds,
+}
+
+impl Report {
+    /// Parses the path segment of a report
+    pub fn parse(name: &str) -> Result<Self, Error> {
+        match name {
+            "orphans" => Ok(Report::Orphans),
+            "wanted" => Ok(Report::Wanted),
+            "dead-ends" => Ok(Report::DeadEnds),
+            name => Err(Error::NotFound(format!("report {}", name))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Linked {
+    pub title: String,
+    /// Subjects linking to the title
+    pub links: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct LinkedInfo {
+    pub title: String,
+    /// Path segment of the title
+    pub slug: String,
+    /// Subjects linking to the title, other than itself
+    pub links: i64,
+}
+
+impl From<&Linked> for LinkedInfo {
+    fn from(l: &Linked) -> Self {
+        LinkedInfo {
+            title: l.title.clone(),
+            slug: title::slug(&l.title),
+            links: l.links,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReportQuery {
+    pub after: Option<String>,
+    pub limit: Option<i64>,
+}
+
+impl ReportQuery {
+    fn limit(&self) -> Result<i64, Error> {
+        match self.limit {
+            None => Ok(DEFAULT_LIMIT),
+            Some(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+            Some(limit) => Err(Error::BadRequest(format!("limit must be between 1 and {}, not {}", MAX_LIMIT, limit))),
+        }
+    }
+}
+
+/// Extracts the canonical titles content links to, once each regardless of
+/// case, in the order they first appear. Links in code are no links, and
+/// links to paths that are no titles are left out.
+pub fn links(content: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    Parser::new(content)
+        .filter_map(|event| match event {
+            Event::Start(Tag::Link { dest_url, .. }) => target(&dest_url),
+            _ => None,
+        })
+        .filter(|title| seen.insert(title.as_str().to_lowercase()))
+        .map(|title| title.as_str().to_string())
+        .collect()
+}
+
+/// Title of a link destination to a subject, ignoring fragments and queries
+fn target(destination: &str) -> Option<Title> {
+    let segment = destination.strip_prefix(PREFIX)?;
+    let segment = segment.split(['#', '?']).next().unwrap_or_default();
+    Title::from_segment(segment, title::MAX_LENGTH).ok()
+}
+
+pub fn filter<L>(links: Arc<L>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    L: Links + Send + Sync + 'static,
+{
+    warp::path!("reports" / ..)
+        .and(
+            with_report().and(warp::get()).and(endpoints::report(links))
+            .or(with_report().and(error::method_not_allowed(&[Method::GET])))
+        )
+}
+
+/// Rejects paths other than those of reports, like with_tail
+fn with_report() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and_then(|peek: Peek| async move {
+            Report::parse(peek.as_str()).map(|_| ()).map_err(|_| warp::reject::not_found())
+        })
+        .untuple_one()
+}
+
+pub fn operations() -> Vec<Operation> {
+    [
+        ("/reports/orphans", "reportOrphans", "Lists subjects no other subject links to"),
+        ("/reports/wanted", "reportWanted", "Lists titles linked to that have no subject yet, with the subjects linking to them"),
+        ("/reports/dead-ends", "reportDeadEnds", "Lists subjects that link to no title but their own"),
+    ]
+    .into_iter()
+    .map(|(path, id, summary)| Operation {
+        method: Method::GET,
+        path,
+        id,
+        summary,
+        scope: None,
+        query: &[("after", "string"), ("limit", "integer")],
+        headers: &[],
+        request: None,
+        responses: vec![
+            Response::ok(200, "Titles", Some(Body::json::<Vec<LinkedInfo>>())),
+            Response::problem(400, "Bad limit"),
+        ],
+    })
+    .collect()
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::error;
+
+    use super::{handlers, Links, Report, ReportQuery};
+
+    pub fn report<L>(links: Arc<L>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        L: Links + Send + Sync + 'static
+    {
+        warp::path::param::<String>()
+            .and_then(|name: String| async move {
+                Report::parse(&name).map_err(warp::reject::custom)
+            })
+            .and(warp::path::end())
+            .and(warp::query::<ReportQuery>())
+            .and(with_links(links))
+            .and_then(handlers::report)
+            .recover(error::recover)
+    }
+
+    fn with_links<L>(links: Arc<L>) -> impl Filter<Extract = (Arc<L>,), Error = Infallible> + Clone
+    where
+        L: Links + Send + Sync + 'static
+    {
+        warp::any().map(move || links.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{reject::Rejection, reply::Reply};
+
+    use super::{LinkedInfo, Links, Report, ReportQuery};
+
+    pub async fn report<L: Links>(report: Report, query: ReportQuery, links: Arc<L>) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let limit = query.limit()?;
+            links.report_links(report, query.after.as_deref(), limit).await
+        }.await;
+
+        match r {
+            Ok(linked) => Ok(warp::reply::json(&linked.iter().map(LinkedInfo::from).collect::<Vec<_>>())),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests links, filter, and endpoints and handlers modules, with links in
+/// memory. Exposes links in memory to test modules.
+///
+/// Test plan:
+/// 1. Links to subjects are extracted once each, as canonical titles
+/// 2. Links in code, external links and images are left out
+/// 3. Reports reply with their titles, after a title and up to a limit
+/// 4. Bad requests reply with problems, bad methods with 405
+#[cfg(test)]
+pub mod tests {
+    use warp::http::StatusCode;
+
+    use crate::error::Problem;
+
+    use super::*;
+
+    /// MemoryLinks reports on subjects and their links, as Postgres would
+    pub struct MemoryLinks {
+        pub subjects: Vec<(&'static str, &'static str)>,
+    }
+
+    impl Links for MemoryLinks {
+        async fn report_links(&self, report: Report, after: Option<&str>, limit: i64) -> Result<Vec<Linked>, Error> {
+            let edges: Vec<(&str, String)> = self.subjects.iter()
+                .flat_map(|(source, content)| links(content).into_iter().map(move |target| (*source, target)))
+                .filter(|(source, target)| !source.eq_ignore_ascii_case(target))
+                .collect();
+            let exists = |title: &str| self.subjects.iter().any(|(t, _)| t.eq_ignore_ascii_case(title));
+            let incoming = |title: &str| edges.iter().filter(|(_, target)| target.eq_ignore_ascii_case(title)).count() as i64;
+
+            let mut titles: Vec<String> = match report {
+                Report::Orphans => self.subjects.iter()
+                    .map(|(t, _)| t.to_string())
+                    .filter(|t| incoming(t) == 0)
+                    .collect(),
+                Report::Wanted => edges.iter()
+                    .map(|(_, target)| target.clone())
+                    .filter(|t| !exists(t))
+                    .collect(),
+                Report::DeadEnds => self.subjects.iter()
+                    .map(|(t, _)| t.to_string())
+                    .filter(|t| !edges.iter().any(|(source, _)| source == t))
+                    .collect(),
+            };
+            titles.sort_by_key(|t| t.to_lowercase());
+            titles.dedup_by_key(|t| t.to_lowercase());
+            Ok(titles.into_iter()
+                .filter(|t| after.is_none_or(|after| t.to_lowercase() > after.to_lowercase()))
+                .take(limit as usize)
+                .map(|title| Linked { links: incoming(&title), title })
+                .collect())
+        }
+    }
+
+    pub fn new_links() -> Arc<MemoryLinks> {
+        Arc::new(MemoryLinks {
+            subjects: vec![
+                ("Home", "See [Rust](/wiki/Rust) and [the book](/wiki/The_Book#intro)."),
+                ("Rust", "Back [home](/wiki/home), on to [Cargo][cargo].\n\n[cargo]: /wiki/Cargo"),
+                ("Lonely", "Only [itself](/wiki/Lonely)."),
+            ],
+        })
+    }
+
+    #[test]
+    fn test_links() {
+        let content = "\
+            # [Rust](/wiki/Rust)\n\
+            Read [the book](/wiki/The_Book#ownership), [THE BOOK](/wiki/the%20book?rev=2) \
+            and <https://www.rust-lang.org/>, or [Cargo][].\n\n\
+            [Cargo]: /wiki/Cargo\n";
+        assert_eq!(links(content), vec!["Rust", "The Book", "Cargo"]);
+    }
+
+    #[test]
+    fn test_no_links() {
+        let content = "\
+            `[code](/wiki/Code)` and\n\n\
+            ```\n[fenced](/wiki/Fenced)\n```\n\n\
+            ![image](/wiki/Image) [relative](Relative) [empty](/wiki/) \
+            [nested](/wiki/a/b) [bad](/wiki/%FF) /wiki/Bare\n";
+        assert_eq!(links(content), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_reports() {
+        let f = filter(new_links());
+        for (report, titles) in [
+            ("orphans", vec![("Lonely", 0)]),
+            ("wanted", vec![("Cargo", 1), ("The Book", 1)]),
+            ("dead-ends", vec![("Lonely", 0)]),
+        ] {
+            let res = warp::test::request().path(&format!("/reports/{}", report)).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", report);
+            let linked: Vec<LinkedInfo> = serde_json::from_slice(res.body()).unwrap();
+            let linked: Vec<(&str, i64)> = linked.iter().map(|l| (l.title.as_str(), l.links)).collect();
+            assert_eq!(linked, titles, "{}", report);
+        }
+
+        let res = warp::test::request().path("/reports/wanted?after=cargo&limit=1").reply(&f).await;
+        let linked: Vec<LinkedInfo> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(linked.len(), 1);
+        assert_eq!((linked[0].title.as_str(), linked[0].slug.as_str()), ("The Book", "The_Book"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let f = filter(new_links());
+        for (path, code) in [
+            ("/reports/orphans?limit=0", "bad_request"),
+            ("/reports/wanted?limit=101", "bad_request"),
+            ("/reports/dead-ends?limit=many", "invalid_query"),
+        ] {
+            let res = warp::test::request().path(path).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}: {:?}", path, res.body());
+            let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(problem.code, code, "{}", path);
+        }
+
+        let res = warp::test::request().method("POST").path("/reports/wanted").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()["allow"], "GET");
+        // other reports are left to other filters
+        for method in ["GET", "POST"] {
+            let res = warp::test::request().method(method).path("/reports/most-viewed").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", method);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// openapi describes the API as an OpenAPI 3.1 document. Each API module lists
+// its operations next to its filter, and the document is built from those
+// lists and the JSON schemas of the types their bodies hold. A test keeps the
+// lists honest by asking every path which methods it allows.
+
+use std::sync::Arc;
+
+use schemars::{generate::SchemaSettings, JsonSchema, Schema, SchemaGenerator};
+use serde_json::{json, Map, Value};
+use warp::{http::{header::{CONTENT_SECURITY_POLICY, CONTENT_TYPE}, Method}, reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::user::Scope, error::{self, Problem}};
+
+const VIEWER_HTML: &str = include_str!("openapi/viewer.html");
+const VIEWER_JS: &str = include_str!("openapi/viewer.js");
+
+// the viewer loads nothing but its script and the document
+const VIEWER_CSP: &str = "default-src 'none'; script-src 'self'; connect-src 'self'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'";
+
+pub enum Body {
+    /// text/plain
+    Text,
+    /// application/json holding the schema
+    Json(fn(&mut SchemaGenerator) -> Schema),
+    /// Bytes of the media type, such as image/*
+    Binary(&'static str),
+}
+
+impl Body {
+    pub fn json<T: JsonSchema>() -> Self {
+        Body::Json(SchemaGenerator::subschema_for::<T>)
+    }
+
+    fn content(&self, generator: &mut SchemaGenerator) -> Value {
+        match self {
+            Body::Text => json!({"text/plain": {"schema": {"type": "string"}}}),
+            Body::Json(schema) => json!({"application/json": {"schema": schema(generator)}}),
+            Body::Binary(media_type) => json!({*media_type: {}}),
+        }
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub description: &'static str,
+    /// None for responses without a body, and for problems
+    pub body: Option<Body>,
+}
+
+impl Response {
+    pub fn ok(status: u16, description: &'static str, body: Option<Body>) -> Self {
+        Response { status, description, body }
+    }
+
+    /// A problem the operation is known to reply, besides the default
+    pub fn problem(status: u16, description: &'static str) -> Self {
+        Response { status, description, body: None }
+    }
+}
+
+pub struct Operation {
+    pub method: Method,
+    /// Path below the API root, with parameters in braces
+    pub path: &'static str,
+    pub id: &'static str,
+    pub summary: &'static str,
+    /// Scope a token needs, None for operations anyone may call
+    pub scope: Option<Scope>,
+    /// Names and JSON types of optional query parameters
+    pub query: &'static [(&'static str, &'static str)],
+    /// Names and JSON types of optional request headers
+    pub headers: &'static [(&'static str, &'static str)],
+    pub request: Option<Body>,
+    pub responses: Vec<Response>,
+}
+
+/// Builds the document of operations served below root, which are all
+/// deprecated when the root is
+pub fn document(operations: &[Operation], root: &str, deprecated: bool) -> Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|s| {
+            s.definitions_path = "/components/schemas".into();
+            s.meta_schema = None;
+        })
+        .into_generator();
+    let problem = generator.subschema_for::<Problem>();
+
+    let mut paths = Map::new();
+    for op in operations {
+        let mut responses = Map::new();
+        for r in &op.responses {
+            let mut response = json!({"description": r.description});
+            if let Some(body) = &r.body {
+                response["content"] = body.content(&mut generator);
+            } else if r.status >= 400 {
+                response["content"] = json!({"application/problem+json": {"schema": problem}});
+            }
+            responses.insert(r.status.to_string(), response);
+        }
+        responses.insert("default".into(), json!({
+            "description": "Problem",
+            "content": {"application/problem+json": {"schema": problem}},
+        }));
+
+        let mut operation = json!({
+            "operationId": op.id,
+            "summary": op.summary,
+            "parameters": parameters(op.path, op.query, op.headers),
+            "responses": responses,
+        });
+        if deprecated {
+            operation["deprecated"] = json!(true);
+        }
+        if let Some(scope) = op.scope {
+            operation["description"] = json!(format!("Requires a token with the {} scope.", scope.as_str()));
+            operation["security"] = json!([{"bearer": []}, {"basic": []}]);
+        }
+        if let Some(body) = &op.request {
+            operation["requestBody"] = json!({"required": true, "content": body.content(&mut generator)});
+        }
+
+        let path = paths.entry(op.path).or_insert_with(|| json!({}));
+        path[op.method.as_str().to_lowercase()] = operation;
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "wiki",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{"url": root}],
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer", "description": "Access token or personal access token"},
+                "basic": {"type": "http", "scheme": "basic"},
+            },
+        },
+    })
+}
+
+fn parameters(path: &str, query: &[(&str, &str)], headers: &[(&str, &str)]) -> Vec<Value> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}}))
+        .chain(query.iter().map(|(name, kind)| json!({"name": name, "in": "query", "schema": {"type": kind}})))
+        .chain(headers.iter().map(|(name, kind)| json!({"name": name, "in": "header", "schema": {"type": kind}})))
+        .collect()
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::GET,
+            path: "/openapi.json",
+            id: "readOpenApi",
+            summary: "Reads this document",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![Response::ok(200, "OpenAPI document", Some(Body::Json(|_| Schema::from(true))))],
+        },
+    ]
+}
+
+/// Serves the document, and with viewer a page that renders it at /docs.
+pub fn filter(document: Arc<Value>, viewer: bool) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+{
+    let docs = warp::any()
+        .and_then(move || async move {
+            if viewer { Ok(()) } else { Err(warp::reject::not_found()) }
+        })
+        .untuple_one()
+        .and(warp::get())
+        .and(
+            warp::path!("docs").map(|| page(VIEWER_HTML, "text/html; charset=utf-8"))
+            .or(warp::path!("docs" / "viewer.js").map(|| page(VIEWER_JS, "text/javascript; charset=utf-8")))
+        );
+
+    warp::path!("openapi.json")
+        .and(
+            warp::get().map(move || warp::reply::json(document.as_ref()))
+            .or(error::method_not_allowed(&[Method::GET]))
+        )
+        .or(docs)
+}
+
+fn page(body: &'static str, content_type: &'static str) -> impl Reply {
+    let reply = warp::reply::with_header(body, CONTENT_TYPE, content_type);
+    warp::reply::with_header(reply, CONTENT_SECURITY_POLICY, VIEWER_CSP)
+}
+
+/// Tests document and filter. Routes of every API module are checked
+/// against their operations.
+///
+/// Test plan:
+/// 1. Documents describe operations, their parameters, bodies and problems
+/// 2. Every path of the v1 and v2 documents allows exactly its methods
+/// 3. The viewer is served only when enabled
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use warp::http::StatusCode;
+
+    use crate::api::{self, draft, stats, idempotency::tests::new_idempotency, link, profile, transclusion, subject, title::MAX_LENGTH, v2};
+    use crate::auth::mock_user;
+
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let doc = document(&api::operations(), "/api/v1", true);
+        assert_eq!(doc["openapi"], "3.1.0");
+        assert_eq!(doc["servers"][0]["url"], "/api/v1");
+
+        let create = &doc["paths"]["/subject/{title}"]["post"];
+        assert_eq!(create["operationId"], "createSubject");
+        assert_eq!(create["parameters"][0]["name"], "title");
+        assert_eq!(create["parameters"][0]["in"], "path");
+        assert_eq!(create["parameters"][1], json!({"name": "Idempotency-Key", "in": "header", "schema": {"type": "string"}}));
+        assert_eq!(create["security"][0], json!({"bearer": []}));
+        assert!(create["requestBody"]["content"]["text/plain"].is_object());
+        assert_eq!(create["responses"]["409"]["content"]["application/problem+json"]["schema"]["$ref"], "#/components/schemas/Problem");
+        assert!(doc["paths"]["/subjects"]["get"].get("security").is_none());
+        assert_eq!(create["deprecated"], true);
+
+        let read = &doc["paths"]["/users/{username}"]["get"];
+        assert_eq!(read["responses"]["200"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ProfileInfo");
+        let contributions = &doc["paths"]["/users/{username}/contributions"]["get"];
+        assert_eq!(contributions["responses"]["200"]["content"]["application/json"]["schema"]["items"]["$ref"], "#/components/schemas/ContributionInfo");
+
+        let schemas = &doc["components"]["schemas"];
+        for name in ["Problem", "ProfileInfo", "ProfileUpdate", "ContributionInfo", "ContributionKind"] {
+            assert!(schemas[name].is_object(), "{}", name);
+        }
+        assert_eq!(schemas["Problem"]["properties"]["type"]["type"], "string");
+
+        // operations are unique
+        let ids: BTreeSet<_> = api::operations().iter().map(|op| op.id).collect();
+        assert_eq!(ids.len(), api::operations().len());
+    }
+
+    /// Asks every path of doc which methods f allows on it
+    async fn assert_routes_match<F>(f: &F, doc: &Value)
+    where
+        F: Filter + 'static,
+        F::Extract: Reply + Send,
+    {
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            let documented: BTreeSet<String> = item.as_object().unwrap().keys()
+                .map(|m| m.to_uppercase())
+                .collect();
+            let example = path.replace("{title}", "Some_title").replace("{username}", "bob").replace("{number}", "1");
+
+            // no route takes TRACE, so the path answers with all it allows
+            let res = warp::test::request().method("TRACE").path(&example).reply(f).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            let allowed: BTreeSet<String> = res.headers()["allow"].to_str().unwrap()
+                .split(", ")
+                .map(String::from)
+                .collect();
+            assert_eq!(allowed, documented, "{}", path);
+        }
+
+        let res = warp::test::request().path("/openapi.json").reply(f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(res.body()).unwrap(), *doc);
+    }
+
+    #[tokio::test]
+    async fn test_routes_match_document() {
+        let users = Arc::new(mock_user::Mock::new());
+        let settings = api::Settings { max_title_length: MAX_LENGTH, max_transclusion_depth: transclusion::MAX_DEPTH, openapi_viewer: false };
+
+        let stores = api::Stores {
+            subjects: Arc::new(subject::tests::good_subjects()),
+            drafts: Arc::new(draft::tests::MemoryDrafts::default()),
+            renders: Arc::new(transclusion::tests::MemoryRenders::default()),
+            profiles: Arc::new(profile::tests::MemoryProfiles::default()),
+            stats: Arc::new(stats::tests::MemoryStats::default()),
+            links: link::tests::new_links(),
+        };
+        let f = api::routes(stores, stats::tests::new_views(), users.clone(), new_idempotency(), settings);
+        assert_routes_match(&f, &document(&api::operations(), "/api/v1", true)).await;
+
+        let f = v2::routes(Arc::new(subject::tests::good_subjects()), stats::tests::new_views(), users, new_idempotency(), settings);
+        assert_routes_match(&f, &document(&api::v2::operations(), "/api/v2", false)).await;
+    }
+
+    #[tokio::test]
+    async fn test_viewer() {
+        let doc = Arc::new(json!({}));
+        let hidden = filter(doc.clone(), false);
+        assert!(!warp::test::request().path("/docs").matches(&hidden).await);
+
+        let shown = filter(doc, true);
+        for (path, content_type) in [("/docs", "text/html; charset=utf-8"), ("/docs/viewer.js", "text/javascript; charset=utf-8")] {
+            let res = warp::test::request().path(path).reply(&shown).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+            assert_eq!(res.headers()[CONTENT_TYPE], content_type);
+            assert_eq!(res.headers()[CONTENT_SECURITY_POLICY], VIEWER_CSP);
+        }
+    }
+}
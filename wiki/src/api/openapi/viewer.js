@@ -0,0 +1,76 @@
+// viewer renders the OpenAPI document next to it as plain HTML. It builds
+// elements from text only, so that nothing in the document runs as markup.
+
+function el(tag, text, ...children) {
+    let e = document.createElement(tag);
+    if (text != null) {
+        e.textContent = text;
+    }
+    e.append(...children);
+    return e;
+}
+
+function schemaName(schema) {
+    if (!schema) {
+        return "";
+    }
+    if (schema.$ref) {
+        return schema.$ref.split("/").pop();
+    }
+    if (schema.type == "array") {
+        return schemaName(schema.items) + "[]";
+    }
+    return schema.type || "any";
+}
+
+function content(c) {
+    let list = el("ul");
+    for (let [mediaType, media] of Object.entries(c || {})) {
+        let schema = schemaName(media.schema);
+        list.append(el("li", mediaType + (schema ? " " + schema : "")));
+    }
+    return list;
+}
+
+function operation(path, method, op) {
+    let details = el(
+        "details",
+        null,
+        el("summary", null, el("code", method.toUpperCase() + " " + path), " " + op.summary),
+    );
+    if (op.description) {
+        details.append(el("p", op.description));
+    }
+    if (op.requestBody) {
+        details.append(el("h4", "Request"), content(op.requestBody.content));
+    }
+    let responses = el("dl");
+    for (let [status, r] of Object.entries(op.responses)) {
+        responses.append(el("dt", status + " " + r.description), el("dd", null, content(r.content)));
+    }
+    details.append(el("h4", "Responses"), responses);
+    return details;
+}
+
+function render(doc) {
+    let root = document.getElementById("api");
+    root.replaceChildren(el("h1", doc.info.title + " " + doc.info.version), el("p", "Served below " + doc.servers[0].url));
+
+    for (let [path, item] of Object.entries(doc.paths)) {
+        for (let [method, op] of Object.entries(item)) {
+            root.append(operation(path, method, op));
+        }
+    }
+
+    root.append(el("h2", "Schemas"));
+    for (let [name, schema] of Object.entries(doc.components.schemas)) {
+        root.append(el("details", null, el("summary", name), el("pre", JSON.stringify(schema, null, 2))));
+    }
+}
+
+fetch("openapi.json")
+    .then((res) => res.json())
+    .then(render)
+    .catch((err) => {
+        document.getElementById("api").replaceChildren(el("p", "Could not load the API: " + err.message));
+    });
@@ -0,0 +1,660 @@
+// profile describes the people behind usernames: a display name, a bio, an
+// avatar, and the subjects they created or edited.
+
+use std::{collections::{HashMap, HashSet}, future::Future, sync::{Arc, LazyLock}, time::{SystemTime, UNIX_EPOCH}};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::{http::Method, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{openapi::{Body, Operation, Response}, with_tail}, auth::user::{Role, Scope, User, Users}, error::{self, Error}};
+
+const MAX_DISPLAY_NAME_LENGTH: usize = 256;
+const MAX_BIO_LENGTH: usize = 4096;
+const MAX_AVATAR_SIZE: u64 = 1024 * 1024;
+// avatars are images to show, never documents to run, whatever they contain
+const AVATAR_CSP: &str = "default-src 'none'; sandbox";
+
+// IMAGE_TYPES are the image types among the attachment mime types. SVG is
+// left out, since it can carry scripts.
+static IMAGE_TYPES: LazyLock<HashSet<String>> = LazyLock::new(|| {
+    let types: HashMap<String, String> = serde_yaml::from_str(include_str!("../../mime_types.yaml"))
+        .unwrap_or_default();
+    types.into_values()
+        .filter(|t| t.starts_with("image/") && t != "image/svg+xml")
+        .collect()
+});
+
+/// Tells the image type of content by its signature, None for content of no
+/// image type avatars may have. WBMP has no signature, so WBMP avatars are
+/// refused.
+fn image_type(content: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, signature: &[u8]| content.get(offset..offset + signature.len()) == Some(signature);
+    if at(0, b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if at(0, b"\x8bJNG\r\n\x1a\n") {
+        Some("image/x-jng")
+    } else if at(0, b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        Some("image/gif")
+    } else if at(0, b"RIFF") && at(8, b"WEBP") {
+        Some("image/webp")
+    } else if at(4, b"ftypavif") || at(4, b"ftypavis") {
+        Some("image/avif")
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        Some("image/tiff")
+    } else if at(0, b"\0\0\x01\0") {
+        Some("image/x-icon")
+    } else if at(0, b"BM") {
+        Some("image/x-ms-bmp")
+    } else {
+        None
+    }
+}
+
+pub trait Profiles {
+    /// Reads the profile of username. Users who are known but never set a
+    /// profile have an empty one.
+    fn read_profile(&self, username: &str) -> impl Future<Output = Result<Profile, Error>> + Send;
+    fn update_profile(&self, username: &str, update: &ProfileUpdate) -> impl Future<Output = Result<(), Error>> + Send;
+    fn read_avatar(&self, username: &str) -> impl Future<Output = Result<Avatar, Error>> + Send;
+    fn update_avatar(&self, username: &str, avatar: &Avatar) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Lists the subjects username created or edited, newest first
+    fn list_contributions(&self, username: &str) -> impl Future<Output = Result<Vec<Contribution>, Error>> + Send;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub username: String,
+    pub display_name: String,
+    pub bio: String,
+    pub avatar_content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct ProfileUpdate {
+    pub display_name: String,
+    pub bio: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Avatar {
+    pub content_type: String,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contribution {
+    pub title: String,
+    pub created: bool,
+    pub edited_at: SystemTime,
+    pub summary: String,
+    pub minor: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ProfileInfo {
+    pub username: String,
+    pub display_name: String,
+    pub bio: String,
+    /// Path of the avatar, None for users without one
+    pub avatar: Option<String>,
+}
+
+impl From<&Profile> for ProfileInfo {
+    fn from(p: &Profile) -> Self {
+        ProfileInfo {
+            username: p.username.clone(),
+            display_name: p.display_name.clone(),
+            bio: p.bio.clone(),
+            avatar: p.avatar_content_type.as_ref()
+                .map(|_| format!("/api/v1/users/{}/avatar", p.username)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ContributionKind {
+    Created,
+    Edited,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ContributionInfo {
+    pub title: String,
+    pub kind: ContributionKind,
+    /// Seconds since the unix epoch
+    pub edited_at: u64,
+    /// Why the content changed, empty without a reason
+    pub summary: String,
+    pub minor: bool,
+}
+
+impl From<&Contribution> for ContributionInfo {
+    fn from(c: &Contribution) -> Self {
+        ContributionInfo {
+            title: c.title.clone(),
+            kind: if c.created { ContributionKind::Created } else { ContributionKind::Edited },
+            edited_at: c.edited_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            summary: c.summary.clone(),
+            minor: c.minor,
+        }
+    }
+}
+
+fn validate(update: &ProfileUpdate) -> Result<(), Error> {
+    if update.display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "display name must be at most {} characters", MAX_DISPLAY_NAME_LENGTH,
+        )));
+    }
+    if update.bio.chars().count() > MAX_BIO_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "bio must be at most {} characters", MAX_BIO_LENGTH,
+        )));
+    }
+    Ok(())
+}
+
+// may_edit allows users to edit their own profile, and admins every profile
+fn may_edit(user: &User, username: &str) -> Result<(), Error> {
+    if user.name == username || user.roles.contains(&Role::Admin) {
+        Ok(())
+    } else {
+        Err(Error::Forbidden(format!("{} may not edit the profile of {}", user.name, username)))
+    }
+}
+
+pub fn filter<P, U>(profiles: Arc<P>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    P: Profiles + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+{
+    warp::path!("users" / ..)
+        .and(
+            with_tail("").and(warp::get()).and(endpoints::read(profiles.clone()))
+            .or(with_tail("").and(warp::put()).and(endpoints::update(profiles.clone(), users.clone())))
+            .or(with_tail("avatar").and(warp::get()).and(endpoints::read_avatar(profiles.clone())))
+            .or(with_tail("avatar").and(warp::put()).and(endpoints::update_avatar(profiles.clone(), users)))
+            .or(with_tail("contributions").and(warp::get()).and(endpoints::contributions(profiles)))
+            .or(with_tail("").and(error::method_not_allowed(&[Method::GET, Method::PUT])))
+            .or(with_tail("avatar").and(error::method_not_allowed(&[Method::GET, Method::PUT])))
+            .or(with_tail("contributions").and(error::method_not_allowed(&[Method::GET])))
+        )
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::GET,
+            path: "/users/{username}",
+            id: "readProfile",
+            summary: "Reads the profile of a user",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Profile", Some(Body::json::<ProfileInfo>())),
+                Response::problem(404, "No such user"),
+            ],
+        },
+        Operation {
+            method: Method::PUT,
+            path: "/users/{username}",
+            id: "updateProfile",
+            summary: "Replaces the profile of a user, by themselves or an admin",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: &[],
+            request: Some(Body::json::<ProfileUpdate>()),
+            responses: vec![
+                Response::ok(204, "Updated", None),
+                Response::problem(400, "Display name or bio too long"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(403, "Profile of someone else"),
+            ],
+        },
+        Operation {
+            method: Method::GET,
+            path: "/users/{username}/avatar",
+            id: "readAvatar",
+            summary: "Reads the avatar of a user",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Avatar", Some(Body::Binary("image/*"))),
+                Response::problem(404, "No avatar"),
+            ],
+        },
+        Operation {
+            method: Method::PUT,
+            path: "/users/{username}/avatar",
+            id: "updateAvatar",
+            summary: "Replaces the avatar of a user, by themselves or an admin",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: &[],
+            request: Some(Body::Binary("image/*")),
+            responses: vec![
+                Response::ok(204, "Updated", None),
+                Response::problem(400, "Not an image"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(403, "Avatar of someone else"),
+                Response::problem(413, "Larger than 1 MiB"),
+            ],
+        },
+        Operation {
+            method: Method::GET,
+            path: "/users/{username}/contributions",
+            id: "listContributions",
+            summary: "Lists the subjects a user created or edited, newest first",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![Response::ok(200, "Contributions", Some(Body::json::<Vec<ContributionInfo>>()))],
+        },
+    ]
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{auth::user::{with_authorization, Scope, Users}, error};
+
+    use super::{handlers, Profiles, MAX_AVATAR_SIZE};
+
+    pub fn read<P>(profiles: Arc<P>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        P: Profiles + Send + Sync + 'static
+    {
+        warp::path::param()
+            .and(warp::path::end())
+            .and(with_profiles(profiles))
+            .and_then(handlers::read)
+            .recover(error::recover)
+    }
+
+    pub fn update<P, U>(profiles: Arc<P>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        P: Profiles + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        warp::path::param()
+            .and(warp::path::end())
+            .and(with_profiles(profiles))
+            .and(with_authorization(users, Scope::Write))
+            .and(warp::body::json())
+            .and_then(handlers::update)
+            .recover(error::recover)
+    }
+
+    pub fn read_avatar<P>(profiles: Arc<P>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        P: Profiles + Send + Sync + 'static
+    {
+        warp::path::param()
+            .and(warp::path!("avatar"))
+            .and(with_profiles(profiles))
+            .and_then(handlers::read_avatar)
+            .recover(error::recover)
+    }
+
+    pub fn update_avatar<P, U>(profiles: Arc<P>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        P: Profiles + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        warp::path::param()
+            .and(warp::path!("avatar"))
+            .and(with_profiles(profiles))
+            .and(with_authorization(users, Scope::Write))
+            .and(warp::header::optional::<String>("Content-Type"))
+            .and(warp::body::content_length_limit(MAX_AVATAR_SIZE))
+            .and(warp::body::bytes())
+            .and_then(handlers::update_avatar)
+            .recover(error::recover)
+    }
+
+    pub fn contributions<P>(profiles: Arc<P>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        P: Profiles + Send + Sync + 'static
+    {
+        warp::path::param()
+            .and(warp::path!("contributions"))
+            .and(with_profiles(profiles))
+            .and_then(handlers::contributions)
+            .recover(error::recover)
+    }
+
+    fn with_profiles<P>(profiles: Arc<P>) -> impl Filter<Extract = (Arc<P>,), Error = Infallible> + Clone
+    where
+        P: Profiles + Send + Sync + 'static
+    {
+        warp::any().map(move || profiles.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use warp::{http::{header::{HeaderValue, CONTENT_DISPOSITION, CONTENT_SECURITY_POLICY, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS}, StatusCode}, reject::Rejection, reply::Reply};
+
+    use crate::{auth::user::User, error::Error};
+
+    use super::{image_type, may_edit, validate, Avatar, ContributionInfo, ProfileInfo, ProfileUpdate, Profiles, AVATAR_CSP, IMAGE_TYPES};
+
+    pub async fn read<P: Profiles>(username: String, profiles: Arc<P>) -> Result<impl Reply, Rejection> {
+        match profiles.read_profile(&username).await {
+            Ok(profile) => Ok(warp::reply::json(&ProfileInfo::from(&profile))),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn update<P: Profiles>(username: String, profiles: Arc<P>, user: User, update: ProfileUpdate) -> Result<impl Reply, Rejection> {
+        let r = async {
+            may_edit(&user, &username)?;
+            validate(&update)?;
+            profiles.update_profile(&username, &update).await
+        }.await;
+
+        match r {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn read_avatar<P: Profiles>(username: String, profiles: Arc<P>) -> Result<impl Reply, Rejection> {
+        match profiles.read_avatar(&username).await {
+            Ok(avatar) => {
+                // browsers opening avatars take them for nothing but images
+                let mut res = warp::reply::with_header(avatar.content, CONTENT_TYPE, avatar.content_type).into_response();
+                let headers = res.headers_mut();
+                headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+                headers.insert(CONTENT_DISPOSITION, HeaderValue::from_static("inline; filename=\"avatar\""));
+                headers.insert(CONTENT_SECURITY_POLICY, HeaderValue::from_static(AVATAR_CSP));
+                Ok(res)
+            },
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn update_avatar<P: Profiles>(username: String, profiles: Arc<P>, user: User, content_type: Option<String>, content: Bytes) -> Result<impl Reply, Rejection> {
+        let r = async {
+            may_edit(&user, &username)?;
+            let content_type = content_type
+                .filter(|t| IMAGE_TYPES.contains(t))
+                .ok_or(Error::BadRequest("avatar must be an image".into()))?;
+            if content.is_empty() {
+                return Err(Error::BadRequest("no body".into()));
+            }
+            if image_type(&content) != Some(content_type.as_str()) {
+                return Err(Error::BadRequest(format!("avatar is not of type {}", content_type)));
+            }
+            profiles.update_avatar(&username, &Avatar {
+                content_type,
+                content: content.to_vec(),
+            }).await
+        }.await;
+
+        match r {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn contributions<P: Profiles>(username: String, profiles: Arc<P>) -> Result<impl Reply, Rejection> {
+        match profiles.list_contributions(&username).await {
+            Ok(list) => Ok(warp::reply::json(&list.iter().map(ContributionInfo::from).collect::<Vec<_>>())),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules.
+/// Exposes memory profiles to test modules.
+///
+/// Test plan:
+/// 1. Profiles are read, and edited by their owner only
+/// 2. Avatars must be images of the type they claim, and are served as images
+///    only
+/// 3. Contributions are listed newest first
+/// 4. Reject bad paths, and bad methods with 405 and the allowed methods
+#[cfg(test)]
+pub mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use warp::http::StatusCode;
+
+    use crate::auth::mock_user;
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct MemoryProfiles {
+        profiles: Mutex<HashMap<String, Profile>>,
+        avatars: Mutex<HashMap<String, Avatar>>,
+        contributions: Mutex<Vec<(String, Contribution)>>,
+    }
+
+    impl Profiles for MemoryProfiles {
+        async fn read_profile(&self, username: &str) -> Result<Profile, Error> {
+            if let Some(p) = self.profiles.lock().unwrap().get(username) {
+                return Ok(p.clone());
+            }
+            if self.contributions.lock().unwrap().iter().any(|(u, _)| u == username) {
+                return Ok(Profile { username: username.into(), ..Default::default() });
+            }
+            Err(Error::NotFound(username.to_string()))
+        }
+
+        async fn update_profile(&self, username: &str, update: &ProfileUpdate) -> Result<(), Error> {
+            let mut profiles = self.profiles.lock().unwrap();
+            let p = profiles.entry(username.into())
+                .or_insert(Profile { username: username.into(), ..Default::default() });
+            p.display_name = update.display_name.clone();
+            p.bio = update.bio.clone();
+            Ok(())
+        }
+
+        async fn read_avatar(&self, username: &str) -> Result<Avatar, Error> {
+            match self.avatars.lock().unwrap().get(username) {
+                Some(a) => Ok(a.clone()),
+                None => Err(Error::NotFound(username.to_string())),
+            }
+        }
+
+        async fn update_avatar(&self, username: &str, avatar: &Avatar) -> Result<(), Error> {
+            self.profiles.lock().unwrap().entry(username.into())
+                .or_insert(Profile { username: username.into(), ..Default::default() })
+                .avatar_content_type = Some(avatar.content_type.clone());
+            self.avatars.lock().unwrap().insert(username.into(), avatar.clone());
+            Ok(())
+        }
+
+        async fn list_contributions(&self, username: &str) -> Result<Vec<Contribution>, Error> {
+            let mut list: Vec<Contribution> = self.contributions.lock().unwrap().iter()
+                .filter(|(u, _)| u == username)
+                .map(|(_, c)| c.clone())
+                .collect();
+            list.sort_by_key(|c| std::cmp::Reverse(c.edited_at));
+            Ok(list)
+        }
+    }
+
+    fn new_filter(profiles: Arc<MemoryProfiles>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+        filter(profiles, Arc::new(mock_user::Mock::new()))
+    }
+
+    #[tokio::test]
+    async fn test_profiles() {
+        let profiles = Arc::new(MemoryProfiles::default());
+        let f = new_filter(profiles.clone());
+
+        let res = warp::test::request().path("/users/bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/users/bob")
+            .header("Authorization", "Basic bob:pass")
+            .json(&serde_json::json!({"display_name": "Bob", "bio": "Edits things"}))
+            .reply(&f)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let res = warp::test::request().path("/users/bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let info: ProfileInfo = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(info.display_name, "Bob");
+        assert_eq!(info.bio, "Edits things");
+        assert_eq!(info.avatar, None);
+
+        // others may not edit
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/users/bob")
+            .header("Authorization", "Basic alice:pass")
+            .json(&serde_json::json!({"display_name": "Not Bob", "bio": ""}))
+            .reply(&f)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // too long
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/users/bob")
+            .header("Authorization", "Basic bob:pass")
+            .json(&serde_json::json!({"display_name": "b".repeat(MAX_DISPLAY_NAME_LENGTH + 1), "bio": ""}))
+            .reply(&f)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // no auth
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/users/bob")
+            .json(&serde_json::json!({"display_name": "Bob", "bio": ""}))
+            .reply(&f)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[tokio::test]
+    async fn test_avatars() {
+        let profiles = Arc::new(MemoryProfiles::default());
+        let f = new_filter(profiles.clone());
+        let put = |content_type: &str, body: Vec<u8>| warp::test::request()
+            .method("PUT")
+            .path("/users/bob/avatar")
+            .header("Authorization", "Basic bob:pass")
+            .header("Content-Type", content_type)
+            .body(body);
+
+        let res = warp::test::request().path("/users/bob/avatar").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        for (content_type, body) in [
+            ("image/svg+xml", b"<svg/>".to_vec()),
+            ("text/html", b"<p>".to_vec()),
+            ("image/png", vec![]),
+            ("image/png", b"<html><script>alert(1)</script>".to_vec()),
+            ("image/png", PNG[..4].to_vec()),
+            ("image/gif", PNG.to_vec()),
+            ("image/vnd.wap.wbmp", vec![0, 0, 1, 1, 0]),
+        ] {
+            let res = put(content_type, body).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", content_type);
+        }
+        let res = put("image/png", vec![0; MAX_AVATAR_SIZE as usize + 1]).reply(&f).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = put("image/png", PNG.to_vec()).reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = warp::test::request().path("/users/bob/avatar").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["Content-Type"], "image/png");
+        assert_eq!(res.headers()["X-Content-Type-Options"], "nosniff");
+        assert_eq!(res.headers()["Content-Disposition"], "inline; filename=\"avatar\"");
+        assert_eq!(res.headers()["Content-Security-Policy"], AVATAR_CSP);
+        assert_eq!(res.body(), PNG);
+
+        for (content_type, body) in [
+            ("image/jpeg", b"\xff\xd8\xff\xe0".to_vec()),
+            ("image/gif", b"GIF89a".to_vec()),
+            ("image/webp", b"RIFF\0\0\0\0WEBPVP8 ".to_vec()),
+            ("image/avif", b"\0\0\0\x1cftypavif".to_vec()),
+        ] {
+            let res = put(content_type, body).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT, "{}", content_type);
+        }
+
+        let res = warp::test::request().path("/users/bob").reply(&f).await;
+        let info: ProfileInfo = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(info.avatar.unwrap(), "/api/v1/users/bob/avatar");
+    }
+
+    #[tokio::test]
+    async fn test_contributions() {
+        let profiles = Arc::new(MemoryProfiles::default());
+        let now = SystemTime::now();
+        for (title, created, ago, summary) in [("First", true, 60, ""), ("First", false, 30, "Fix typo"), ("Second", true, 10, "")] {
+            profiles.contributions.lock().unwrap().push(("bob".into(), Contribution {
+                title: title.into(),
+                created,
+                edited_at: now - Duration::from_secs(ago),
+                summary: summary.into(),
+                minor: !created,
+            }));
+        }
+        let f = new_filter(profiles);
+
+        let res = warp::test::request().path("/users/bob/contributions").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let list: Vec<ContributionInfo> = serde_json::from_slice(res.body()).unwrap();
+        let actual: Vec<_> = list.iter().map(|c| (c.title.as_str(), c.kind)).collect();
+        assert_eq!(actual, vec![
+            ("Second", ContributionKind::Created),
+            ("First", ContributionKind::Edited),
+            ("First", ContributionKind::Created),
+        ]);
+        assert_eq!((list[1].summary.as_str(), list[1].minor), ("Fix typo", true));
+
+        // known from contributions alone
+        let res = warp::test::request().path("/users/bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = warp::test::request().path("/users/alice/contributions").reply(&f).await;
+        assert_eq!(res.body(), "[]");
+    }
+
+    #[tokio::test]
+    async fn test_reject_bad_paths_and_methods() {
+        let f = new_filter(Arc::new(MemoryProfiles::default()));
+
+        for path in ["/users/bob/sessions", "/users/bob/avatar/extra", "/user/bob"] {
+            assert!(!warp::test::request().path(path).matches(&f).await, "{}", path);
+        }
+        let res = warp::test::request().path("/users").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        for (method, path, allow) in [
+            ("POST", "/users/bob", "GET, PUT"),
+            ("PUT", "/users/bob/contributions", "GET"),
+            ("DELETE", "/users/bob/avatar", "GET, PUT"),
+        ] {
+            let res = warp::test::request().method(method).path(path).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+            assert_eq!(res.headers()["allow"], allow, "{} {}", method, path);
+        }
+    }
+}
@@ -0,0 +1,575 @@
+// section lets editors work on one part of a long subject. The Markdown
+// headings of content outline it: section n runs from the nth heading up to
+// the next heading of the same or a higher level, so it holds its subsections,
+// and section 0 is the lead before the first heading. Sections are read with
+// an ETag, and replacing one with If-Match fails while the section changed
+// since, rather than overwriting the change.
+
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::{filters::path::Peek, http::Method, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{edit, idempotency::{Idempotency, Keys}, openapi::{Body, Operation, Response}, subject::Subjects, with_tail}, auth::user::{Scope, Users}, error};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// Position among the headings, from 1
+    pub number: usize,
+    /// 1 to 6, like h1 to h6
+    pub level: u8,
+    pub heading: String,
+    pub anchor: String,
+    /// Bytes of content from the heading up to the next section that is no
+    /// subsection
+    pub range: Range<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SectionInfo {
+    /// Number of the section in paths
+    pub number: usize,
+    pub level: u8,
+    /// Text of the heading
+    pub heading: String,
+    /// Fragment that links to the heading, unique in the subject
+    pub anchor: String,
+}
+
+impl From<&Section> for SectionInfo {
+    fn from(s: &Section) -> Self {
+        SectionInfo {
+            number: s.number,
+            level: s.level,
+            heading: s.heading.clone(),
+            anchor: s.anchor.clone(),
+        }
+    }
+}
+
+/// Outlines content by its headings, in order. Headings in block quotes and
+/// lists are part of those, and start no section.
+pub fn outline(content: &str) -> Vec<Section> {
+    let mut headings: Vec<(u8, String, usize)> = vec![];
+    let mut depth = 0;
+    let mut heading: Option<(u8, String, usize)> = None;
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) if depth == 0 => {
+                // back to the start of the line, before indentation
+                let start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+                heading = Some((level as u8, String::new(), start));
+                depth += 1;
+            },
+            Event::Start(_) => depth += 1,
+            Event::End(TagEnd::Heading(_)) if depth == 1 => {
+                headings.extend(heading.take());
+                depth -= 1;
+            },
+            Event::End(_) => depth -= 1,
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, h, _)) = heading.as_mut() {
+                    h.push_str(&text);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut anchors = HashMap::new();
+    headings.iter().enumerate()
+        .map(|(i, (level, text, start))| {
+            let end = headings[i + 1..].iter()
+                .find(|(l, _, _)| l <= level)
+                .map_or(content.len(), |(_, _, start)| *start);
+            Section {
+                number: i + 1,
+                level: *level,
+                heading: text.trim().to_string(),
+                anchor: unique(&mut anchors, anchor(text)),
+                range: *start..end,
+            }
+        })
+        .collect()
+}
+
+/// Bytes of section number of content, the lead for 0
+pub fn section(content: &str, number: usize) -> Option<Range<usize>> {
+    let outline = outline(content);
+    match number {
+        0 => Some(0..outline.first().map_or(content.len(), |s| s.range.start)),
+        n => outline.get(n - 1).map(|s| s.range.clone()),
+    }
+}
+
+/// Lowercases the heading, turns whitespace into dashes and drops
+/// punctuation, like most Markdown renderers
+pub fn anchor(heading: &str) -> String {
+    heading.trim()
+        .chars()
+        .flat_map(char::to_lowercase)
+        .filter_map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            c if c.is_whitespace() => Some('-'),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Numbers anchors seen before, from 1
+fn unique(seen: &mut HashMap<String, usize>, anchor: String) -> String {
+    let count = seen.entry(anchor.clone()).or_insert(0);
+    *count += 1;
+    match *count {
+        1 => anchor,
+        n => format!("{}-{}", anchor, n - 1),
+    }
+}
+
+/// Strong entity tag of the text of a section
+pub fn etag(text: &str) -> String {
+    let hash = Sha256::digest(text.as_bytes());
+    format!("\"{}\"", hash.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+/// Whether an If-Match header value matches the tag
+fn matches(if_match: &str, etag: &str) -> bool {
+    if_match.split(',').map(str::trim).any(|tag| tag == "*" || tag == etag)
+}
+
+/// Replaces the bytes of a section with text, returning the content and the
+/// bytes of the text in it. Text followed by more content ends with a blank
+/// line, so that the next heading is no part of it.
+fn replace(content: &str, range: Range<usize>, text: &str) -> (String, Range<usize>) {
+    let mut replaced = String::with_capacity(content.len() + text.len() + 2);
+    replaced.push_str(&content[..range.start]);
+    replaced.push_str(text);
+    if range.end < content.len() && !text.ends_with("\n\n") {
+        replaced.push_str(if text.ends_with('\n') { "\n" } else { "\n\n" });
+    }
+    let written = range.start..replaced.len();
+    replaced.push_str(&content[range.end..]);
+    (replaced, written)
+}
+
+pub fn filter<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    S: Subjects + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+    K: Keys + Send + Sync + 'static,
+{
+    warp::path!("subject" / ..)
+        .and(
+            with_tail("toc").and(warp::get()).and(endpoints::toc(subjects.clone(), max_title_length))
+            .or(with_tail("toc").and(error::method_not_allowed(&[Method::GET])))
+            .or(with_section().and(warp::get()).and(endpoints::read(subjects.clone(), max_title_length)))
+            .or(with_section().and(warp::put()).and(endpoints::replace(subjects, users, idempotency, max_title_length)))
+            .or(with_section().and(error::method_not_allowed(&[Method::GET, Method::PUT])))
+        )
+}
+
+/// Rejects paths other than those of sections, like with_tail
+fn with_section() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and_then(|peek: Peek| async move {
+            match peek.as_str().split_once('/') {
+                Some((_, tail)) if tail.strip_prefix("section/").is_some_and(|n| !n.is_empty() && !n.contains('/')) => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::GET,
+            path: "/subject/{title}/toc",
+            id: "readTableOfContents",
+            summary: "Outlines a subject by its headings, numbering the sections",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Sections", Some(Body::json::<Vec<SectionInfo>>())),
+                Response::problem(400, "Bad title"),
+                Response::problem(404, "No such subject"),
+            ],
+        },
+        Operation {
+            method: Method::GET,
+            path: "/subject/{title}/section/{number}",
+            id: "readSection",
+            summary: "Reads a section of a subject, 0 for the lead, with an ETag to replace it",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Section", Some(Body::Text)),
+                Response::problem(400, "Bad title or number"),
+                Response::problem(404, "No such subject or section"),
+            ],
+        },
+        Operation {
+            method: Method::PUT,
+            path: "/subject/{title}/section/{number}",
+            id: "replaceSection",
+            summary: "Replaces a section of a subject, If-Match with its ETag fails once the section changed",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: SECTION_HEADERS,
+            request: Some(Body::Text),
+            responses: vec![
+                Response::ok(200, "Replaced, ETag holds the tag of the text", None),
+                Response::problem(400, "Bad title, number, edit or no content"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(404, "No such subject or section"),
+                Response::problem(412, "The section or subject changed"),
+            ],
+        },
+    ]
+}
+
+/// Request headers of replacing sections, those of edits and If-Match
+const SECTION_HEADERS: &[(&str, &str)] = &[
+    edit::HEADERS[0],
+    edit::HEADERS[1],
+    edit::HEADERS[2],
+    ("If-Match", "string"),
+];
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use bytes::Bytes;
+    use warp::{reject::Rejection, reply::Reply, Filter};
+
+    use crate::{api::{edit, idempotency::{self, Idempotency, Keys}, title}, auth::user::{with_authorization, Scope, Users}, error::{self, Error}};
+
+    use super::{handlers, Subjects};
+
+    pub fn toc<S>(subjects: Arc<S>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static
+    {
+        title::param(max_title_length)
+            .and(warp::path!("toc"))
+            .and(with_subjects(subjects))
+            .and_then(handlers::toc)
+            .recover(error::recover)
+    }
+
+    pub fn read<S>(subjects: Arc<S>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static
+    {
+        title::param(max_title_length)
+            .and(number())
+            .and(with_subjects(subjects))
+            .and_then(handlers::read)
+            .recover(error::recover)
+    }
+
+    pub fn replace<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+        K: Keys + Send + Sync + 'static,
+    {
+        title::param(max_title_length)
+            .and(number())
+            .and(with_subjects(subjects))
+            .and(with_authorization(users, Scope::Write))
+            .and(warp::body::bytes().map(|body: Bytes| {
+                String::from_utf8_lossy(&body).to_string()
+            }))
+            .and(edit::headers())
+            .and(warp::header::optional::<String>("if-match"))
+            .and(idempotency::attempt(idempotency))
+            .and_then(handlers::replace)
+            .recover(error::recover)
+    }
+
+    /// Extracts the number of a section from section/{number}, rejecting
+    /// other numbers with Error::BadRequest
+    fn number() -> impl Filter<Extract = (usize,), Error = Rejection> + Clone {
+        warp::path("section")
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and_then(|number: String| async move {
+                number.parse::<usize>()
+                    .map_err(|_| warp::reject::custom(Error::BadRequest(format!("section must be a number, not {}", number))))
+            })
+    }
+
+    fn with_subjects<S>(subjects: Arc<S>) -> impl Filter<Extract = (Arc<S>,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static
+    {
+        warp::any().map(move || subjects.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{http::header::{CONTENT_TYPE, ETAG}, reject::Rejection, reply::Reply};
+
+    use crate::{api::{edit::Edit, idempotency::{Attempt, Keys}, title::Title}, auth::user::User, error::Error};
+
+    use super::{etag, matches, outline, replace as replace_section, section, SectionInfo, Subjects};
+
+    pub async fn toc<S: Subjects>(title: Title, subjects: Arc<S>) -> Result<impl Reply, Rejection> {
+        match subjects.read(&title).await {
+            Ok(content) => Ok(warp::reply::json(&outline(&content).iter().map(SectionInfo::from).collect::<Vec<_>>())),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn read<S: Subjects>(title: Title, number: usize, subjects: Arc<S>) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let content = subjects.read(&title).await?;
+            let range = section(&content, number)
+                .ok_or_else(|| Error::NotFound(format!("section {} of {}", number, title)))?;
+            Ok::<_, Error>(content[range].to_string())
+        }.await;
+
+        match r {
+            Ok(text) => {
+                let tag = etag(&text);
+                Ok(warp::reply::with_header(
+                    warp::reply::with_header(text, CONTENT_TYPE, "text/plain"),
+                    ETAG,
+                    tag))
+            },
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn replace<S: Subjects, K: Keys>(title: Title, number: usize, subjects: Arc<S>, user: User, text: String, edit: Edit, if_match: Option<String>, attempt: Attempt<K>) -> Result<impl Reply, Rejection> {
+        // a retry must not replace the section under another precondition
+        let body = serde_json::to_vec(&(&edit, &if_match, &text)).unwrap_or_default();
+        attempt.run(&user.name, &body, async {
+            if text.is_empty() {
+                return Err(Error::BadRequest("no body".into()));
+            }
+            let previous = subjects.read(&title).await?;
+            let range = section(&previous, number)
+                .ok_or_else(|| Error::NotFound(format!("section {} of {}", number, title)))?;
+            if let Some(if_match) = if_match
+                && !matches(&if_match, &etag(&previous[range.clone()]))
+            {
+                return Err(Error::PreconditionFailed(format!("section {} of {} changed", number, title)));
+            }
+            let (content, written) = replace_section(&previous, range, &text);
+            // fails if another edit landed since the read, even in another
+            // section
+            subjects.update_if(&user.name, &title, &previous, &content, &edit).await?;
+            Ok(warp::reply::with_header(warp::reply(), ETAG, etag(&content[written])))
+        }).await
+    }
+}
+
+/// Tests outline, filter, and endpoints and handlers modules, with subjects in
+/// memory.
+///
+/// Test plan:
+/// 1. Headings outline content into sections holding their subsections
+/// 2. The table of contents lists the sections
+/// 3. Sections are read with an ETag, and replaced
+/// 4. Replacing with a stale ETag, or after a concurrent edit, fails with 412,
+///    and retries must carry the same If-Match
+/// 5. Bad requests reply with problems, bad methods with 405
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use crate::{api::{idempotency::tests::new_idempotency, subject::tests::MemorySubjects, title::MAX_LENGTH}, auth::mock_user, error::{Error, Problem}};
+
+    use super::*;
+
+    const RUNBOOK: &str = "\
+Lead paragraph.
+
+# Setup
+Install it.
+
+## Linux
+  ### `apt`
+Use apt.
+
+## Windows
+Use the installer.
+
+> # Quoted
+> not a section
+
+Setup
+=====
+Again, with `code` in [links](/wiki/Links).
+```
+# not a heading
+```
+";
+
+    fn new_filter(subjects: Arc<MemorySubjects>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        filter(subjects, Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH)
+    }
+
+    fn request(method: &str, path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", "Basic bob:pass")
+    }
+
+    #[test]
+    fn test_outline() {
+        let outline = outline(RUNBOOK);
+        let headings: Vec<(usize, u8, &str, &str)> = outline.iter()
+            .map(|s| (s.number, s.level, s.heading.as_str(), s.anchor.as_str()))
+            .collect();
+        assert_eq!(headings, vec![
+            (1, 1, "Setup", "setup"),
+            (2, 2, "Linux", "linux"),
+            (3, 3, "apt", "apt"),
+            (4, 2, "Windows", "windows"),
+            (5, 1, "Setup", "setup-1"),
+        ]);
+
+        let text = |n| section(RUNBOOK, n).map(|r| &RUNBOOK[r]);
+        assert_eq!(text(0), Some("Lead paragraph.\n\n"));
+        assert!(text(1).unwrap().starts_with("# Setup\n") && text(1).unwrap().ends_with("> not a section\n\n"));
+        assert_eq!(text(2), Some("## Linux\n  ### `apt`\nUse apt.\n\n"));
+        assert_eq!(text(3), Some("  ### `apt`\nUse apt.\n\n"));
+        assert!(text(5).unwrap().starts_with("Setup\n=====\n") && text(5).unwrap().ends_with("```\n"));
+        assert_eq!(text(6), None);
+
+        assert_eq!(section("No headings", 0).map(|r| r.len()), Some(11));
+        assert_eq!(section("No headings", 1), None);
+    }
+
+    #[tokio::test]
+    async fn test_toc() {
+        let f = new_filter(Arc::new(MemorySubjects::with(&[("Runbook", RUNBOOK)])));
+        let res = warp::test::request().path("/subject/runbook/toc").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let toc: Vec<SectionInfo> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(toc.len(), 5);
+        assert_eq!((toc[3].number, toc[3].level, toc[3].heading.as_str()), (4, 2, "Windows"));
+    }
+
+    #[tokio::test]
+    async fn test_sections() {
+        let subjects = Arc::new(MemorySubjects::with(&[("Runbook", RUNBOOK)]));
+        let f = new_filter(subjects.clone());
+
+        let res = warp::test::request().path("/subject/Runbook/section/4").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "## Windows\nUse the installer.\n\n> # Quoted\n> not a section\n\n");
+        let tag = res.headers()["etag"].to_str().unwrap().to_string();
+
+        let res = request("PUT", "/subject/Runbook/section/4")
+            .header("if-match", &tag)
+            .header("x-edit-summary", "Winget")
+            .body("## Windows\nUse winget.")
+            .reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        assert_eq!(res.headers()["etag"], etag("## Windows\nUse winget.\n\n"));
+        let content = subjects.content("Runbook").unwrap();
+        assert!(content.contains("## Windows\nUse winget.\n\nSetup\n=====\n"), "{}", content);
+        assert!(content.starts_with("Lead paragraph.\n\n# Setup\nInstall it.\n\n## Linux\n"));
+        assert_eq!(subjects.edits().last().unwrap().1.summary, "Winget");
+
+        // without If-Match, the section is replaced as it is
+        let res = request("PUT", "/subject/Runbook/section/0").body("New lead.\n\n").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(subjects.content("Runbook").unwrap().starts_with("New lead.\n\n# Setup\n"));
+    }
+
+    #[tokio::test]
+    async fn test_conflicts() {
+        let subjects = Arc::new(MemorySubjects::with(&[("Runbook", RUNBOOK)]));
+        let f = new_filter(subjects.clone());
+        let res = warp::test::request().path("/subject/Runbook/section/2").reply(&f).await;
+        let tag = res.headers()["etag"].to_str().unwrap().to_string();
+
+        // someone else changes the section
+        request("PUT", "/subject/Runbook/section/2").body("## Linux\nUse apt or dnf.\n\n").reply(&f).await;
+        let res = request("PUT", "/subject/Runbook/section/2")
+            .header("if-match", &tag)
+            .body("## Linux\nUse apt, nothing else.\n\n")
+            .reply(&f).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(problem.code, "precondition_failed");
+        assert!(subjects.content("Runbook").unwrap().contains("Use apt or dnf."));
+
+        // changes to other sections leave the tag valid
+        request("PUT", "/subject/Runbook/section/0").body("Other lead.\n\n").reply(&f).await;
+        let res = warp::test::request().path("/subject/Runbook/section/2").reply(&f).await;
+        let tag = res.headers()["etag"].to_str().unwrap().to_string();
+        request("PUT", "/subject/Runbook/section/4").body("## Windows\nUse winget.\n\n").reply(&f).await;
+        let res = request("PUT", "/subject/Runbook/section/2")
+            .header("if-match", format!("\"other\", {}", tag))
+            .header("idempotency-key", "dnf")
+            .body("## Linux\nUse dnf.\n\n")
+            .reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // retries under another precondition are other requests
+        let res = request("PUT", "/subject/Runbook/section/2")
+            .header("if-match", "\"other\"")
+            .header("idempotency-key", "dnf")
+            .body("## Linux\nUse dnf.\n\n")
+            .reply(&f).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_edits() {
+        // the subject changes between the read and the write of a replace
+        let subjects = MemorySubjects::with(&[("Runbook", RUNBOOK)]);
+        let title = crate::api::title::Title::parse("Runbook", MAX_LENGTH).unwrap();
+        subjects.update("alice", &title, "Changed", &edit::Edit::default()).await.unwrap();
+        let r = subjects.update_if("bob", &title, RUNBOOK, "Replaced", &edit::Edit::default()).await;
+        assert!(matches!(r, Err(Error::PreconditionFailed(_))), "{:?}", r);
+        assert_eq!(subjects.content("Runbook").unwrap(), "Changed");
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let f = new_filter(Arc::new(MemorySubjects::with(&[("Runbook", RUNBOOK)])));
+        for (req, status, code) in [
+            (warp::test::request().path("/subject/Missing/toc"), StatusCode::NOT_FOUND, "not_found"),
+            (warp::test::request().path("/subject/Runbook/section/6"), StatusCode::NOT_FOUND, "not_found"),
+            (warp::test::request().path("/subject/Runbook/section/first"), StatusCode::BAD_REQUEST, "bad_request"),
+            (request("PUT", "/subject/Runbook/section/1"), StatusCode::BAD_REQUEST, "bad_request"),
+            (request("PUT", "/subject/Runbook/section/9").body("# Nine"), StatusCode::NOT_FOUND, "not_found"),
+            (warp::test::request().method("PUT").path("/subject/Runbook/section/1").body("# One"), StatusCode::UNAUTHORIZED, "unauthorized"),
+        ] {
+            let res = req.reply(&f).await;
+            assert_eq!(res.status(), status, "{:?}", res.body());
+            let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(problem.code, code);
+        }
+
+        for (path, allow) in [("/subject/Runbook/toc", "GET"), ("/subject/Runbook/section/1", "GET, PUT")] {
+            let res = warp::test::request().method("POST").path(path).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            assert_eq!(res.headers()["allow"], allow);
+        }
+        // other paths are left to other filters
+        for path in ["/subject/Runbook/section", "/subject/Runbook/section/1/more", "/subject/Runbook/tocs"] {
+            let res = warp::test::request().path(path).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert!(res.body().is_empty(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,616 @@
+// stats tells which subjects are used. Reads count as views in memory, once
+// per viewer and subject within a window, and the counts are flushed to daily
+// aggregates in batches, off the path of requests. Subjects report their views
+// and edits per day, and reports rank subjects by views or edits.
+
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::{filters::path::Peek, http::{header::{AUTHORIZATION, USER_AGENT}, HeaderMap, Method}, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{openapi::{Body, Operation, Response}, title::{self, Title}, with_tail}, error::{self, Error}, rate_limit::{self, Cidr}};
+
+const DEFAULT_DAYS: i64 = 30;
+const MAX_DAYS: i64 = 366;
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+// viewers seen are pruned of those past the window past this many
+const MAX_SEEN: usize = 100_000;
+
+pub trait Stats {
+    /// Adds views to the counts of today by title. Views of subjects that no
+    /// longer exist are dropped.
+    fn record_views(&self, views: &[(String, i64)]) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Reads the views and edits of a subject per day over the last days,
+    /// oldest first. Days without either are left out.
+    fn read_stats(&self, title: &Title, days: i64) -> impl Future<Output = Result<SubjectStats, Error>> + Send;
+    /// Ranks every subject by its views or edits over the last days
+    fn report(&self, report: Report, days: i64, limit: i64) -> impl Future<Output = Result<Vec<Usage>, Error>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    MostViewed,
+    LeastViewed,
+    MostEdited,
+}
+
+impl Report {
+    /// Parses the path segment of a report
+    pub fn parse(name: &str) -> Result<Self, Error> {
+        match name {
+            "most-viewed" => Ok(Report::MostViewed),
+            "least-viewed" => Ok(Report::LeastViewed),
+            "most-edited" => Ok(Report::MostEdited),
+            name => Err(Error::NotFound(format!("report {}", name))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubjectStats {
+    pub title: String,
+    pub days: Vec<DayStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DayStats {
+    /// Date as YYYY-MM-DD
+    pub day: String,
+    pub views: i64,
+    pub edits: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub title: String,
+    pub views: i64,
+    pub edits: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct StatsInfo {
+    pub title: String,
+    /// Path segment of the subject
+    pub slug: String,
+    /// Views over the days
+    pub views: i64,
+    /// Edits over the days
+    pub edits: i64,
+    pub days: Vec<DayStats>,
+}
+
+impl From<&SubjectStats> for StatsInfo {
+    fn from(s: &SubjectStats) -> Self {
+        StatsInfo {
+            title: s.title.clone(),
+            slug: title::slug(&s.title),
+            views: s.days.iter().map(|d| d.views).sum(),
+            edits: s.days.iter().map(|d| d.edits).sum(),
+            days: s.days.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct UsageInfo {
+    pub title: String,
+    pub slug: String,
+    pub views: i64,
+    pub edits: i64,
+}
+
+impl From<&Usage> for UsageInfo {
+    fn from(u: &Usage) -> Self {
+        UsageInfo {
+            title: u.title.clone(),
+            slug: title::slug(&u.title),
+            views: u.views,
+            edits: u.edits,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StatsQuery {
+    pub days: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl StatsQuery {
+    fn days(&self) -> Result<i64, Error> {
+        match self.days {
+            None => Ok(DEFAULT_DAYS),
+            Some(days) if (1..=MAX_DAYS).contains(&days) => Ok(days),
+            Some(days) => Err(Error::BadRequest(format!("days must be between 1 and {}, not {}", MAX_DAYS, days))),
+        }
+    }
+
+    fn limit(&self) -> Result<i64, Error> {
+        match self.limit {
+            None => Ok(DEFAULT_LIMIT),
+            Some(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+            Some(limit) => Err(Error::BadRequest(format!("limit must be between 1 and {}, not {}", MAX_LIMIT, limit))),
+        }
+    }
+}
+
+/// Views counts reads in memory until they are flushed. A viewer reading a
+/// subject again within the window is no new view.
+pub struct Views {
+    window: Duration,
+    counts: Mutex<HashMap<String, (String, i64)>>,
+    seen: Mutex<HashMap<(String, String), Instant>>,
+    max_seen: usize,
+    proxies: Vec<Cidr>,
+}
+
+impl Views {
+    pub fn new(window: Duration) -> Self {
+        Views {
+            window,
+            counts: Mutex::new(HashMap::new()),
+            seen: Mutex::new(HashMap::new()),
+            max_seen: MAX_SEEN,
+            proxies: vec![],
+        }
+    }
+
+    /// Trusts proxies to forward the address of clients, whose views would
+    /// otherwise count as the proxies'
+    pub fn with_proxies(self, proxies: Vec<Cidr>) -> Self {
+        Views { proxies, ..self }
+    }
+
+    /// Counts a view of title by viewer, unless viewer saw it within the
+    /// window
+    pub fn view(&self, viewer: &str, title: &Title) {
+        let key = title.as_str().to_lowercase();
+        let now = Instant::now();
+        {
+            let mut seen = self.seen.lock().unwrap();
+            let viewed = (viewer.to_string(), key.clone());
+            if seen.get(&viewed).is_some_and(|last| now.duration_since(*last) < self.window) {
+                return;
+            }
+            if seen.len() >= self.max_seen {
+                seen.retain(|_, last| now.duration_since(*last) < self.window);
+                // more viewers within the window than fit, such as from a
+                // flood of addresses, are forgotten rather than pruned on
+                // every view, and may count once more
+                if seen.len() >= self.max_seen / 2 {
+                    seen.clear();
+                }
+            }
+            seen.insert(viewed, now);
+        }
+        self.counts.lock().unwrap()
+            .entry(key)
+            .or_insert_with(|| (title.to_string(), 0))
+            .1 += 1;
+    }
+
+    /// Takes the counts since the last take, and forgets viewers past the
+    /// window
+    fn take(&self) -> Vec<(String, i64)> {
+        let now = Instant::now();
+        self.seen.lock().unwrap().retain(|_, last| now.duration_since(*last) < self.window);
+        std::mem::take(&mut *self.counts.lock().unwrap())
+            .into_values()
+            .collect()
+    }
+
+    /// Puts back counts that failed to flush, for the next flush
+    fn restore(&self, views: Vec<(String, i64)>) {
+        let mut counts = self.counts.lock().unwrap();
+        for (title, n) in views {
+            counts.entry(title.to_lowercase()).or_insert_with(|| (title, 0)).1 += n;
+        }
+    }
+}
+
+/// Records the counted views, keeping them for the next flush on failure.
+pub async fn flush<S: Stats>(stats: &S, views: &Views) -> Result<(), Error> {
+    let counts = views.take();
+    if counts.is_empty() {
+        return Ok(());
+    }
+    if let Err(err) = stats.record_views(&counts).await {
+        views.restore(counts);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Calls flush every interval, forever.
+pub async fn flush_periodically<S: Stats>(stats: Arc<S>, views: Arc<Views>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = flush(stats.as_ref(), views.as_ref()).await {
+            log::error!(target: "wiki::api", "failed to flush views: {:?}", e);
+        }
+    }
+}
+
+/// Extracts who reads, to count their views once: a hash of the credential,
+/// which stands for the session, or of the address past the trusted proxies
+/// of views and user agent of anonymous readers.
+pub fn viewer(views: Arc<Views>) -> impl Filter<Extract = (String,), Error = std::convert::Infallible> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::addr::remote())
+        .map(move |headers: HeaderMap, addr: Option<std::net::SocketAddr>| {
+            let mut hash = Sha256::new();
+            match headers.get(AUTHORIZATION) {
+                Some(authorization) => hash.update(authorization.as_bytes()),
+                None => {
+                    let ip = rate_limit::client_ip(addr, &headers, &views.proxies);
+                    hash.update(ip.map(|ip| ip.to_string()).unwrap_or_default());
+                    hash.update(b"\n");
+                    hash.update(headers.get(USER_AGENT).map(|a| a.as_bytes()).unwrap_or_default());
+                },
+            }
+            hash.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+        })
+}
+
+pub fn filter<S>(stats: Arc<S>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    S: Stats + Send + Sync + 'static,
+{
+    warp::path!("subject" / ..)
+        .and(
+            with_tail("stats").and(warp::get()).and(endpoints::read(stats.clone(), max_title_length))
+            .or(with_tail("stats").and(error::method_not_allowed(&[Method::GET])))
+        )
+        .or(
+            warp::path!("reports" / ..)
+                .and(
+                    with_report().and(warp::get()).and(endpoints::report(stats))
+                    .or(with_report().and(error::method_not_allowed(&[Method::GET])))
+                )
+        )
+}
+
+/// Rejects paths other than those of reports, like with_tail
+fn with_report() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::peek()
+        .and_then(|peek: Peek| async move {
+            Report::parse(peek.as_str()).map(|_| ()).map_err(|_| warp::reject::not_found())
+        })
+        .untuple_one()
+}
+
+pub fn operations() -> Vec<Operation> {
+    let reports = [
+        ("/reports/most-viewed", "reportMostViewed", "Ranks subjects by views over the last days, most first"),
+        ("/reports/least-viewed", "reportLeastViewed", "Ranks subjects by views over the last days, least first, to find stale ones"),
+        ("/reports/most-edited", "reportMostEdited", "Ranks subjects by edits over the last days, most first"),
+    ];
+    std::iter::once(Operation {
+        method: Method::GET,
+        path: "/subject/{title}/stats",
+        id: "readSubjectStats",
+        summary: "Reads the views and edits of a subject per day over the last days, 30 by default",
+        scope: None,
+        query: &[("days", "integer")],
+        headers: &[],
+        request: None,
+        responses: vec![
+            Response::ok(200, "Stats", Some(Body::json::<StatsInfo>())),
+            Response::problem(400, "Bad title or days"),
+            Response::problem(404, "No such subject"),
+        ],
+    })
+    .chain(reports.into_iter().map(|(path, id, summary)| Operation {
+        method: Method::GET,
+        path,
+        id,
+        summary,
+        scope: None,
+        query: &[("days", "integer"), ("limit", "integer")],
+        headers: &[],
+        request: None,
+        responses: vec![
+            Response::ok(200, "Subjects", Some(Body::json::<Vec<UsageInfo>>())),
+            Response::problem(400, "Bad days or limit"),
+        ],
+    }))
+    .collect()
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{api::title, error};
+
+    use super::{handlers, Report, Stats, StatsQuery};
+
+    pub fn read<S>(stats: Arc<S>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Stats + Send + Sync + 'static
+    {
+        title::param(max_title_length)
+            .and(warp::path!("stats"))
+            .and(warp::query::<StatsQuery>())
+            .and(with_stats(stats))
+            .and_then(handlers::read)
+            .recover(error::recover)
+    }
+
+    pub fn report<S>(stats: Arc<S>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Stats + Send + Sync + 'static
+    {
+        warp::path::param::<String>()
+            .and_then(|name: String| async move {
+                Report::parse(&name).map_err(warp::reject::custom)
+            })
+            .and(warp::path::end())
+            .and(warp::query::<StatsQuery>())
+            .and(with_stats(stats))
+            .and_then(handlers::report)
+            .recover(error::recover)
+    }
+
+    fn with_stats<S>(stats: Arc<S>) -> impl Filter<Extract = (Arc<S>,), Error = Infallible> + Clone
+    where
+        S: Stats + Send + Sync + 'static
+    {
+        warp::any().map(move || stats.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{reject::Rejection, reply::Reply};
+
+    use crate::api::title::Title;
+
+    use super::{Report, Stats, StatsInfo, StatsQuery, UsageInfo};
+
+    pub async fn read<S: Stats>(title: Title, query: StatsQuery, stats: Arc<S>) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let days = query.days()?;
+            stats.read_stats(&title, days).await
+        }.await;
+
+        match r {
+            Ok(stats) => Ok(warp::reply::json(&StatsInfo::from(&stats))),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn report<S: Stats>(report: Report, query: StatsQuery, stats: Arc<S>) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let days = query.days()?;
+            let limit = query.limit()?;
+            stats.report(report, days, limit).await
+        }.await;
+
+        match r {
+            Ok(usage) => Ok(warp::reply::json(&usage.iter().map(UsageInfo::from).collect::<Vec<_>>())),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests views, filter, and endpoints and handlers modules, with stats in
+/// memory. Exposes views to test modules.
+///
+/// Test plan:
+/// 1. Views are counted once per viewer and subject within the window, and
+///    viewers seen are bounded
+/// 2. Flushes record counts in batches, and keep them on failure
+/// 3. Viewers are told apart by credential, or address past trusted proxies and
+///    user agent
+/// 4. Stats and reports reply with their subjects
+/// 5. Bad requests reply with problems, bad methods with 405
+#[cfg(test)]
+pub mod tests {
+    use warp::http::StatusCode;
+
+    use crate::{api::title::MAX_LENGTH, error::Problem};
+
+    use super::*;
+
+    pub fn new_views() -> Arc<Views> {
+        Arc::new(Views::new(Duration::from_secs(1800)))
+    }
+
+    /// MemoryStats keeps recorded views, and replies fixed stats
+    #[derive(Default)]
+    pub struct MemoryStats {
+        pub recorded: Mutex<Vec<Vec<(String, i64)>>>,
+        pub fail: bool,
+    }
+
+    impl Stats for MemoryStats {
+        async fn record_views(&self, views: &[(String, i64)]) -> Result<(), Error> {
+            if self.fail {
+                return Err(Error::Internal("test error".into()));
+            }
+            let mut views = views.to_vec();
+            views.sort();
+            self.recorded.lock().unwrap().push(views);
+            Ok(())
+        }
+
+        async fn read_stats(&self, title: &Title, days: i64) -> Result<SubjectStats, Error> {
+            if title.as_str() != "Used" {
+                return Err(Error::NotFound(title.to_string()));
+            }
+            Ok(SubjectStats {
+                title: "Used".into(),
+                days: (0..days.min(2))
+                    .map(|d| DayStats { day: format!("2026-01-0{}", d + 1), views: 3, edits: d })
+                    .collect(),
+            })
+        }
+
+        async fn report(&self, report: Report, _days: i64, limit: i64) -> Result<Vec<Usage>, Error> {
+            let mut usage = vec![
+                Usage { title: "Used".into(), views: 6, edits: 1 },
+                Usage { title: "Stale page".into(), views: 0, edits: 3 },
+            ];
+            match report {
+                Report::MostViewed => usage.sort_by_key(|u| -u.views),
+                Report::LeastViewed => usage.sort_by_key(|u| u.views),
+                Report::MostEdited => usage.sort_by_key(|u| -u.edits),
+            }
+            usage.truncate(limit as usize);
+            Ok(usage)
+        }
+    }
+
+    fn title(t: &str) -> Title {
+        Title::parse(t, MAX_LENGTH).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_views() {
+        let views = new_views();
+        for (viewer, t) in [("a", "One"), ("a", "one"), ("b", "ONE"), ("a", "Two")] {
+            views.view(viewer, &title(t));
+        }
+        let mut counts = views.take();
+        counts.sort();
+        assert_eq!(counts, vec![("One".to_string(), 2), ("Two".to_string(), 1)]);
+
+        // seen within the window, after a take
+        views.view("a", &title("One"));
+        assert!(views.take().is_empty());
+
+        let views = Views::new(Duration::ZERO);
+        views.view("a", &title("One"));
+        views.view("a", &title("One"));
+        assert_eq!(views.take(), vec![("One".to_string(), 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_views_bounded() {
+        // pruned of viewers past the window
+        let views = Views { max_seen: 6, ..Views::new(Duration::from_millis(50)) };
+        for viewer in ["a", "b", "c", "d"] {
+            views.view(viewer, &title("One"));
+        }
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        for viewer in ["e", "f", "g"] {
+            views.view(viewer, &title("One"));
+        }
+        assert_eq!(views.seen.lock().unwrap().len(), 3);
+        views.view("e", &title("One"));
+        assert_eq!(views.take(), vec![("One".to_string(), 7)]);
+
+        // forgotten when all are within the window
+        let views = Views { max_seen: 4, ..Views::new(Duration::from_secs(1800)) };
+        for viewer in ["a", "b", "c", "d", "e"] {
+            views.view(viewer, &title("One"));
+        }
+        assert_eq!(views.seen.lock().unwrap().len(), 1);
+        for viewer in 0..1000 {
+            views.view(&viewer.to_string(), &title("Two"));
+        }
+        assert!(views.seen.lock().unwrap().len() <= 4);
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let views = new_views();
+        let failing = MemoryStats { fail: true, ..Default::default() };
+        views.view("a", &title("One"));
+        assert!(flush(&failing, &views).await.is_err());
+
+        let stats = MemoryStats::default();
+        views.view("b", &title("One"));
+        views.view("b", &title("Two"));
+        flush(&stats, &views).await.unwrap();
+        flush(&stats, &views).await.unwrap();
+        assert_eq!(*stats.recorded.lock().unwrap(), vec![
+            vec![("One".to_string(), 2), ("Two".to_string(), 1)],
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_viewers() {
+        let views = Arc::new(Views::new(Duration::ZERO).with_proxies(vec![Cidr::parse("10.0.0.0/8").unwrap()]));
+        let viewer = |req: warp::test::RequestBuilder| {
+            let views = views.clone();
+            async move { req.filter(&super::viewer(views)).await.unwrap() }
+        };
+        let anonymous = viewer(warp::test::request().header("user-agent", "curl")).await;
+        assert_eq!(anonymous, viewer(warp::test::request().header("user-agent", "curl")).await);
+        assert_ne!(anonymous, viewer(warp::test::request().header("user-agent", "wget")).await);
+        assert_ne!(anonymous, viewer(warp::test::request().remote_addr("10.0.0.1:80".parse().unwrap()).header("user-agent", "curl")).await);
+
+        let bob = viewer(warp::test::request().header("authorization", "Bearer bob").header("user-agent", "curl")).await;
+        assert_eq!(bob, viewer(warp::test::request().header("authorization", "Bearer bob")).await);
+        assert_ne!(bob, anonymous);
+
+        // readers behind trusted proxies are told apart by the address they forward
+        let proxied = |client: &str| warp::test::request()
+            .remote_addr("10.0.0.2:80".parse().unwrap())
+            .header("x-forwarded-for", client)
+            .header("user-agent", "curl");
+        let alice = viewer(proxied("203.0.113.1")).await;
+        assert_eq!(alice, viewer(proxied("203.0.113.1")).await);
+        assert_ne!(alice, viewer(proxied("203.0.113.2")).await);
+    }
+
+    #[tokio::test]
+    async fn test_stats_and_reports() {
+        let f = filter(Arc::new(MemoryStats::default()), MAX_LENGTH);
+
+        let res = warp::test::request().path("/subject/Used/stats").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+        let info: StatsInfo = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!((info.title.as_str(), info.views, info.edits, info.days.len()), ("Used", 6, 1, 2));
+        let res = warp::test::request().path("/subject/Used/stats?days=1").reply(&f).await;
+        let info: StatsInfo = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(info.days.len(), 1);
+
+        for (report, first) in [("most-viewed", "Used"), ("least-viewed", "Stale page"), ("most-edited", "Stale page")] {
+            let res = warp::test::request().path(&format!("/reports/{}", report)).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", report);
+            let usage: Vec<UsageInfo> = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(usage.len(), 2);
+            assert_eq!(usage[0].title, first, "{}", report);
+        }
+        let res = warp::test::request().path("/reports/least-viewed?limit=1&days=90").reply(&f).await;
+        let usage: Vec<UsageInfo> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!((usage.len(), usage[0].slug.as_str()), (1, "Stale_page"));
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let f = filter(Arc::new(MemoryStats::default()), MAX_LENGTH);
+        for (path, status, code) in [
+            ("/subject/Missing/stats", StatusCode::NOT_FOUND, "not_found"),
+            ("/subject/Used/stats?days=0", StatusCode::BAD_REQUEST, "bad_request"),
+            ("/subject/Used/stats?days=367", StatusCode::BAD_REQUEST, "bad_request"),
+            ("/reports/most-viewed?limit=101", StatusCode::BAD_REQUEST, "bad_request"),
+            ("/reports/most-viewed?limit=many", StatusCode::BAD_REQUEST, "invalid_query"),
+        ] {
+            let res = warp::test::request().path(path).reply(&f).await;
+            assert_eq!(res.status(), status, "{}: {:?}", path, res.body());
+            let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(problem.code, code, "{}", path);
+        }
+
+        for (path, allow) in [("/subject/Used/stats", "GET"), ("/reports/most-edited", "GET")] {
+            let res = warp::test::request().method("POST").path(path).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            assert_eq!(res.headers()["allow"], allow);
+        }
+        // other paths are left to other filters
+        for method in ["GET", "POST"] {
+            let res = warp::test::request().method(method).path("/reports/unknown").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", method);
+        }
+    }
+}
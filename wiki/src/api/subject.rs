@@ -1,40 +1,132 @@
 use std::{future::Future, sync::Arc};
 
-use warp::{reject::Rejection, reply::Reply, Filter};
+use warp::{http::Method, reject::Rejection, reply::Reply, Filter};
 
-use crate::{auth::user::Users, error::Error};
+use crate::{api::{edit::{self, Edit}, idempotency::{Idempotency, Keys}, openapi::{Body, Operation, Response}, stats::Views, title::Title, with_tail}, auth::user::{Scope, Users}, error::{self, Error}};
 
+/// Subjects are keyed by canonical titles, compared case-insensitively.
 pub trait Subjects {
+    type UnitOfWork: UnitOfWork + Send;
+
     fn list(&self) -> impl Future<Output = Result<Vec<String>, Error>> + Send;
-    fn create(&self, user: &str, title: &str, content: &str) -> impl Future<Output = Result<(), Error>> + Send;
-    fn read(&self, title: &str) -> impl Future<Output = Result<String, Error>> + Send;
-    fn update(&self, user: &str, title: &str, content: &str) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Lists at most limit titles in case-insensitive order, starting after
+    /// the title after
+    fn list_after(&self, after: Option<&str>, limit: i64) -> impl Future<Output = Result<Vec<String>, Error>> + Send;
+    fn create(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> impl Future<Output = Result<(), Error>> + Send;
+    fn read(&self, title: &Title) -> impl Future<Output = Result<String, Error>> + Send;
+    fn update(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Updates like update, but only while the content still is previous,
+    /// failing with Error::PreconditionFailed once it changed
+    fn update_if(&self, user: &str, title: &Title, previous: &str, content: &str, edit: &Edit) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Begins changes that are applied together on commit, or not at all
+    fn begin(&self) -> impl Future<Output = Result<Self::UnitOfWork, Error>> + Send;
+}
+
+/// UnitOfWork changes subjects like Subjects does, but nobody sees the changes
+/// before commit. Dropping it before commit discards them.
+pub trait UnitOfWork {
+    fn create(&mut self, user: &str, title: &Title, content: &str, edit: &Edit) -> impl Future<Output = Result<(), Error>> + Send;
+    fn update(&mut self, user: &str, title: &Title, content: &str, edit: &Edit) -> impl Future<Output = Result<(), Error>> + Send;
+    fn delete(&mut self, title: &Title) -> impl Future<Output = Result<(), Error>> + Send;
+    fn commit(self) -> impl Future<Output = Result<(), Error>> + Send;
 }
 
-pub fn filter<S, U>(subjects: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+pub fn filter<S, U, K>(subjects: Arc<S>, views: Arc<Views>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
 where
     S: Subjects + Send + Sync + 'static,
     U: Users + Send + Sync + 'static,
+    K: Keys + Send + Sync + 'static,
 {
     warp::path!("subjects")
-        .and(warp::get().and(endpoints::list(subjects.clone())))
+        .and(
+            warp::get().and(endpoints::list(subjects.clone()))
+            .or(error::method_not_allowed(&[Method::GET]))
+        )
         .or(
             warp::path!("subject" / ..)
                 .and(
-                    warp::get().and(endpoints::read(subjects.clone()))
-                    .or(warp::patch().and(endpoints::update(subjects.clone(), users.clone())))
-                    .or(warp::post().and(endpoints::create(subjects, users)))
+                    with_tail("").and(warp::get()).and(endpoints::read(subjects.clone(), views, max_title_length))
+                    .or(with_tail("").and(warp::patch()).and(endpoints::update(subjects.clone(), users.clone(), idempotency.clone(), max_title_length)))
+                    .or(with_tail("").and(warp::post()).and(endpoints::create(subjects, users, idempotency, max_title_length)))
+                    .or(
+                        warp::path::param::<String>().map(|_| ()).untuple_one()
+                            .and(warp::path::end())
+                            .and(error::method_not_allowed(&[Method::GET, Method::PATCH, Method::POST]))
+                    )
                 )
         )
 }
 
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::GET,
+            path: "/subjects",
+            id: "listSubjects",
+            summary: "Lists the titles of all subjects, one per line",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![Response::ok(200, "Titles", Some(Body::Text))],
+        },
+        Operation {
+            method: Method::GET,
+            path: "/subject/{title}",
+            id: "readSubject",
+            summary: "Reads the content of a subject",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Content", Some(Body::Text)),
+                Response::problem(400, "Bad title"),
+                Response::problem(404, "No such subject"),
+            ],
+        },
+        Operation {
+            method: Method::PATCH,
+            path: "/subject/{title}",
+            id: "updateSubject",
+            summary: "Replaces the content of a subject",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: edit::HEADERS,
+            request: Some(Body::Text),
+            responses: vec![
+                Response::ok(200, "Updated", None),
+                Response::problem(400, "Bad title, edit or no content"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(404, "No such subject"),
+            ],
+        },
+        Operation {
+            method: Method::POST,
+            path: "/subject/{title}",
+            id: "createSubject",
+            summary: "Creates a subject, Location holds its canonical path",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: edit::HEADERS,
+            request: Some(Body::Text),
+            responses: vec![
+                Response::ok(200, "Created", None),
+                Response::problem(400, "Bad title, edit or no content"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(409, "The subject exists"),
+            ],
+        },
+    ]
+}
+
 mod endpoints {
     use std::{convert::Infallible, sync::Arc};
 
     use bytes::Bytes;
-    use warp::{reject::Rejection, reply::Reply, Filter};
+    use warp::{reply::Reply, Filter};
 
-    use crate::auth::user::Users;
+    use crate::{api::{edit, idempotency::{self, Idempotency, Keys}, stats::{self, Views}, title}, auth::user::{with_authorization, Scope, Users}, error};
 
     use super::{handlers, Subjects};
 
@@ -44,47 +136,56 @@ mod endpoints {
     {
         with_subjects(subjects)
             .and_then(handlers::list)
-            .recover(handlers::error)
+            .recover(error::recover)
     }
 
-    pub fn read<S>(subjects: Arc<S>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    pub fn read<S>(subjects: Arc<S>, views: Arc<Views>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
     where
         S: Subjects + Send + Sync + 'static
     {
-        warp::path::param()
+        let viewer = stats::viewer(views.clone());
+        title::param(max_title_length)
             .and(with_subjects(subjects))
+            .and(warp::any().map(move || views.clone()))
+            .and(viewer)
             .and_then(handlers::read)
-            .recover(handlers::error)
+            .recover(error::recover)
     }
 
-    pub fn update<S, U>(subjects: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    pub fn update<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
     where
         S: Subjects + Send + Sync + 'static,
         U: Users + Send + Sync + 'static,
+        K: Keys + Send + Sync + 'static,
     {
-        warp::path::param()
+        title::param(max_title_length)
             .and(with_subjects(subjects))
-            .and(with_authorization(users))
+            .and(with_authorization(users, Scope::Write))
             .and(warp::body::bytes().map(|body: Bytes| {
                 String::from_utf8_lossy(&body).to_string()
             }))
+            .and(edit::headers())
+            .and(idempotency::attempt(idempotency))
             .and_then(handlers::update)
-            .recover(handlers::error)
+            .recover(error::recover)
     }
 
-    pub fn create<S, U>(subjects: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    pub fn create<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
     where
         S: Subjects + Send + Sync + 'static,
         U: Users + Send + Sync + 'static,
+        K: Keys + Send + Sync + 'static,
     {
-        warp::path::param()
+        title::param(max_title_length)
             .and(with_subjects(subjects))
-            .and(with_authorization(users))
+            .and(with_authorization(users, Scope::Write))
             .and(warp::body::bytes().map(|body: Bytes| {
                 String::from_utf8_lossy(&body).to_string()
             }))
+            .and(edit::headers())
+            .and(idempotency::attempt(idempotency))
             .and_then(handlers::create)
-            .recover(handlers::error)
+            .recover(error::recover)
     }
 
     fn with_subjects<S>(subjects: Arc<S>) -> impl Filter<Extract = (Arc<S>,), Error = Infallible> + Clone
@@ -94,34 +195,14 @@ mod endpoints {
         warp::any().map(move || subjects.clone())
     }
 
-    fn with_authorization<U>(users: Arc<U>) -> impl Filter<Extract = (String,), Error = Rejection> + Clone
-    where
-        U: Users + Send + Sync + 'static
-    {
-        warp::header("Authorization")
-            .and(with_users(users))
-            .and_then(|header: String, users: Arc<U>| async move {
-                match users.authorize(header).await {
-                    Ok(user) => Ok(user),
-                    Err(err) => Err(warp::reject::custom(err)),
-                }
-            })
-    }
-
-    fn with_users<U>(users: Arc<U>) -> impl Filter<Extract = (Arc<U>,), Error = Infallible> + Clone
-    where
-        U: Users + Send + Sync + 'static
-    {
-        warp::any().map(move || users.clone())
-    }
 }
 
 mod handlers {
-    use std::{convert::Infallible, sync::Arc};
+    use std::sync::Arc;
 
-    use warp::{http::StatusCode, reject::{MissingHeader, Rejection}, reply::Reply};
+    use warp::{reject::Rejection, reply::Reply};
 
-    use crate::error::Error;
+    use crate::{api::{edit::Edit, idempotency::{Attempt, Keys}, stats::Views, title::Title}, auth::user::User, error::Error};
 
     use super::Subjects;
 
@@ -136,73 +217,42 @@ mod handlers {
         }
     }
 
-    pub async fn read<S: Subjects>(title: String, subjects: Arc<S>) -> Result<impl Reply, Rejection> {
+    pub async fn read<S: Subjects>(title: Title, subjects: Arc<S>, views: Arc<Views>, viewer: String) -> Result<impl Reply, Rejection> {
         let subjects = subjects.as_ref();
         match subjects.read(&title).await {
-            Ok(content) => Ok(warp::reply::with_header(
-                content,
-                "Content-Type",
-                "text/plain")),
+            Ok(content) => {
+                views.view(&viewer, &title);
+                Ok(warp::reply::with_header(
+                    content,
+                    "Content-Type",
+                    "text/plain"))
+            },
             Err(err) => Err(warp::reject::custom(err)),
         }
     }
 
-    pub async fn update<S: Subjects>(title: String, subjects: Arc<S>, user: String, content: String) -> Result<impl Reply, Rejection> {
-        if content.is_empty() {
-            return Err(warp::reject::custom(Error::BadRequest("no body".into())));
-        }
-        let subjects = subjects.as_ref();
-        match subjects.update(&user, &title, &content).await {
-            Ok(()) => Ok(warp::reply()),
-            Err(err) => Err(warp::reject::custom(err)),
-        }
-    }
-
-    pub async fn create<S: Subjects>(title: String, subjects: Arc<S>, user: String, content: String) -> Result<impl Reply, Rejection> {
-        if content.is_empty() {
-            return Err(warp::reject::custom(Error::BadRequest("no body".into())));
-        }
-        let subjects = subjects.as_ref();
-        match subjects.create(&user, &title, &content).await {
-            Ok(()) => Ok(warp::reply()),
-            Err(err) => Err(warp::reject::custom(err)),
-        }
+    pub async fn update<S: Subjects, K: Keys>(title: Title, subjects: Arc<S>, user: User, content: String, edit: Edit, attempt: Attempt<K>) -> Result<impl Reply, Rejection> {
+        // the edit headers are part of the request, as the fields are in v2
+        let body = serde_json::to_vec(&(&edit, &content)).unwrap_or_default();
+        attempt.run(&user.name, &body, async {
+            if content.is_empty() {
+                return Err(Error::BadRequest("no body".into()));
+            }
+            subjects.update(&user.name, &title, &content, &edit).await?;
+            Ok(warp::reply())
+        }).await
     }
 
-    pub async fn error(err: Rejection) -> Result<impl Reply, Infallible> {
-        let status;
-        let message;
-
-        if let Some(e) = err.find::<Error>() {
-            (status, message) = match e {
-                Error::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
-                Error::Internal(msg) => {
-                    log::error!(target: "wiki::api", "internal error: {}", msg);
-                    (StatusCode::INTERNAL_SERVER_ERROR, "".into())
-                },
-                Error::NotFound(_) => (StatusCode::NOT_FOUND, "".into()),
-                Error::Unauthorized(msg) => {
-                    log::warn!(target: "wiki::api", "unauthorized: {}", msg);
-                    (StatusCode::UNAUTHORIZED, "".into())
-                }
-            }
-        } else if err.is_not_found() {
-            status = StatusCode::NOT_FOUND;
-            message = "".to_string();
-        } else if let Some(e) = err.find::<MissingHeader>() {
-            if e.name() == "Authorization" {
-                status = StatusCode::UNAUTHORIZED;
-                message = "unauthorized".to_string();
-            } else {
-                status = StatusCode::INTERNAL_SERVER_ERROR;
-                message = "".to_string();
+    pub async fn create<S: Subjects, K: Keys>(title: Title, subjects: Arc<S>, user: User, content: String, edit: Edit, attempt: Attempt<K>) -> Result<impl Reply, Rejection> {
+        let body = serde_json::to_vec(&(&edit, &content)).unwrap_or_default();
+        attempt.run(&user.name, &body, async {
+            if content.is_empty() {
+                return Err(Error::BadRequest("no body".into()));
             }
-        } else {
-            status = StatusCode::INTERNAL_SERVER_ERROR;
-            message = "".into();
-        }
-
-        Ok(warp::reply::with_status(message, status))
+            subjects.create(&user.name, &title, &content, &edit).await?;
+            // relative to the request path, so it holds wherever the API is mounted
+            Ok(warp::reply::with_header(warp::reply(), "Location", title.slug()))
+        }).await
     }
 }
 
@@ -211,79 +261,233 @@ mod handlers {
 /// 
 /// Test plan:
 /// 1. Reject bad path
-/// 2. Reject bad methods
+/// 2. Reject bad methods with 405 and the allowed methods
 /// 3. Bad bodies reply with error
 /// 4. Bad auth replies with error
 /// 5. Good requests reply subjects errors
 /// 6. Good requests reply subjects data
+/// 7. Preflights allow configured origins, methods and headers
+/// 8. Bad titles reply with error
+/// 9. Retries with an idempotency key replay the response, unless the body or
+///    edit headers differ
+/// 10. Edit headers reach subjects with the change
+/// 11. Reads count views, failed reads do not
 #[cfg(test)]
-mod tests {
-    use std::sync::Arc;
+pub mod tests {
+    use std::{collections::BTreeMap, sync::{Arc, Mutex}};
 
-    use warp::http::StatusCode;
+    use warp::{http::StatusCode, Filter};
 
-    use crate::{auth::mock_user, error::Error};
+    use crate::{api::{edit::{Edit, EDIT_SUMMARY, MINOR_EDIT}, idempotency::{tests::new_idempotency, IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED}, stats::{flush, tests::{new_views, MemoryStats}}, title::{Title, MAX_LENGTH}}, auth::mock_user, error::{Error, Problem}};
 
-    use super::{filter, Subjects};
+    use super::{filter, Subjects, UnitOfWork};
 
-    struct MockSubjects {
-        list_response: Result<Vec<String>, Error>,
-        read_response: Result<String, Error>,
-        update_response: Result<(), Error>,
-        create_response: Result<(), Error>,
+    pub struct MockSubjects {
+        pub list_response: Result<Vec<String>, Error>,
+        pub read_response: Result<String, Error>,
+        pub update_response: Result<(), Error>,
+        pub create_response: Result<(), Error>,
     }
 
     impl Subjects for MockSubjects {
+        type UnitOfWork = MockUnitOfWork;
+
         async fn list(&self) -> Result<Vec<String>, Error> {
             match &self.list_response {
                 Ok(titles) => Ok(titles.to_vec()),
-                Err(err) => match err {
-                    Error::Internal(msg) => Err(Error::Internal(msg.clone())),
-                    Error::NotFound(msg) => Err(Error::NotFound(msg.clone())),
-                    Error::BadRequest(msg) => Err(Error::BadRequest(msg.clone())),
-                    Error::Unauthorized(msg) => Err(Error::Unauthorized(msg.clone())),
-                }
+                Err(err) => Err(err.clone()),
             }
         }
 
-        async fn read(&self, _title: &str) -> Result<String, Error>{
+        async fn list_after(&self, after: Option<&str>, limit: i64) -> Result<Vec<String>, Error> {
+            let mut titles = self.list().await?;
+            titles.sort_by_key(|t| t.to_lowercase());
+            Ok(titles.into_iter()
+                .filter(|t| after.is_none_or(|a| t.to_lowercase() > a.to_lowercase()))
+                .take(limit as usize)
+                .collect())
+        }
+
+        async fn read(&self, _title: &Title) -> Result<String, Error>{
             match &self.read_response {
                 Ok(content) => Ok(content.clone()),
-                Err(err) => match err {
-                    Error::Internal(msg) => Err(Error::Internal(msg.clone())),
-                    Error::NotFound(msg) => Err(Error::NotFound(msg.clone())),
-                    Error::BadRequest(msg) => Err(Error::BadRequest(msg.clone())),
-                    Error::Unauthorized(msg) => Err(Error::Unauthorized(msg.clone())),
-                }
+                Err(err) => Err(err.clone()),
             }
         }
 
-        async fn update(&self, _user: &str, _title: &str, _content: &str) -> Result<(), Error> {
+        async fn update(&self, _user: &str, _title: &Title, _content: &str, _edit: &Edit) -> Result<(), Error> {
             match &self.update_response {
                 Ok(()) => Ok(()),
-                Err(err) => match err {
-                    Error::Internal(msg) => Err(Error::Internal(msg.clone())),
-                    Error::NotFound(msg) => Err(Error::NotFound(msg.clone())),
-                    Error::BadRequest(msg) => Err(Error::BadRequest(msg.clone())),
-                    Error::Unauthorized(msg) => Err(Error::Unauthorized(msg.clone())),
-                }
+                Err(err) => Err(err.clone()),
             }
         }
 
-        async fn create(&self, _user: &str, _title: &str, _content: &str) -> Result<(), Error> {
+        async fn update_if(&self, user: &str, title: &Title, _previous: &str, content: &str, edit: &Edit) -> Result<(), Error> {
+            self.update(user, title, content, edit).await
+        }
+
+        async fn create(&self, _user: &str, _title: &Title, _content: &str, _edit: &Edit) -> Result<(), Error> {
             match &self.create_response {
                 Ok(()) => Ok(()),
-                Err(err) => match err {
-                    Error::Internal(msg) => Err(Error::Internal(msg.clone())),
-                    Error::NotFound(msg) => Err(Error::NotFound(msg.clone())),
-                    Error::BadRequest(msg) => Err(Error::BadRequest(msg.clone())),
-                    Error::Unauthorized(msg) => Err(Error::Unauthorized(msg.clone())),
-                }
+                Err(err) => Err(err.clone()),
+            }
+        }
+
+        async fn begin(&self) -> Result<MockUnitOfWork, Error> {
+            Ok(MockUnitOfWork {
+                create_response: self.create_response.clone(),
+                update_response: self.update_response.clone(),
+            })
+        }
+    }
+
+    /// MockUnitOfWork replies the responses of the mock subjects that began it
+    pub struct MockUnitOfWork {
+        create_response: Result<(), Error>,
+        update_response: Result<(), Error>,
+    }
+
+    impl UnitOfWork for MockUnitOfWork {
+        async fn create(&mut self, _user: &str, _title: &Title, _content: &str, _edit: &Edit) -> Result<(), Error> {
+            self.create_response.clone()
+        }
+
+        async fn update(&mut self, _user: &str, _title: &Title, _content: &str, _edit: &Edit) -> Result<(), Error> {
+            self.update_response.clone()
+        }
+
+        async fn delete(&mut self, _title: &Title) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn commit(self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// MemorySubjects keeps subjects by lowercase title, and the edits made to
+    /// them, for tests that need subjects to change. Units of work change a
+    /// copy, which commit swaps in.
+    #[derive(Default)]
+    pub struct MemorySubjects {
+        pub subjects: Arc<Mutex<BTreeMap<String, (String, String)>>>,
+        pub edits: Arc<Mutex<Vec<(String, Edit)>>>,
+    }
+
+    impl MemorySubjects {
+        pub fn with(subjects: &[(&str, &str)]) -> Self {
+            let memory = MemorySubjects::default();
+            for (title, content) in subjects {
+                memory.subjects.lock().unwrap().insert(title.to_lowercase(), (title.to_string(), content.to_string()));
             }
+            memory
+        }
+
+        pub fn content(&self, title: &str) -> Option<String> {
+            self.subjects.lock().unwrap().get(&title.to_lowercase()).map(|(_, content)| content.clone())
+        }
+
+        /// Returns the edits made, oldest first
+        pub fn edits(&self) -> Vec<(String, Edit)> {
+            self.edits.lock().unwrap().clone()
         }
     }
 
-    fn good_subjects() -> MockSubjects {
+    impl Subjects for MemorySubjects {
+        type UnitOfWork = MemoryUnitOfWork;
+
+        async fn list(&self) -> Result<Vec<String>, Error> {
+            Ok(self.subjects.lock().unwrap().values().map(|(title, _)| title.clone()).collect())
+        }
+
+        async fn list_after(&self, after: Option<&str>, limit: i64) -> Result<Vec<String>, Error> {
+            let after = after.map(str::to_lowercase);
+            Ok(self.subjects.lock().unwrap().iter()
+                .filter(|(key, _)| after.as_ref().is_none_or(|a| *key > a))
+                .take(limit as usize)
+                .map(|(_, (title, _))| title.clone())
+                .collect())
+        }
+
+        async fn create(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+            let mut uow = self.begin().await?;
+            uow.create(user, title, content, edit).await?;
+            uow.commit().await
+        }
+
+        async fn read(&self, title: &Title) -> Result<String, Error> {
+            self.content(title.as_str()).ok_or_else(|| Error::NotFound(title.to_string()))
+        }
+
+        async fn update(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+            let mut uow = self.begin().await?;
+            uow.update(user, title, content, edit).await?;
+            uow.commit().await
+        }
+
+        async fn update_if(&self, user: &str, title: &Title, previous: &str, content: &str, edit: &Edit) -> Result<(), Error> {
+            let mut uow = self.begin().await?;
+            if uow.subjects.get(&title.as_str().to_lowercase()).is_some_and(|(_, current)| current != previous) {
+                return Err(Error::PreconditionFailed(format!("subject changed: {}", title)));
+            }
+            uow.update(user, title, content, edit).await?;
+            uow.commit().await
+        }
+
+        async fn begin(&self) -> Result<MemoryUnitOfWork, Error> {
+            Ok(MemoryUnitOfWork {
+                shared: self.subjects.clone(),
+                subjects: self.subjects.lock().unwrap().clone(),
+                shared_edits: self.edits.clone(),
+                edits: vec![],
+            })
+        }
+    }
+
+    pub struct MemoryUnitOfWork {
+        shared: Arc<Mutex<BTreeMap<String, (String, String)>>>,
+        subjects: BTreeMap<String, (String, String)>,
+        shared_edits: Arc<Mutex<Vec<(String, Edit)>>>,
+        edits: Vec<(String, Edit)>,
+    }
+
+    impl UnitOfWork for MemoryUnitOfWork {
+        async fn create(&mut self, _user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+            let key = title.as_str().to_lowercase();
+            if self.subjects.contains_key(&key) {
+                return Err(Error::Conflict(format!("subject already exists: {}", title)));
+            }
+            self.subjects.insert(key, (title.to_string(), content.to_string()));
+            self.edits.push((title.to_string(), edit.clone()));
+            Ok(())
+        }
+
+        async fn update(&mut self, _user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+            match self.subjects.get_mut(&title.as_str().to_lowercase()) {
+                Some(subject) => {
+                    subject.1 = content.to_string();
+                    self.edits.push((subject.0.clone(), edit.clone()));
+                    Ok(())
+                },
+                None => Err(Error::NotFound(title.to_string())),
+            }
+        }
+
+        async fn delete(&mut self, title: &Title) -> Result<(), Error> {
+            match self.subjects.remove(&title.as_str().to_lowercase()) {
+                Some(_) => Ok(()),
+                None => Err(Error::NotFound(title.to_string())),
+            }
+        }
+
+        async fn commit(self) -> Result<(), Error> {
+            *self.shared.lock().unwrap() = self.subjects;
+            self.shared_edits.lock().unwrap().extend(self.edits);
+            Ok(())
+        }
+    }
+
+    pub fn good_subjects() -> MockSubjects {
         MockSubjects {
             list_response: Ok(vec![
                 "Good Subject 1".into(),
@@ -319,7 +523,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_reject_bad_paths() {
-        let f = filter(Arc::new(good_subjects()), Arc::new(mock_user::Mock::new()));
+        let f = filter(Arc::new(good_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
         // no title
         for m in ["GET", "PATCH", "POST"] {
             let res = test_request(m)
@@ -329,6 +533,15 @@ mod tests {
             assert_eq!(res.status(), StatusCode::NOT_FOUND, "method: {}", m);
         }
 
+        // more after the title
+        for m in ["GET", "PATCH", "POST"] {
+            let res = test_request(m)
+                .path("/subject/some_title/more")
+                .reply(&f)
+                .await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "method: {}", m);
+        }
+
         // list with title
         let req = test_request("GET")
             .path("/subjects/some_title")
@@ -339,26 +552,28 @@ mod tests {
 
     #[tokio::test]
     async fn test_reject_bad_methods() {
-        let f = filter(Arc::new(good_subjects()), Arc::new(mock_user::Mock::new()));
-        // with title
-        assert!(
-            !test_request("PUT")
-                .path("/subject/some_title")
-                .matches(&f)
-                .await
-        );
-        // list
-        assert!(
-            !test_request("PUT")
-                .path("/subjects")
-                .matches(&f)
-                .await
-        );
+        let f = filter(Arc::new(good_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
+        for (path, allow) in [
+            ("/subject/some_title", "GET, PATCH, POST"),
+            ("/subjects", "GET"),
+        ] {
+            for m in ["PUT", "DELETE"] {
+                let res = test_request(m)
+                    .path(path)
+                    .reply(&f)
+                    .await;
+                assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", m, path);
+                assert_eq!(res.headers()["allow"], allow);
+                assert_eq!(res.headers()["content-type"], "application/problem+json");
+                let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+                assert_eq!(problem.code, "method_not_allowed");
+            }
+        }
     }
 
     #[tokio::test]
     async fn test_bad_bodies_reply_with_error() {
-        let f = filter(Arc::new(good_subjects()), Arc::new(mock_user::Mock::new()));
+        let f = filter(Arc::new(good_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
         for m in ["PATCH", "POST"] {
             let res = warp::test::request()
                 .method(m)
@@ -372,7 +587,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_bad_auth_replies_with_error() {
-        let f = filter(Arc::new(good_subjects()), Arc::new(mock_user::Mock::new()));
+        let f = filter(Arc::new(good_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
         for m in ["PATCH", "POST"] {
             let res = warp::test::request()
                 .method(m)
@@ -386,7 +601,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_good_requests_reply_with_subject_errors() {
-        let f = filter(Arc::new(error_subjects()), Arc::new(mock_user::Mock::new()));
+        let f = filter(Arc::new(error_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
         for m in ["GET", "PATCH", "POST"] {
             let res = test_request(m)
                 .path("/subject/some_title")
@@ -400,11 +615,25 @@ mod tests {
             .reply(&f)
             .await;
         assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let conflict = MockSubjects {
+            create_response: Err(Error::Conflict("subject already exists: some title".into())),
+            ..good_subjects()
+        };
+        let f = filter(Arc::new(conflict), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
+        let res = test_request("POST")
+            .path("/subject/some_title")
+            .reply(&f)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(problem.code, "conflict");
+        assert_eq!(problem.detail.unwrap(), "subject already exists: some title");
     }
 
     #[tokio::test]
     async fn test_good_requests_reply_with_subject_data() {
-        let f = filter(Arc::new(good_subjects()), Arc::new(mock_user::Mock::new()));
+        let f = filter(Arc::new(good_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
         for m in ["GET", "PATCH", "POST"] {
             let res = test_request(m)
                 .path("/subject/some_title")
@@ -415,6 +644,9 @@ mod tests {
 
             if m == "GET" {
                 assert_eq!(res.body(), "Good content");
+            } else if m == "POST" {
+                assert_eq!(res.headers()["location"], "some_title");
+                assert_eq!(res.body(), "");
             } else {
                 assert_eq!(res.body(), "");
             }
@@ -439,4 +671,133 @@ mod tests {
 
         assert_eq!(actual, expected);
     }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let cors = crate::cors::tests::config(&["https://app.test"], true).cors();
+        let f = filter(Arc::new(good_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH).with(cors);
+        for m in ["PATCH", "POST"] {
+            let res = warp::test::request()
+                .method("OPTIONS")
+                .path("/subject/some_title")
+                .header("Origin", "https://app.test")
+                .header("Access-Control-Request-Method", m)
+                .header("Access-Control-Request-Headers", "authorization, content-type")
+                .reply(&f)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK, "method: {}", m);
+            assert_eq!(res.headers()["access-control-allow-origin"], "https://app.test");
+            assert_eq!(res.headers()["access-control-allow-credentials"], "true");
+        }
+
+        // unknown origins, methods and headers are refused
+        for (origin, method, headers) in [
+            ("https://evil.test", "PATCH", "authorization"),
+            ("https://app.test", "PUT", "authorization"),
+            ("https://app.test", "PATCH", "x-custom"),
+        ] {
+            let res = warp::test::request()
+                .method("OPTIONS")
+                .path("/subject/some_title")
+                .header("Origin", origin)
+                .header("Access-Control-Request-Method", method)
+                .header("Access-Control-Request-Headers", headers)
+                .reply(&f)
+                .await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{} {} {}", origin, method, headers);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_titles_reply_with_error() {
+        let f = filter(Arc::new(good_subjects()), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), 10);
+        for m in ["GET", "PATCH", "POST"] {
+            for title in ["a%2Fb", "line%0Abreak", "%20_%20", "eleven_long", "%FF"] {
+                let res = test_request(m)
+                    .path(&format!("/subject/{}", title))
+                    .reply(&f)
+                    .await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST, "method: {}, title: {}", m, title);
+                let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+                assert!(problem.detail.unwrap().starts_with("title "), "{}", title);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_replay() {
+        let subjects = Arc::new(MemorySubjects::default());
+        let f = filter(subjects.clone(), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
+        let request = |method: &str, key: &str, content: &str| warp::test::request()
+            .method(method)
+            .path("/subject/New_subject")
+            .header("Authorization", "Basic bob:pass")
+            .header(IDEMPOTENCY_KEY, key)
+            .body(content);
+
+        // a retried create replays, where another would conflict
+        for replayed in [false, true] {
+            let res = request("POST", "create", "Content").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+            assert_eq!(res.headers()["location"], "New_subject");
+            assert_eq!(res.headers().contains_key(IDEMPOTENT_REPLAYED), replayed);
+        }
+        let res = request("POST", "other", "Content").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = request("PATCH", "update", "Newer").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("PATCH", "update", "Newest").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(subjects.content("New subject").as_deref(), Some("Newer"));
+
+        // edit headers are part of the request
+        for (name, value) in [(EDIT_SUMMARY, "Fix"), (MINOR_EDIT, "true")] {
+            let res = request("PATCH", "update", "Newer").header(name, value).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_edits() {
+        let subjects = Arc::new(MemorySubjects::default());
+        let f = filter(subjects.clone(), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
+        let request = |method: &str| warp::test::request()
+            .method(method)
+            .path("/subject/Policy")
+            .header("Authorization", "Basic bob:pass")
+            .body("Content");
+
+        let res = request("POST").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("PATCH")
+            .header(EDIT_SUMMARY, "Agreed%20on%20review")
+            .header(MINOR_EDIT, "true")
+            .reply(&f)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(subjects.edits(), vec![
+            ("Policy".to_string(), Edit::default()),
+            ("Policy".to_string(), Edit { summary: "Agreed on review".into(), minor: true }),
+        ]);
+
+        let res = request("PATCH").header(MINOR_EDIT, "maybe").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(subjects.edits().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reads_count_views() {
+        let subjects = Arc::new(MemorySubjects::with(&[("Read me", "Content")]));
+        let views = new_views();
+        let f = filter(subjects, views.clone(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH);
+        for (path, status) in [("/subject/Read_me", StatusCode::OK), ("/subject/read_me", StatusCode::OK), ("/subject/Missing", StatusCode::NOT_FOUND)] {
+            let res = warp::test::request().path(path).reply(&f).await;
+            assert_eq!(res.status(), status, "{}", path);
+        }
+
+        let stats = MemoryStats::default();
+        flush(&stats, &views).await.unwrap();
+        assert_eq!(*stats.recorded.lock().unwrap(), vec![vec![("Read me".to_string(), 1)]]);
+    }
 }
@@ -0,0 +1,142 @@
+// title is the canonical form of subject titles. Titles arrive percent-encoded
+// in paths, are normalized to NFC, and fold underscores and runs of whitespace
+// into single spaces, so that "Rust_ programming" and "Rust programming" name
+// the same subject. Titles differing only in case name the same subject too,
+// which the database enforces with an index on lower(title).
+
+use std::fmt;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use unicode_normalization::UnicodeNormalization;
+use warp::{reject::Rejection, Filter};
+
+use crate::error::Error;
+
+/// Default of --max-title-length, in characters
+pub const MAX_LENGTH: usize = 255;
+
+/// Characters slugs keep as they are, besides alphanumerics
+const SLUG: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Title(String);
+
+impl Title {
+    /// Canonicalizes a title, rejecting empty and overlong titles, and titles
+    /// with control characters, such as newlines, or slashes, which would not
+    /// survive a round trip through a path.
+    pub fn parse(raw: &str, max_length: usize) -> Result<Self, Error> {
+        let normalized: String = raw.nfc().collect();
+        if let Some(c) = normalized.chars().find(|c| c.is_control() || *c == '/') {
+            return Err(Error::BadRequest(format!("title may not contain {:?}: {:?}", c, raw)));
+        }
+
+        let folded = normalized
+            .split(|c: char| c == '_' || c.is_whitespace())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if folded.is_empty() {
+            return Err(Error::BadRequest("title is empty".into()));
+        }
+        let length = folded.chars().count();
+        if length > max_length {
+            return Err(Error::BadRequest(format!("title is {} characters long, the most allowed is {}", length, max_length)));
+        }
+        Ok(Title(folded))
+    }
+
+    /// Canonicalizes a percent-encoded path segment
+    pub fn from_segment(segment: &str, max_length: usize) -> Result<Self, Error> {
+        let decoded = percent_decode_str(segment)
+            .decode_utf8()
+            .map_err(|e| Error::BadRequest(format!("title is not UTF-8: {}", e)))?;
+        Self::parse(&decoded, max_length)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Path segment of the title, with spaces as underscores, which parse
+    /// back to the same title
+    pub fn slug(&self) -> String {
+        slug(&self.0)
+    }
+}
+
+/// Slug of a title that is already canonical, such as one that was stored
+pub fn slug(title: &str) -> String {
+    utf8_percent_encode(&title.replace(' ', "_"), SLUG).to_string()
+}
+
+impl fmt::Display for Title {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for Title {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Extracts the next path segment as a title, rejecting bad titles with
+/// Error::BadRequest
+pub fn param(max_length: usize) -> impl Filter<Extract = (Title,), Error = Rejection> + Clone {
+    warp::path::param()
+        .and_then(move |segment: String| async move {
+            Title::from_segment(&segment, max_length).map_err(warp::reject::custom)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for (raw, expected) in [
+            ("Rust", "Rust"),
+            ("  Rust   programming ", "Rust programming"),
+            ("Rust_programming", "Rust programming"),
+            ("Rust _\u{a0}programming", "Rust programming"),
+            // decomposed é composes
+            ("Caf\u{65}\u{301}", "Caf\u{e9}"),
+            ("C++ & C#?", "C++ & C#?"),
+        ] {
+            assert_eq!(Title::parse(raw, MAX_LENGTH).unwrap().as_str(), expected, "{:?}", raw);
+        }
+
+        for raw in ["", " _ ", "Line\nbreak", "Tab\there", "a/b", "\u{7f}"] {
+            let r = Title::parse(raw, MAX_LENGTH);
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}: {:?}", raw, r);
+        }
+
+        // length counts characters, not bytes
+        assert!(Title::parse("ééé", 3).is_ok());
+        let r = Title::parse("éééé", 3);
+        assert!(matches!(r, Err(Error::BadRequest(ref msg)) if msg.contains("most allowed is 3")), "{:?}", r);
+    }
+
+    #[test]
+    fn test_segments_and_slugs() {
+        for (segment, expected) in [
+            ("Rust%20programming", "Rust programming"),
+            ("Rust_programming", "Rust programming"),
+            ("Caf%C3%A9", "Caf\u{e9}"),
+            ("C%2B%2B%20%26%20C%23%3F", "C++ & C#?"),
+        ] {
+            let title = Title::from_segment(segment, MAX_LENGTH).unwrap();
+            assert_eq!(title.as_str(), expected, "{}", segment);
+            assert_eq!(Title::from_segment(&title.slug(), MAX_LENGTH).unwrap(), title, "{}", title.slug());
+        }
+        assert_eq!(Title::parse("Café au lait", MAX_LENGTH).unwrap().slug(), "Caf%C3%A9_au_lait");
+
+        for segment in ["a%2Fb", "a%0Ab", "%FF"] {
+            let r = Title::from_segment(segment, MAX_LENGTH);
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{}: {:?}", segment, r);
+        }
+    }
+}
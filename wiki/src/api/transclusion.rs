@@ -0,0 +1,439 @@
+// transclusion embeds subjects in others when they are rendered: {{Title}}
+// stands for the content of Title, and {{Title#section}} for what its section
+// with that heading or anchor holds under the heading. Embedded subjects may embed others, down to a depth;
+// a subject embedding itself, through others or not, is a cycle and embeds
+// nothing. Renders are Markdown with every transclusion expanded, cached
+// until a subject they depend on changes.
+
+use std::{collections::{HashMap, HashSet}, future::Future, ops::Range, sync::Arc};
+
+use pulldown_cmark::{Event, Parser, Tag};
+use warp::{http::Method, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{openapi::{Body, Operation, Response}, section, subject::Subjects, title::{self, Title}, with_tail}, error::{self, Error}};
+
+/// Default of --max-transclusion-depth
+pub const MAX_DEPTH: usize = 5;
+/// Most subjects a render reads, however many it embeds
+const MAX_SUBJECTS: usize = 100;
+/// Most transclusions a render expands, to bound renders of subjects
+/// embedding others many times
+const MAX_EXPANSIONS: usize = 500;
+
+pub trait Renders {
+    /// Reads the cached render of a subject, if any
+    fn read_render(&self, title: &Title) -> impl Future<Output = Result<Option<String>, Error>> + Send;
+    /// Caches the render of a subject, given the content of the subjects it
+    /// depends on as they were read, None for those that did not exist. The
+    /// render is not cached if any of them changed since.
+    fn save_render(&self, title: &Title, render: &str, dependencies: &[(String, Option<String>)]) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transclusion {
+    /// Bytes of the braces and what they hold
+    pub range: Range<usize>,
+    pub title: Title,
+    /// Heading or anchor of a section
+    pub section: Option<String>,
+}
+
+/// Finds the transclusions of content, in order. Braces in code transclude
+/// nothing, and neither do those that hold no title.
+pub fn transclusions(content: &str) -> Vec<Transclusion> {
+    let code: Vec<Range<usize>> = Parser::new(content).into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Code(_) | Event::Start(Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect();
+
+    let mut found = vec![];
+    let mut from = 0;
+    while let Some(open) = content[from..].find("{{").map(|i| from + i) {
+        let inner = open + 2;
+        let Some(close) = content[inner..].find("}}").map(|i| inner + i) else {
+            break;
+        };
+        let held = &content[inner..close];
+        // {{{Title}}} holds {{Title}}
+        if held.contains(['{', '}', '\n']) {
+            from = open + 1;
+            continue;
+        }
+        from = close + 2;
+        if code.iter().any(|c| c.contains(&open)) {
+            continue;
+        }
+        let (title, section) = match held.split_once('#') {
+            Some((title, section)) => (title, Some(section.trim().to_string())),
+            None => (held, None),
+        };
+        if let Ok(title) = Title::parse(title, title::MAX_LENGTH) {
+            found.push(Transclusion { range: open..close + 2, title, section });
+        }
+    }
+    found
+}
+
+/// Canonical titles content transcludes, once each regardless of case
+pub fn targets(content: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    transclusions(content).into_iter()
+        .filter(|t| seen.insert(key(&t.title)))
+        .map(|t| t.title.to_string())
+        .collect()
+}
+
+/// Renders a subject, expanding its transclusions and theirs down to
+/// max_depth. Reads every subject once, and returns them with the render as
+/// its dependencies, None for subjects that do not exist.
+pub async fn render<S: Subjects>(subjects: &S, title: &Title, max_depth: usize) -> Result<(String, Vec<(String, Option<String>)>), Error> {
+    let mut pages = HashMap::new();
+    pages.insert(key(title), (title.to_string(), Some(subjects.read(title).await?)));
+
+    // breadth first, so subjects are read at their least depth
+    let mut level = vec![key(title)];
+    for _ in 0..max_depth {
+        let mut next = vec![];
+        for page in level {
+            let Some(content) = pages[&page].1.clone() else {
+                continue;
+            };
+            for t in transclusions(&content) {
+                if pages.contains_key(&key(&t.title)) || pages.len() >= MAX_SUBJECTS {
+                    continue;
+                }
+                let content = match subjects.read(&t.title).await {
+                    Ok(content) => Some(content),
+                    Err(Error::NotFound(_)) => None,
+                    Err(err) => return Err(err),
+                };
+                pages.insert(key(&t.title), (t.title.to_string(), content));
+                next.push(key(&t.title));
+            }
+        }
+        level = next;
+    }
+
+    let mut expansion = Expansion { pages: &pages, stack: vec![key(title)], max_depth, expansions: 0 };
+    let render = expansion.expand(pages[&key(title)].1.as_deref().unwrap_or_default());
+    Ok((render, pages.into_values().collect()))
+}
+
+fn key(title: &Title) -> String {
+    title.as_str().to_lowercase()
+}
+
+/// Expansion expands transclusions with the subjects read for a render
+struct Expansion<'a> {
+    /// Title and content by lowercase title
+    pages: &'a HashMap<String, (String, Option<String>)>,
+    /// Subjects being expanded, the rendered one first
+    stack: Vec<String>,
+    max_depth: usize,
+    expansions: usize,
+}
+
+impl Expansion<'_> {
+    fn expand(&mut self, content: &str) -> String {
+        let mut expanded = String::with_capacity(content.len());
+        let mut last = 0;
+        for t in transclusions(content) {
+            expanded.push_str(&content[last..t.range.start]);
+            expanded.push_str(&self.embed(&content[t.range.clone()], &t));
+            last = t.range.end;
+        }
+        expanded.push_str(&content[last..]);
+        expanded
+    }
+
+    /// Replaces a transclusion with the content it stands for, or with why it
+    /// cannot
+    fn embed(&mut self, source: &str, t: &Transclusion) -> String {
+        let key = key(&t.title);
+        if self.stack.contains(&key) {
+            return failed(source, "it includes itself");
+        }
+        if self.stack.len() > self.max_depth {
+            return failed(source, &format!("it is nested deeper than {}", self.max_depth));
+        }
+        if self.expansions >= MAX_EXPANSIONS {
+            return failed(source, "the subject includes too many others");
+        }
+        let content = match self.pages.get(&key) {
+            None => return failed(source, "the subject includes too many others"),
+            // a link to create it
+            Some((title, None)) => return format!("[{}](/wiki/{})", title, title::slug(title)),
+            Some((_, Some(content))) => content,
+        };
+        let text = match &t.section {
+            None => content.as_str(),
+            Some(name) => {
+                // underscores stand for spaces, as in titles
+                let anchors = [section::anchor(name), section::anchor(&name.replace('_', " "))];
+                match section::outline(content).into_iter().find(|s| anchors.contains(&s.anchor)) {
+                    Some(s) => under_heading(&content[s.range]),
+                    None => return failed(source, "there is no such section"),
+                }
+            },
+        };
+
+        self.expansions += 1;
+        self.stack.push(key);
+        let expanded = self.expand(text);
+        self.stack.pop();
+        // so that inline transclusions stay inline
+        expanded.trim_end_matches('\n').to_string()
+    }
+}
+
+/// Text of a section under its heading, so that a section embedded inline
+/// stays inline, and one embedded in a block goes under the heading there
+fn under_heading(section: &str) -> &str {
+    let heading = Parser::new(section).into_offset_iter().next().map_or(0, |(_, range)| range.end);
+    // headings may end before their line break
+    let end = if section[..heading].ends_with('\n') {
+        heading
+    } else {
+        section[heading..].find('\n').map_or(section.len(), |i| heading + i + 1)
+    };
+    &section[end..]
+}
+
+fn failed(source: &str, reason: &str) -> String {
+    format!("*{} was not included: {}*", source.trim_start_matches('{').trim_end_matches('}'), reason)
+}
+
+pub fn filter<S, R>(subjects: Arc<S>, renders: Arc<R>, max_depth: usize, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    S: Subjects + Send + Sync + 'static,
+    R: Renders + Send + Sync + 'static,
+{
+    warp::path!("subject" / ..)
+        .and(
+            with_tail("rendered").and(warp::get()).and(endpoints::render(subjects, renders, max_depth, max_title_length))
+            .or(with_tail("rendered").and(error::method_not_allowed(&[Method::GET])))
+        )
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::GET,
+            path: "/subject/{title}/rendered",
+            id: "renderSubject",
+            summary: "Renders a subject to Markdown with {{Title}} and {{Title#section}} replaced by what they include",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Render", Some(Body::Text)),
+                Response::problem(400, "Bad title"),
+                Response::problem(404, "No such subject"),
+            ],
+        },
+    ]
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{api::title, error};
+
+    use super::{handlers, Renders, Subjects};
+
+    pub fn render<S, R>(subjects: Arc<S>, renders: Arc<R>, max_depth: usize, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static,
+        R: Renders + Send + Sync + 'static,
+    {
+        title::param(max_title_length)
+            .and(warp::path!("rendered"))
+            .and(warp::any().map(move || subjects.clone()))
+            .and(warp::any().map(move || renders.clone()))
+            .and(warp::any().map(move || max_depth))
+            .and_then(handlers::render)
+            .recover(error::recover)
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{reject::Rejection, reply::Reply};
+
+    use crate::{api::title::Title, error::Error};
+
+    use super::{render as render_subject, Renders, Subjects};
+
+    pub async fn render<S: Subjects, R: Renders>(title: Title, subjects: Arc<S>, renders: Arc<R>, max_depth: usize) -> Result<impl Reply, Rejection> {
+        let r = async {
+            if let Some(render) = renders.read_render(&title).await? {
+                return Ok::<_, Error>(render);
+            }
+            let (render, dependencies) = render_subject(subjects.as_ref(), &title, max_depth).await?;
+            // the render is good without the cache
+            if let Err(err) = renders.save_render(&title, &render, &dependencies).await {
+                log::warn!(target: "wiki::api", "render of {} was not cached: {:?}", title, err);
+            }
+            Ok(render)
+        }.await;
+
+        match r {
+            Ok(render) => Ok(warp::reply::with_header(
+                render,
+                "Content-Type",
+                "text/plain")),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests transclusions, render, filter, and endpoints and handlers modules,
+/// with subjects and renders in memory. Exposes renders in memory to test
+/// modules.
+///
+/// Test plan:
+/// 1. Transclusions are found outside code, with their sections
+/// 2. Renders embed subjects, and sections without their heading, in subjects
+///    they embed too
+/// 3. Cycles, depths past the limit, and missing sections embed nothing,
+///    missing subjects embed a link
+/// 4. Renders are cached with their dependencies, and served from the cache
+/// 5. Bad requests reply with problems, bad methods with 405
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Mutex;
+
+    use warp::http::StatusCode;
+
+    use crate::{api::{subject::tests::MemorySubjects, title::MAX_LENGTH}, error::Problem};
+
+    use super::*;
+
+    /// MemoryRenders caches renders by lowercase title, with their
+    /// dependencies
+    #[derive(Default)]
+    pub struct MemoryRenders {
+        #[allow(clippy::type_complexity)]
+        pub renders: Mutex<HashMap<String, (String, Vec<(String, Option<String>)>)>>,
+    }
+
+    impl Renders for MemoryRenders {
+        async fn read_render(&self, title: &Title) -> Result<Option<String>, Error> {
+            Ok(self.renders.lock().unwrap().get(&key(title)).map(|(render, _)| render.clone()))
+        }
+
+        async fn save_render(&self, title: &Title, render: &str, dependencies: &[(String, Option<String>)]) -> Result<(), Error> {
+            let mut dependencies = dependencies.to_vec();
+            dependencies.sort();
+            self.renders.lock().unwrap().insert(key(title), (render.to_string(), dependencies));
+            Ok(())
+        }
+    }
+
+    fn title(t: &str) -> Title {
+        Title::parse(t, MAX_LENGTH).unwrap()
+    }
+
+    fn wiki() -> MemorySubjects {
+        MemorySubjects::with(&[
+            ("Runbook", "# Runbook\n{{Contacts}}\n\n{{Warnings#Power}}\n\nCall {{contacts#on_call}} now.\n"),
+            ("Contacts", "Ask {{Team}}.\n\n## On call\nBob\n"),
+            ("Team", "the team\n"),
+            ("Warnings", "# Heat\nKeep it cool.\n\nPower\n=====\nNever unplug it.\n"),
+            ("Loop", "Loop {{Loop}}."),
+            ("Ping", "Ping {{Pong}}."),
+            ("Pong", "Pong {{ping}}."),
+            ("Deep", "0 {{Deep 1}}"),
+            ("Deep 1", "1 {{Deep 2}}"),
+            ("Deep 2", "2 {{Deep 3}}"),
+            ("Deep 3", "3"),
+        ])
+    }
+
+    #[test]
+    fn test_transclusions() {
+        let content = "{{One}} and {{ two_words # Some section }}, `{{Code}}`\n\n    {{Indented code}}\n\n```\n{{Fenced}}\n```\n{{}} {{a/b}} {{{Three}}} {{Four";
+        let transclusions = transclusions(content);
+        let found: Vec<(&str, Option<&str>, &str)> = transclusions.iter()
+            .map(|t| (t.title.as_str(), t.section.as_deref(), &content[t.range.clone()]))
+            .collect();
+        assert_eq!(found, vec![
+            ("One", None, "{{One}}"),
+            ("two words", Some("Some section"), "{{ two_words # Some section }}"),
+            ("Three", None, "{{Three}}"),
+        ]);
+        assert_eq!(targets("{{One}} {{one#Section}} {{Two}}"), vec!["One", "Two"]);
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let subjects = wiki();
+        let (render, mut dependencies) = super::render(&subjects, &title("Runbook"), MAX_DEPTH).await.unwrap();
+        assert_eq!(render, "# Runbook\nAsk the team.\n\n## On call\nBob\n\nNever unplug it.\n\nCall Bob now.\n");
+        dependencies.sort();
+        assert_eq!(
+            dependencies.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>(),
+            vec!["Contacts", "Runbook", "Team", "Warnings"],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_failures() {
+        let subjects = wiki();
+        let render = |t: &'static str, depth| {
+            let subjects = &subjects;
+            async move { super::render(subjects, &title(t), depth).await.map(|(render, _)| render) }
+        };
+        assert_eq!(render("Loop", MAX_DEPTH).await.unwrap(), "Loop *Loop was not included: it includes itself*.");
+        assert_eq!(render("Ping", MAX_DEPTH).await.unwrap(), "Ping Pong *ping was not included: it includes itself*..");
+        assert_eq!(render("Deep", 2).await.unwrap(), "0 1 2 *Deep 3 was not included: it is nested deeper than 2*");
+        assert_eq!(render("Deep", 3).await.unwrap(), "0 1 2 3");
+        assert!(matches!(render("Missing", MAX_DEPTH).await, Err(Error::NotFound(_))));
+
+        let subjects = MemorySubjects::with(&[("Page", "{{New page}} {{Page 2#Nowhere}}"), ("Page 2", "# Somewhere")]);
+        let (render, dependencies) = super::render(&subjects, &title("Page"), MAX_DEPTH).await.unwrap();
+        assert_eq!(render, "[New page](/wiki/New_page) *Page 2#Nowhere was not included: there is no such section*");
+        assert!(dependencies.contains(&("New page".to_string(), None)));
+    }
+
+    #[tokio::test]
+    async fn test_rendered() {
+        let subjects = Arc::new(wiki());
+        let renders = Arc::new(MemoryRenders::default());
+        let f = filter(subjects.clone(), renders.clone(), MAX_DEPTH, MAX_LENGTH);
+
+        let res = warp::test::request().path("/subject/Contacts/rendered").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "Ask the team.\n\n## On call\nBob\n");
+        assert_eq!(renders.renders.lock().unwrap()["contacts"].1, vec![
+            ("Contacts".to_string(), Some("Ask {{Team}}.\n\n## On call\nBob\n".to_string())),
+            ("Team".to_string(), Some("the team\n".to_string())),
+        ]);
+
+        // cached renders are served as they are
+        renders.renders.lock().unwrap().get_mut("contacts").unwrap().0 = "cached".into();
+        let res = warp::test::request().path("/subject/contacts/rendered").reply(&f).await;
+        assert_eq!(res.body(), "cached");
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let f = filter(Arc::new(wiki()), Arc::new(MemoryRenders::default()), MAX_DEPTH, MAX_LENGTH);
+        let res = warp::test::request().path("/subject/Missing/rendered").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(problem.code, "not_found");
+
+        let res = warp::test::request().method("POST").path("/subject/Runbook/rendered").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()["allow"], "GET");
+        let res = warp::test::request().path("/subject/Runbook/renders").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
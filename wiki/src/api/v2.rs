@@ -0,0 +1,90 @@
+// v2 is the JSON API. Successful replies wrap their data in an envelope, lists
+// add pagination to it, and failures are problems like in v1.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::user::Users, error::Error};
+
+use super::{idempotency::{Idempotency, Keys}, openapi::{self, Operation}, stats::Views, subject::Subjects, Settings};
+
+pub mod subject;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+pub fn filter() -> impl Filter<Extract = (), Error = Rejection> + Clone
+{
+    warp::path!("api" / "v2" / ..)
+}
+
+/// Routes of every v2 module, under /api/v2, and its OpenAPI document
+pub fn routes<S, U, K>(subjects: Arc<S>, views: Arc<Views>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, settings: Settings) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    S: Subjects + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+    K: Keys + Send + Sync + 'static,
+{
+    subject::filter(subjects, views, users, idempotency, settings.max_title_length)
+        .or(openapi::filter(Arc::new(openapi::document(&operations(), "/api/v2", false)), settings.openapi_viewer))
+}
+
+/// Operations of every v2 module, which its OpenAPI document describes
+pub fn operations() -> Vec<Operation> {
+    subject::operations().into_iter()
+        .chain(openapi::operations())
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Envelope<T> {
+    pub data: T,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Page<T> {
+    pub data: Vec<T>,
+    pub pagination: Pagination,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Pagination {
+    pub limit: i64,
+    /// Cursor of the next page, None on the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PageQuery {
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+impl PageQuery {
+    pub fn limit(&self) -> Result<i64, Error> {
+        match self.limit {
+            None => Ok(DEFAULT_LIMIT),
+            Some(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+            Some(limit) => Err(Error::BadRequest(format!("limit must be between 1 and {}, not {}", MAX_LIMIT, limit))),
+        }
+    }
+}
+
+/// Pages items fetched with one more than limit, so that whether there is a
+/// next page is known. The cursor of the next page is the key of the last
+/// item on this one.
+pub fn page<T>(mut items: Vec<T>, limit: i64, key: impl Fn(&T) -> String) -> Page<T> {
+    let next_cursor = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(key)
+    } else {
+        None
+    };
+    Page {
+        data: items,
+        pagination: Pagination { limit, next_cursor },
+    }
+}
@@ -0,0 +1,400 @@
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::{http::Method, reject::Rejection, reply::Reply, Filter};
+
+use crate::{api::{edit::Edit, idempotency::{self, Idempotency, Keys}, openapi::{Body, Operation, Response}, stats::Views, subject::Subjects, title}, auth::user::{Scope, Users}, error};
+
+use super::{Envelope, Page};
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SubjectSummary {
+    pub title: String,
+    /// Path segment of the subject
+    pub slug: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SubjectInfo {
+    pub title: String,
+    pub slug: String,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct NewSubject {
+    pub title: String,
+    pub content: String,
+    #[serde(flatten)]
+    pub edit: Edit,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SubjectUpdate {
+    pub content: String,
+    #[serde(flatten)]
+    pub edit: Edit,
+}
+
+impl From<String> for SubjectSummary {
+    fn from(title: String) -> Self {
+        SubjectSummary { slug: title::slug(&title), title }
+    }
+}
+
+pub fn filter<S, U, K>(subjects: Arc<S>, views: Arc<Views>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    S: Subjects + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+    K: Keys + Send + Sync + 'static,
+{
+    warp::path!("subjects")
+        .and(
+            warp::get().and(endpoints::list(subjects.clone()))
+            .or(warp::post().and(endpoints::create(subjects.clone(), users.clone(), idempotency.clone(), max_title_length)))
+            .or(error::method_not_allowed(&[Method::GET, Method::POST]))
+        )
+        .or(
+            warp::path!("subjects" / ..)
+                .and(
+                    warp::get().and(endpoints::read(subjects.clone(), views, max_title_length))
+                    .or(warp::patch().and(endpoints::update(subjects, users, idempotency, max_title_length)))
+                    .or(
+                        warp::path::param::<String>().map(|_| ()).untuple_one()
+                            .and(warp::path::end())
+                            .and(error::method_not_allowed(&[Method::GET, Method::PATCH]))
+                    )
+                )
+        )
+}
+
+pub fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            method: Method::GET,
+            path: "/subjects",
+            id: "listSubjects",
+            summary: "Lists subjects in case-insensitive order of their titles",
+            scope: None,
+            query: &[("limit", "integer"), ("cursor", "string")],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "A page of subjects", Some(Body::json::<Page<SubjectSummary>>())),
+                Response::problem(400, "Bad limit"),
+            ],
+        },
+        Operation {
+            method: Method::POST,
+            path: "/subjects",
+            id: "createSubject",
+            summary: "Creates a subject, Location holds its path",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: idempotency::HEADERS,
+            request: Some(Body::json::<NewSubject>()),
+            responses: vec![
+                Response::ok(201, "Created", Some(Body::json::<Envelope<SubjectInfo>>())),
+                Response::problem(400, "Bad title, edit or no content"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(409, "The subject exists"),
+            ],
+        },
+        Operation {
+            method: Method::GET,
+            path: "/subjects/{title}",
+            id: "readSubject",
+            summary: "Reads a subject",
+            scope: None,
+            query: &[],
+            headers: &[],
+            request: None,
+            responses: vec![
+                Response::ok(200, "Subject", Some(Body::json::<Envelope<SubjectInfo>>())),
+                Response::problem(400, "Bad title"),
+                Response::problem(404, "No such subject"),
+            ],
+        },
+        Operation {
+            method: Method::PATCH,
+            path: "/subjects/{title}",
+            id: "updateSubject",
+            summary: "Replaces the content of a subject",
+            scope: Some(Scope::Write),
+            query: &[],
+            headers: idempotency::HEADERS,
+            request: Some(Body::json::<SubjectUpdate>()),
+            responses: vec![
+                Response::ok(200, "Updated", Some(Body::json::<Envelope<SubjectInfo>>())),
+                Response::problem(400, "Bad title, edit or no content"),
+                Response::problem(401, "Not signed in"),
+                Response::problem(404, "No such subject"),
+            ],
+        },
+    ]
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{api::{idempotency::{self, Idempotency, Keys}, stats::{self, Views}, subject::Subjects, title, v2::PageQuery}, auth::user::{with_authorization, Scope, Users}, error};
+
+    use super::handlers;
+
+    pub fn list<S>(subjects: Arc<S>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static
+    {
+        warp::query::<PageQuery>()
+            .and(with_subjects(subjects))
+            .and_then(handlers::list)
+            .recover(error::recover)
+    }
+
+    pub fn create<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+        K: Keys + Send + Sync + 'static,
+    {
+        with_subjects(subjects)
+            .and(with_authorization(users, Scope::Write))
+            .and(warp::body::json())
+            .and(warp::any().map(move || max_title_length))
+            .and(idempotency::attempt(idempotency))
+            .and_then(handlers::create)
+            .recover(error::recover)
+    }
+
+    pub fn read<S>(subjects: Arc<S>, views: Arc<Views>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static
+    {
+        let viewer = stats::viewer(views.clone());
+        title::param(max_title_length)
+            .and(warp::path::end())
+            .and(with_subjects(subjects))
+            .and(warp::any().map(move || views.clone()))
+            .and(viewer)
+            .and_then(handlers::read)
+            .recover(error::recover)
+    }
+
+    pub fn update<S, U, K>(subjects: Arc<S>, users: Arc<U>, idempotency: Arc<Idempotency<K>>, max_title_length: usize) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+        K: Keys + Send + Sync + 'static,
+    {
+        title::param(max_title_length)
+            .and(warp::path::end())
+            .and(with_subjects(subjects))
+            .and(with_authorization(users, Scope::Write))
+            .and(warp::body::json())
+            .and(idempotency::attempt(idempotency))
+            .and_then(handlers::update)
+            .recover(error::recover)
+    }
+
+    fn with_subjects<S>(subjects: Arc<S>) -> impl Filter<Extract = (Arc<S>,), Error = Infallible> + Clone
+    where
+        S: Subjects + Send + Sync + 'static
+    {
+        warp::any().map(move || subjects.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{api::{idempotency::{Attempt, Keys}, stats::Views, subject::Subjects, title::Title, v2::{page, Envelope, Page, PageQuery}}, auth::user::User, error::Error};
+
+    use super::{NewSubject, SubjectInfo, SubjectSummary, SubjectUpdate};
+
+    pub async fn list<S: Subjects>(query: PageQuery, subjects: Arc<S>) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let limit = query.limit()?;
+            let titles = subjects.list_after(query.cursor.as_deref(), limit + 1).await?;
+            Ok::<_, Error>(page(titles, limit, String::clone))
+        }.await;
+
+        match r {
+            Ok(page) => Ok(warp::reply::json(&Page {
+                data: page.data.into_iter().map(SubjectSummary::from).collect(),
+                pagination: page.pagination,
+            })),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn create<S: Subjects, K: Keys>(subjects: Arc<S>, user: User, new: NewSubject, max_title_length: usize, attempt: Attempt<K>) -> Result<impl Reply, Rejection> {
+        let body = serde_json::to_vec(&new).unwrap_or_default();
+        attempt.run(&user.name, &body, async {
+            let title = Title::parse(&new.title, max_title_length)?;
+            if new.content.is_empty() {
+                return Err(Error::BadRequest("no content".into()));
+            }
+            new.edit.validate()?;
+            subjects.create(&user.name, &title, &new.content, &new.edit).await?;
+            let info = info(&title, new.content);
+
+            // relative to the request path, so it holds wherever the API is mounted
+            Ok(warp::reply::with_header(
+                warp::reply::with_status(warp::reply::json(&Envelope { data: &info }), StatusCode::CREATED),
+                "Location",
+                format!("subjects/{}", info.slug),
+            ))
+        }).await
+    }
+
+    pub async fn read<S: Subjects>(title: Title, subjects: Arc<S>, views: Arc<Views>, viewer: String) -> Result<impl Reply, Rejection> {
+        match subjects.read(&title).await {
+            Ok(content) => {
+                views.view(&viewer, &title);
+                Ok(warp::reply::json(&Envelope { data: info(&title, content) }))
+            },
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn update<S: Subjects, K: Keys>(title: Title, subjects: Arc<S>, user: User, update: SubjectUpdate, attempt: Attempt<K>) -> Result<impl Reply, Rejection> {
+        let body = serde_json::to_vec(&update).unwrap_or_default();
+        attempt.run(&user.name, &body, async {
+            if update.content.is_empty() {
+                return Err(Error::BadRequest("no content".into()));
+            }
+            update.edit.validate()?;
+            subjects.update(&user.name, &title, &update.content, &update.edit).await?;
+            Ok(warp::reply::json(&Envelope { data: info(&title, update.content) }))
+        }).await
+    }
+
+    fn info(title: &Title, content: String) -> SubjectInfo {
+        SubjectInfo {
+            title: title.to_string(),
+            slug: title.slug(),
+            content,
+        }
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules, with the mock subjects
+/// of v1.
+///
+/// Test plan:
+/// 1. Lists are paged
+/// 2. Subjects are read, created and updated in envelopes
+/// 3. Bad requests reply with problems
+/// 4. Reject bad methods with 405 and the allowed methods
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use crate::{api::{idempotency::tests::new_idempotency, stats::tests::new_views, subject::tests::{good_subjects, MockSubjects}, title::MAX_LENGTH, v2::Pagination}, auth::mock_user, error::{Error, Problem}};
+
+    use super::*;
+
+    fn new_filter(subjects: MockSubjects) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        filter(Arc::new(subjects), new_views(), Arc::new(mock_user::Mock::new()), new_idempotency(), MAX_LENGTH)
+    }
+
+    fn write(method: &str, path: &str, body: serde_json::Value) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("Authorization", "Basic bob:pass")
+            .json(&body)
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let f = new_filter(MockSubjects {
+            list_response: Ok(vec!["b".into(), "A".into(), "c".into()]),
+            ..good_subjects()
+        });
+
+        let res = warp::test::request().path("/subjects?limit=2").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page: Page<SubjectSummary> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(page.data.iter().map(|s| s.title.as_str()).collect::<Vec<_>>(), vec!["A", "b"]);
+        assert_eq!(page.pagination, Pagination { limit: 2, next_cursor: Some("b".into()) });
+
+        let res = warp::test::request().path("/subjects?limit=2&cursor=b").reply(&f).await;
+        let page: Page<SubjectSummary> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(page.data.iter().map(|s| s.title.as_str()).collect::<Vec<_>>(), vec!["c"]);
+        assert_eq!(page.pagination.next_cursor, None);
+
+        let res = warp::test::request().path("/subjects").reply(&f).await;
+        let page: Page<SubjectSummary> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(page.data.len(), 3);
+        assert_eq!(page.pagination, Pagination { limit: 50, next_cursor: None });
+
+        for limit in ["0", "201", "many"] {
+            let res = warp::test::request().path(&format!("/subjects?limit={}", limit)).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", limit);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subjects() {
+        let f = new_filter(good_subjects());
+
+        let res = warp::test::request().path("/subjects/Good_subject").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let info: Envelope<SubjectInfo> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!((info.data.title.as_str(), info.data.slug.as_str(), info.data.content.as_str()), ("Good subject", "Good_subject", "Good content"));
+
+        let res = write("POST", "/subjects", json!({"title": "Café  au lait", "content": "Hot"})).reply(&f).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()["location"], "subjects/Caf%C3%A9_au_lait");
+        let info: Envelope<SubjectInfo> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(info.data.title, "Café au lait");
+
+        let res = write("PATCH", "/subjects/Good_subject", json!({"content": "Better", "summary": "Clarify", "minor": true})).reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let info: Envelope<SubjectInfo> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(info.data.content, "Better");
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        let f = new_filter(MockSubjects {
+            create_response: Err(Error::Conflict("subject already exists: A".into())),
+            read_response: Err(Error::NotFound("A".into())),
+            ..good_subjects()
+        });
+
+        for (req, status, code) in [
+            (write("POST", "/subjects", json!({"title": "a/b", "content": "x"})), StatusCode::BAD_REQUEST, "bad_request"),
+            (write("POST", "/subjects", json!({"title": "A", "content": ""})), StatusCode::BAD_REQUEST, "bad_request"),
+            (write("POST", "/subjects", json!({"content": "x"})), StatusCode::BAD_REQUEST, "invalid_body"),
+            (write("POST", "/subjects", json!({"title": "A", "content": "x"})), StatusCode::CONFLICT, "conflict"),
+            (warp::test::request().method("POST").path("/subjects").json(&json!({"title": "A", "content": "x"})), StatusCode::UNAUTHORIZED, "unauthorized"),
+            (write("PATCH", "/subjects/A", json!({"text": "x"})), StatusCode::BAD_REQUEST, "invalid_body"),
+            (write("PATCH", "/subjects/A", json!({"content": "x", "summary": "two\nlines"})), StatusCode::BAD_REQUEST, "bad_request"),
+            (warp::test::request().path("/subjects/A"), StatusCode::NOT_FOUND, "not_found"),
+        ] {
+            let res = req.reply(&f).await;
+            assert_eq!(res.status(), status, "{:?}", res.body());
+            assert_eq!(res.headers()["content-type"], "application/problem+json");
+            let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!(problem.code, code);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reject_bad_methods() {
+        let f = new_filter(good_subjects());
+        for (path, allow) in [("/subjects", "GET, POST"), ("/subjects/A", "GET, PATCH")] {
+            let res = warp::test::request().method("DELETE").path(path).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            assert_eq!(res.headers()["allow"], allow);
+        }
+    }
+}
@@ -1,2 +1,24 @@
+use warp::{reject::Rejection, Filter};
+
+pub mod account;
+pub mod audit;
+pub mod backend;
+pub mod database;
+pub mod email;
+pub mod jwt;
+pub mod lockout;
+pub mod mail;
 pub mod mock_user;
+pub mod oidc;
+pub mod password;
+pub mod personal_token;
+pub mod session;
+pub mod static_file;
+pub mod token;
+pub mod totp;
 pub mod user;
+
+pub fn filter() -> impl Filter<Extract = (), Error = Rejection> + Clone
+{
+    warp::path!("auth" / "v1" / ..)
+}
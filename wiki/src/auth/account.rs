@@ -0,0 +1,616 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::{audit::Audit, jwt::Jwt, lockout::{Lockouts, Throttle}, password, session::Sessions, totp::SecondFactors, user::{Role, User}}, error::Error};
+
+const MAX_USERNAME_LENGTH: usize = 256;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// DUMMY_HASH is verified against when a user does not exist or has no
+// password, so that signing in as them takes as long as a wrong password.
+pub const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$Ua2nF3kA0Gv2hTvbqXkL9pQm6KqAWGZ9gZ0NZ/7v3xM";
+
+pub trait Accounts {
+    fn create_user(&self, username: &str, password_hash: &str) -> impl Future<Output = Result<(), Error>> + Send;
+    fn read_user(&self, username: &str) -> impl Future<Output = Result<Account, Error>> + Send;
+    /// Creates or updates a user authenticated by an external identity
+    /// provider, identified by subject. Roles are replaced by roles.
+    fn upsert_external_user(&self, subject: &str, username: &str, roles: &[Role]) -> impl Future<Output = Result<Account, Error>> + Send;
+    fn update_password(&self, username: &str, password_hash: &str) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub username: String,
+    /// None for users authenticated by an external identity provider
+    pub password_hash: Option<String>,
+    pub roles: Vec<Role>,
+    /// Whether signing in requires a TOTP code
+    pub totp_enabled: bool,
+}
+
+impl Account {
+    pub fn user(&self) -> User {
+        User {
+            roles: self.roles.clone(),
+            ..User::new(&self.username)
+        }
+    }
+}
+
+/// Lifetimes of issued tokens.
+#[derive(Debug, Clone, Copy)]
+pub struct Lifetimes {
+    pub access: Duration,
+    pub refresh: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    /// TOTP or recovery code, required once the user enrolled
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Refresh {
+    pub refresh: String,
+}
+
+/// Tokens matches the shape returned by the UI's mock auth.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tokens {
+    pub username: String,
+    pub token: String,
+    pub refresh: String,
+}
+
+/// Sign up and sign in are not found unless passwords is set, sign out and
+/// refresh serve every session. Sign ins are throttled per account and IP.
+pub fn filter<A, S, L>(accounts: Arc<A>, sessions: Arc<S>, jwt: Arc<Jwt>, throttle: Arc<Throttle<L>>, lifetimes: Lifetimes, passwords: bool) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    A: Accounts + SecondFactors + Send + Sync + 'static,
+    S: Sessions + Send + Sync + 'static,
+    L: Lockouts + Audit + Send + Sync + 'static,
+{
+    warp::post()
+        .and(
+            warp::path!("sign-up").and(endpoints::sign_up(accounts.clone(), sessions.clone(), jwt.clone(), lifetimes, passwords))
+            .or(warp::path!("sign-in").and(endpoints::sign_in(accounts.clone(), sessions.clone(), jwt.clone(), throttle, lifetimes, passwords)))
+            .or(warp::path!("sign-out").and(endpoints::sign_out(sessions.clone())))
+            .or(warp::path!("refresh").and(endpoints::refresh(accounts, sessions, jwt, lifetimes)))
+        )
+}
+
+// verify_credentials returns the account if the password matches the user's
+// password
+pub async fn verify_credentials<A: Accounts>(accounts: &A, username: &str, password: &str) -> Result<Account, Error> {
+    let account = match accounts.read_user(username).await {
+        Ok(account) => Some(account),
+        Err(Error::NotFound(_)) => None,
+        Err(err) => return Err(err),
+    };
+
+    let hash = account.as_ref()
+        .and_then(|a| a.password_hash.as_deref())
+        .unwrap_or(DUMMY_HASH);
+    let verified = password::verify(password, hash).await?;
+
+    match account {
+        Some(account) if verified && account.password_hash.is_some() => Ok(account),
+        _ => Err(Error::Unauthorized(format!("bad credentials for user: {}", username))),
+    }
+}
+
+pub fn validate_username(username: &str) -> Result<(), Error> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "username must be between 1 and {} bytes", MAX_USERNAME_LENGTH,
+        )));
+    }
+    // ASCII only, so that no username looks like another, such as one
+    // spelled with a Cyrillic о
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || ['-', '_', '.'].contains(&c)) {
+        return Err(Error::BadRequest(
+            "username may only contain ASCII letters, digits, '-', '_' and '.'".into(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), Error> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "password must be at least {} characters", MIN_PASSWORD_LENGTH,
+        )));
+    }
+    Ok(())
+}
+
+mod endpoints {
+    use std::{convert::Infallible, net::IpAddr, sync::Arc};
+
+    use warp::{http::HeaderMap, reject::Rejection, reply::Reply, Filter};
+
+    use crate::{auth::{audit::Audit, jwt::Jwt, lockout::{Lockouts, Throttle}, session::Sessions, totp::SecondFactors}, error};
+
+    use super::{handlers, Accounts, Lifetimes};
+
+    pub fn sign_up<A, S>(accounts: Arc<A>, sessions: Arc<S>, jwt: Arc<Jwt>, lifetimes: Lifetimes, passwords: bool) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        A: Accounts + Send + Sync + 'static,
+        S: Sessions + Send + Sync + 'static,
+    {
+        with_passwords(passwords)
+            .and(with_accounts(accounts))
+            .and(with_sessions(sessions))
+            .and(with_jwt(jwt))
+            .and(warp::any().map(move || lifetimes))
+            .and(warp::body::json())
+            .and_then(handlers::sign_up)
+            .recover(error::recover)
+    }
+
+    pub fn sign_in<A, S, L>(accounts: Arc<A>, sessions: Arc<S>, jwt: Arc<Jwt>, throttle: Arc<Throttle<L>>, lifetimes: Lifetimes, passwords: bool) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        A: Accounts + SecondFactors + Send + Sync + 'static,
+        S: Sessions + Send + Sync + 'static,
+        L: Lockouts + Audit + Send + Sync + 'static,
+    {
+        let client_ip = with_client_ip(throttle.clone());
+        with_passwords(passwords)
+            .and(with_accounts(accounts))
+            .and(with_sessions(sessions))
+            .and(with_jwt(jwt))
+            .and(warp::any().map(move || throttle.clone()))
+            .and(warp::any().map(move || lifetimes))
+            .and(client_ip)
+            .and(warp::body::json())
+            .and_then(handlers::sign_in)
+            .recover(error::recover)
+    }
+
+    pub fn sign_out<S>(sessions: Arc<S>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Sessions + Send + Sync + 'static
+    {
+        with_sessions(sessions)
+            .and(warp::body::json())
+            .and_then(handlers::sign_out)
+            .recover(error::recover)
+    }
+
+    pub fn refresh<A, S>(accounts: Arc<A>, sessions: Arc<S>, jwt: Arc<Jwt>, lifetimes: Lifetimes) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        A: Accounts + Send + Sync + 'static,
+        S: Sessions + Send + Sync + 'static,
+    {
+        with_accounts(accounts)
+            .and(with_sessions(sessions))
+            .and(with_jwt(jwt))
+            .and(warp::any().map(move || lifetimes))
+            .and(warp::body::json())
+            .and_then(handlers::refresh)
+            .recover(error::recover)
+    }
+
+    fn with_client_ip<L>(throttle: Arc<Throttle<L>>) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone
+    where
+        L: Lockouts + Audit + Send + Sync + 'static
+    {
+        warp::addr::remote()
+            .and(warp::header::headers_cloned())
+            .map(move |remote, headers: HeaderMap| throttle.client_ip(remote, &headers))
+    }
+
+    fn with_passwords(passwords: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone
+    {
+        warp::any().and_then(move || async move {
+            if passwords { Ok(()) } else { Err(warp::reject::not_found()) }
+        }).untuple_one()
+    }
+
+    fn with_accounts<A>(accounts: Arc<A>) -> impl Filter<Extract = (Arc<A>,), Error = Infallible> + Clone
+    where
+        A: Accounts + Send + Sync + 'static
+    {
+        warp::any().map(move || accounts.clone())
+    }
+
+    fn with_sessions<S>(sessions: Arc<S>) -> impl Filter<Extract = (Arc<S>,), Error = Infallible> + Clone
+    where
+        S: Sessions + Send + Sync + 'static
+    {
+        warp::any().map(move || sessions.clone())
+    }
+
+    fn with_jwt(jwt: Arc<Jwt>) -> impl Filter<Extract = (Arc<Jwt>,), Error = Infallible> + Clone
+    {
+        warp::any().map(move || jwt.clone())
+    }
+}
+
+mod handlers {
+    use std::{net::IpAddr, sync::Arc};
+
+    use warp::{reject::Rejection, reply::Reply};
+
+    use crate::auth::{audit::Audit, jwt::Jwt, lockout::{self, Lockouts, Throttle}, password, session::{self, Sessions}, totp::{self, SecondFactors}, user::User};
+
+    use super::{validate_password, validate_username, verify_credentials, Accounts, Credentials, Lifetimes, Refresh};
+
+    pub async fn sign_up<A: Accounts, S: Sessions>(accounts: Arc<A>, sessions: Arc<S>, jwt: Arc<Jwt>, lifetimes: Lifetimes, credentials: Credentials) -> Result<impl Reply, Rejection> {
+        let r = async {
+            validate_username(&credentials.username)?;
+            validate_password(&credentials.password)?;
+            let hash = password::hash(&credentials.password).await?;
+            accounts.create_user(&credentials.username, &hash).await?;
+            session::start(sessions.as_ref(), &jwt, &User::new(&credentials.username), lifetimes).await
+        }.await;
+
+        match r {
+            Ok(tokens) => Ok(warp::reply::json(&tokens)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn sign_in<A: Accounts + SecondFactors, S: Sessions, L: Lockouts + Audit>(accounts: Arc<A>, sessions: Arc<S>, jwt: Arc<Jwt>, throttle: Arc<Throttle<L>>, lifetimes: Lifetimes, ip: Option<IpAddr>, credentials: Credentials) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let keys = lockout::keys(Some(&credentials.username), ip);
+            let user = throttle.attempt(&keys, async {
+                let account = verify_credentials(accounts.as_ref(), &credentials.username, &credentials.password).await?;
+                totp::sign_in(accounts.as_ref(), &account, credentials.code.as_deref()).await
+            }).await?;
+            session::start(sessions.as_ref(), &jwt, &user, lifetimes).await
+        }.await;
+
+        match r {
+            Ok(tokens) => Ok(warp::reply::json(&tokens)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn sign_out<S: Sessions>(sessions: Arc<S>, body: Refresh) -> Result<impl Reply, Rejection> {
+        match session::sign_out(sessions.as_ref(), &body.refresh).await {
+            Ok(()) => Ok(warp::reply()),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn refresh<A: Accounts, S: Sessions>(accounts: Arc<A>, sessions: Arc<S>, jwt: Arc<Jwt>, lifetimes: Lifetimes, body: Refresh) -> Result<impl Reply, Rejection> {
+        match session::refresh(accounts.as_ref(), sessions.as_ref(), &jwt, &body.refresh, lifetimes).await {
+            Ok(tokens) => Ok(warp::reply::json(&tokens)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules.
+/// Exposes in-memory accounts to other test modules.
+///
+/// Test plan:
+/// 1. Sign up issues tokens, duplicate and invalid sign ups are rejected
+/// 2. Sign in checks credentials, and locks out after repeated failures
+/// 3. Refresh tokens rotate, and reuse revokes the session
+/// 4. Sign out revokes the session
+/// 5. Bad bodies reply with error
+/// 6. Sign up and sign in are not found without passwords
+#[cfg(test)]
+pub mod tests {
+    use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+
+    use warp::http::StatusCode;
+
+    use crate::{auth::{jwt::{self, tests::new_jwt}, lockout::tests::new_throttle, session::{self, tests::MemorySessions}, token, totp::Totp}, error::Error};
+
+    use super::*;
+
+    /// MemoryAccounts also keeps second factors, as they belong to accounts
+    #[derive(Default)]
+    pub struct MemoryAccounts {
+        users: Mutex<HashMap<String, Account>>,
+        subjects: Mutex<HashMap<String, String>>,
+        totps: Mutex<HashMap<String, Totp>>,
+        recovery_codes: Mutex<HashMap<String, Vec<Vec<u8>>>>,
+    }
+
+    impl Accounts for MemoryAccounts {
+        async fn create_user(&self, username: &str, password_hash: &str) -> Result<(), Error> {
+            let mut users = self.users.lock().unwrap();
+            if users.contains_key(username) {
+                return Err(Error::Conflict(format!("user already exists: {}", username)));
+            }
+            users.insert(username.to_string(), Account {
+                username: username.to_string(),
+                password_hash: Some(password_hash.to_string()),
+                roles: vec![],
+                totp_enabled: false,
+            });
+            Ok(())
+        }
+
+        async fn read_user(&self, username: &str) -> Result<Account, Error> {
+            match self.users.lock().unwrap().get(username) {
+                Some(a) => Ok(Account {
+                    totp_enabled: self.totps.lock().unwrap().get(username).is_some_and(|t| t.enabled),
+                    ..a.clone()
+                }),
+                None => Err(Error::NotFound(username.to_string())),
+            }
+        }
+
+        async fn upsert_external_user(&self, subject: &str, username: &str, roles: &[Role]) -> Result<Account, Error> {
+            let mut users = self.users.lock().unwrap();
+            let mut subjects = self.subjects.lock().unwrap();
+            let username = match subjects.get(subject) {
+                Some(u) => u.clone(),
+                None => {
+                    if users.contains_key(username) {
+                        return Err(Error::Conflict(format!("user already exists: {}", username)));
+                    }
+                    subjects.insert(subject.to_string(), username.to_string());
+                    username.to_string()
+                },
+            };
+            let account = Account {
+                username: username.clone(),
+                password_hash: None,
+                roles: roles.to_vec(),
+                totp_enabled: self.totps.lock().unwrap().get(&username).is_some_and(|t| t.enabled),
+            };
+            users.insert(username, account.clone());
+            Ok(account)
+        }
+
+        async fn update_password(&self, username: &str, password_hash: &str) -> Result<(), Error> {
+            match self.users.lock().unwrap().get_mut(username) {
+                Some(a) => {
+                    a.password_hash = Some(password_hash.to_string());
+                    Ok(())
+                },
+                None => Err(Error::NotFound(username.to_string())),
+            }
+        }
+    }
+
+    impl SecondFactors for MemoryAccounts {
+        async fn create_totp(&self, username: &str, secret: &[u8]) -> Result<(), Error> {
+            let known = self.users.lock().unwrap().contains_key(username);
+            let mut totps = self.totps.lock().unwrap();
+            if !known || totps.get(username).is_some_and(|t| t.enabled) {
+                return Err(Error::NotFound(username.to_string()));
+            }
+            totps.insert(username.to_string(), Totp { secret: secret.to_vec(), enabled: false, last_step: None });
+            Ok(())
+        }
+
+        async fn read_totp(&self, username: &str) -> Result<Totp, Error> {
+            match self.totps.lock().unwrap().get(username) {
+                Some(t) => Ok(t.clone()),
+                None => Err(Error::NotFound(username.to_string())),
+            }
+        }
+
+        async fn enable_totp(&self, username: &str, step: i64) -> Result<(), Error> {
+            match self.totps.lock().unwrap().get_mut(username) {
+                Some(t) if !t.enabled => {
+                    t.enabled = true;
+                    t.last_step = Some(step);
+                    Ok(())
+                },
+                _ => Err(Error::NotFound(username.to_string())),
+            }
+        }
+
+        async fn use_totp_step(&self, username: &str, step: i64) -> Result<(), Error> {
+            match self.totps.lock().unwrap().get_mut(username) {
+                Some(t) if t.enabled && t.last_step.is_none_or(|last| step > last) => {
+                    t.last_step = Some(step);
+                    Ok(())
+                },
+                _ => Err(Error::NotFound(format!("step {} of {}", step, username))),
+            }
+        }
+
+        async fn delete_totp(&self, username: &str) -> Result<(), Error> {
+            self.recovery_codes.lock().unwrap().remove(username);
+            match self.totps.lock().unwrap().remove(username) {
+                Some(_) => Ok(()),
+                None => Err(Error::NotFound(username.to_string())),
+            }
+        }
+
+        async fn replace_recovery_codes(&self, username: &str, hashes: &[Vec<u8>]) -> Result<(), Error> {
+            self.recovery_codes.lock().unwrap().insert(username.to_string(), hashes.to_vec());
+            Ok(())
+        }
+
+        async fn use_recovery_code(&self, username: &str, hash: &[u8]) -> Result<(), Error> {
+            let mut codes = self.recovery_codes.lock().unwrap();
+            let codes = codes.entry(username.to_string()).or_default();
+            match codes.iter().position(|c| c == hash) {
+                Some(i) => {
+                    codes.remove(i);
+                    Ok(())
+                },
+                None => Err(Error::NotFound("recovery code".into())),
+            }
+        }
+    }
+
+    pub fn lifetimes() -> Lifetimes {
+        Lifetimes {
+            access: Duration::from_secs(60),
+            refresh: Duration::from_secs(600),
+        }
+    }
+
+    async fn new_filter(accounts: Arc<MemoryAccounts>) -> (impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static, Arc<MemorySessions>, Arc<Jwt>) {
+        let sessions = Arc::new(MemorySessions::default());
+        let jwt = Arc::new(new_jwt(jwt::Algorithm::EdDSA).await);
+        let (throttle, _) = new_throttle();
+        (filter(accounts, sessions.clone(), jwt.clone(), throttle, lifetimes(), true), sessions, jwt)
+    }
+
+    async fn post(f: &(impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static), path: &str, body: serde_json::Value) -> warp::http::Response<bytes::Bytes> {
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .json(&body)
+            .reply(f)
+            .await
+    }
+
+    fn tokens(res: &warp::http::Response<bytes::Bytes>) -> Tokens {
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sign_up() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let (f, sessions, jwt) = new_filter(accounts.clone()).await;
+
+        let res = post(&f, "/sign-up", serde_json::json!({"username": "bob", "password": "password1"})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let t = tokens(&res);
+        let claims = jwt.verify(&t.token).unwrap();
+        assert_eq!(claims.sub, "bob");
+        let refresh = sessions.read_token(&token::hash(&t.refresh)).await.unwrap();
+        assert_eq!(refresh.username, "bob");
+        assert_eq!(refresh.session, claims.sid);
+
+        // duplicate
+        let res = post(&f, "/sign-up", serde_json::json!({"username": "bob", "password": "password2"})).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // invalid
+        // including lookalikes of bob, with a Cyrillic о and a fullwidth ｂ
+        for (username, password) in [("", "password1"), ("bob smith", "password1"), ("alice", "short"), ("b\u{43e}b", "password1"), ("\u{ff42}ob", "password1")] {
+            let res = post(&f, "/sign-up", serde_json::json!({"username": username, "password": password})).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}:{}", username, password);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sign_in() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let (f, _, _) = new_filter(accounts.clone()).await;
+        post(&f, "/sign-up", serde_json::json!({"username": "bob", "password": "password1"})).await;
+
+        let res = post(&f, "/sign-in", serde_json::json!({"username": "bob", "password": "password1"})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        tokens(&res);
+
+        for (username, password) in [("bob", "password2"), ("alice", "password1")] {
+            let res = post(&f, "/sign-in", serde_json::json!({"username": username, "password": password})).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}:{}", username, password);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_lockout() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let (f, _, _) = new_filter(accounts.clone()).await;
+        post(&f, "/sign-up", serde_json::json!({"username": "bob", "password": "password1"})).await;
+
+        for _ in 0..5 {
+            let res = post(&f, "/sign-in", serde_json::json!({"username": "bob", "password": "password2"})).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // even the right password is refused while locked out
+        let res = post(&f, "/sign-in", serde_json::json!({"username": "bob", "password": "password1"})).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "30");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_reuse_revokes_session() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let (f, sessions, jwt) = new_filter(accounts.clone()).await;
+        let t = tokens(&post(&f, "/sign-up", serde_json::json!({"username": "bob", "password": "password1"})).await);
+        let other = tokens(&post(&f, "/sign-in", serde_json::json!({"username": "bob", "password": "password1"})).await);
+
+        // refresh rotates within the session
+        let res = post(&f, "/refresh", serde_json::json!({"refresh": t.refresh})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let next = tokens(&res);
+        assert_ne!(next.refresh, t.refresh);
+        assert_eq!(jwt.verify(&next.token).unwrap().sid, jwt.verify(&t.token).unwrap().sid);
+
+        // access tokens cannot be used to refresh
+        let res = post(&f, "/refresh", serde_json::json!({"refresh": next.token})).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // reuse revokes the whole session, including its newest tokens
+        let res = post(&f, "/refresh", serde_json::json!({"refresh": t.refresh})).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = post(&f, "/refresh", serde_json::json!({"refresh": next.refresh})).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let r = session::authorize(sessions.as_ref(), &jwt, &next.token).await;
+        assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+
+        // other sessions are unaffected
+        let res = post(&f, "/refresh", serde_json::json!({"refresh": other.refresh})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_sign_out_revokes_session() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let (f, sessions, jwt) = new_filter(accounts.clone()).await;
+        let t = tokens(&post(&f, "/sign-up", serde_json::json!({"username": "bob", "password": "password1"})).await);
+        assert!(session::authorize(sessions.as_ref(), &jwt, &t.token).await.is_ok());
+
+        let res = post(&f, "/sign-out", serde_json::json!({"refresh": t.refresh})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let r = sessions.read_token(&token::hash(&t.refresh)).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let res = post(&f, "/refresh", serde_json::json!({"refresh": t.refresh})).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let r = session::authorize(sessions.as_ref(), &jwt, &t.token).await;
+        assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+
+        // signing out twice succeeds
+        let res = post(&f, "/sign-out", serde_json::json!({"refresh": t.refresh})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_passwords_disabled() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        accounts.create_user("bob", &password::hash("password1").await.unwrap()).await.unwrap();
+        let sessions = Arc::new(MemorySessions::default());
+        let jwt = Arc::new(new_jwt(jwt::Algorithm::EdDSA).await);
+        let f = filter(accounts, sessions.clone(), jwt.clone(), new_throttle().0, lifetimes(), false);
+
+        for path in ["/sign-up", "/sign-in"] {
+            let res = post(&f, path, serde_json::json!({"username": "bob", "password": "password1"})).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        // sessions from other sign ins still refresh
+        let t = session::start(sessions.as_ref(), &jwt, &User::new("bob"), lifetimes()).await.unwrap();
+        let res = post(&f, "/refresh", serde_json::json!({"refresh": t.refresh})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_bad_bodies_reply_with_error() {
+        let (f, _, _) = new_filter(Arc::new(MemoryAccounts::default())).await;
+        for path in ["/sign-up", "/sign-in", "/sign-out", "/refresh"] {
+            let res = warp::test::request()
+                .method("POST")
+                .path(path)
+                .body("not json")
+                .reply(&f)
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "path: {}", path);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+// audit records security events, such as lockouts and unlocks, so that
+// administrators can review them later.
+
+use std::{future::Future, time::SystemTime};
+
+use crate::error::Error;
+
+pub trait Audit {
+    fn record_audit(&self, entry: &Entry) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub at: SystemTime,
+    /// User who acted, None for the wiki itself
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: String,
+    pub detail: String,
+}
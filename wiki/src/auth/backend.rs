@@ -0,0 +1,129 @@
+// backend selects how API requests are authorized. The mock trusts any
+// credential, so it is refused unless the wiki is built with the
+// insecure-dev-auth feature or started with --insecure-dev-auth. Backends are
+// checked against the flags they require before the wiki starts anything.
+
+use crate::error::Error;
+
+use super::{
+    account::Accounts,
+    database::Database,
+    mock_user::Mock,
+    personal_token::PersonalTokens,
+    session::Sessions,
+    static_file::StaticFile,
+    user::{User, Users},
+};
+
+pub const INSECURE_DEV_AUTH: bool = cfg!(feature = "insecure-dev-auth");
+
+/// Flags that backends require
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Flags<'a> {
+    pub insecure_dev_auth: bool,
+    pub users_file: Option<&'a str>,
+    pub oidc_issuer: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Mock,
+    /// Password accounts and sessions stored by persistence.
+    Database,
+    /// Like database, but accounts only sign in through the OpenID Connect
+    /// provider.
+    Oidc,
+    StaticFile,
+}
+
+impl Kind {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "mock" => Ok(Kind::Mock),
+            "database" => Ok(Kind::Database),
+            "oidc" => Ok(Kind::Oidc),
+            "static-file" => Ok(Kind::StaticFile),
+            b => Err(Error::BadRequest(format!("unknown auth backend: {}", b))),
+        }
+    }
+
+    /// Checks that the backend has the flags it requires, and that the mock
+    /// is only used where forged credentials are acceptable.
+    pub fn allow(self, flags: Flags) -> Result<Self, Error> {
+        match self {
+            Kind::Mock if !(INSECURE_DEV_AUTH || flags.insecure_dev_auth) => Err(Error::BadRequest(
+                "mock auth accepts forged credentials, it requires --insecure-dev-auth".into()
+            )),
+            Kind::StaticFile if flags.users_file.is_none() => Err(Error::BadRequest(
+                "static-file auth requires --auth-users-file".into()
+            )),
+            Kind::Oidc if flags.oidc_issuer.is_none() => Err(Error::BadRequest(
+                "oidc auth requires --oidc-issuer".into()
+            )),
+            _ => Ok(self),
+        }
+    }
+
+    /// Whether accounts sign up and sign in with passwords.
+    pub fn passwords(self) -> bool {
+        self == Kind::Database
+    }
+}
+
+pub enum Backend<A, T, S> {
+    Mock(Mock),
+    Database(Database<A, T, S>),
+    StaticFile(StaticFile),
+}
+
+impl<A, T, S> Users for Backend<A, T, S>
+where
+    A: Accounts + Send + Sync,
+    T: PersonalTokens + Send + Sync,
+    S: Sessions + Send + Sync,
+{
+    async fn authorize(&self, header: String) -> Result<User, Error> {
+        match self {
+            Backend::Mock(mock) => mock.authorize(header).await,
+            Backend::Database(database) => database.authorize(header).await,
+            Backend::StaticFile(file) => file.authorize(header).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for (s, kind) in [
+            ("mock", Kind::Mock),
+            ("database", Kind::Database),
+            ("oidc", Kind::Oidc),
+            ("static-file", Kind::StaticFile),
+        ] {
+            assert_eq!(Kind::parse(s).unwrap(), kind);
+        }
+        assert!(Kind::parse("ldap").is_err());
+    }
+
+    #[test]
+    fn test_mock_requires_insecure_dev_auth() {
+        let all = Flags { insecure_dev_auth: false, users_file: Some("users.yaml"), oidc_issuer: Some("https://id.example.com") };
+        assert!(Kind::Mock.allow(Flags { insecure_dev_auth: true, ..Flags::default() }).is_ok());
+        assert_eq!(Kind::Mock.allow(all).is_ok(), INSECURE_DEV_AUTH);
+        for kind in [Kind::Database, Kind::Oidc, Kind::StaticFile] {
+            assert!(kind.allow(all).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_backends_require_flags() {
+        assert!(Kind::Database.allow(Flags::default()).is_ok());
+        for kind in [Kind::StaticFile, Kind::Oidc] {
+            let r = kind.allow(Flags { insecure_dev_auth: true, ..Flags::default() });
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+// database authorizes users against the accounts stored by persistence. Basic
+// credentials are checked against password hashes, personal access tokens
+// against their hashes, and other bearer tokens are checked by jwt and must
+// belong to a live session. Users who enrolled a second factor cannot use
+// basic credentials, which would bypass it.
+
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+use crate::error::Error;
+
+use super::{account::{verify_credentials, Accounts}, jwt::Jwt, personal_token::{self, PersonalTokens}, session::{self, Sessions}, totp::Policy, user::{User, Users}};
+
+pub struct Database<A, T, S> {
+    accounts: Arc<A>,
+    tokens: Arc<T>,
+    sessions: Arc<S>,
+    jwt: Arc<Jwt>,
+    policy: Policy,
+}
+
+impl<A, T, S> Database<A, T, S> {
+    pub fn new(accounts: Arc<A>, tokens: Arc<T>, sessions: Arc<S>, jwt: Arc<Jwt>) -> Self {
+        Database { accounts, tokens, sessions, jwt, policy: Policy::default() }
+    }
+
+    /// Applies policy to sessions and basic credentials. Personal access
+    /// tokens are exempt, as minting them needs the admin scope, which the
+    /// policy withholds until a second factor.
+    pub fn with_policy(self, policy: Policy) -> Self {
+        Database { policy, ..self }
+    }
+}
+
+impl<A, T, S> Users for Database<A, T, S>
+where
+    A: Accounts + Send + Sync,
+    T: PersonalTokens + Send + Sync,
+    S: Sessions + Send + Sync,
+{
+    async fn authorize(&self, header: String) -> Result<User, Error> {
+        match header.split_once(' ') {
+            Some(("Basic", credentials)) => self.basic_auth(credentials).await.map(|u| self.policy.apply(u)),
+            Some(("Bearer", token)) if token.starts_with(personal_token::PREFIX) =>
+                personal_token::authorize(self.accounts.as_ref(), self.tokens.as_ref(), token).await,
+            Some(("Bearer", token)) => session::authorize(self.sessions.as_ref(), &self.jwt, token).await
+                .map(|u| self.policy.apply(u)),
+            _ => Err(Error::Unauthorized("bad auth type".into())),
+        }
+    }
+}
+
+impl<A: Accounts + Send + Sync, T, S> Database<A, T, S> {
+    async fn basic_auth(&self, credentials: &str) -> Result<User, Error> {
+        let decoded = STANDARD.decode(credentials)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?;
+        let decoded = String::from_utf8(decoded)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?;
+        let (username, password) = decoded.split_once(':')
+            .ok_or(Error::Unauthorized("bad basic auth header".into()))?;
+
+        let account = verify_credentials(self.accounts.as_ref(), username, password).await?;
+        if account.totp_enabled {
+            return Err(Error::Unauthorized(format!("{} has a second factor, sign in instead", username)));
+        }
+        Ok(account.user())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::auth::{
+        account::tests::MemoryAccounts,
+        jwt::{tests::new_jwt, Algorithm},
+        password,
+        personal_token::{tests::MemoryPersonalTokens, PersonalToken, PREFIX},
+        session::{tests::MemorySessions, Session},
+        token,
+        user::Scope,
+    };
+
+    use super::*;
+
+    async fn new_database() -> (Database<MemoryAccounts, MemoryPersonalTokens, MemorySessions>, Arc<Jwt>) {
+        let accounts = Arc::new(MemoryAccounts::default());
+        accounts.create_user("bob", &password::hash("password1").await.unwrap()).await.unwrap();
+        let jwt = Arc::new(new_jwt(Algorithm::EdDSA).await);
+        let tokens = Arc::new(MemoryPersonalTokens::default());
+        let sessions = Arc::new(MemorySessions::default());
+        (Database::new(accounts, tokens, sessions, jwt.clone()), jwt)
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let (db, _) = new_database().await;
+
+        let r = db.authorize(format!("Basic {}", STANDARD.encode("bob:password1"))).await;
+        assert_eq!(r.unwrap().name, "bob");
+
+        for header in [
+            format!("Basic {}", STANDARD.encode("bob:password2")),
+            format!("Basic {}", STANDARD.encode("alice:password1")),
+            format!("Basic {}", STANDARD.encode("bob")),
+            "Basic bob:password1".to_string(),
+            "Digest bob".to_string(),
+            "".to_string(),
+        ] {
+            let r = db.authorize(header.clone()).await;
+            assert!(matches!(r, Err(Error::Unauthorized(_))), "{}: {:?}", header, r);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_auth() {
+        let (db, jwt) = new_database().await;
+        let now = SystemTime::now();
+        let sid = db.sessions.create_session(&Session {
+            id: 0,
+            username: "bob".into(),
+            created_at: now,
+            expires_at: now + Duration::from_secs(60),
+            second_factor_at: None,
+        }).await.unwrap();
+
+        let token = jwt.sign(&User::new("bob"), sid, Duration::from_secs(60)).unwrap();
+        let r = db.authorize(format!("Bearer {}", token)).await;
+        assert_eq!(r.unwrap().name, "bob");
+
+        let other = new_jwt(Algorithm::EdDSA).await;
+        for token in [
+            other.sign(&User::new("bob"), sid, Duration::from_secs(60)).unwrap(),
+            jwt.sign(&User::new("bob"), sid + 1, Duration::from_secs(60)).unwrap(),
+            "not a token".to_string(),
+        ] {
+            let r = db.authorize(format!("Bearer {}", token)).await;
+            assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_personal_token_auth() {
+        let (db, _) = new_database().await;
+        let now = SystemTime::now();
+        let new_token = |secret: &str, expires_at| PersonalToken {
+            id: 0,
+            hash: token::hash(secret),
+            username: "bob".into(),
+            name: "bot".into(),
+            scopes: vec![Scope::Read],
+            created_at: now,
+            expires_at,
+            last_used_at: None,
+        };
+        let live = format!("{}live", PREFIX);
+        let expired = format!("{}expired", PREFIX);
+        db.tokens.create_personal_token(&new_token(&live, None)).await.unwrap();
+        db.tokens.create_personal_token(&new_token(&expired, Some(now - Duration::from_secs(1)))).await.unwrap();
+
+        let user = db.authorize(format!("Bearer {}", live)).await.unwrap();
+        assert_eq!(user.name, "bob");
+        assert_eq!(user.scopes, vec![Scope::Read]);
+        let used = db.tokens.list_personal_tokens("bob").await.unwrap();
+        assert!(used.iter().any(|t| t.hash == token::hash(&live) && t.last_used_at.is_some()));
+
+        for token in [expired, format!("{}unknown", PREFIX)] {
+            let r = db.authorize(format!("Bearer {}", token)).await;
+            assert!(matches!(r, Err(Error::Unauthorized(_))), "{}: {:?}", token, r);
+        }
+    }
+}
@@ -0,0 +1,637 @@
+// email lets users prove they own an email address, and reset a forgotten
+// password through it. Tokens are mailed to the address, are single use,
+// expire, and are only stored as hashes.
+
+use std::{future::Future, sync::Arc, time::{Duration, SystemTime}};
+
+use serde::Deserialize;
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::{
+    auth::{
+        account::{validate_password, Accounts},
+        mail::{self, Mailer, Template},
+        password,
+        session::Sessions,
+        token,
+        user::Users,
+    },
+    error::Error,
+};
+
+const VERIFY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const RESET_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+const VERIFY_TEMPLATE: Template = Template {
+    subject: "Verify your email address",
+    body: "Hello {username},\n\
+        \n\
+        Open this link to verify your email address for the wiki:\n\
+        \n\
+        {link}\n\
+        \n\
+        The link expires in {hours} hours. If you did not ask for this, ignore this message.\n",
+};
+
+const RESET_TEMPLATE: Template = Template {
+    subject: "Reset your password",
+    body: "Hello {username},\n\
+        \n\
+        Use this code to reset your password for the wiki:\n\
+        \n\
+        {token}\n\
+        \n\
+        The code expires in {minutes} minutes. If you did not ask for this, ignore this message, your password is unchanged.\n",
+};
+
+pub trait Emails {
+    /// Sets the unverified email address of username
+    fn set_email(&self, username: &str, email: &str) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Marks email verified, if it is still the address of username. Only
+    /// one user may verify an address.
+    fn verify_email(&self, username: &str, email: &str) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Reads the user whose verified address is email, ignoring case
+    fn read_verified_username(&self, email: &str) -> impl Future<Output = Result<String, Error>> + Send;
+    /// Stores token, and deletes the user's other tokens of the same purpose
+    fn create_email_token(&self, token: &EmailToken) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Deletes and returns the token with hash, so that it is used once
+    fn take_email_token(&self, hash: &[u8]) -> impl Future<Output = Result<EmailToken, Error>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Verify,
+    Reset,
+}
+
+impl Purpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Purpose::Verify => "verify",
+            Purpose::Reset => "reset",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "verify" => Ok(Purpose::Verify),
+            "reset" => Ok(Purpose::Reset),
+            p => Err(Error::BadRequest(format!("unknown purpose: {}", p))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EmailToken {
+    pub hash: Vec<u8>,
+    pub username: String,
+    pub purpose: Purpose,
+    /// Address the token was mailed to
+    pub email: String,
+    pub expires_at: SystemTime,
+}
+
+/// Mail sends messages with links back to the wiki at public_url.
+pub struct Mail<M> {
+    pub mailer: M,
+    pub public_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailAddress {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Verification {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Reset {
+    pub token: String,
+    pub password: String,
+}
+
+/// Sets the address of username, and mails it a verification link.
+pub async fn request_verification<E: Emails, M: Mailer>(emails: &E, mail: &Mail<M>, username: &str, email: &str) -> Result<(), Error> {
+    mail::parse_address(email)?;
+    emails.set_email(username, email).await?;
+
+    let secret = token::generate();
+    emails.create_email_token(&EmailToken {
+        hash: token::hash(&secret),
+        username: username.to_string(),
+        purpose: Purpose::Verify,
+        email: email.to_string(),
+        expires_at: SystemTime::now() + VERIFY_LIFETIME,
+    }).await?;
+
+    let link = format!("{}/auth/v1/email/verify?token={}", mail.public_url.trim_end_matches('/'), secret);
+    let hours = (VERIFY_LIFETIME.as_secs() / 3600).to_string();
+    mail.mailer.send(email, &VERIFY_TEMPLATE.render(&[
+        ("username", username),
+        ("link", &link),
+        ("hours", &hours),
+    ])).await
+}
+
+pub async fn verify<E: Emails>(emails: &E, secret: &str) -> Result<(), Error> {
+    let t = take(emails, secret, Purpose::Verify).await?;
+    emails.verify_email(&t.username, &t.email).await
+}
+
+/// Mails a reset code to the user with the verified address email. Unknown
+/// addresses succeed, so that they cannot be told apart.
+pub async fn request_reset<A: Accounts, E: Emails, M: Mailer>(accounts: &A, emails: &E, mail: &Mail<M>, email: &str) -> Result<(), Error> {
+    let username = match emails.read_verified_username(email).await {
+        Ok(username) => username,
+        Err(Error::NotFound(_)) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    // users of an external identity provider reset their password there
+    if accounts.read_user(&username).await?.password_hash.is_none() {
+        return Ok(());
+    }
+
+    let secret = token::generate();
+    emails.create_email_token(&EmailToken {
+        hash: token::hash(&secret),
+        username: username.clone(),
+        purpose: Purpose::Reset,
+        email: email.to_string(),
+        expires_at: SystemTime::now() + RESET_LIFETIME,
+    }).await?;
+
+    let minutes = (RESET_LIFETIME.as_secs() / 60).to_string();
+    mail.mailer.send(email, &RESET_TEMPLATE.render(&[
+        ("username", &username),
+        ("token", &secret),
+        ("minutes", &minutes),
+    ])).await
+}
+
+/// Sets a new password, and signs the user out everywhere.
+pub async fn reset<A: Accounts, E: Emails, S: Sessions>(accounts: &A, emails: &E, sessions: &S, reset: &Reset) -> Result<(), Error> {
+    validate_password(&reset.password)?;
+    let t = take(emails, &reset.token, Purpose::Reset).await?;
+
+    let hash = password::hash(&reset.password).await?;
+    accounts.update_password(&t.username, &hash).await?;
+    sessions.revoke_sessions(&t.username).await?;
+    Ok(())
+}
+
+async fn take<E: Emails>(emails: &E, secret: &str, purpose: Purpose) -> Result<EmailToken, Error> {
+    let t = match emails.take_email_token(&token::hash(secret)).await {
+        Ok(t) => t,
+        Err(Error::NotFound(_)) => return Err(Error::Unauthorized("unknown email token".into())),
+        Err(err) => return Err(err),
+    };
+    if t.purpose != purpose {
+        return Err(Error::Unauthorized(format!("email token is not for {}", purpose.as_str())));
+    }
+    if SystemTime::now() > t.expires_at {
+        return Err(Error::Unauthorized("email token is expired".into()));
+    }
+    Ok(t)
+}
+
+/// Routes are not found without mail, and resets are not found without
+/// passwords.
+#[allow(clippy::too_many_arguments)]
+pub fn filter<A, E, S, M, U>(
+    accounts: Arc<A>,
+    emails: Arc<E>,
+    sessions: Arc<S>,
+    mail: Option<Arc<Mail<M>>>,
+    users: Arc<U>,
+    passwords: bool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    A: Accounts + Send + Sync + 'static,
+    E: Emails + Send + Sync + 'static,
+    S: Sessions + Send + Sync + 'static,
+    M: Mailer + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+{
+    let reset_mail = mail.clone().filter(|_| passwords);
+    warp::path!("email")
+        .and(warp::post())
+        .and(endpoints::request_verification(emails.clone(), mail.clone(), users))
+        .or(warp::path!("email" / "verify").and(warp::get()).and(endpoints::verify(emails.clone(), mail)))
+        .or(warp::path!("password" / "reset").and(warp::post()).and(endpoints::request_reset(accounts.clone(), emails.clone(), reset_mail.clone())))
+        .or(warp::path!("password" / "reset" / "confirm").and(warp::post()).and(endpoints::reset(accounts, emails, sessions, reset_mail)))
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reject::Rejection, reply::Reply, Filter};
+
+    use crate::{auth::{account::Accounts, mail::Mailer, session::Sessions, user::{with_authorization, Scope, Users}}, error};
+
+    use super::{handlers, Emails, Mail, Verification};
+
+    pub fn request_verification<E, M, U>(emails: Arc<E>, mail: Option<Arc<Mail<M>>>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        E: Emails + Send + Sync + 'static,
+        M: Mailer + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_mail(mail)
+            .and(with_emails(emails))
+            .and(with_authorization(users, Scope::Admin))
+            .and(warp::body::json())
+            .and_then(handlers::request_verification)
+            .recover(error::recover)
+    }
+
+    pub fn verify<E, M>(emails: Arc<E>, mail: Option<Arc<Mail<M>>>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        E: Emails + Send + Sync + 'static,
+        M: Mailer + Send + Sync + 'static,
+    {
+        with_mail(mail)
+            .and(with_emails(emails))
+            .and(warp::query::<Verification>())
+            .and_then(handlers::verify)
+            .recover(error::recover)
+    }
+
+    pub fn request_reset<A, E, M>(accounts: Arc<A>, emails: Arc<E>, mail: Option<Arc<Mail<M>>>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        A: Accounts + Send + Sync + 'static,
+        E: Emails + Send + Sync + 'static,
+        M: Mailer + Send + Sync + 'static,
+    {
+        with_mail(mail)
+            .and(warp::any().map(move || accounts.clone()))
+            .and(with_emails(emails))
+            .and(warp::body::json())
+            .and_then(handlers::request_reset)
+            .recover(error::recover)
+    }
+
+    pub fn reset<A, E, S, M>(accounts: Arc<A>, emails: Arc<E>, sessions: Arc<S>, mail: Option<Arc<Mail<M>>>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        A: Accounts + Send + Sync + 'static,
+        E: Emails + Send + Sync + 'static,
+        S: Sessions + Send + Sync + 'static,
+        M: Mailer + Send + Sync + 'static,
+    {
+        with_mail(mail)
+            .and(warp::any().map(move || accounts.clone()))
+            .and(with_emails(emails))
+            .and(warp::any().map(move || sessions.clone()))
+            .and(warp::body::json())
+            .and_then(handlers::reset)
+            .recover(error::recover)
+    }
+
+    fn with_mail<M>(mail: Option<Arc<Mail<M>>>) -> impl Filter<Extract = (Arc<Mail<M>>,), Error = Rejection> + Clone
+    where
+        M: Mailer + Send + Sync + 'static
+    {
+        warp::any().and_then(move || {
+            let mail = mail.clone();
+            async move { mail.ok_or_else(warp::reject::not_found) }
+        })
+    }
+
+    fn with_emails<E>(emails: Arc<E>) -> impl Filter<Extract = (Arc<E>,), Error = Infallible> + Clone
+    where
+        E: Emails + Send + Sync + 'static
+    {
+        warp::any().map(move || emails.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::auth::{account::Accounts, mail::Mailer, session::Sessions, user::User};
+
+    use super::{EmailAddress, Emails, Mail, Reset, Verification};
+
+    pub async fn request_verification<E: Emails, M: Mailer>(mail: Arc<Mail<M>>, emails: Arc<E>, user: User, address: EmailAddress) -> Result<impl Reply, Rejection> {
+        match super::request_verification(emails.as_ref(), mail.as_ref(), &user.name, &address.email).await {
+            Ok(()) => Ok(StatusCode::ACCEPTED),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn verify<E: Emails, M>(_mail: Arc<Mail<M>>, emails: Arc<E>, verification: Verification) -> Result<impl Reply, Rejection> {
+        match super::verify(emails.as_ref(), &verification.token).await {
+            Ok(()) => Ok(warp::reply::with_header(
+                "Your email address is verified.",
+                "Content-Type",
+                "text/plain")),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    /// Replies before looking the address up, so that the time taken does
+    /// not tell whether it is known.
+    pub async fn request_reset<A, E, M>(mail: Arc<Mail<M>>, accounts: Arc<A>, emails: Arc<E>, address: EmailAddress) -> Result<impl Reply, Rejection>
+    where
+        A: Accounts + Send + Sync + 'static,
+        E: Emails + Send + Sync + 'static,
+        M: Mailer + Send + Sync + 'static,
+    {
+        tokio::spawn(async move {
+            let r = super::request_reset(accounts.as_ref(), emails.as_ref(), mail.as_ref(), &address.email).await;
+            if let Err(err) = r {
+                log::error!(target: "wiki::auth", "password reset failed: {:?}", err);
+            }
+        });
+        Ok(StatusCode::ACCEPTED)
+    }
+
+    pub async fn reset<A: Accounts, E: Emails, S: Sessions, M>(_mail: Arc<Mail<M>>, accounts: Arc<A>, emails: Arc<E>, sessions: Arc<S>, reset: Reset) -> Result<impl Reply, Rejection> {
+        match super::reset(accounts.as_ref(), emails.as_ref(), sessions.as_ref(), &reset).await {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules, end-to-end against a
+/// local SMTP capture server.
+/// Exposes in-memory emails to other test modules.
+///
+/// Test plan:
+/// 1. Verification links verify the address once
+/// 2. Resets set the password and revoke sessions, tokens are single use
+/// 3. Unknown and unverified addresses are not mailed
+/// 4. Expired tokens and tokens of another purpose are rejected
+/// 5. Routes are not found without mail
+#[cfg(test)]
+pub mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use warp::http::StatusCode;
+
+    use crate::auth::{
+        account::{tests::{lifetimes, MemoryAccounts}, verify_credentials},
+        database::Database,
+        jwt::{tests::new_jwt, Algorithm, Jwt},
+        mail::{tests::Capture, Smtp},
+        personal_token::tests::MemoryPersonalTokens,
+        session::{self, tests::MemorySessions},
+        user::User,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct MemoryEmails {
+        // username to address and whether it is verified
+        addresses: Mutex<HashMap<String, (String, bool)>>,
+        tokens: Mutex<Vec<EmailToken>>,
+    }
+
+    impl Emails for MemoryEmails {
+        async fn set_email(&self, username: &str, email: &str) -> Result<(), Error> {
+            self.addresses.lock().unwrap().insert(username.into(), (email.into(), false));
+            Ok(())
+        }
+
+        async fn verify_email(&self, username: &str, email: &str) -> Result<(), Error> {
+            let mut addresses = self.addresses.lock().unwrap();
+            if addresses.iter().any(|(u, (e, v))| u != username && *v && e.eq_ignore_ascii_case(email)) {
+                return Err(Error::Conflict(format!("email already in use: {}", email)));
+            }
+            match addresses.get_mut(username) {
+                Some((e, verified)) if e == email => {
+                    *verified = true;
+                    Ok(())
+                },
+                _ => Err(Error::NotFound(format!("email of {}", username))),
+            }
+        }
+
+        async fn read_verified_username(&self, email: &str) -> Result<String, Error> {
+            self.addresses.lock().unwrap().iter()
+                .find(|(_, (e, v))| *v && e.eq_ignore_ascii_case(email))
+                .map(|(u, _)| u.clone())
+                .ok_or(Error::NotFound(email.to_string()))
+        }
+
+        async fn create_email_token(&self, token: &EmailToken) -> Result<(), Error> {
+            let mut tokens = self.tokens.lock().unwrap();
+            tokens.retain(|t| t.username != token.username || t.purpose != token.purpose);
+            tokens.push(token.clone());
+            Ok(())
+        }
+
+        async fn take_email_token(&self, hash: &[u8]) -> Result<EmailToken, Error> {
+            let mut tokens = self.tokens.lock().unwrap();
+            match tokens.iter().position(|t| t.hash == hash) {
+                Some(i) => Ok(tokens.remove(i)),
+                None => Err(Error::NotFound("email token".into())),
+            }
+        }
+    }
+
+    struct Harness {
+        filter: warp::filters::BoxedFilter<(Box<dyn Reply>,)>,
+        accounts: Arc<MemoryAccounts>,
+        emails: Arc<MemoryEmails>,
+        sessions: Arc<MemorySessions>,
+        jwt: Arc<Jwt>,
+        capture: Capture,
+    }
+
+    impl Harness {
+        async fn new() -> Self {
+            let accounts = Arc::new(MemoryAccounts::default());
+            accounts.create_user("bob", &password::hash("password1").await.unwrap()).await.unwrap();
+            let emails = Arc::new(MemoryEmails::default());
+            let sessions = Arc::new(MemorySessions::default());
+            let jwt = Arc::new(new_jwt(Algorithm::EdDSA).await);
+            let users = Arc::new(Database::new(accounts.clone(), Arc::new(MemoryPersonalTokens::default()), sessions.clone(), jwt.clone()));
+            let capture = Capture::start().await;
+            let mail = Arc::new(Mail {
+                mailer: capture.smtp(),
+                public_url: "http://wiki.example.com/".into(),
+            });
+            Harness {
+                filter: filter(accounts.clone(), emails.clone(), sessions.clone(), Some(mail), users, true)
+                    .map(|r| Box::new(r) as Box<dyn Reply>)
+                    .boxed(),
+                accounts,
+                emails,
+                sessions,
+                jwt,
+                capture,
+            }
+        }
+
+        async fn request_verification(&self, email: &str) -> StatusCode {
+            warp::test::request()
+                .method("POST")
+                .path("/email")
+                .header("Authorization", format!("Basic {}", STANDARD.encode("bob:password1")))
+                .json(&serde_json::json!({"email": email}))
+                .reply(&self.filter)
+                .await
+                .status()
+        }
+
+        async fn verify(&self, secret: &str) -> StatusCode {
+            warp::test::request()
+                .path(&format!("/email/verify?token={}", secret))
+                .reply(&self.filter)
+                .await
+                .status()
+        }
+
+        async fn request_reset(&self, email: &str) -> StatusCode {
+            warp::test::request()
+                .method("POST")
+                .path("/password/reset")
+                .json(&serde_json::json!({"email": email}))
+                .reply(&self.filter)
+                .await
+                .status()
+        }
+
+        async fn reset(&self, secret: &str, password: &str) -> StatusCode {
+            warp::test::request()
+                .method("POST")
+                .path("/password/reset/confirm")
+                .json(&serde_json::json!({"token": secret, "password": password}))
+                .reply(&self.filter)
+                .await
+                .status()
+        }
+
+        // mailed waits for the nth message, since resets are mailed in the
+        // background, and returns its recipients and the secret in its body
+        async fn mailed(&self, n: usize) -> (Vec<String>, String) {
+            for _ in 0..100 {
+                if let Some(captured) = self.capture.messages.lock().unwrap().get(n) {
+                    let body = captured.body();
+                    let line = body.lines().skip_while(|l| !l.ends_with("wiki:")).nth(2).unwrap();
+                    return (captured.to.clone(), line.rsplit("token=").next().unwrap().to_string());
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("message {} was not mailed", n);
+        }
+
+        async fn verified(&self) {
+            assert_eq!(self.request_verification("bob@example.com").await, StatusCode::ACCEPTED);
+            let (_, secret) = self.mailed(0).await;
+            assert_eq!(self.verify(&secret).await, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verification() {
+        let h = Harness::new().await;
+
+        assert_eq!(h.request_verification("not an address").await, StatusCode::BAD_REQUEST);
+        assert_eq!(h.request_verification("bob@example.com").await, StatusCode::ACCEPTED);
+        let (to, secret) = h.mailed(0).await;
+        assert_eq!(to, vec!["bob@example.com".to_string()]);
+        let captured = h.capture.messages.lock().unwrap()[0].clone();
+        assert!(captured.data.contains("Subject: Verify your email address"), "{:?}", captured);
+        assert!(captured.body().contains(&format!("http://wiki.example.com/auth/v1/email/verify?token={}", secret)), "{:?}", captured);
+        assert!(h.emails.read_verified_username("bob@example.com").await.is_err());
+
+        assert_eq!(h.verify(&secret).await, StatusCode::OK);
+        assert_eq!(h.emails.read_verified_username("BOB@example.com").await.unwrap(), "bob");
+
+        // single use
+        assert_eq!(h.verify(&secret).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(h.verify("unknown").await, StatusCode::UNAUTHORIZED);
+
+        // changing the address unverifies it, and replaces earlier links
+        assert_eq!(h.request_verification("bob@example.org").await, StatusCode::ACCEPTED);
+        let (_, first) = h.mailed(1).await;
+        assert_eq!(h.request_verification("bob@example.net").await, StatusCode::ACCEPTED);
+        let (_, second) = h.mailed(2).await;
+        assert!(h.emails.read_verified_username("bob@example.com").await.is_err());
+        assert_eq!(h.verify(&first).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(h.verify(&second).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let h = Harness::new().await;
+        h.verified().await;
+        let tokens = session::start(h.sessions.as_ref(), &h.jwt, &User::new("bob"), lifetimes()).await.unwrap();
+
+        assert_eq!(h.request_reset("bob@example.com").await, StatusCode::ACCEPTED);
+        let (to, secret) = h.mailed(1).await;
+        assert_eq!(to, vec!["bob@example.com".to_string()]);
+
+        assert_eq!(h.reset(&secret, "short").await, StatusCode::BAD_REQUEST);
+        assert_eq!(h.reset(&secret, "password2").await, StatusCode::NO_CONTENT);
+        assert!(verify_credentials(h.accounts.as_ref(), "bob", "password2").await.is_ok());
+        assert!(verify_credentials(h.accounts.as_ref(), "bob", "password1").await.is_err());
+        let r = session::authorize(h.sessions.as_ref(), &h.jwt, &tokens.token).await;
+        assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+
+        // single use
+        assert_eq!(h.reset(&secret, "password3").await, StatusCode::UNAUTHORIZED);
+        assert!(verify_credentials(h.accounts.as_ref(), "bob", "password2").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_addresses_are_not_mailed() {
+        let h = Harness::new().await;
+
+        assert_eq!(h.request_reset("alice@example.com").await, StatusCode::ACCEPTED);
+        // unverified
+        assert_eq!(h.request_verification("bob@example.com").await, StatusCode::ACCEPTED);
+        h.mailed(0).await;
+        assert_eq!(h.request_reset("bob@example.com").await, StatusCode::ACCEPTED);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(h.capture.messages.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_bad_tokens() {
+        let h = Harness::new().await;
+        h.verified().await;
+        let new_token = |secret: &str, purpose, expires_at| EmailToken {
+            hash: token::hash(secret),
+            username: "bob".into(),
+            purpose,
+            email: "bob@example.com".into(),
+            expires_at,
+        };
+
+        let now = SystemTime::now();
+        h.emails.create_email_token(&new_token("expired", Purpose::Reset, now - Duration::from_secs(1))).await.unwrap();
+        assert_eq!(h.reset("expired", "password2").await, StatusCode::UNAUTHORIZED);
+
+        h.emails.create_email_token(&new_token("verify", Purpose::Verify, now + VERIFY_LIFETIME)).await.unwrap();
+        assert_eq!(h.reset("verify", "password2").await, StatusCode::UNAUTHORIZED);
+        h.emails.create_email_token(&new_token("reset", Purpose::Reset, now + RESET_LIFETIME)).await.unwrap();
+        assert_eq!(h.verify("reset").await, StatusCode::UNAUTHORIZED);
+
+        assert!(verify_credentials(h.accounts.as_ref(), "bob", "password1").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_routes_not_found_without_mail() {
+        let accounts = Arc::new(MemoryAccounts::default());
+        let sessions = Arc::new(MemorySessions::default());
+        let jwt = Arc::new(new_jwt(Algorithm::EdDSA).await);
+        let users = Arc::new(Database::new(accounts.clone(), Arc::new(MemoryPersonalTokens::default()), sessions.clone(), jwt));
+        let f = filter(accounts, Arc::new(MemoryEmails::default()), sessions, None::<Arc<Mail<Smtp>>>, users, true);
+
+        for (method, path) in [("POST", "/email"), ("GET", "/email/verify?token=x"), ("POST", "/password/reset"), ("POST", "/password/reset/confirm")] {
+            let res = warp::test::request().method(method).path(path).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{} {}", method, path);
+        }
+    }
+}
@@ -0,0 +1,568 @@
+// jwt signs and verifies access tokens. Tokens are signed by the newest
+// signing key and verified by any unexpired key, identified by the token's kid
+// header. Keys are persisted so that every replica shares them, and are
+// replaced on a schedule by rotate.
+
+use std::{fmt, future::Future, sync::{Arc, RwLock}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use jsonwebtoken::{jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse}, DecodingKey, EncodingKey, Header, Validation};
+use log::error;
+use rand::RngCore;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::error::Error;
+
+use super::user::{Role, User};
+
+// RELOAD_INTERVAL is how often keys are reloaded and rotated. A new key is not
+// used for signing until every replica has had a chance to load it.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+const KID_LENGTH: usize = 12;
+const HS256_SECRET_LENGTH: usize = 64;
+const LEEWAY_SECONDS: u64 = 30;
+
+pub trait Keys {
+    fn create_key(&self, key: &SigningKey) -> impl Future<Output = Result<(), Error>> + Send;
+    fn list_keys(&self) -> impl Future<Output = Result<Vec<SigningKey>, Error>> + Send;
+    fn delete_expired_keys(&self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HS256,
+    EdDSA,
+}
+
+impl Algorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::HS256 => "HS256",
+            Algorithm::EdDSA => "EdDSA",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "HS256" | "hs256" => Ok(Algorithm::HS256),
+            "EdDSA" | "eddsa" => Ok(Algorithm::EdDSA),
+            a => Err(Error::BadRequest(format!("unsupported algorithm: {}", a))),
+        }
+    }
+
+    fn jwt(&self) -> jsonwebtoken::Algorithm {
+        match self {
+            Algorithm::HS256 => jsonwebtoken::Algorithm::HS256,
+            Algorithm::EdDSA => jsonwebtoken::Algorithm::EdDSA,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    /// HS256 secret, or Ed25519 PKCS#8 document
+    pub private_key: Vec<u8>,
+    /// Ed25519 public key, empty for HS256
+    pub public_key: Vec<u8>,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+// private keys are left out so that keys can be logged
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .field("created_at", &self.created_at)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+impl SigningKey {
+    pub fn generate(algorithm: Algorithm, created_at: SystemTime, expires_at: SystemTime) -> Result<Self, Error> {
+        let mut kid = [0u8; KID_LENGTH];
+        rand::rng().fill_bytes(&mut kid);
+
+        let (private_key, public_key) = match algorithm {
+            Algorithm::HS256 => {
+                let mut secret = vec![0u8; HS256_SECRET_LENGTH];
+                rand::rng().fill_bytes(&mut secret);
+                (secret, vec![])
+            },
+            Algorithm::EdDSA => {
+                let rng = ring::rand::SystemRandom::new();
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+                let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+                (pkcs8.as_ref().to_vec(), pair.public_key().as_ref().to_vec())
+            },
+        };
+
+        Ok(SigningKey {
+            kid: URL_SAFE_NO_PAD.encode(kid),
+            algorithm,
+            private_key,
+            public_key,
+            created_at,
+            expires_at,
+        })
+    }
+
+    fn encoding_key(&self) -> EncodingKey {
+        match self.algorithm {
+            Algorithm::HS256 => EncodingKey::from_secret(&self.private_key),
+            Algorithm::EdDSA => EncodingKey::from_ed_der(&self.private_key),
+        }
+    }
+
+    fn decoding_key(&self) -> DecodingKey {
+        match self.algorithm {
+            Algorithm::HS256 => DecodingKey::from_secret(&self.private_key),
+            Algorithm::EdDSA => DecodingKey::from_ed_der(&self.public_key),
+        }
+    }
+
+    // jwk returns the public key, symmetric keys cannot be published
+    fn jwk(&self) -> Option<Jwk> {
+        match self.algorithm {
+            Algorithm::HS256 => None,
+            Algorithm::EdDSA => Some(Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(KeyAlgorithm::EdDSA),
+                    key_id: Some(self.kid.clone()),
+                    ..Default::default()
+                },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(&self.public_key),
+                }),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub aud: String,
+    pub sub: String,
+    pub iat: u64,
+    pub nbf: u64,
+    pub exp: u64,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// Session the token was issued to
+    pub sid: i64,
+}
+
+impl Claims {
+    pub fn user(&self) -> User {
+        User {
+            roles: self.roles.clone(),
+            ..User::new(&self.sub)
+        }
+    }
+}
+
+/// Rotation schedule for signing keys. Keys sign for interval, and are kept
+/// for overlap afterwards so that tokens they signed can still be verified.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub algorithm: Algorithm,
+    pub interval: Duration,
+    pub overlap: Duration,
+}
+
+pub struct Jwt {
+    issuer: String,
+    audience: String,
+    keys: RwLock<Vec<SigningKey>>,
+}
+
+impl Jwt {
+    pub fn new(issuer: &str, audience: &str) -> Self {
+        Jwt {
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            keys: RwLock::new(vec![]),
+        }
+    }
+
+    pub fn set_keys(&self, keys: Vec<SigningKey>) {
+        *self.keys.write().unwrap() = keys;
+    }
+
+    pub fn sign(&self, user: &User, session: i64, lifetime: Duration) -> Result<String, Error> {
+        let now = SystemTime::now();
+        let keys = self.keys.read().unwrap();
+        let key = signing_key(&keys, now)
+            .ok_or(Error::Internal("no signing keys".into()))?;
+
+        let now = seconds(now);
+        let claims = Claims {
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            sub: user.name.clone(),
+            iat: now,
+            nbf: now,
+            exp: now + lifetime.as_secs(),
+            roles: user.roles.clone(),
+            sid: session,
+        };
+
+        let mut header = Header::new(key.algorithm.jwt());
+        header.kid = Some(key.kid.clone());
+        jsonwebtoken::encode(&header, &claims, &key.encoding_key())
+            .map_err(|e| Error::Internal(e.to_string()))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?;
+        let kid = header.kid
+            .ok_or(Error::Unauthorized("no kid in token".into()))?;
+
+        let now = SystemTime::now();
+        let keys = self.keys.read().unwrap();
+        let key = keys.iter()
+            .find(|k| k.kid == kid && k.expires_at > now)
+            .ok_or(Error::Unauthorized(format!("unknown kid: {}", kid)))?;
+        if header.alg != key.algorithm.jwt() {
+            return Err(Error::Unauthorized(format!("algorithm does not match kid: {}", kid)));
+        }
+
+        let mut validation = Validation::new(key.algorithm.jwt());
+        validation.leeway = LEEWAY_SECONDS;
+        validation.validate_nbf = true;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
+
+        jsonwebtoken::decode::<Claims>(token, &key.decoding_key(), &validation)
+            .map(|t| t.claims)
+            .map_err(|e| Error::Unauthorized(e.to_string()))
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        let now = SystemTime::now();
+        JwkSet {
+            keys: self.keys.read().unwrap()
+                .iter()
+                .filter(|k| k.expires_at > now)
+                .filter_map(SigningKey::jwk)
+                .collect(),
+        }
+    }
+}
+
+// signing_key returns the newest key that every replica has loaded, or the
+// newest key if none is old enough.
+fn signing_key(keys: &[SigningKey], now: SystemTime) -> Option<&SigningKey> {
+    let loaded = now.checked_sub(RELOAD_INTERVAL).unwrap_or(UNIX_EPOCH);
+    keys.iter()
+        .filter(|k| k.expires_at > now && k.created_at <= loaded)
+        .max_by_key(|k| k.created_at)
+        .or_else(|| keys.iter()
+            .filter(|k| k.expires_at > now)
+            .max_by_key(|k| k.created_at))
+}
+
+fn seconds(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Deletes expired keys, creates a new key if the newest key for the
+/// configured algorithm is due for rotation, and loads keys into jwt.
+pub async fn rotate<K: Keys>(store: &K, jwt: &Jwt, rotation: &Rotation) -> Result<(), Error> {
+    store.delete_expired_keys().await?;
+    let mut keys = store.list_keys().await?;
+
+    let now = SystemTime::now();
+    let due = keys.iter()
+        .filter(|k| k.algorithm == rotation.algorithm)
+        .map(|k| k.created_at)
+        .max()
+        .is_none_or(|created_at| created_at + rotation.interval <= now);
+
+    if due {
+        // a key may be the signing key for up to two reload intervals after
+        // its rotation is due, until its replacement has propagated
+        let expires_at = now + rotation.interval + rotation.overlap + 2 * RELOAD_INTERVAL;
+        let key = SigningKey::generate(rotation.algorithm, now, expires_at)?;
+        store.create_key(&key).await?;
+        log::info!(target: "wiki::auth", "created signing key: {}", key.kid);
+        keys.push(key);
+    }
+
+    jwt.set_keys(keys);
+    Ok(())
+}
+
+/// Calls rotate every RELOAD_INTERVAL, forever.
+pub async fn rotate_periodically<K: Keys>(store: Arc<K>, jwt: Arc<Jwt>, rotation: Rotation) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = rotate(store.as_ref(), jwt.as_ref(), &rotation).await {
+            error!(target: "wiki::auth", "failed to rotate signing keys: {:?}", e);
+        }
+    }
+}
+
+pub fn filter(jwt: Arc<Jwt>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+{
+    warp::get()
+        .and(warp::path!(".well-known" / "jwks.json"))
+        .map(move || warp::reply::with_header(
+            warp::reply::json(&jwt.jwks()),
+            "Cache-Control",
+            format!("public, max-age={}", RELOAD_INTERVAL.as_secs()),
+        ))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Mutex;
+
+    use warp::http::StatusCode;
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct MemoryKeys {
+        keys: Mutex<Vec<SigningKey>>,
+    }
+
+    impl Keys for MemoryKeys {
+        async fn create_key(&self, key: &SigningKey) -> Result<(), Error> {
+            self.keys.lock().unwrap().push(key.clone());
+            Ok(())
+        }
+
+        async fn list_keys(&self) -> Result<Vec<SigningKey>, Error> {
+            Ok(self.keys.lock().unwrap().clone())
+        }
+
+        async fn delete_expired_keys(&self) -> Result<(), Error> {
+            let now = SystemTime::now();
+            self.keys.lock().unwrap().retain(|k| k.expires_at > now);
+            Ok(())
+        }
+    }
+
+    const ISSUER: &str = "https://wiki.example.com";
+    const AUDIENCE: &str = "wiki";
+
+    pub fn rotation(algorithm: Algorithm) -> Rotation {
+        Rotation {
+            algorithm,
+            interval: Duration::from_secs(3600),
+            overlap: Duration::from_secs(300),
+        }
+    }
+
+    /// Returns a Jwt with a single key, ready to sign
+    pub async fn new_jwt(algorithm: Algorithm) -> Jwt {
+        let jwt = Jwt::new(ISSUER, AUDIENCE);
+        rotate(&MemoryKeys::default(), &jwt, &rotation(algorithm)).await.unwrap();
+        jwt
+    }
+
+    fn encode(jwt: &Jwt, header: Header, claims: &serde_json::Value) -> String {
+        let keys = jwt.keys.read().unwrap();
+        jsonwebtoken::encode(&header, claims, &keys[0].encoding_key()).unwrap()
+    }
+
+    fn header(jwt: &Jwt) -> Header {
+        let keys = jwt.keys.read().unwrap();
+        let mut header = Header::new(keys[0].algorithm.jwt());
+        header.kid = Some(keys[0].kid.clone());
+        header
+    }
+
+    fn claims(overrides: serde_json::Value) -> serde_json::Value {
+        let now = seconds(SystemTime::now());
+        let mut claims = serde_json::json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "sub": "bob",
+            "sid": 1,
+            "iat": now,
+            "nbf": now,
+            "exp": now + 60,
+        });
+        for (k, v) in overrides.as_object().unwrap() {
+            if v.is_null() {
+                claims.as_object_mut().unwrap().remove(k);
+            } else {
+                claims[k] = v.clone();
+            }
+        }
+        claims
+    }
+
+    #[tokio::test]
+    async fn test_sign_and_verify() {
+        for algorithm in [Algorithm::HS256, Algorithm::EdDSA] {
+            let jwt = new_jwt(algorithm).await;
+            let token = jwt.sign(&User::new("bob"), 1, Duration::from_secs(60)).unwrap();
+            let claims = jwt.verify(&token).unwrap();
+            assert_eq!(claims.sub, "bob", "{:?}", algorithm);
+            assert_eq!(claims.iss, ISSUER);
+            assert_eq!(claims.aud, AUDIENCE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_claims_carry_user_and_session() {
+        let jwt = new_jwt(Algorithm::EdDSA).await;
+        let user = User {
+            roles: vec![Role::Editor, Role::Admin],
+            ..User::new("bob")
+        };
+        let token = jwt.sign(&user, 7, Duration::from_secs(60)).unwrap();
+        let claims = jwt.verify(&token).unwrap();
+        assert_eq!(claims.user(), user);
+        assert_eq!(claims.sid, 7);
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_bad_claims() {
+        let now = seconds(SystemTime::now());
+        for algorithm in [Algorithm::HS256, Algorithm::EdDSA] {
+            let jwt = new_jwt(algorithm).await;
+            for overrides in [
+                serde_json::json!({"exp": now - 2 * LEEWAY_SECONDS}),
+                serde_json::json!({"nbf": now + 2 * LEEWAY_SECONDS}),
+                serde_json::json!({"iss": "https://evil.example.com"}),
+                serde_json::json!({"aud": "other"}),
+                serde_json::json!({"exp": null}),
+                serde_json::json!({"nbf": null}),
+                serde_json::json!({"sub": null}),
+                serde_json::json!({"sid": null}),
+            ] {
+                let token = encode(&jwt, header(&jwt), &claims(overrides.clone()));
+                let r = jwt.verify(&token);
+                assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}: {}: {:?}", algorithm, overrides, r);
+            }
+
+            // control
+            let token = encode(&jwt, header(&jwt), &claims(serde_json::json!({})));
+            assert!(jwt.verify(&token).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_bad_keys() {
+        let jwt = new_jwt(Algorithm::EdDSA).await;
+        let other = new_jwt(Algorithm::EdDSA).await;
+
+        // unknown kid
+        let token = other.sign(&User::new("bob"), 1, Duration::from_secs(60)).unwrap();
+        assert!(jwt.verify(&token).is_err());
+
+        // no kid
+        let mut h = header(&jwt);
+        h.kid = None;
+        assert!(jwt.verify(&encode(&jwt, h, &claims(serde_json::json!({})))).is_err());
+
+        // signed by another key with a known kid
+        let mut h = header(&other);
+        h.kid = header(&jwt).kid;
+        assert!(jwt.verify(&encode(&other, h, &claims(serde_json::json!({})))).is_err());
+
+        // algorithm confusion: public key used as an HMAC secret
+        let mut h = Header::new(jsonwebtoken::Algorithm::HS256);
+        h.kid = header(&jwt).kid;
+        let public_key = jwt.keys.read().unwrap()[0].public_key.clone();
+        let token = jsonwebtoken::encode(&h, &claims(serde_json::json!({})), &EncodingKey::from_secret(&public_key)).unwrap();
+        assert!(jwt.verify(&token).is_err());
+
+        // tampered
+        let token = jwt.sign(&User::new("bob"), 1, Duration::from_secs(60)).unwrap();
+        let parts: Vec<&str> = token.split('.').collect();
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims(serde_json::json!({"sub": "admin"}))).unwrap());
+        let tampered = format!("{}.{}.{}", parts[0], payload, parts[2]);
+        assert!(jwt.verify(&tampered).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let store = MemoryKeys::default();
+        let jwt = Jwt::new(ISSUER, AUDIENCE);
+        let r = rotation(Algorithm::EdDSA);
+
+        // 1. First rotation creates a key, and is idempotent
+        rotate(&store, &jwt, &r).await.unwrap();
+        rotate(&store, &jwt, &r).await.unwrap();
+        assert_eq!(store.list_keys().await.unwrap().len(), 1);
+        let old = jwt.sign(&User::new("bob"), 1, Duration::from_secs(60)).unwrap();
+
+        // 2. Due keys are replaced, and old tokens still verify
+        let due = Rotation { interval: Duration::ZERO, ..r };
+        rotate(&store, &jwt, &due).await.unwrap();
+        assert_eq!(store.list_keys().await.unwrap().len(), 2);
+        assert!(jwt.verify(&old).is_ok());
+
+        // 3. Changing algorithm creates a key
+        rotate(&store, &jwt, &rotation(Algorithm::HS256)).await.unwrap();
+        assert_eq!(store.list_keys().await.unwrap().len(), 3);
+
+        // 4. Expired keys are deleted, and their tokens are rejected
+        for k in store.keys.lock().unwrap().iter_mut() {
+            k.expires_at = SystemTime::now() - Duration::from_secs(1);
+        }
+        rotate(&store, &jwt, &r).await.unwrap();
+        assert_eq!(store.list_keys().await.unwrap().len(), 1);
+        assert!(jwt.verify(&old).is_err());
+    }
+
+    #[test]
+    fn test_signing_key_waits_for_propagation() {
+        let now = SystemTime::now();
+        let expires_at = now + Duration::from_secs(3600);
+        let old = SigningKey::generate(Algorithm::HS256, now - 2 * RELOAD_INTERVAL, expires_at).unwrap();
+        let new = SigningKey::generate(Algorithm::HS256, now, expires_at).unwrap();
+
+        assert_eq!(signing_key(std::slice::from_ref(&new), now).unwrap().kid, new.kid);
+        assert_eq!(signing_key(&[old.clone(), new.clone()], now).unwrap().kid, old.kid);
+        assert_eq!(signing_key(&[old.clone(), new.clone()], now + RELOAD_INTERVAL).unwrap().kid, new.kid);
+        assert!(signing_key(&[], now).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_jwks() {
+        let store = MemoryKeys::default();
+        let jwt = Arc::new(Jwt::new(ISSUER, AUDIENCE));
+        rotate(&store, &jwt, &rotation(Algorithm::HS256)).await.unwrap();
+        rotate(&store, &jwt, &rotation(Algorithm::EdDSA)).await.unwrap();
+        let token = jwt.sign(&User::new("bob"), 1, Duration::from_secs(60)).unwrap();
+
+        let f = filter(jwt.clone());
+        let res = warp::test::request()
+            .path("/.well-known/jwks.json")
+            .reply(&f)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // only public keys are published, and they verify tokens
+        let jwks: JwkSet = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(jwks.keys.len(), 1);
+        let kid = jsonwebtoken::decode_header(&token).unwrap().kid.unwrap();
+        let jwk = jwks.find(&kid).unwrap();
+        let mut validation = Validation::new(jsonwebtoken::Algorithm::EdDSA);
+        validation.set_audience(&[AUDIENCE]);
+        let r = jsonwebtoken::decode::<Claims>(&token, &DecodingKey::from_jwk(jwk).unwrap(), &validation);
+        assert_eq!(r.unwrap().claims.sub, "bob");
+    }
+}
@@ -0,0 +1,621 @@
+// lockout protects credentials from brute force. Failed attempts are counted
+// per account and per client IP, and once a counter reaches its threshold the
+// key is locked out, for twice as long after each further failure. Counters
+// are stored by persistence, so that replicas share them. Client IPs are found
+// past trusted proxies, as they are for rate limits.
+
+use std::{fmt, future::Future, net::{IpAddr, SocketAddr}, sync::Arc, time::{Duration, SystemTime}};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Serialize;
+use warp::{http::HeaderMap, reject::Rejection, reply::Reply, Filter};
+
+use crate::{
+    auth::{audit::{Audit, Entry}, personal_token, user::{User, Users}},
+    error::Error,
+    rate_limit::{self, Cidr},
+};
+
+// failures are forgotten a day after the last one
+const WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub trait Lockouts {
+    /// Counts a failed attempt of key at now. Counters whose last failure was
+    /// before since restart unlocked.
+    fn record_failure(&self, key: &str, now: SystemTime, since: SystemTime) -> impl Future<Output = Result<Counter, Error>> + Send;
+    fn lock(&self, key: &str, until: SystemTime) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Reads the counters of keys, keys without one are skipped
+    fn read_counters(&self, keys: &[String]) -> impl Future<Output = Result<Vec<Counter>, Error>> + Send;
+    /// Lists counters that are locked after now
+    fn list_locked(&self, now: SystemTime) -> impl Future<Output = Result<Vec<Counter>, Error>> + Send;
+    fn clear(&self, key: &str) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct Counter {
+    pub key: String,
+    pub failures: i32,
+    pub last_failure_at: SystemTime,
+    pub locked_until: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Key {
+    Account(String),
+    Ip(IpAddr),
+}
+
+impl Key {
+    pub fn parse(kind: &str, value: &str) -> Result<Self, Error> {
+        match kind {
+            "account" => Ok(Key::Account(value.to_string())),
+            "ip" => value.parse()
+                .map(Key::Ip)
+                .map_err(|e| Error::BadRequest(format!("bad ip address: {}", e))),
+            k => Err(Error::BadRequest(format!("unknown lockout kind: {}", k))),
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Account(username) => write!(f, "account:{}", username),
+            Key::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+/// Keys of an attempt to authenticate as username, from a client at ip
+pub fn keys(username: Option<&str>, ip: Option<IpAddr>) -> Vec<Key> {
+    username.map(|u| Key::Account(u.to_string())).into_iter()
+        .chain(ip.map(Key::Ip))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Failures of an account before it is locked out
+    pub account_failures: i32,
+    /// Failures from an IP before it is locked out, higher than for accounts
+    /// as clients share IPs
+    pub ip_failures: i32,
+    /// First lockout, which doubles with each further failure
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl Config {
+    fn lockout(&self, key: &Key, failures: i32) -> Option<Duration> {
+        let threshold = match key {
+            Key::Account(_) => self.account_failures,
+            Key::Ip(_) => self.ip_failures,
+        };
+        if failures < threshold {
+            return None;
+        }
+        let doublings = 1u32.checked_shl((failures - threshold) as u32).unwrap_or(u32::MAX);
+        Some(self.lockout.saturating_mul(doublings).min(self.max_lockout))
+    }
+}
+
+pub struct Throttle<L> {
+    lockouts: Arc<L>,
+    config: Config,
+    proxies: Vec<Cidr>,
+}
+
+impl<L: Lockouts + Audit> Throttle<L> {
+    pub fn new(lockouts: Arc<L>, config: Config) -> Self {
+        Throttle { lockouts, config, proxies: vec![] }
+    }
+
+    /// Trusts proxies to forward the address of clients, which would
+    /// otherwise share the proxies' IPs and their lockouts
+    pub fn with_proxies(self, proxies: Vec<Cidr>) -> Self {
+        Throttle { proxies, ..self }
+    }
+
+    /// The IP of a client connected from remote, past trusted proxies
+    pub fn client_ip(&self, remote: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        rate_limit::client_ip(remote, headers, &self.proxies)
+    }
+
+    /// Runs attempt unless one of keys is locked out. Unauthorized attempts
+    /// count as failures of every key, and successes clear the account's
+    /// counter.
+    pub async fn attempt<T>(&self, keys: &[Key], attempt: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        let now = SystemTime::now();
+        let names: Vec<String> = keys.iter().map(Key::to_string).collect();
+        let counters = self.lockouts.read_counters(&names).await?;
+        let locked = counters.iter()
+            .filter_map(|c| c.locked_until.filter(|until| *until > now).map(|until| (until, &c.key)))
+            .max();
+        if let Some((until, key)) = locked {
+            return Err(Error::TooManyRequests(
+                format!("{} is locked out", key),
+                until.duration_since(now).unwrap_or_default(),
+            ));
+        }
+
+        match attempt.await {
+            Ok(t) => {
+                for key in keys.iter().filter(|k| matches!(k, Key::Account(_))).map(Key::to_string) {
+                    if counters.iter().any(|c| c.key == key) {
+                        self.lockouts.clear(&key).await?;
+                    }
+                }
+                Ok(t)
+            },
+            Err(Error::Unauthorized(msg)) => {
+                for key in keys {
+                    self.fail(key, now).await?;
+                }
+                Err(Error::Unauthorized(msg))
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn fail(&self, key: &Key, now: SystemTime) -> Result<(), Error> {
+        let c = self.lockouts.record_failure(&key.to_string(), now, now - WINDOW).await?;
+        let Some(lockout) = self.config.lockout(key, c.failures) else {
+            return Ok(());
+        };
+        self.lockouts.lock(&c.key, now + lockout).await?;
+        log::warn!(target: "wiki::auth", "locked out {} for {}s after {} failures", c.key, lockout.as_secs(), c.failures);
+        self.lockouts.record_audit(&Entry {
+            at: now,
+            actor: None,
+            action: "lockout".into(),
+            target: c.key,
+            detail: format!("locked for {}s after {} failures", lockout.as_secs(), c.failures),
+        }).await
+    }
+
+    pub async fn list_locked(&self) -> Result<Vec<Counter>, Error> {
+        self.lockouts.list_locked(SystemTime::now()).await
+    }
+
+    /// Clears the counter of key, on behalf of admin
+    pub async fn unlock(&self, key: &Key, admin: &str) -> Result<(), Error> {
+        self.lockouts.clear(&key.to_string()).await?;
+        self.lockouts.record_audit(&Entry {
+            at: SystemTime::now(),
+            actor: Some(admin.to_string()),
+            action: "unlock".into(),
+            target: key.to_string(),
+            detail: "".into(),
+        }).await
+    }
+}
+
+/// Guarded throttles the users it guards, so that failed Authorization
+/// headers count towards the same lockouts as failed sign ins. Signed access
+/// tokens cannot be guessed, and routinely fail once expired or revoked, so
+/// they do not count.
+pub struct Guarded<U, L> {
+    users: U,
+    throttle: Arc<Throttle<L>>,
+}
+
+impl<U, L> Guarded<U, L> {
+    pub fn new(users: U, throttle: Arc<Throttle<L>>) -> Self {
+        Guarded { users, throttle }
+    }
+}
+
+impl<U, L> Users for Guarded<U, L>
+where
+    U: Users + Send + Sync,
+    L: Lockouts + Audit + Send + Sync,
+{
+    async fn authorize(&self, header: String) -> Result<User, Error> {
+        self.authorize_client(header, None, &HeaderMap::new()).await
+    }
+
+    async fn authorize_client(&self, header: String, remote: Option<SocketAddr>, headers: &HeaderMap) -> Result<User, Error> {
+        match header.split_once(' ') {
+            Some(("Bearer", token)) if !token.starts_with(personal_token::PREFIX) =>
+                self.users.authorize(header).await,
+            _ => {
+                let keys = keys(basic_username(&header).as_deref(), self.throttle.client_ip(remote, headers));
+                self.throttle.attempt(&keys, self.users.authorize(header.clone())).await
+            },
+        }
+    }
+}
+
+fn basic_username(header: &str) -> Option<String> {
+    let credentials = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(credentials).ok()?).ok()?;
+    decoded.split_once(':').map(|(username, _)| username.to_string())
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockoutInfo {
+    pub key: String,
+    pub failures: i32,
+    /// Seconds since the unix epoch, as is locked_until
+    pub last_failure_at: u64,
+    pub locked_until: u64,
+}
+
+impl LockoutInfo {
+    fn new(c: &Counter) -> Self {
+        LockoutInfo {
+            key: c.key.clone(),
+            failures: c.failures,
+            last_failure_at: unix_secs(c.last_failure_at),
+            locked_until: c.locked_until.map(unix_secs).unwrap_or_default(),
+        }
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Listing and unlocking require the admin scope and the admin role.
+pub fn filter<L, U>(throttle: Arc<Throttle<L>>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    L: Lockouts + Audit + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+{
+    warp::path!("lockouts")
+        .and(warp::get())
+        .and(endpoints::list(throttle.clone(), users.clone()))
+        .or(
+            warp::path!("lockouts" / ..)
+                .and(warp::delete())
+                .and(endpoints::unlock(throttle, users))
+        )
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{auth::{audit::Audit, user::{with_authorization, Scope, Users}}, error};
+
+    use super::{handlers, Lockouts, Throttle};
+
+    pub fn list<L, U>(throttle: Arc<Throttle<L>>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        L: Lockouts + Audit + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_throttle(throttle)
+            .and(with_authorization(users, Scope::Admin))
+            .and_then(handlers::list)
+            .recover(error::recover)
+    }
+
+    pub fn unlock<L, U>(throttle: Arc<Throttle<L>>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        L: Lockouts + Audit + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        warp::path::param()
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_throttle(throttle))
+            .and(with_authorization(users, Scope::Admin))
+            .and_then(handlers::unlock)
+            .recover(error::recover)
+    }
+
+    fn with_throttle<L>(throttle: Arc<Throttle<L>>) -> impl Filter<Extract = (Arc<Throttle<L>>,), Error = Infallible> + Clone
+    where
+        L: Lockouts + Audit + Send + Sync + 'static
+    {
+        warp::any().map(move || throttle.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{auth::{audit::Audit, user::{Role, User}}, error::Error};
+
+    use super::{Key, LockoutInfo, Lockouts, Throttle};
+
+    fn require_admin(user: &User) -> Result<(), Error> {
+        if user.roles.contains(&Role::Admin) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!("{} is not an admin", user.name)))
+        }
+    }
+
+    pub async fn list<L: Lockouts + Audit>(throttle: Arc<Throttle<L>>, user: User) -> Result<impl Reply, Rejection> {
+        let r = async {
+            require_admin(&user)?;
+            throttle.list_locked().await
+        }.await;
+
+        match r {
+            Ok(counters) => Ok(warp::reply::json(&counters.iter().map(LockoutInfo::new).collect::<Vec<_>>())),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn unlock<L: Lockouts + Audit>(kind: String, value: String, throttle: Arc<Throttle<L>>, user: User) -> Result<impl Reply, Rejection> {
+        let r = async {
+            require_admin(&user)?;
+            let key = Key::parse(&kind, &value)?;
+            throttle.unlock(&key, &user.name).await?;
+            log::info!(target: "wiki::auth", "{} unlocked {}", user.name, key);
+            Ok::<_, Error>(())
+        }.await;
+
+        match r {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests Throttle, Guarded and filter.
+/// Exposes in-memory lockouts to other test modules.
+///
+/// Test plan:
+/// 1. Lockouts double after the threshold, up to the maximum
+/// 2. Failed attempts lock out accounts and IPs, successes clear accounts
+/// 3. Guarded counts basic auth and personal tokens, not signed tokens, by
+///    the IP of clients past trusted proxies
+/// 4. Admins list and unlock lockouts, which is audited
+#[cfg(test)]
+pub mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use warp::http::StatusCode;
+
+    use crate::auth::user::{self, Role, Scope};
+
+    use super::*;
+
+    /// MemoryLockouts also keeps the audit log, as persistence keeps both
+    #[derive(Default)]
+    pub struct MemoryLockouts {
+        counters: Mutex<HashMap<String, Counter>>,
+        pub entries: Mutex<Vec<Entry>>,
+    }
+
+    impl Lockouts for MemoryLockouts {
+        async fn record_failure(&self, key: &str, now: SystemTime, since: SystemTime) -> Result<Counter, Error> {
+            let mut counters = self.counters.lock().unwrap();
+            let c = counters.entry(key.to_string())
+                .and_modify(|c| if c.last_failure_at < since {
+                    c.failures = 0;
+                    c.locked_until = None;
+                })
+                .or_insert(Counter { key: key.to_string(), failures: 0, last_failure_at: now, locked_until: None });
+            c.failures += 1;
+            c.last_failure_at = now;
+            Ok(c.clone())
+        }
+
+        async fn lock(&self, key: &str, until: SystemTime) -> Result<(), Error> {
+            match self.counters.lock().unwrap().get_mut(key) {
+                Some(c) => {
+                    c.locked_until = Some(until);
+                    Ok(())
+                },
+                None => Err(Error::NotFound(key.to_string())),
+            }
+        }
+
+        async fn read_counters(&self, keys: &[String]) -> Result<Vec<Counter>, Error> {
+            let counters = self.counters.lock().unwrap();
+            Ok(keys.iter().filter_map(|k| counters.get(k).cloned()).collect())
+        }
+
+        async fn list_locked(&self, now: SystemTime) -> Result<Vec<Counter>, Error> {
+            let mut locked: Vec<Counter> = self.counters.lock().unwrap().values()
+                .filter(|c| c.locked_until.is_some_and(|until| until > now))
+                .cloned()
+                .collect();
+            locked.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(locked)
+        }
+
+        async fn clear(&self, key: &str) -> Result<(), Error> {
+            match self.counters.lock().unwrap().remove(key) {
+                Some(_) => Ok(()),
+                None => Err(Error::NotFound(key.to_string())),
+            }
+        }
+    }
+
+    impl Audit for MemoryLockouts {
+        async fn record_audit(&self, entry: &Entry) -> Result<(), Error> {
+            self.entries.lock().unwrap().push(entry.clone());
+            Ok(())
+        }
+    }
+
+    pub fn config() -> Config {
+        Config {
+            account_failures: 5,
+            ip_failures: 10,
+            lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(100),
+        }
+    }
+
+    pub fn new_throttle() -> (Arc<Throttle<MemoryLockouts>>, Arc<MemoryLockouts>) {
+        let lockouts = Arc::new(MemoryLockouts::default());
+        (Arc::new(Throttle::new(lockouts.clone(), config())), lockouts)
+    }
+
+    async fn fail(throttle: &Throttle<MemoryLockouts>, keys: &[Key]) -> Result<(), Error> {
+        throttle.attempt(keys, async { Err::<(), _>(Error::Unauthorized("bad".into())) }).await
+    }
+
+    fn addr(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_lockout_doubles() {
+        let c = config();
+        let bob = Key::Account("bob".into());
+        assert_eq!(c.lockout(&bob, 4), None);
+        assert_eq!(c.lockout(&bob, 5), Some(Duration::from_secs(30)));
+        assert_eq!(c.lockout(&bob, 6), Some(Duration::from_secs(60)));
+        assert_eq!(c.lockout(&bob, 7), Some(Duration::from_secs(100)));
+        assert_eq!(c.lockout(&bob, 100), Some(Duration::from_secs(100)));
+        assert_eq!(c.lockout(&Key::Ip("127.0.0.1".parse().unwrap()), 9), None);
+    }
+
+    #[tokio::test]
+    async fn test_attempts() {
+        let (throttle, lockouts) = new_throttle();
+        let bob = keys(Some("bob"), addr("10.0.0.1"));
+
+        // 1. failures below the threshold are passed through
+        for _ in 0..4 {
+            let r = fail(&throttle, &bob).await;
+            assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+        }
+
+        // 2. success clears the account, not the ip
+        throttle.attempt(&bob, async { Ok::<_, Error>(()) }).await.unwrap();
+        let counters = lockouts.read_counters(&["account:bob".into(), "ip:10.0.0.1".into()]).await.unwrap();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].key, "ip:10.0.0.1");
+
+        // 3. reaching the threshold locks out the account, from other ips too
+        for _ in 0..5 {
+            fail(&throttle, &keys(Some("bob"), addr("10.0.0.2"))).await.unwrap_err();
+        }
+        let r = throttle.attempt(&keys(Some("bob"), addr("10.0.0.3")), async { Ok::<_, Error>(()) }).await;
+        match r {
+            Err(Error::TooManyRequests(msg, retry)) => {
+                assert_eq!(msg, "account:bob is locked out");
+                assert!(retry <= Duration::from_secs(30) && retry > Duration::from_secs(25), "{:?}", retry);
+            },
+            r => panic!("{:?}", r),
+        }
+        let entries = lockouts.entries.lock().unwrap().clone();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].action.as_str(), entries[0].target.as_str()), ("lockout", "account:bob"));
+
+        // 4. other accounts from the ip stay unlocked until the ip threshold
+        for _ in 0..5 {
+            fail(&throttle, &keys(Some("alice"), addr("10.0.0.1"))).await.unwrap_err();
+        }
+        let r = fail(&throttle, &keys(Some("carol"), addr("10.0.0.1"))).await;
+        assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+        let r = fail(&throttle, &keys(Some("dave"), addr("10.0.0.1"))).await;
+        assert!(matches!(r, Err(Error::TooManyRequests(..))), "{:?}", r);
+        let locked: Vec<String> = throttle.list_locked().await.unwrap().into_iter().map(|c| c.key).collect();
+        assert_eq!(locked, vec!["account:alice", "account:bob", "ip:10.0.0.1"]);
+
+        // 5. other errors do not count
+        let erin = keys(Some("erin"), None);
+        for _ in 0..5 {
+            let r = throttle.attempt(&erin, async { Err::<(), _>(Error::BadRequest("bad".into())) }).await;
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+        }
+        assert!(lockouts.read_counters(&["account:erin".into()]).await.unwrap().is_empty());
+    }
+
+    struct Fails;
+
+    impl Users for Fails {
+        async fn authorize(&self, _header: String) -> Result<User, Error> {
+            Err(Error::Unauthorized("bad".into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_guarded() {
+        let (throttle, lockouts) = new_throttle();
+        let users = Guarded::new(Fails, throttle);
+
+        let basic = format!("Basic {}", STANDARD.encode("bob:password"));
+        for header in [basic.as_str(), "Bearer wiki_pat_guess", "Bearer not.a.jwt", "Digest bob"] {
+            let r = users.authorize_client(header.into(), Some(SocketAddr::new("10.0.0.1".parse().unwrap(), 1234)), &HeaderMap::new()).await;
+            assert!(matches!(r, Err(Error::Unauthorized(_))), "{}: {:?}", header, r);
+        }
+
+        let counters = lockouts.read_counters(&["account:bob".into(), "ip:10.0.0.1".into()]).await.unwrap();
+        let failures: Vec<(String, i32)> = counters.into_iter().map(|c| (c.key, c.failures)).collect();
+        assert_eq!(failures, vec![("account:bob".into(), 1), ("ip:10.0.0.1".into(), 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_guarded_behind_proxy() {
+        let lockouts = Arc::new(MemoryLockouts::default());
+        let throttle = Throttle::new(lockouts.clone(), config())
+            .with_proxies(vec![Cidr::parse("10.0.0.0/8").unwrap()]);
+        let f = user::with_authorization(Arc::new(Guarded::new(Fails, Arc::new(throttle))), Scope::Read)
+            .map(|u: User| u.name)
+            .recover(crate::error::recover);
+        let request = |username: &str, client: &str| warp::test::request()
+            .remote_addr("10.0.0.1:1234".parse().unwrap())
+            .header("X-Forwarded-For", client)
+            .header("Authorization", format!("Basic {}", STANDARD.encode(format!("{}:password", username))));
+
+        // clients behind the proxy are locked out on their own
+        for i in 0..config().ip_failures {
+            let res = request(&format!("user{}", i), "203.0.113.1").reply(&f).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        let res = request("alice", "203.0.113.1").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = request("alice", "203.0.113.2").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let locked: Vec<String> = lockouts.list_locked(SystemTime::now()).await.unwrap().into_iter().map(|c| c.key).collect();
+        assert_eq!(locked, vec!["ip:203.0.113.1"]);
+    }
+
+    /// Named authorizes the user named by the header, admin is an admin
+    struct Named;
+
+    impl Users for Named {
+        async fn authorize(&self, header: String) -> Result<User, Error> {
+            let roles = if header == "admin" { vec![Role::Admin] } else { vec![] };
+            Ok(User { roles, ..User::new(&header) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let (throttle, lockouts) = new_throttle();
+        for _ in 0..5 {
+            fail(&throttle, &keys(Some("bob"), None)).await.unwrap_err();
+        }
+        let f = filter(throttle, Arc::new(Named));
+
+        let res = warp::test::request().path("/lockouts").header("Authorization", "admin").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let locked: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(locked[0]["key"], "account:bob");
+        assert_eq!(locked[0]["failures"], 5);
+
+        // non admins are forbidden
+        let res = warp::test::request().method("DELETE").path("/lockouts/account/bob").header("Authorization", "bob").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = warp::test::request().method("DELETE").path("/lockouts/account/bob").header("Authorization", "admin").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let entry = lockouts.entries.lock().unwrap().last().cloned().unwrap();
+        assert_eq!((entry.actor.as_deref(), entry.action.as_str(), entry.target.as_str()), (Some("admin"), "unlock", "account:bob"));
+
+        for (path, status) in [
+            ("/lockouts/account/bob", StatusCode::NOT_FOUND),
+            ("/lockouts/ip/not-an-ip", StatusCode::BAD_REQUEST),
+            ("/lockouts/user/bob", StatusCode::BAD_REQUEST),
+        ] {
+            let res = warp::test::request().method("DELETE").path(path).header("Authorization", "admin").reply(&f).await;
+            assert_eq!(res.status(), status, "{}", path);
+        }
+    }
+}
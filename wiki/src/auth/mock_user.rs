@@ -1,4 +1,5 @@
-// mock_users implements a fake user auth scheme
+// mock_users implements a fake user auth scheme. It trusts whatever the client
+// claims, so it is only for local development, see backend.
 
 use std::time::SystemTime;
 
@@ -7,7 +8,7 @@ use regex::Regex;
 
 use crate::error::Error;
 
-use super::user::Users;
+use super::user::{User, Users};
 
 pub struct Mock {
     re: Regex,
@@ -22,14 +23,14 @@ impl Mock {
 }
 
 impl Users for Mock {
-    async fn authorize(&self, header: String) -> Result<String, Error> {
+    async fn authorize(&self, header: String) -> Result<User, Error> {
         match self.re.captures(&header) {
             Some(captures) => {
                 let (_, [typ, token]) = captures.extract();
                 if typ == "Basic" {
-                    basic_auth(token)
+                    basic_auth(token).map(|name| User::new(&name))
                 } else if typ == "Bearer" {
-                    bearer_auth(token)
+                    bearer_auth(token).map(|name| User::new(&name))
                 } else {
                     Err(Error::Unauthorized("bad auth type".into()))
                 }
@@ -49,30 +50,66 @@ fn basic_auth(token: &str) -> Result<String, Error> {
 }
 
 fn bearer_auth(token: &str) -> Result<String, Error> {
-    match URL_SAFE.decode(token) {
-        Ok(t) => {
-            let v: serde_json::Value = serde_json::from_slice(&t).unwrap();
-            match v.get("expiration") {
-                None => return Err(Error::Unauthorized("no expiration in token".into())),
-                Some(exp) => {
-                    if let Some(exp) = exp.as_u64() {
-                        if SystemTime::now() > millis_to_system_time(exp) {
-                            return Err(Error::Unauthorized("token is expired".into()));
-                        }
-                    } else {
-                        return Err(Error::Unauthorized("invalid expiration in token".into()));
-                    }
-                }
-            }
-            match v.get("username") {
-                Some(u) => Ok(u.to_string()),
-                None => Err(Error::Unauthorized("no username in token".into())),
+    let t = URL_SAFE.decode(token)
+        .map_err(|e| Error::Unauthorized(e.to_string()))?;
+    let v: serde_json::Value = serde_json::from_slice(&t)
+        .map_err(|e| Error::Unauthorized(e.to_string()))?;
+
+    match v.get("expiration") {
+        None => return Err(Error::Unauthorized("no expiration in token".into())),
+        Some(exp) => {
+            match exp.as_u64().and_then(millis_to_system_time) {
+                Some(exp) => if SystemTime::now() > exp {
+                    return Err(Error::Unauthorized("token is expired".into()));
+                },
+                None => return Err(Error::Unauthorized("invalid expiration in token".into())),
             }
         }
-        Err(err) => Err(Error::Unauthorized(err.to_string())),
+    }
+    match v.get("username").and_then(|u| u.as_str()) {
+        Some(u) => Ok(u.into()),
+        None => Err(Error::Unauthorized("no username in token".into())),
     }
 }
 
-fn millis_to_system_time(millis: u64) -> SystemTime {
-    std::time::UNIX_EPOCH.checked_add(std::time::Duration::from_millis(millis)).unwrap()
+fn millis_to_system_time(millis: u64) -> Option<SystemTime> {
+    std::time::UNIX_EPOCH.checked_add(std::time::Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", URL_SAFE.encode(token))
+    }
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let mock = Mock::new();
+
+        let r = mock.authorize("Basic bob:password1".into()).await;
+        assert_eq!(r.unwrap().name, "bob");
+        let r = mock.authorize(bearer(r#"{"username": "bob", "expiration": 18446744073709551}"#)).await;
+        assert_eq!(r.unwrap().name, "bob");
+    }
+
+    #[tokio::test]
+    async fn test_bad_tokens_are_unauthorized() {
+        let mock = Mock::new();
+
+        for header in [
+            bearer("not json"),
+            bearer(r#"{"username": "bob"}"#),
+            bearer(r#"{"username": "bob", "expiration": 1}"#),
+            bearer(r#"{"username": "bob", "expiration": "soon"}"#),
+            bearer(r#"{"expiration": 18446744073709551}"#),
+            "Bearer !!!".to_string(),
+            "Basic bob".to_string(),
+            "Digest bob".to_string(),
+        ] {
+            let r = mock.authorize(header.clone()).await;
+            assert!(matches!(r, Err(Error::Unauthorized(_))), "{}: {:?}", header, r);
+        }
+    }
 }
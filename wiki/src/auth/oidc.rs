@@ -0,0 +1,926 @@
+// oidc signs users in with an external OpenID Connect identity provider, using
+// the authorization code flow with PKCE. Signed in users are issued the same
+// tokens as password users, so their sessions are accepted by Jwt. The callback
+// redirects the browser back to the UI with a short-lived, single use code,
+// which the UI trades for the tokens, so that tokens are never put in URLs.
+// The login sets a cookie with its state, and callbacks without it are refused,
+// so that nobody can finish a login of their own in someone else's browser.
+
+use std::{future::Future, sync::{Arc, RwLock}, time::{Duration, Instant, SystemTime}};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use jsonwebtoken::{jwk::{Jwk, JwkSet}, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::{account::{validate_username, Accounts, Lifetimes}, jwt::Jwt, session::Sessions, user::Role}, error::Error};
+
+const LOGIN_LIFETIME: Duration = Duration::from_secs(600);
+const GRANT_LIFETIME: Duration = Duration::from_secs(60);
+/// Fragment parameter of the UI that receives the code of a grant
+const CODE_PARAMETER: &str = "oidc-code";
+/// Cookie with the state of the login the browser started
+const STATE_COOKIE: &str = "oidc_state";
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const LEEWAY_SECONDS: u64 = 30;
+
+// ALGORITHMS are the ID token algorithms accepted from providers. Symmetric
+// algorithms are not accepted, since they would require sharing the client
+// secret as a signing key.
+const ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::EdDSA,
+];
+
+pub trait Logins {
+    fn create_login(&self, login: &Login) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Deletes and returns the login, so that each state is used once
+    fn take_login(&self, state_hash: &[u8]) -> impl Future<Output = Result<Login, Error>> + Send;
+    fn create_grant(&self, grant: &Grant) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Deletes and returns the grant, so that each code is used once
+    fn take_grant(&self, code_hash: &[u8]) -> impl Future<Output = Result<Grant, Error>> + Send;
+}
+
+/// Login is a pending sign in, between redirecting the user to the provider
+/// and the provider redirecting back.
+#[derive(Debug, Clone)]
+pub struct Login {
+    pub state_hash: Vec<u8>,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub expires_at: SystemTime,
+}
+
+/// Grant is a completed sign in, between redirecting the user back to the UI
+/// and the UI trading its code for tokens.
+#[derive(Debug, Clone)]
+pub struct Grant {
+    pub code_hash: Vec<u8>,
+    pub username: String,
+    pub expires_at: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    pub username_claim: String,
+    pub role_claim: String,
+    /// Values of role_claim, and the roles they map to
+    pub role_map: Vec<(String, Role)>,
+}
+
+/// Parses a role mapping of the form `claim-value=role`
+pub fn parse_role_mapping(s: &str) -> Result<(String, Role), Error> {
+    match s.rsplit_once('=') {
+        Some((value, role)) if !value.is_empty() => Ok((value.to_string(), Role::parse(role)?)),
+        _ => Err(Error::BadRequest(format!("role mapping must be claim-value=role: {}", s))),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub code_challenge_methods_supported: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Identity is a user as asserted by a provider's ID token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Unique across providers
+    pub subject: String,
+    pub username: String,
+    pub roles: Vec<Role>,
+}
+
+pub struct Provider {
+    config: Config,
+    discovery: Discovery,
+    http: reqwest::Client,
+    jwks: RwLock<(JwkSet, Option<Instant>)>,
+}
+
+impl Provider {
+    /// Fetches and checks the provider's discovery document
+    pub async fn discover(config: Config) -> Result<Self, Error> {
+        let http = reqwest::Client::new();
+        let url = format!("{}/.well-known/openid-configuration", config.issuer.trim_end_matches('/'));
+        let discovery: Discovery = get_json(&http, &url).await?;
+
+        if discovery.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(Error::Internal(format!(
+                "discovered issuer {} does not match configured issuer {}",
+                discovery.issuer, config.issuer,
+            )));
+        }
+        if !discovery.code_challenge_methods_supported.is_empty()
+            && !discovery.code_challenge_methods_supported.iter().any(|m| m == "S256") {
+            return Err(Error::Internal("provider does not support S256 code challenges".into()));
+        }
+
+        Ok(Provider {
+            config,
+            discovery,
+            http,
+            jwks: RwLock::new((JwkSet { keys: vec![] }, None)),
+        })
+    }
+
+    fn authorization_url(&self, state: &str, nonce: &str, code_verifier: &str) -> Result<String, Error> {
+        let mut url = url::Url::parse(&self.discovery.authorization_endpoint)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", &code_challenge(code_verifier))
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.into())
+    }
+
+    /// Set-Cookie value of the state of a login, only sent back to the
+    /// callback. An empty state for no time clears it.
+    fn state_cookie(&self, state: &str, max_age: Duration) -> String {
+        let path = url::Url::parse(&self.config.redirect_uri)
+            .map_or_else(|_| "/".to_string(), |url| url.path().to_string());
+        format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+            STATE_COOKIE, state, path, max_age.as_secs(),
+        )
+    }
+
+    // exchange trades an authorization code for an ID token
+    async fn exchange(&self, code: &str, code_verifier: &str) -> Result<String, Error> {
+        let mut req = self.http.post(&self.discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("client_id", &self.config.client_id),
+                ("code_verifier", code_verifier),
+            ]);
+        if let Some(secret) = &self.config.client_secret {
+            req = req.basic_auth(&self.config.client_id, Some(secret));
+        }
+
+        let res = req.send().await
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(Error::Unauthorized(format!("token endpoint replied {}", res.status())));
+        }
+        let body: TokenResponse = res.json().await
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        Ok(body.id_token)
+    }
+
+    /// Validates an ID token, and maps its claims to an identity
+    pub async fn validate(&self, id_token: &str, nonce: &str) -> Result<Identity, Error> {
+        let header = jsonwebtoken::decode_header(id_token)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(Error::Unauthorized(format!("unsupported ID token algorithm: {:?}", header.alg)));
+        }
+        let jwk = self.jwk(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = LEEWAY_SECONDS;
+        validation.set_issuer(&[&self.discovery.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(id_token, &key, &validation)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?
+            .claims;
+
+        if claims.get("nonce").and_then(|n| n.as_str()) != Some(nonce) {
+            return Err(Error::Unauthorized("ID token nonce does not match".into()));
+        }
+        let multiple_audiences = claims.get("aud")
+            .and_then(|a| a.as_array())
+            .is_some_and(|a| a.len() > 1);
+        let azp = claims.get("azp").and_then(|a| a.as_str());
+        if (multiple_audiences || azp.is_some()) && azp != Some(self.config.client_id.as_str()) {
+            return Err(Error::Unauthorized("ID token azp does not match client".into()));
+        }
+
+        self.identity(&claims)
+    }
+
+    fn identity(&self, claims: &serde_json::Map<String, serde_json::Value>) -> Result<Identity, Error> {
+        let sub = claims.get("sub")
+            .and_then(|s| s.as_str())
+            .ok_or(Error::Unauthorized("ID token has no sub".into()))?;
+        let username = claims.get(&self.config.username_claim)
+            .and_then(|u| u.as_str())
+            .ok_or(Error::BadRequest(format!("ID token has no {} claim", self.config.username_claim)))?;
+        validate_username(username)?;
+
+        let values: Vec<&str> = match claims.get(&self.config.role_claim) {
+            Some(serde_json::Value::String(v)) => vec![v.as_str()],
+            Some(serde_json::Value::Array(vs)) => vs.iter().filter_map(|v| v.as_str()).collect(),
+            _ => vec![],
+        };
+        let mut roles = vec![];
+        for (value, role) in &self.config.role_map {
+            if values.contains(&value.as_str()) && !roles.contains(role) {
+                roles.push(*role);
+            }
+        }
+
+        Ok(Identity {
+            subject: format!("{} {}", self.discovery.issuer, sub),
+            username: username.to_string(),
+            roles,
+        })
+    }
+
+    // jwk finds a provider key by kid, refetching the provider's keys if it is
+    // unknown, so that provider key rotation is picked up.
+    async fn jwk(&self, kid: Option<&str>) -> Result<Jwk, Error> {
+        if let Some(jwk) = self.find_jwk(kid) {
+            return Ok(jwk);
+        }
+
+        let stale = self.jwks.read().unwrap().1
+            .is_none_or(|fetched| fetched.elapsed() >= JWKS_REFRESH_INTERVAL);
+        if stale {
+            let jwks: JwkSet = get_json(&self.http, &self.discovery.jwks_uri).await?;
+            *self.jwks.write().unwrap() = (jwks, Some(Instant::now()));
+        }
+
+        self.find_jwk(kid)
+            .ok_or(Error::Unauthorized(format!("unknown ID token kid: {:?}", kid)))
+    }
+
+    fn find_jwk(&self, kid: Option<&str>) -> Option<Jwk> {
+        let jwks = self.jwks.read().unwrap();
+        match kid {
+            Some(kid) => jwks.0.find(kid).cloned(),
+            None if jwks.0.keys.len() == 1 => Some(jwks.0.keys[0].clone()),
+            None => None,
+        }
+    }
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(http: &reqwest::Client, url: &str) -> Result<T, Error> {
+    http.get(url)
+        .send().await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| Error::Internal(format!("{}: {}", url, e)))?
+        .json().await
+        .map_err(|e| Error::Internal(format!("{}: {}", url, e)))
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Callback {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Code {
+    pub code: String,
+}
+
+/// Routes are not found when provider is None, so that OIDC is optional.
+pub fn filter<A, L, S>(
+    provider: Option<Arc<Provider>>,
+    accounts: Arc<A>,
+    logins: Arc<L>,
+    sessions: Arc<S>,
+    jwt: Arc<Jwt>,
+    lifetimes: Lifetimes,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    A: Accounts + Send + Sync + 'static,
+    L: Logins + Send + Sync + 'static,
+    S: Sessions + Send + Sync + 'static,
+{
+    warp::path!("oidc" / ..)
+        .and(
+            warp::path!("login").and(warp::get()).and(endpoints::login(provider.clone(), logins.clone()))
+            .or(warp::path!("callback").and(warp::get()).and(endpoints::callback(provider.clone(), accounts.clone(), logins.clone())))
+            .or(warp::path!("token").and(warp::post()).and(endpoints::token(provider, accounts, logins, sessions, jwt, lifetimes)))
+        )
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reject::Rejection, reply::Reply, Filter};
+
+    use crate::{auth::{account::{Accounts, Lifetimes}, jwt::Jwt, session::Sessions}, error};
+
+    use super::{handlers, Callback, Code, Logins, Provider, STATE_COOKIE};
+
+    pub fn login<L>(provider: Option<Arc<Provider>>, logins: Arc<L>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        L: Logins + Send + Sync + 'static
+    {
+        with_provider(provider)
+            .and(warp::any().map(move || logins.clone()))
+            .and_then(handlers::login)
+            .recover(error::recover)
+    }
+
+    pub fn callback<A, L>(provider: Option<Arc<Provider>>, accounts: Arc<A>, logins: Arc<L>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        A: Accounts + Send + Sync + 'static,
+        L: Logins + Send + Sync + 'static,
+    {
+        with_provider(provider)
+            .and(warp::any().map(move || accounts.clone()))
+            .and(warp::any().map(move || logins.clone()))
+            .and(warp::query::<Callback>())
+            .and(warp::cookie::optional::<String>(STATE_COOKIE))
+            .and_then(handlers::callback)
+            .recover(error::recover)
+    }
+
+    pub fn token<A, L, S>(provider: Option<Arc<Provider>>, accounts: Arc<A>, logins: Arc<L>, sessions: Arc<S>, jwt: Arc<Jwt>, lifetimes: Lifetimes) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        A: Accounts + Send + Sync + 'static,
+        L: Logins + Send + Sync + 'static,
+        S: Sessions + Send + Sync + 'static,
+    {
+        // codes are only granted by the provider's callback
+        with_provider(provider)
+            .map(|_| ())
+            .untuple_one()
+            .and(warp::any().map(move || accounts.clone()))
+            .and(warp::any().map(move || logins.clone()))
+            .and(warp::any().map(move || sessions.clone()))
+            .and(warp::any().map(move || jwt.clone()))
+            .and(warp::any().map(move || lifetimes))
+            .and(warp::body::json::<Code>())
+            .and_then(handlers::token)
+            .recover(error::recover)
+    }
+
+    fn with_provider(provider: Option<Arc<Provider>>) -> impl Filter<Extract = (Arc<Provider>,), Error = Rejection> + Clone
+    {
+        warp::any().and_then(move || {
+            let provider = provider.clone();
+            async move { provider.ok_or_else(warp::reject::not_found) }
+        })
+    }
+}
+
+mod handlers {
+    use std::{sync::Arc, time::{Duration, SystemTime}};
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{auth::{account::{Accounts, Lifetimes}, jwt::Jwt, session::{self, Sessions}, token}, error::Error};
+
+    use super::{Callback, Code, Grant, Login, Logins, Provider, CODE_PARAMETER, GRANT_LIFETIME, LOGIN_LIFETIME};
+
+    pub async fn login<L: Logins>(provider: Arc<Provider>, logins: Arc<L>) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let state = token::generate();
+            let login = Login {
+                state_hash: token::hash(&state),
+                code_verifier: token::generate(),
+                nonce: token::generate(),
+                expires_at: SystemTime::now() + LOGIN_LIFETIME,
+            };
+            logins.create_login(&login).await?;
+            let url = provider.authorization_url(&state, &login.nonce, &login.code_verifier)?;
+            Ok::<_, Error>((url, state))
+        }.await;
+
+        match r {
+            Ok((url, state)) => Ok(warp::reply::with_header(
+                warp::reply::with_header(
+                    warp::reply::with_status(warp::reply(), StatusCode::FOUND),
+                    "Location",
+                    url,
+                ),
+                "Set-Cookie",
+                provider.state_cookie(&state, LOGIN_LIFETIME),
+            )),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn callback<A: Accounts, L: Logins>(
+        provider: Arc<Provider>,
+        accounts: Arc<A>,
+        logins: Arc<L>,
+        callback: Callback,
+        state_cookie: Option<String>,
+    ) -> Result<impl Reply, Rejection> {
+        let r = async {
+            // the login was started in another browser
+            if state_cookie.as_deref() != Some(callback.state.as_str()) {
+                return Err(Error::Forbidden("login state does not match the state cookie".into()));
+            }
+            let login = match logins.take_login(&token::hash(&callback.state)).await {
+                Ok(login) => login,
+                Err(Error::NotFound(_)) => return Err(Error::Unauthorized("unknown login state".into())),
+                Err(err) => return Err(err),
+            };
+            if SystemTime::now() > login.expires_at {
+                return Err(Error::Unauthorized("login is expired".into()));
+            }
+            if let Some(error) = callback.error {
+                return Err(Error::Unauthorized(format!("provider replied: {}", error)));
+            }
+            let code = callback.code
+                .ok_or(Error::BadRequest("no code".into()))?;
+
+            let id_token = provider.exchange(&code, &login.code_verifier).await?;
+            let identity = provider.validate(&id_token, &login.nonce).await?;
+            let account = accounts.upsert_external_user(&identity.subject, &identity.username, &identity.roles).await?;
+
+            let code = token::generate();
+            logins.create_grant(&Grant {
+                code_hash: token::hash(&code),
+                username: account.username,
+                expires_at: SystemTime::now() + GRANT_LIFETIME,
+            }).await?;
+            Ok(code)
+        }.await;
+
+        match r {
+            Ok(code) => Ok(warp::reply::with_header(
+                warp::reply::with_header(
+                    warp::reply::with_status(warp::reply(), StatusCode::FOUND),
+                    "Location",
+                    format!("/#{}={}", CODE_PARAMETER, code),
+                ),
+                "Set-Cookie",
+                provider.state_cookie("", Duration::ZERO),
+            )),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn token<A: Accounts, L: Logins, S: Sessions>(
+        accounts: Arc<A>,
+        logins: Arc<L>,
+        sessions: Arc<S>,
+        jwt: Arc<Jwt>,
+        lifetimes: Lifetimes,
+        code: Code,
+    ) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let grant = match logins.take_grant(&token::hash(&code.code)).await {
+                Ok(grant) => grant,
+                Err(Error::NotFound(_)) => return Err(Error::Unauthorized("unknown code".into())),
+                Err(err) => return Err(err),
+            };
+            if SystemTime::now() > grant.expires_at {
+                return Err(Error::Unauthorized("code is expired".into()));
+            }
+            let account = accounts.read_user(&grant.username).await?;
+            session::start(sessions.as_ref(), &jwt, &account.user(), lifetimes).await
+        }.await;
+
+        match r {
+            Ok(tokens) => Ok(warp::reply::json(&tokens)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules, end-to-end against a
+/// mock provider.
+///
+/// Test plan:
+/// 1. Role mappings parse
+/// 2. Discovery rejects mismatched issuers
+/// 3. Sign in redirects to the provider, the callback redirects to the UI
+///    with a code, and the code is traded for tokens
+/// 4. Login states and codes are single use
+/// 5. Bad ID tokens are rejected
+/// 6. Routes are not found without a provider
+/// 7. Callbacks without the state cookie of their login are forbidden
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+    use jsonwebtoken::{jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType}, EncodingKey, Header};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use warp::http::StatusCode;
+
+    use crate::auth::{account::{tests::{lifetimes, MemoryAccounts}, Tokens}, jwt::{self, tests::new_jwt}, session::{self, tests::MemorySessions}, user::User};
+
+    use super::*;
+
+    const CLIENT_ID: &str = "wiki";
+    const REDIRECT_URI: &str = "https://wiki.example.com/auth/v1/oidc/callback";
+
+    #[derive(Default)]
+    struct MemoryLogins {
+        logins: Mutex<HashMap<Vec<u8>, Login>>,
+        grants: Mutex<HashMap<Vec<u8>, Grant>>,
+    }
+
+    impl Logins for MemoryLogins {
+        async fn create_login(&self, login: &Login) -> Result<(), Error> {
+            self.logins.lock().unwrap().insert(login.state_hash.clone(), login.clone());
+            Ok(())
+        }
+
+        async fn take_login(&self, state_hash: &[u8]) -> Result<Login, Error> {
+            self.logins.lock().unwrap()
+                .remove(state_hash)
+                .ok_or(Error::NotFound("login".into()))
+        }
+
+        async fn create_grant(&self, grant: &Grant) -> Result<(), Error> {
+            self.grants.lock().unwrap().insert(grant.code_hash.clone(), grant.clone());
+            Ok(())
+        }
+
+        async fn take_grant(&self, code_hash: &[u8]) -> Result<Grant, Error> {
+            self.grants.lock().unwrap()
+                .remove(code_hash)
+                .ok_or(Error::NotFound("grant".into()))
+        }
+    }
+
+    // MockProvider is an identity provider that approves every authorization
+    // request. Claims in overrides replace the claims of issued ID tokens.
+    struct MockProvider {
+        base: String,
+        kid: String,
+        pkcs8: Vec<u8>,
+        public_key: Vec<u8>,
+        codes: Mutex<HashMap<String, (String, String)>>,
+        overrides: Mutex<serde_json::Value>,
+    }
+
+    impl MockProvider {
+        async fn start() -> Arc<MockProvider> {
+            let rng = ring::rand::SystemRandom::new();
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec();
+            let public_key = Ed25519KeyPair::from_pkcs8(&pkcs8).unwrap().public_key().as_ref().to_vec();
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr: SocketAddr = listener.local_addr().unwrap();
+            let provider = Arc::new(MockProvider {
+                base: format!("http://{}", addr),
+                kid: "mock-key".into(),
+                pkcs8,
+                public_key,
+                codes: Mutex::new(HashMap::new()),
+                overrides: Mutex::new(serde_json::json!({})),
+            });
+
+            let p = provider.clone();
+            let discovery = warp::path!(".well-known" / "openid-configuration")
+                .map(move || warp::reply::json(&serde_json::json!({
+                    "issuer": p.base,
+                    "authorization_endpoint": format!("{}/authorize", p.base),
+                    "token_endpoint": format!("{}/token", p.base),
+                    "jwks_uri": format!("{}/jwks", p.base),
+                    "code_challenge_methods_supported": ["S256"],
+                })));
+            let p = provider.clone();
+            let jwks = warp::path!("jwks")
+                .map(move || warp::reply::json(&JwkSet { keys: vec![p.jwk()] }));
+            let p = provider.clone();
+            let authorize = warp::path!("authorize")
+                .and(warp::query::<HashMap<String, String>>())
+                .map(move |q: HashMap<String, String>| {
+                    assert_eq!(q["response_type"], "code");
+                    assert_eq!(q["code_challenge_method"], "S256");
+                    let code = crate::auth::token::generate();
+                    p.codes.lock().unwrap().insert(code.clone(), (q["code_challenge"].clone(), q["nonce"].clone()));
+                    let mut url = url::Url::parse(&q["redirect_uri"]).unwrap();
+                    url.query_pairs_mut()
+                        .append_pair("code", &code)
+                        .append_pair("state", &q["state"]);
+                    warp::reply::with_header(
+                        warp::reply::with_status(warp::reply(), StatusCode::FOUND),
+                        "Location",
+                        String::from(url),
+                    )
+                });
+            let p = provider.clone();
+            let token = warp::path!("token")
+                .and(warp::body::form::<HashMap<String, String>>())
+                .map(move |f: HashMap<String, String>| {
+                    let (challenge, nonce) = match p.codes.lock().unwrap().remove(&f["code"]) {
+                        Some(c) => c,
+                        None => return warp::reply::with_status(warp::reply::json(&"bad code"), StatusCode::BAD_REQUEST),
+                    };
+                    if code_challenge(&f["code_verifier"]) != challenge || f["client_id"] != CLIENT_ID {
+                        return warp::reply::with_status(warp::reply::json(&"bad verifier"), StatusCode::BAD_REQUEST);
+                    }
+                    warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({"id_token": p.id_token(&nonce)})),
+                        StatusCode::OK,
+                    )
+                });
+
+            tokio::spawn(warp::serve(discovery.or(jwks).or(authorize).or(token)).run_incoming(
+                tokio_stream::wrappers::TcpListenerStream::new(listener),
+            ));
+            provider
+        }
+
+        fn jwk(&self) -> Jwk {
+            Jwk {
+                common: CommonParameters {
+                    key_algorithm: Some(KeyAlgorithm::EdDSA),
+                    key_id: Some(self.kid.clone()),
+                    ..Default::default()
+                },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(&self.public_key),
+                }),
+            }
+        }
+
+        fn id_token(&self, nonce: &str) -> String {
+            let now = jsonwebtoken::get_current_timestamp();
+            let mut claims = serde_json::json!({
+                "iss": self.base,
+                "aud": CLIENT_ID,
+                "sub": "user-1",
+                "iat": now,
+                "exp": now + 60,
+                "nonce": nonce,
+                "preferred_username": "alice",
+                "groups": ["wiki-admins", "staff"],
+            });
+            for (k, v) in self.overrides.lock().unwrap().as_object().unwrap() {
+                claims[k] = v.clone();
+            }
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.clone());
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_ed_der(&self.pkcs8)).unwrap()
+        }
+
+        fn config(&self) -> Config {
+            Config {
+                issuer: self.base.clone(),
+                client_id: CLIENT_ID.into(),
+                client_secret: None,
+                redirect_uri: REDIRECT_URI.into(),
+                scopes: "openid profile".into(),
+                username_claim: "preferred_username".into(),
+                role_claim: "groups".into(),
+                role_map: vec![
+                    ("wiki-admins".into(), Role::Admin),
+                    ("wiki-editors".into(), Role::Editor),
+                ],
+            }
+        }
+    }
+
+    struct Harness {
+        mock: Arc<MockProvider>,
+        accounts: Arc<MemoryAccounts>,
+        sessions: Arc<MemorySessions>,
+        jwt: Arc<Jwt>,
+        filter: warp::filters::BoxedFilter<(Box<dyn Reply>,)>,
+    }
+
+    impl Harness {
+        async fn new() -> Self {
+            let mock = MockProvider::start().await;
+            let provider = Arc::new(Provider::discover(mock.config()).await.unwrap());
+            let accounts = Arc::new(MemoryAccounts::default());
+            let sessions = Arc::new(MemorySessions::default());
+            let jwt = Arc::new(new_jwt(jwt::Algorithm::EdDSA).await);
+            let filter = filter(Some(provider), accounts.clone(), Arc::new(MemoryLogins::default()), sessions.clone(), jwt.clone(), lifetimes())
+                .map(|r| Box::new(r) as Box<dyn Reply>)
+                .boxed();
+            Harness { mock, accounts, sessions, jwt, filter }
+        }
+
+        // authorize follows the login redirect to the provider, and returns
+        // the provider's redirect back to the callback, with the state cookie
+        // of the login
+        async fn authorize(&self) -> (String, String) {
+            let res = warp::test::request()
+                .path("/oidc/login")
+                .reply(&self.filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::FOUND);
+            let cookie = res.headers()["Set-Cookie"].to_str().unwrap();
+            assert!(cookie.ends_with("; Path=/auth/v1/oidc/callback; Max-Age=600; HttpOnly; Secure; SameSite=Lax"), "{}", cookie);
+            let cookie = cookie.split_once(';').unwrap().0.to_string();
+            let location = res.headers()["Location"].to_str().unwrap();
+            assert!(location.starts_with(&format!("{}/authorize?", self.mock.base)), "{}", location);
+
+            let res = reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build().unwrap()
+                .get(location)
+                .send().await.unwrap();
+            assert_eq!(res.status(), reqwest::StatusCode::FOUND);
+            let callback = res.headers()["Location"].to_str().unwrap();
+            let path = callback.strip_prefix("https://wiki.example.com/auth/v1").unwrap();
+            (path.to_string(), cookie)
+        }
+
+        async fn callback(&self, path: &str, cookie: Option<&str>) -> warp::http::Response<bytes::Bytes> {
+            let mut req = warp::test::request().path(path);
+            if let Some(cookie) = cookie {
+                req = req.header("Cookie", cookie);
+            }
+            req.reply(&self.filter).await
+        }
+
+        // complete authorizes and calls back from the same browser
+        async fn complete(&self) -> warp::http::Response<bytes::Bytes> {
+            let (path, cookie) = self.authorize().await;
+            self.callback(&path, Some(&cookie)).await
+        }
+
+        // code returns the code of the callback's redirect back to the UI
+        fn code(res: &warp::http::Response<bytes::Bytes>) -> String {
+            assert_eq!(res.status(), StatusCode::FOUND, "{:?}", res.body());
+            let location = res.headers()["Location"].to_str().unwrap();
+            location.strip_prefix("/#oidc-code=").unwrap().to_string()
+        }
+
+        async fn token(&self, code: &str) -> warp::http::Response<bytes::Bytes> {
+            warp::test::request()
+                .method("POST")
+                .path("/oidc/token")
+                .json(&serde_json::json!({"code": code}))
+                .reply(&self.filter)
+                .await
+        }
+
+        async fn sign_in(&self) -> Tokens {
+            let res = self.complete().await;
+            let res = self.token(&Self::code(&res)).await;
+            assert_eq!(res.status(), StatusCode::OK, "{:?}", res.body());
+            serde_json::from_slice(res.body()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_parse_role_mapping() {
+        assert_eq!(parse_role_mapping("wiki-admins=admin").unwrap(), ("wiki-admins".into(), Role::Admin));
+        assert_eq!(parse_role_mapping("a=b=editor").unwrap(), ("a=b".into(), Role::Editor));
+        for s in ["wiki-admins", "=admin", "wiki-admins=root"] {
+            assert!(parse_role_mapping(s).is_err(), "{}", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery_rejects_mismatched_issuer() {
+        let mock = MockProvider::start().await;
+        let mut config = mock.config();
+        config.issuer = format!("{}/", mock.base);
+        assert!(Provider::discover(config.clone()).await.is_ok());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let base = mock.base.clone();
+        tokio::spawn(warp::serve(warp::any().map(move || warp::reply::json(&serde_json::json!({
+            "issuer": base,
+            "authorization_endpoint": "",
+            "token_endpoint": "",
+            "jwks_uri": "",
+        })))).run_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)));
+        config.issuer = format!("http://{}", addr);
+        assert!(Provider::discover(config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sign_in() {
+        let h = Harness::new().await;
+        let tokens = h.sign_in().await;
+        assert_eq!(tokens.username, "alice");
+
+        let user = session::authorize(h.sessions.as_ref(), &h.jwt, &tokens.token).await.unwrap();
+        assert_eq!(user, User {
+            roles: vec![Role::Admin],
+            session: Some(h.jwt.verify(&tokens.token).unwrap().sid),
+            ..User::new("alice")
+        });
+
+        // roles follow the provider on every sign in
+        *h.mock.overrides.lock().unwrap() = serde_json::json!({"groups": "wiki-editors"});
+        let tokens = h.sign_in().await;
+        let user = session::authorize(h.sessions.as_ref(), &h.jwt, &tokens.token).await.unwrap();
+        assert_eq!(user.roles, vec![Role::Editor]);
+
+        // provider users cannot sign in with a password
+        let account = h.accounts.read_user("alice").await.unwrap();
+        assert!(account.password_hash.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_login_state_is_single_use() {
+        let h = Harness::new().await;
+        let (callback, cookie) = h.authorize().await;
+        let res = h.callback(&callback, Some(&cookie)).await;
+        assert!(res.headers()["Set-Cookie"].to_str().unwrap().starts_with("oidc_state=; Path=/auth/v1/oidc/callback; Max-Age=0;"));
+        let code = Harness::code(&res);
+        assert_eq!(h.callback(&callback, Some(&cookie)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(h.token(&code).await.status(), StatusCode::OK);
+        assert_eq!(h.token(&code).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(h.token("unknown").await.status(), StatusCode::UNAUTHORIZED);
+
+        let res = h.callback("/oidc/callback?state=unknown&code=unknown", Some("oidc_state=unknown")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = h.callback("/oidc/callback", None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_callback_requires_state_cookie() {
+        let h = Harness::new().await;
+        // an attacker's login, called back in a victim's browser
+        let (callback, _) = h.authorize().await;
+        let (_, victims) = h.authorize().await;
+        for cookie in [None, Some(victims.as_str()), Some("oidc_state=")] {
+            let res = h.callback(&callback, cookie).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{:?}", cookie);
+        }
+        assert!(h.accounts.read_user("alice").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bad_id_tokens_are_rejected() {
+        let h = Harness::new().await;
+        let now = jsonwebtoken::get_current_timestamp();
+        for overrides in [
+            serde_json::json!({"iss": "https://evil.example.com"}),
+            serde_json::json!({"aud": "other-client"}),
+            serde_json::json!({"aud": [CLIENT_ID, "other-client"]}),
+            serde_json::json!({"azp": "other-client"}),
+            serde_json::json!({"nonce": "replayed"}),
+            serde_json::json!({"exp": now - 2 * LEEWAY_SECONDS}),
+        ] {
+            *h.mock.overrides.lock().unwrap() = overrides.clone();
+            let res = h.complete().await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", overrides);
+        }
+
+        for overrides in [
+            serde_json::json!({"preferred_username": null}),
+            serde_json::json!({"preferred_username": "alice smith"}),
+        ] {
+            *h.mock.overrides.lock().unwrap() = overrides.clone();
+            let res = h.complete().await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", overrides);
+        }
+
+        // control
+        *h.mock.overrides.lock().unwrap() = serde_json::json!({"aud": [CLIENT_ID, "other-client"], "azp": CLIENT_ID});
+        let res = h.complete().await;
+        assert_eq!(res.status(), StatusCode::FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_not_found_without_provider() {
+        let f = filter(
+            None,
+            Arc::new(MemoryAccounts::default()),
+            Arc::new(MemoryLogins::default()),
+            Arc::new(MemorySessions::default()),
+            Arc::new(new_jwt(jwt::Algorithm::EdDSA).await),
+            lifetimes(),
+        );
+        for (method, path) in [("GET", "/oidc/login"), ("GET", "/oidc/callback?state=a&code=b"), ("POST", "/oidc/token")] {
+            let res = warp::test::request()
+                .method(method)
+                .path(path)
+                .json(&serde_json::json!({"code": "b"}))
+                .reply(&f)
+                .await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+}
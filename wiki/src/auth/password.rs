@@ -0,0 +1,67 @@
+// password hashes and verifies passwords with argon2. Hashing is deliberately
+// expensive, so it runs on the blocking thread pool.
+
+use argon2::{password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
+use rand::RngCore;
+
+use crate::error::Error;
+
+const SALT_LENGTH: usize = 16;
+
+pub async fn hash(password: &str) -> Result<String, Error> {
+    let password = password.to_string();
+    let r = tokio::task::spawn_blocking(move || {
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|h| h.to_string())
+            .map_err(|e| Error::Internal(e.to_string()))
+    }).await;
+
+    match r {
+        Ok(r) => r,
+        Err(e) => Err(Error::Internal(e.to_string())),
+    }
+}
+
+pub async fn verify(password: &str, hash: &str) -> Result<bool, Error> {
+    let password = password.to_string();
+    let hash = hash.to_string();
+    let r = tokio::task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&hash)
+            .map_err(|e| Error::Internal(e.to_string()))?;
+
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok())
+    }).await;
+
+    match r {
+        Ok(r) => r,
+        Err(e) => Err(Error::Internal(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hash_and_verify() {
+        let h = hash("correct horse").await.unwrap();
+        assert_ne!(h, "correct horse");
+        assert!(verify("correct horse", &h).await.unwrap());
+        assert!(!verify("battery staple", &h).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_hashes_are_salted() {
+        let a = hash("correct horse").await.unwrap();
+        let b = hash("correct horse").await.unwrap();
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,460 @@
+// personal_token lets users mint long lived bearer tokens for automation, so
+// bots need not sign in as a human. Tokens are limited to scopes, are only
+// stored as hashes, and record when they were last used.
+
+use std::{future::Future, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::{account::Accounts, token, user::{Scope, User, Users}}, error::Error};
+
+/// PREFIX tells personal access tokens apart from JWTs in bearer headers.
+pub const PREFIX: &str = "wiki_pat_";
+
+const MAX_NAME_LENGTH: usize = 128;
+/// Longest lifetime of tokens that expire, five years
+const MAX_EXPIRES_IN: u64 = 5 * 366 * 24 * 60 * 60;
+
+pub trait PersonalTokens {
+    /// Stores token and returns its id
+    fn create_personal_token(&self, token: &PersonalToken) -> impl Future<Output = Result<i64, Error>> + Send;
+    fn list_personal_tokens(&self, username: &str) -> impl Future<Output = Result<Vec<PersonalToken>, Error>> + Send;
+    /// Reads the token with hash, and records it as last used at now
+    fn use_personal_token(&self, hash: &[u8], now: SystemTime) -> impl Future<Output = Result<PersonalToken, Error>> + Send;
+    /// Deletes the token with id, if it belongs to username
+    fn delete_personal_token(&self, username: &str, id: i64) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct PersonalToken {
+    pub id: i64,
+    pub hash: Vec<u8>,
+    pub username: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: SystemTime,
+    /// None for tokens that never expire
+    pub expires_at: Option<SystemTime>,
+    pub last_used_at: Option<SystemTime>,
+}
+
+impl PersonalToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| SystemTime::now() > t)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Lifetime of the token in seconds, at most five years, omit for tokens
+    /// that never expire
+    pub expires_in: Option<u64>,
+}
+
+/// TokenInfo describes a token. The token itself is only returned once, when
+/// it is created.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Times are seconds since the unix epoch
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl From<&PersonalToken> for TokenInfo {
+    fn from(t: &PersonalToken) -> Self {
+        TokenInfo {
+            id: t.id,
+            name: t.name.clone(),
+            scopes: t.scopes.clone(),
+            created_at: unix(t.created_at),
+            expires_at: t.expires_at.map(unix),
+            last_used_at: t.last_used_at.map(unix),
+            token: None,
+        }
+    }
+}
+
+fn unix(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Authorizes a personal access token, including its prefix. The user's roles
+/// are read from their account, so that revoked roles take effect at once.
+pub async fn authorize<A, T>(accounts: &A, tokens: &T, personal_token: &str) -> Result<User, Error>
+where
+    A: Accounts,
+    T: PersonalTokens,
+{
+    let t = match tokens.use_personal_token(&token::hash(personal_token), SystemTime::now()).await {
+        Ok(t) => t,
+        Err(Error::NotFound(_)) => return Err(Error::Unauthorized("unknown personal token".into())),
+        Err(err) => return Err(err),
+    };
+    if t.is_expired() {
+        return Err(Error::Unauthorized(format!("personal token {} is expired", t.id)));
+    }
+
+    let account = accounts.read_user(&t.username).await?;
+    Ok(User {
+        roles: account.roles,
+        scopes: t.scopes,
+        ..User::new(&account.username)
+    })
+}
+
+fn validate(new: &NewToken) -> Result<(), Error> {
+    if new.name.is_empty() || new.name.chars().count() > MAX_NAME_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "name must be between 1 and {} characters", MAX_NAME_LENGTH,
+        )));
+    }
+    if new.scopes.is_empty() {
+        return Err(Error::BadRequest("at least one scope is required".into()));
+    }
+    if let Some(expires_in) = new.expires_in
+        && !(1..=MAX_EXPIRES_IN).contains(&expires_in)
+    {
+        return Err(Error::BadRequest(format!("expires_in must be between 1 and {} seconds", MAX_EXPIRES_IN)));
+    }
+    Ok(())
+}
+
+/// Managing tokens requires the admin scope, so a token cannot mint tokens
+/// with more scopes than itself.
+pub fn filter<T, U>(tokens: Arc<T>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    T: PersonalTokens + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+{
+    warp::path!("tokens")
+        .and(
+            warp::get().and(endpoints::list(tokens.clone(), users.clone()))
+            .or(warp::post().and(endpoints::create(tokens.clone(), users.clone())))
+        )
+        .or(warp::path!("tokens" / ..).and(warp::delete()).and(endpoints::revoke(tokens, users)))
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{auth::user::{with_authorization, Scope, Users}, error};
+
+    use super::{handlers, PersonalTokens};
+
+    pub fn list<T, U>(tokens: Arc<T>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        T: PersonalTokens + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_tokens(tokens)
+            .and(with_authorization(users, Scope::Admin))
+            .and_then(handlers::list)
+            .recover(error::recover)
+    }
+
+    pub fn create<T, U>(tokens: Arc<T>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        T: PersonalTokens + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_tokens(tokens)
+            .and(with_authorization(users, Scope::Admin))
+            .and(warp::body::json())
+            .and_then(handlers::create)
+            .recover(error::recover)
+    }
+
+    pub fn revoke<T, U>(tokens: Arc<T>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        T: PersonalTokens + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        warp::path::param()
+            .and(warp::path::end())
+            .and(with_tokens(tokens))
+            .and(with_authorization(users, Scope::Admin))
+            .and_then(handlers::revoke)
+            .recover(error::recover)
+    }
+
+    fn with_tokens<T>(tokens: Arc<T>) -> impl Filter<Extract = (Arc<T>,), Error = Infallible> + Clone
+    where
+        T: PersonalTokens + Send + Sync + 'static
+    {
+        warp::any().map(move || tokens.clone())
+    }
+}
+
+mod handlers {
+    use std::{sync::Arc, time::{Duration, SystemTime}};
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{auth::{token, user::User}, error::Error};
+
+    use super::{validate, NewToken, PersonalToken, PersonalTokens, TokenInfo, PREFIX};
+
+    pub async fn list<T: PersonalTokens>(tokens: Arc<T>, user: User) -> Result<impl Reply, Rejection> {
+        match tokens.list_personal_tokens(&user.name).await {
+            Ok(list) => Ok(warp::reply::json(&list.iter().map(TokenInfo::from).collect::<Vec<_>>())),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn create<T: PersonalTokens>(tokens: Arc<T>, user: User, new: NewToken) -> Result<impl Reply, Rejection> {
+        let r = async {
+            validate(&new)?;
+            let secret = format!("{}{}", PREFIX, token::generate());
+            let now = SystemTime::now();
+            let expires_at = match new.expires_in {
+                Some(s) => Some(now.checked_add(Duration::from_secs(s))
+                    .ok_or_else(|| Error::BadRequest("expires_in is too long".into()))?),
+                None => None,
+            };
+            let mut t = PersonalToken {
+                id: 0,
+                hash: token::hash(&secret),
+                username: user.name.clone(),
+                name: new.name,
+                scopes: new.scopes,
+                created_at: now,
+                expires_at,
+                last_used_at: None,
+            };
+            t.id = tokens.create_personal_token(&t).await?;
+            Ok::<_, Error>(TokenInfo {
+                token: Some(secret),
+                ..TokenInfo::from(&t)
+            })
+        }.await;
+
+        match r {
+            Ok(info) => Ok(warp::reply::with_status(warp::reply::json(&info), StatusCode::CREATED)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn revoke<T: PersonalTokens>(id: i64, tokens: Arc<T>, user: User) -> Result<impl Reply, Rejection> {
+        match tokens.delete_personal_token(&user.name, id).await {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests filter, and endpoints and handlers modules.
+/// Exposes in-memory personal tokens to other test modules.
+///
+/// Test plan:
+/// 1. Tokens are created, listed and revoked by their owner
+/// 2. Tokens authorize with their scopes only
+/// 3. Invalid tokens are rejected
+/// 4. Bad auth replies with error
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use warp::http::StatusCode;
+
+    use crate::auth::{
+        account::tests::{lifetimes, MemoryAccounts},
+        database::Database,
+        jwt::{tests::new_jwt, Algorithm, Jwt},
+        session::{self, tests::MemorySessions},
+        user::Users,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct MemoryPersonalTokens {
+        tokens: Mutex<Vec<PersonalToken>>,
+    }
+
+    impl PersonalTokens for MemoryPersonalTokens {
+        async fn create_personal_token(&self, token: &PersonalToken) -> Result<i64, Error> {
+            let mut tokens = self.tokens.lock().unwrap();
+            let id = tokens.iter().map(|t| t.id).max().unwrap_or(0) + 1;
+            tokens.push(PersonalToken { id, ..token.clone() });
+            Ok(id)
+        }
+
+        async fn list_personal_tokens(&self, username: &str) -> Result<Vec<PersonalToken>, Error> {
+            Ok(self.tokens.lock().unwrap().iter().filter(|t| t.username == username).cloned().collect())
+        }
+
+        async fn use_personal_token(&self, hash: &[u8], now: SystemTime) -> Result<PersonalToken, Error> {
+            match self.tokens.lock().unwrap().iter_mut().find(|t| t.hash == hash) {
+                Some(t) => {
+                    t.last_used_at = Some(now);
+                    Ok(t.clone())
+                },
+                None => Err(Error::NotFound("personal token".into())),
+            }
+        }
+
+        async fn delete_personal_token(&self, username: &str, id: i64) -> Result<(), Error> {
+            let mut tokens = self.tokens.lock().unwrap();
+            match tokens.iter().position(|t| t.id == id && t.username == username) {
+                Some(i) => {
+                    tokens.remove(i);
+                    Ok(())
+                },
+                None => Err(Error::NotFound(format!("personal token {}", id))),
+            }
+        }
+    }
+
+    struct Harness {
+        filter: warp::filters::BoxedFilter<(Box<dyn Reply>,)>,
+        users: Arc<Database<MemoryAccounts, MemoryPersonalTokens, MemorySessions>>,
+        sessions: Arc<MemorySessions>,
+        jwt: Arc<Jwt>,
+    }
+
+    impl Harness {
+        async fn new() -> Self {
+            let accounts = Arc::new(MemoryAccounts::default());
+            accounts.create_user("bob", "hash").await.unwrap();
+            accounts.create_user("alice", "hash").await.unwrap();
+            let tokens = Arc::new(MemoryPersonalTokens::default());
+            let sessions = Arc::new(MemorySessions::default());
+            let jwt = Arc::new(new_jwt(Algorithm::EdDSA).await);
+            let users = Arc::new(Database::new(accounts, tokens.clone(), sessions.clone(), jwt.clone()));
+            Harness {
+                filter: filter(tokens, users.clone()).map(|r| Box::new(r) as Box<dyn Reply>).boxed(),
+                users,
+                sessions,
+                jwt,
+            }
+        }
+
+        async fn session(&self, username: &str) -> String {
+            let tokens = session::start(self.sessions.as_ref(), &self.jwt, &User::new(username), lifetimes()).await.unwrap();
+            format!("Bearer {}", tokens.token)
+        }
+
+        async fn request(&self, method: &str, path: &str, auth: &str, body: Option<serde_json::Value>) -> warp::http::Response<bytes::Bytes> {
+            let mut req = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Authorization", auth);
+            if let Some(body) = body {
+                req = req.json(&body);
+            }
+            req.reply(&self.filter).await
+        }
+
+        async fn create(&self, auth: &str, body: serde_json::Value) -> TokenInfo {
+            let res = self.request("POST", "/tokens", auth, Some(body)).await;
+            assert_eq!(res.status(), StatusCode::CREATED, "{:?}", res.body());
+            serde_json::from_slice(res.body()).unwrap()
+        }
+
+        async fn list(&self, auth: &str) -> Vec<TokenInfo> {
+            let res = self.request("GET", "/tokens", auth, None).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            serde_json::from_slice(res.body()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_managed_by_owner() {
+        let h = Harness::new().await;
+        let bob = h.session("bob").await;
+        let alice = h.session("alice").await;
+
+        let info = h.create(&bob, serde_json::json!({"name": "status bot", "scopes": ["read", "write"], "expires_in": 3600})).await;
+        assert!(info.token.as_ref().unwrap().starts_with(PREFIX));
+        assert_eq!(info.scopes, vec![Scope::Read, Scope::Write]);
+        assert_eq!(info.expires_at, Some(info.created_at + 3600));
+
+        // secrets are only returned on creation
+        let list = h.list(&bob).await;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, info.id);
+        assert!(list[0].token.is_none());
+        assert!(list[0].last_used_at.is_none());
+        assert!(h.list(&alice).await.is_empty());
+
+        // last use is recorded
+        h.users.authorize(format!("Bearer {}", info.token.unwrap())).await.unwrap();
+        assert!(h.list(&bob).await[0].last_used_at.is_some());
+
+        // only the owner may revoke
+        let path = format!("/tokens/{}", info.id);
+        let res = h.request("DELETE", &path, &alice, None).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = h.request("DELETE", &path, &bob, None).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(h.list(&bob).await.is_empty());
+        let res = h.request("DELETE", &path, &bob, None).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_tokens_authorize_with_their_scopes() {
+        let h = Harness::new().await;
+        let bob = h.session("bob").await;
+
+        let read = h.create(&bob, serde_json::json!({"name": "reader", "scopes": ["read"]})).await;
+        let read = format!("Bearer {}", read.token.unwrap());
+        let user = h.users.authorize(read.clone()).await.unwrap();
+        assert_eq!(user.name, "bob");
+        assert_eq!(user.scopes, vec![Scope::Read]);
+        assert!(matches!(user.require(Scope::Write), Err(Error::Forbidden(_))));
+
+        // managing tokens requires the admin scope
+        let res = h.request("GET", "/tokens", &read, None).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = h.request("POST", "/tokens", &read, Some(serde_json::json!({"name": "escalate", "scopes": ["admin"]}))).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let admin = h.create(&bob, serde_json::json!({"name": "admin", "scopes": ["admin"]})).await;
+        assert_eq!(h.list(&format!("Bearer {}", admin.token.unwrap())).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_tokens_are_rejected() {
+        let h = Harness::new().await;
+        let bob = h.session("bob").await;
+
+        for body in [
+            serde_json::json!({"name": "", "scopes": ["read"]}),
+            serde_json::json!({"name": "x".repeat(MAX_NAME_LENGTH + 1), "scopes": ["read"]}),
+            serde_json::json!({"name": "bot", "scopes": []}),
+            serde_json::json!({"name": "bot", "scopes": ["root"]}),
+            serde_json::json!({"name": "bot", "scopes": ["read"], "expires_in": 0}),
+            serde_json::json!({"name": "bot", "scopes": ["read"], "expires_in": MAX_EXPIRES_IN + 1}),
+            serde_json::json!({"name": "bot", "scopes": ["read"], "expires_in": u64::MAX}),
+            serde_json::json!({"name": "bot"}),
+        ] {
+            let res = h.request("POST", "/tokens", &bob, Some(body.clone())).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        assert!(h.list(&bob).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bad_auth_replies_with_error() {
+        let h = Harness::new().await;
+
+        let res = warp::test::request().method("GET").path("/tokens").reply(&h.filter).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        for auth in [format!("Bearer {}unknown", PREFIX), "Bearer not a token".into()] {
+            let res = h.request("GET", "/tokens", &auth, None).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", auth);
+        }
+    }
+}
@@ -0,0 +1,592 @@
+// session tracks signed in users on the server, so that tokens can be revoked.
+// Each sign in starts a session. Its refresh tokens rotate on every use, and
+// a refresh token used twice was leaked, so its whole session is revoked.
+// Access tokens carry their session id, and are rejected once it is revoked.
+// Sessions also record when they last passed a second factor.
+
+use std::{future::Future, sync::Arc, time::{Duration, SystemTime}};
+
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::{account::{Accounts, Lifetimes, Tokens}, jwt::Jwt, token, user::{User, Users}}, error::Error};
+
+pub trait Sessions {
+    /// Stores session and returns its id
+    fn create_session(&self, session: &Session) -> impl Future<Output = Result<i64, Error>> + Send;
+    /// Reads a live session, revoked and expired sessions are not found
+    fn read_session(&self, id: i64) -> impl Future<Output = Result<Session, Error>> + Send;
+    /// Records that a live session passed a second factor at
+    fn record_second_factor(&self, id: i64, at: SystemTime) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Revokes a session and its refresh tokens
+    fn revoke_session(&self, id: i64) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Revokes all of a user's sessions, and returns their ids
+    fn revoke_sessions(&self, username: &str) -> impl Future<Output = Result<Vec<i64>, Error>> + Send;
+    /// Stores a refresh token, and extends its session until the token expires
+    fn create_token(&self, token: &Token) -> impl Future<Output = Result<(), Error>> + Send;
+    fn read_token(&self, hash: &[u8]) -> impl Future<Output = Result<Token, Error>> + Send;
+    /// Marks a refresh token as used at now. The returned token's used_at is
+    /// when it was used before, if it was.
+    fn use_token(&self, hash: &[u8], now: SystemTime) -> impl Future<Output = Result<Token, Error>> + Send;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: i64,
+    pub username: String,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+    #[serde(default)]
+    pub second_factor_at: Option<SystemTime>,
+}
+
+/// Token is a stored refresh token. Access tokens are signed JWTs, and are
+/// not stored.
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub hash: Vec<u8>,
+    pub session: i64,
+    pub username: String,
+    pub expires_at: SystemTime,
+    pub used_at: Option<SystemTime>,
+}
+
+impl Token {
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() > self.expires_at
+    }
+}
+
+/// Starts a session for user, and issues its first tokens
+pub async fn start<S: Sessions>(sessions: &S, jwt: &Jwt, user: &User, lifetimes: Lifetimes) -> Result<Tokens, Error> {
+    let now = SystemTime::now();
+    let id = sessions.create_session(&Session {
+        id: 0,
+        username: user.name.clone(),
+        created_at: now,
+        expires_at: now + lifetimes.refresh,
+        second_factor_at: user.second_factor_at,
+    }).await?;
+    issue(sessions, jwt, user, id, lifetimes).await
+}
+
+async fn issue<S: Sessions>(sessions: &S, jwt: &Jwt, user: &User, session: i64, lifetimes: Lifetimes) -> Result<Tokens, Error> {
+    let refresh = token::generate();
+    sessions.create_token(&Token {
+        hash: token::hash(&refresh),
+        session,
+        username: user.name.clone(),
+        expires_at: SystemTime::now() + lifetimes.refresh,
+        used_at: None,
+    }).await?;
+
+    Ok(Tokens {
+        username: user.name.clone(),
+        token: jwt.sign(user, session, lifetimes.access)?,
+        refresh,
+    })
+}
+
+/// Exchanges a refresh token for new tokens in the same session. Refresh
+/// tokens are single use, and reusing one revokes its session.
+pub async fn refresh<A, S>(accounts: &A, sessions: &S, jwt: &Jwt, refresh: &str, lifetimes: Lifetimes) -> Result<Tokens, Error>
+where
+    A: Accounts,
+    S: Sessions,
+{
+    let t = match sessions.use_token(&token::hash(refresh), SystemTime::now()).await {
+        Ok(t) => t,
+        Err(Error::NotFound(_)) => return Err(Error::Unauthorized("unknown refresh token".into())),
+        Err(err) => return Err(err),
+    };
+    if t.used_at.is_some() {
+        sessions.revoke_session(t.session).await?;
+        return Err(Error::Unauthorized(format!(
+            "refresh token reused, revoked session {} of {}", t.session, t.username,
+        )));
+    }
+    if t.is_expired() {
+        return Err(Error::Unauthorized("refresh token is expired".into()));
+    }
+
+    // roles may have changed since the token was issued
+    let account = accounts.read_user(&t.username).await?;
+    issue(sessions, jwt, &account.user(), t.session, lifetimes).await
+}
+
+/// Revokes the session of a refresh token. Unknown tokens are ignored, so
+/// signing out twice succeeds.
+pub async fn sign_out<S: Sessions>(sessions: &S, refresh: &str) -> Result<(), Error> {
+    match sessions.read_token(&token::hash(refresh)).await {
+        Ok(t) => sessions.revoke_session(t.session).await,
+        Err(Error::NotFound(_)) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Authorizes an access token whose session is live
+pub async fn authorize<S: Sessions>(sessions: &S, jwt: &Jwt, token: &str) -> Result<User, Error> {
+    let claims = jwt.verify(token)?;
+    match sessions.read_session(claims.sid).await {
+        Ok(session) => Ok(User {
+            session: Some(session.id),
+            second_factor_at: session.second_factor_at,
+            ..claims.user()
+        }),
+        Err(Error::NotFound(_)) => Err(Error::Unauthorized(format!("session {} is revoked", claims.sid))),
+        Err(err) => Err(err),
+    }
+}
+
+/// Cached caches live and revoked sessions in Redis, so that authorizing
+/// access tokens does not query sessions on every request. Redis is optional,
+/// and errors fall back to sessions.
+pub struct Cached<S> {
+    sessions: Arc<S>,
+    redis: Option<ConnectionManager>,
+    ttl: Duration,
+}
+
+const CACHE_PREFIX: &str = "wiki:session:";
+const CACHE_REVOKED: &str = "revoked";
+
+impl<S> Cached<S> {
+    /// Entries are cached for at most ttl, which bounds how long a revocation
+    /// made without the cache takes to apply.
+    pub fn new(sessions: Arc<S>, redis: Option<ConnectionManager>, ttl: Duration) -> Self {
+        Cached { sessions, redis, ttl }
+    }
+
+    async fn get(&self, id: i64) -> Option<Option<Session>> {
+        let mut redis = self.redis.clone()?;
+        let r: redis::RedisResult<Option<String>> = redis.get(format!("{}{}", CACHE_PREFIX, id)).await;
+        match r {
+            Ok(Some(v)) if v == CACHE_REVOKED => Some(None),
+            Ok(Some(v)) => serde_json::from_str(&v).ok().map(Some),
+            Ok(None) => None,
+            Err(err) => {
+                log::warn!(target: "wiki::auth", "session cache: {}", err);
+                None
+            },
+        }
+    }
+
+    async fn set(&self, id: i64, session: Option<&Session>) {
+        let Some(mut redis) = self.redis.clone() else { return };
+        let (value, ttl) = match session {
+            Some(s) => {
+                let remaining = s.expires_at.duration_since(SystemTime::now()).unwrap_or_default();
+                (serde_json::to_string(s).unwrap_or_default(), remaining.min(self.ttl))
+            },
+            None => (CACHE_REVOKED.to_string(), self.ttl),
+        };
+        if ttl.as_secs() == 0 {
+            return;
+        }
+        let r: redis::RedisResult<()> = redis.set_ex(format!("{}{}", CACHE_PREFIX, id), value, ttl.as_secs()).await;
+        if let Err(err) = r {
+            log::warn!(target: "wiki::auth", "session cache: {}", err);
+        }
+    }
+
+    async fn forget(&self, id: i64) {
+        let Some(mut redis) = self.redis.clone() else { return };
+        let r: redis::RedisResult<()> = redis.del(format!("{}{}", CACHE_PREFIX, id)).await;
+        if let Err(err) = r {
+            log::warn!(target: "wiki::auth", "session cache: {}", err);
+        }
+    }
+}
+
+impl<S: Sessions + Send + Sync> Sessions for Cached<S> {
+    async fn create_session(&self, session: &Session) -> Result<i64, Error> {
+        self.sessions.create_session(session).await
+    }
+
+    async fn read_session(&self, id: i64) -> Result<Session, Error> {
+        match self.get(id).await {
+            Some(Some(session)) => return Ok(session),
+            Some(None) => return Err(Error::NotFound(format!("session {}", id))),
+            None => {},
+        }
+        match self.sessions.read_session(id).await {
+            Ok(session) => {
+                self.set(id, Some(&session)).await;
+                Ok(session)
+            },
+            Err(Error::NotFound(msg)) => {
+                self.set(id, None).await;
+                Err(Error::NotFound(msg))
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn record_second_factor(&self, id: i64, at: SystemTime) -> Result<(), Error> {
+        self.sessions.record_second_factor(id, at).await?;
+        self.forget(id).await;
+        Ok(())
+    }
+
+    async fn revoke_session(&self, id: i64) -> Result<(), Error> {
+        self.sessions.revoke_session(id).await?;
+        self.set(id, None).await;
+        Ok(())
+    }
+
+    async fn revoke_sessions(&self, username: &str) -> Result<Vec<i64>, Error> {
+        let ids = self.sessions.revoke_sessions(username).await?;
+        for id in &ids {
+            self.set(*id, None).await;
+        }
+        Ok(ids)
+    }
+
+    async fn create_token(&self, token: &Token) -> Result<(), Error> {
+        // the session's expiry is extended, so drop its cached entry
+        self.sessions.create_token(token).await?;
+        self.forget(token.session).await;
+        Ok(())
+    }
+
+    async fn read_token(&self, hash: &[u8]) -> Result<Token, Error> {
+        self.sessions.read_token(hash).await
+    }
+
+    async fn use_token(&self, hash: &[u8], now: SystemTime) -> Result<Token, Error> {
+        self.sessions.use_token(hash, now).await
+    }
+}
+
+/// Signing out everywhere and revoking other users' sessions require the
+/// admin scope, revoking other users' sessions also requires the admin role.
+pub fn filter<S, U>(sessions: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    S: Sessions + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+{
+    warp::path!("sign-out-everywhere")
+        .and(warp::post())
+        .and(endpoints::sign_out_everywhere(sessions.clone(), users.clone()))
+        .or(
+            warp::path!("users" / ..)
+                .and(warp::delete())
+                .and(endpoints::revoke(sessions, users))
+        )
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{auth::user::{with_authorization, Scope, Users}, error};
+
+    use super::{handlers, Sessions};
+
+    pub fn sign_out_everywhere<S, U>(sessions: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Sessions + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_sessions(sessions)
+            .and(with_authorization(users, Scope::Admin))
+            .and_then(handlers::sign_out_everywhere)
+            .recover(error::recover)
+    }
+
+    pub fn revoke<S, U>(sessions: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        S: Sessions + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        warp::path::param()
+            .and(warp::path!("sessions"))
+            .and(with_sessions(sessions))
+            .and(with_authorization(users, Scope::Admin))
+            .and_then(handlers::revoke)
+            .recover(error::recover)
+    }
+
+    fn with_sessions<S>(sessions: Arc<S>) -> impl Filter<Extract = (Arc<S>,), Error = Infallible> + Clone
+    where
+        S: Sessions + Send + Sync + 'static
+    {
+        warp::any().map(move || sessions.clone())
+    }
+}
+
+mod handlers {
+    use std::sync::Arc;
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{auth::user::{Role, User}, error::Error};
+
+    use super::Sessions;
+
+    pub async fn sign_out_everywhere<S: Sessions>(sessions: Arc<S>, user: User) -> Result<impl Reply, Rejection> {
+        match sessions.revoke_sessions(&user.name).await {
+            Ok(_) => Ok(warp::reply()),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn revoke<S: Sessions>(username: String, sessions: Arc<S>, user: User) -> Result<impl Reply, Rejection> {
+        let r = async {
+            if !user.roles.contains(&Role::Admin) {
+                return Err(Error::Forbidden(format!("{} is not an admin", user.name)));
+            }
+            let ids = sessions.revoke_sessions(&username).await?;
+            log::info!(target: "wiki::auth", "{} revoked {} sessions of {}", user.name, ids.len(), username);
+            Ok(())
+        }.await;
+
+        match r {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests sessions, filter, and endpoints and handlers modules.
+/// Exposes in-memory sessions to other test modules.
+///
+/// Test plan:
+/// 1. Access tokens are rejected once their session is revoked
+/// 2. Sign out everywhere revokes all of the user's sessions
+/// 3. Admins revoke other users' sessions
+/// 4. Cached sessions follow revocations and second factors, with and without
+///    Redis
+#[cfg(test)]
+pub mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use warp::http::StatusCode;
+
+    use crate::auth::{
+        account::tests::{lifetimes, MemoryAccounts},
+        database::Database,
+        jwt::{tests::new_jwt, Algorithm},
+        personal_token::tests::MemoryPersonalTokens,
+        user::Role,
+    };
+
+    use super::*;
+
+    #[derive(Default)]
+    pub struct MemorySessions {
+        sessions: Mutex<HashMap<i64, Session>>,
+        tokens: Mutex<HashMap<Vec<u8>, Token>>,
+    }
+
+    impl Sessions for MemorySessions {
+        async fn create_session(&self, session: &Session) -> Result<i64, Error> {
+            let mut sessions = self.sessions.lock().unwrap();
+            let id = sessions.keys().max().unwrap_or(&0) + 1;
+            sessions.insert(id, Session { id, ..session.clone() });
+            Ok(id)
+        }
+
+        async fn read_session(&self, id: i64) -> Result<Session, Error> {
+            match self.sessions.lock().unwrap().get(&id) {
+                Some(s) if s.expires_at > SystemTime::now() => Ok(s.clone()),
+                _ => Err(Error::NotFound(format!("session {}", id))),
+            }
+        }
+
+        async fn record_second_factor(&self, id: i64, at: SystemTime) -> Result<(), Error> {
+            match self.sessions.lock().unwrap().get_mut(&id) {
+                Some(s) if s.expires_at > SystemTime::now() => {
+                    s.second_factor_at = Some(at);
+                    Ok(())
+                },
+                _ => Err(Error::NotFound(format!("session {}", id))),
+            }
+        }
+
+        async fn revoke_session(&self, id: i64) -> Result<(), Error> {
+            self.sessions.lock().unwrap().remove(&id);
+            self.tokens.lock().unwrap().retain(|_, t| t.session != id);
+            Ok(())
+        }
+
+        async fn revoke_sessions(&self, username: &str) -> Result<Vec<i64>, Error> {
+            let ids: Vec<i64> = self.sessions.lock().unwrap().values()
+                .filter(|s| s.username == username)
+                .map(|s| s.id)
+                .collect();
+            for id in &ids {
+                self.revoke_session(*id).await?;
+            }
+            Ok(ids)
+        }
+
+        async fn create_token(&self, token: &Token) -> Result<(), Error> {
+            if let Some(s) = self.sessions.lock().unwrap().get_mut(&token.session) {
+                s.expires_at = s.expires_at.max(token.expires_at);
+            }
+            self.tokens.lock().unwrap().insert(token.hash.clone(), token.clone());
+            Ok(())
+        }
+
+        async fn read_token(&self, hash: &[u8]) -> Result<Token, Error> {
+            match self.tokens.lock().unwrap().get(hash) {
+                Some(t) => Ok(t.clone()),
+                None => Err(Error::NotFound("token".into())),
+            }
+        }
+
+        async fn use_token(&self, hash: &[u8], now: SystemTime) -> Result<Token, Error> {
+            match self.tokens.lock().unwrap().get_mut(hash) {
+                Some(t) => {
+                    let used = t.clone();
+                    t.used_at.get_or_insert(now);
+                    Ok(used)
+                },
+                None => Err(Error::NotFound("token".into())),
+            }
+        }
+    }
+
+    struct Harness {
+        filter: warp::filters::BoxedFilter<(Box<dyn Reply>,)>,
+        accounts: Arc<MemoryAccounts>,
+        sessions: Arc<MemorySessions>,
+        jwt: Arc<Jwt>,
+    }
+
+    impl Harness {
+        async fn new() -> Self {
+            let accounts = Arc::new(MemoryAccounts::default());
+            for username in ["bob", "alice"] {
+                accounts.create_user(username, "hash").await.unwrap();
+            }
+            accounts.upsert_external_user("idp root", "root", &[Role::Admin]).await.unwrap();
+            let sessions = Arc::new(MemorySessions::default());
+            let jwt = Arc::new(new_jwt(Algorithm::EdDSA).await);
+            let users = Arc::new(Database::new(
+                accounts.clone(),
+                Arc::new(MemoryPersonalTokens::default()),
+                sessions.clone(),
+                jwt.clone(),
+            ));
+            Harness {
+                filter: filter(sessions.clone(), users).map(|r| Box::new(r) as Box<dyn Reply>).boxed(),
+                accounts,
+                sessions,
+                jwt,
+            }
+        }
+
+        async fn start(&self, username: &str) -> Tokens {
+            let user = self.accounts.read_user(username).await.unwrap().user();
+            start(self.sessions.as_ref(), &self.jwt, &user, lifetimes()).await.unwrap()
+        }
+
+        async fn is_live(&self, tokens: &Tokens) -> bool {
+            authorize(self.sessions.as_ref(), &self.jwt, &tokens.token).await.is_ok()
+        }
+
+        async fn request(&self, method: &str, path: &str, tokens: &Tokens) -> warp::http::Response<bytes::Bytes> {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Authorization", format!("Bearer {}", tokens.token))
+                .reply(&self.filter)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoked_sessions_reject_access_tokens() {
+        let h = Harness::new().await;
+        let t = h.start("bob").await;
+        assert!(h.is_live(&t).await);
+
+        let sid = h.jwt.verify(&t.token).unwrap().sid;
+        h.sessions.revoke_session(sid).await.unwrap();
+        let r = authorize(h.sessions.as_ref(), &h.jwt, &t.token).await;
+        assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+    }
+
+    #[tokio::test]
+    async fn test_sign_out_everywhere() {
+        let h = Harness::new().await;
+        let laptop = h.start("bob").await;
+        let phone = h.start("bob").await;
+        let alice = h.start("alice").await;
+
+        let res = h.request("POST", "/sign-out-everywhere", &phone).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!h.is_live(&laptop).await);
+        assert!(!h.is_live(&phone).await);
+        assert!(h.is_live(&alice).await);
+
+        // the revoked access token can no longer sign out
+        let res = h.request("POST", "/sign-out-everywhere", &phone).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admins_revoke_sessions() {
+        let h = Harness::new().await;
+        let bob = h.start("bob").await;
+        let alice = h.start("alice").await;
+        let root = h.start("root").await;
+
+        let res = h.request("DELETE", "/users/bob/sessions", &alice).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(h.is_live(&bob).await);
+
+        let res = h.request("DELETE", "/users/bob/sessions", &root).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!h.is_live(&bob).await);
+        assert!(h.is_live(&alice).await);
+        assert!(h.is_live(&root).await);
+
+        let res = warp::test::request().method("DELETE").path("/users/bob/sessions").reply(&h.filter).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    async fn test_cache(redis: Option<ConnectionManager>) {
+        let sessions = Arc::new(MemorySessions::default());
+        let cached = Cached::new(sessions.clone(), redis, Duration::from_secs(60));
+        let jwt = new_jwt(Algorithm::EdDSA).await;
+        let bob = start(&cached, &jwt, &User::new("bob"), lifetimes()).await.unwrap();
+        let sid = jwt.verify(&bob.token).unwrap().sid;
+
+        assert_eq!(cached.read_session(sid).await.unwrap().username, "bob");
+        let now = SystemTime::now();
+        cached.record_second_factor(sid, now).await.unwrap();
+        assert_eq!(cached.read_session(sid).await.unwrap().second_factor_at, Some(now));
+        let user = authorize(&cached, &jwt, &bob.token).await.unwrap();
+        assert_eq!((user.session, user.second_factor_at), (Some(sid), Some(now)));
+        cached.revoke_session(sid).await.unwrap();
+        let r = cached.read_session(sid).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        let alice = start(&cached, &jwt, &User::new("alice"), lifetimes()).await.unwrap();
+        let sid = jwt.verify(&alice.token).unwrap().sid;
+        assert!(cached.read_session(sid).await.is_ok());
+        assert_eq!(cached.revoke_sessions("alice").await.unwrap(), vec![sid]);
+        let r = cached.read_session(sid).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+    }
+
+    #[tokio::test]
+    async fn test_cache_without_redis() {
+        test_cache(None).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_cache_with_redis() {
+        let url = std::env::var("WIKI_CI_TEST_REDIS_URL").unwrap_or("redis://localhost".into());
+        let mut redis = redis::Client::open(url).unwrap().get_connection_manager().await.unwrap();
+        // memory sessions number ids from 1, so clear entries of earlier runs
+        let _: () = redis.del(&[format!("{}1", CACHE_PREFIX), format!("{}2", CACHE_PREFIX)]).await.unwrap();
+        test_cache(Some(redis)).await;
+    }
+}
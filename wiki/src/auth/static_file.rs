@@ -0,0 +1,125 @@
+// static_file authorizes users listed in a YAML file, for deployments that
+// do not manage accounts through the wiki. Only basic credentials are
+// accepted, checked against the argon2 hashes in the file, such as:
+//
+// users:
+// - name: alice
+//   password_hash: $argon2id$v=19$...
+//   roles: [admin]
+
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Deserialize;
+
+use crate::error::Error;
+
+use super::{account::DUMMY_HASH, password, user::{Role, User, Users}};
+
+#[derive(Deserialize)]
+struct File {
+    users: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    name: String,
+    password_hash: String,
+    #[serde(default)]
+    roles: Vec<Role>,
+}
+
+pub struct StaticFile {
+    users: HashMap<String, Entry>,
+}
+
+impl StaticFile {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Internal(format!("reading {}: {}", path, e)))?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        let file: File = serde_yaml::from_str(contents)
+            .map_err(|e| Error::BadRequest(e.to_string()))?;
+
+        let mut users = HashMap::new();
+        for entry in file.users {
+            if users.contains_key(&entry.name) {
+                return Err(Error::BadRequest(format!("duplicate user: {}", entry.name)));
+            }
+            users.insert(entry.name.clone(), entry);
+        }
+        Ok(StaticFile { users })
+    }
+}
+
+impl Users for StaticFile {
+    async fn authorize(&self, header: String) -> Result<User, Error> {
+        let credentials = match header.split_once(' ') {
+            Some(("Basic", credentials)) => credentials,
+            _ => return Err(Error::Unauthorized("bad auth type".into())),
+        };
+        let decoded = STANDARD.decode(credentials)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?;
+        let decoded = String::from_utf8(decoded)
+            .map_err(|e| Error::Unauthorized(e.to_string()))?;
+        let (username, password) = decoded.split_once(':')
+            .ok_or(Error::Unauthorized("bad basic auth header".into()))?;
+
+        let entry = self.users.get(username);
+        let hash = entry.map_or(DUMMY_HASH, |e| e.password_hash.as_str());
+        let verified = password::verify(password, hash).await?;
+
+        match entry {
+            Some(entry) if verified => Ok(User {
+                roles: entry.roles.clone(),
+                ..User::new(&entry.name)
+            }),
+            _ => Err(Error::Unauthorized(format!("bad credentials for user: {}", username))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let file = StaticFile::parse(&format!(
+            "users:\n- name: alice\n  password_hash: {}\n  roles: [admin]\n- name: bob\n  password_hash: {}\n",
+            password::hash("password1").await.unwrap(),
+            password::hash("password2").await.unwrap(),
+        )).unwrap();
+
+        let alice = file.authorize(format!("Basic {}", STANDARD.encode("alice:password1"))).await.unwrap();
+        assert_eq!(alice.name, "alice");
+        assert_eq!(alice.roles, vec![Role::Admin]);
+        let bob = file.authorize(format!("Basic {}", STANDARD.encode("bob:password2"))).await.unwrap();
+        assert_eq!(bob.roles, vec![]);
+
+        for header in [
+            format!("Basic {}", STANDARD.encode("alice:password2")),
+            format!("Basic {}", STANDARD.encode("carol:password1")),
+            format!("Basic {}", STANDARD.encode("alice")),
+            "Bearer alice".to_string(),
+        ] {
+            let r = file.authorize(header.clone()).await;
+            assert!(matches!(r, Err(Error::Unauthorized(_))), "{}: {:?}", header, r);
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_files() {
+        for contents in [
+            "users: alice",
+            "users:\n- name: alice\n",
+            "users:\n- name: alice\n  password_hash: x\n  roles: [owner]\n",
+            "users:\n- name: alice\n  password_hash: x\n- name: alice\n  password_hash: y\n",
+        ] {
+            assert!(StaticFile::parse(contents).is_err(), "{}", contents);
+        }
+    }
+}
@@ -0,0 +1,35 @@
+// token creates opaque bearer tokens. Tokens are only stored as hashes, so a
+// leaked table does not leak usable credentials.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+const TOKEN_LENGTH: usize = 32;
+
+pub fn generate() -> String {
+    let mut bytes = [0u8; TOKEN_LENGTH];
+    rand::rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_is_unique() {
+        assert_ne!(generate(), generate());
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        let t = generate();
+        assert_eq!(hash(&t), hash(&t));
+        assert_ne!(hash(&t), hash(&generate()));
+    }
+}
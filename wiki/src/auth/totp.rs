@@ -0,0 +1,680 @@
+// totp is the second factor: time-based one-time passwords (RFC 6238) from an
+// authenticator app, and single-use recovery codes for when the app is lost.
+// Users enroll by scanning the provisioning URI as a QR code, and confirm with
+// a first code. Sessions record when they last passed a second factor, and a
+// policy limits the credentials of roles that must use one until they do.
+
+use std::{future::Future, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use rand::{Rng, RngCore};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use warp::{reject::Rejection, reply::Reply, Filter};
+
+use crate::{
+    auth::{account::Account, session::Sessions, token, user::{Role, Scope, User, Users}},
+    error::Error,
+};
+
+const STEP: u64 = 30;
+const DIGITS: usize = 6;
+// codes of the previous and next step are accepted, for clock skew
+const SKEW: i64 = 1;
+const SECRET_LENGTH: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// How recently privileged operations require a second factor
+pub const RECENT: Duration = Duration::from_secs(15 * 60);
+
+pub trait SecondFactors {
+    /// Stores an unconfirmed secret for username, replacing an unconfirmed
+    /// one. Enrolled users are not found.
+    fn create_totp(&self, username: &str, secret: &[u8]) -> impl Future<Output = Result<(), Error>> + Send;
+    fn read_totp(&self, username: &str) -> impl Future<Output = Result<Totp, Error>> + Send;
+    /// Confirms the unconfirmed secret of username, whose first code was of step
+    fn enable_totp(&self, username: &str, step: i64) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Records that the code of step was used. Steps that are not later than
+    /// the last used step are not found, so that codes are used once.
+    fn use_totp_step(&self, username: &str, step: i64) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Deletes the secret and recovery codes of username
+    fn delete_totp(&self, username: &str) -> impl Future<Output = Result<(), Error>> + Send;
+    fn replace_recovery_codes(&self, username: &str, hashes: &[Vec<u8>]) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Deletes the recovery code with hash, so that it is used once
+    fn use_recovery_code(&self, username: &str, hash: &[u8]) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct Totp {
+    pub secret: Vec<u8>,
+    /// Unconfirmed secrets are not enabled
+    pub enabled: bool,
+    pub last_step: Option<i64>,
+}
+
+/// Policy forces users with any of roles to use a second factor. Until their
+/// credential passes one, it only has the read scope, which still lets them
+/// enroll.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub roles: Vec<Role>,
+}
+
+impl Policy {
+    pub fn apply(&self, user: User) -> User {
+        if user.second_factor_at.is_none() && user.roles.iter().any(|r| self.roles.contains(r)) {
+            User { scopes: vec![Scope::Read], ..user }
+        } else {
+            user
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Enrollment {
+    /// Base32 secret, for apps that cannot scan uri
+    pub secret: String,
+    /// otpauth provisioning URI, to be shown as a QR code
+    pub uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Code {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Computes the code of step, as HOTP (RFC 4226) with HMAC-SHA1
+pub fn code(secret: &[u8], step: i64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let mac = hmac::sign(&key, &step.to_be_bytes());
+    let mac = mac.as_ref();
+    let offset = (mac[mac.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([mac[offset] & 0x7f, mac[offset + 1], mac[offset + 2], mac[offset + 3]]);
+    format!("{:0width$}", binary % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+pub fn step(at: SystemTime) -> i64 {
+    (at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / STEP) as i64
+}
+
+/// Encodes bytes as base32 (RFC 4648) without padding
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([0, 0, 0, buf[0], buf[1], buf[2], buf[3], buf[4]]);
+        for i in 0..(chunk.len() * 8).div_ceil(5) {
+            encoded.push(BASE32[((bits >> (35 - i * 5)) & 0x1f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+pub fn provisioning_uri(issuer: &str, username: &str, secret: &[u8]) -> String {
+    let mut uri = Url::parse("otpauth://totp/").expect("otpauth base URI");
+    uri.path_segments_mut()
+        .expect("otpauth URIs have a path")
+        .push(&format!("{}:{}", issuer, username));
+    uri.query_pairs_mut()
+        .append_pair("secret", &base32(secret))
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP.to_string());
+    uri.to_string()
+}
+
+/// Starts enrolling username, whose secret is unconfirmed until confirm.
+pub async fn enroll<F: SecondFactors>(factors: &F, issuer: &str, username: &str) -> Result<Enrollment, Error> {
+    match factors.read_totp(username).await {
+        Ok(t) if t.enabled => return Err(Error::Conflict(format!("{} is already enrolled", username))),
+        Ok(_) | Err(Error::NotFound(_)) => {},
+        Err(err) => return Err(err),
+    }
+
+    let mut secret = [0u8; SECRET_LENGTH];
+    rand::rng().fill_bytes(&mut secret);
+    factors.create_totp(username, &secret).await?;
+    Ok(Enrollment {
+        secret: base32(&secret),
+        uri: provisioning_uri(issuer, username, &secret),
+    })
+}
+
+/// Enables the unconfirmed secret of username once code matches it, and
+/// returns their first recovery codes.
+pub async fn confirm<F: SecondFactors>(factors: &F, username: &str, code: &str) -> Result<RecoveryCodes, Error> {
+    let t = match factors.read_totp(username).await {
+        Ok(t) if !t.enabled => t,
+        Ok(_) => return Err(Error::Conflict(format!("{} is already enrolled", username))),
+        Err(Error::NotFound(_)) => return Err(Error::BadRequest(format!("{} is not enrolling", username))),
+        Err(err) => return Err(err),
+    };
+    let step = matching_step(&t, code.trim(), SystemTime::now())
+        .ok_or(Error::Unauthorized(format!("bad totp code for {}", username)))?;
+
+    let codes = replace_recovery_codes(factors, username).await?;
+    factors.enable_totp(username, step).await?;
+    Ok(codes)
+}
+
+/// Verifies a TOTP code or recovery code of an enrolled user. Either is
+/// accepted once.
+pub async fn verify<F: SecondFactors>(factors: &F, username: &str, code: &str) -> Result<(), Error> {
+    let t = match factors.read_totp(username).await {
+        Ok(t) if t.enabled => t,
+        Ok(_) | Err(Error::NotFound(_)) => return Err(Error::Unauthorized(format!("{} is not enrolled", username))),
+        Err(err) => return Err(err),
+    };
+
+    let code = code.trim();
+    let r = if code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit()) {
+        let step = matching_step(&t, code, SystemTime::now())
+            .ok_or(Error::Unauthorized(format!("bad totp code for {}", username)))?;
+        factors.use_totp_step(username, step).await
+    } else {
+        factors.use_recovery_code(username, &token::hash(&normalize(code))).await
+    };
+    match r {
+        Ok(()) => Ok(()),
+        Err(Error::NotFound(_)) => Err(Error::Unauthorized(format!("bad or used code for {}", username))),
+        Err(err) => Err(err),
+    }
+}
+
+/// Checks the second factor of a password sign in, and returns the user to
+/// start a session for. Enrolled users must pass code.
+pub async fn sign_in<F: SecondFactors>(factors: &F, account: &Account, code: Option<&str>) -> Result<User, Error> {
+    let mut user = account.user();
+    if !account.totp_enabled {
+        return Ok(user);
+    }
+    let Some(code) = code else {
+        return Err(Error::BadRequest("second factor code required".into()));
+    };
+    verify(factors, &account.username, code).await?;
+    user.second_factor_at = Some(SystemTime::now());
+    Ok(user)
+}
+
+/// Replaces the recovery codes of username, and returns the new codes.
+pub async fn replace_recovery_codes<F: SecondFactors>(factors: &F, username: &str) -> Result<RecoveryCodes, Error> {
+    let codes = generate_recovery_codes();
+    let hashes: Vec<Vec<u8>> = codes.iter().map(|c| token::hash(&normalize(c))).collect();
+    factors.replace_recovery_codes(username, &hashes).await?;
+    Ok(RecoveryCodes { recovery_codes: codes })
+}
+
+fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::rng();
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = (0..RECOVERY_CODE_LENGTH)
+                .map(|_| BASE32[rng.random_range(0..BASE32.len())].to_ascii_lowercase() as char)
+                .collect();
+            format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+        })
+        .collect()
+}
+
+// normalize lets recovery codes be typed without their dash, and in any case
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn matching_step(t: &Totp, code: &str, now: SystemTime) -> Option<i64> {
+    let now = step(now);
+    (now - SKEW..=now + SKEW)
+        .filter(|s| t.last_step.is_none_or(|last| *s > last))
+        .find(|s| equal(self::code(&t.secret, *s).as_bytes(), code.as_bytes()))
+}
+
+// equal compares in constant time, so that timing does not leak digits
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Second factors need a session to record them, so other credentials are
+/// forbidden. Enrolling and verifying only need the read scope, so that the
+/// policy does not lock users out, disabling and new recovery codes need a
+/// recent second factor.
+pub fn filter<F, S, U>(factors: Arc<F>, sessions: Arc<S>, users: Arc<U>, issuer: String) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    F: SecondFactors + Send + Sync + 'static,
+    S: Sessions + Send + Sync + 'static,
+    U: Users + Send + Sync + 'static,
+{
+    warp::path!("2fa" / "totp")
+        .and(warp::post())
+        .and(endpoints::enroll(factors.clone(), users.clone(), issuer))
+        .or(warp::path!("2fa" / "totp").and(warp::delete()).and(endpoints::disable(factors.clone(), users.clone())))
+        .or(warp::path!("2fa" / "totp" / "confirm").and(warp::post()).and(endpoints::confirm(factors.clone(), sessions.clone(), users.clone())))
+        .or(warp::path!("2fa" / "verify").and(warp::post()).and(endpoints::verify(factors.clone(), sessions, users.clone())))
+        .or(warp::path!("2fa" / "recovery-codes").and(warp::post()).and(endpoints::recovery_codes(factors, users)))
+}
+
+mod endpoints {
+    use std::{convert::Infallible, sync::Arc};
+
+    use warp::{reply::Reply, Filter};
+
+    use crate::{auth::{session::Sessions, user::{with_authorization, with_recent_second_factor, Scope, Users}}, error};
+
+    use super::{handlers, SecondFactors, RECENT};
+
+    pub fn enroll<F, U>(factors: Arc<F>, users: Arc<U>, issuer: String) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        F: SecondFactors + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_factors(factors)
+            .and(warp::any().map(move || issuer.clone()))
+            .and(with_authorization(users, Scope::Read))
+            .and_then(handlers::enroll)
+            .recover(error::recover)
+    }
+
+    pub fn confirm<F, S, U>(factors: Arc<F>, sessions: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        F: SecondFactors + Send + Sync + 'static,
+        S: Sessions + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_factors(factors)
+            .and(with_sessions(sessions))
+            .and(with_authorization(users, Scope::Read))
+            .and(warp::body::json())
+            .and_then(handlers::confirm)
+            .recover(error::recover)
+    }
+
+    pub fn verify<F, S, U>(factors: Arc<F>, sessions: Arc<S>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        F: SecondFactors + Send + Sync + 'static,
+        S: Sessions + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_factors(factors)
+            .and(with_sessions(sessions))
+            .and(with_authorization(users, Scope::Read))
+            .and(warp::body::json())
+            .and_then(handlers::verify)
+            .recover(error::recover)
+    }
+
+    pub fn disable<F, U>(factors: Arc<F>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        F: SecondFactors + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_factors(factors)
+            .and(with_recent_second_factor(users, Scope::Admin, RECENT))
+            .and_then(handlers::disable)
+            .recover(error::recover)
+    }
+
+    pub fn recovery_codes<F, U>(factors: Arc<F>, users: Arc<U>) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+    where
+        F: SecondFactors + Send + Sync + 'static,
+        U: Users + Send + Sync + 'static,
+    {
+        with_factors(factors)
+            .and(with_recent_second_factor(users, Scope::Admin, RECENT))
+            .and_then(handlers::recovery_codes)
+            .recover(error::recover)
+    }
+
+    fn with_factors<F>(factors: Arc<F>) -> impl Filter<Extract = (Arc<F>,), Error = Infallible> + Clone
+    where
+        F: SecondFactors + Send + Sync + 'static
+    {
+        warp::any().map(move || factors.clone())
+    }
+
+    fn with_sessions<S>(sessions: Arc<S>) -> impl Filter<Extract = (Arc<S>,), Error = Infallible> + Clone
+    where
+        S: Sessions + Send + Sync + 'static
+    {
+        warp::any().map(move || sessions.clone())
+    }
+}
+
+mod handlers {
+    use std::{sync::Arc, time::SystemTime};
+
+    use warp::{http::StatusCode, reject::Rejection, reply::Reply};
+
+    use crate::{auth::{session::Sessions, user::User}, error::Error};
+
+    use super::{Code, SecondFactors};
+
+    fn session(user: &User) -> Result<i64, Error> {
+        user.session.ok_or(Error::Forbidden(format!("{} did not sign in with a session", user.name)))
+    }
+
+    pub async fn enroll<F: SecondFactors>(factors: Arc<F>, issuer: String, user: User) -> Result<impl Reply, Rejection> {
+        let r = async {
+            session(&user)?;
+            super::enroll(factors.as_ref(), &issuer, &user.name).await
+        }.await;
+
+        match r {
+            Ok(enrollment) => Ok(warp::reply::with_status(warp::reply::json(&enrollment), StatusCode::CREATED)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn confirm<F: SecondFactors, S: Sessions>(factors: Arc<F>, sessions: Arc<S>, user: User, body: Code) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let id = session(&user)?;
+            let codes = super::confirm(factors.as_ref(), &user.name, &body.code).await?;
+            sessions.record_second_factor(id, SystemTime::now()).await?;
+            log::info!(target: "wiki::auth", "{} enrolled a second factor", user.name);
+            Ok::<_, Error>(codes)
+        }.await;
+
+        match r {
+            Ok(codes) => Ok(warp::reply::json(&codes)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn verify<F: SecondFactors, S: Sessions>(factors: Arc<F>, sessions: Arc<S>, user: User, body: Code) -> Result<impl Reply, Rejection> {
+        let r = async {
+            let id = session(&user)?;
+            super::verify(factors.as_ref(), &user.name, &body.code).await?;
+            sessions.record_second_factor(id, SystemTime::now()).await
+        }.await;
+
+        match r {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn disable<F: SecondFactors>(factors: Arc<F>, user: User) -> Result<impl Reply, Rejection> {
+        let r = async {
+            factors.delete_totp(&user.name).await?;
+            log::info!(target: "wiki::auth", "{} disabled their second factor", user.name);
+            Ok::<_, Error>(())
+        }.await;
+
+        match r {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+
+    pub async fn recovery_codes<F: SecondFactors>(factors: Arc<F>, user: User) -> Result<impl Reply, Rejection> {
+        let r = async {
+            match factors.read_totp(&user.name).await {
+                Ok(t) if t.enabled => {},
+                Ok(_) | Err(Error::NotFound(_)) => return Err(Error::BadRequest(format!("{} is not enrolled", user.name))),
+                Err(err) => return Err(err),
+            }
+            super::replace_recovery_codes(factors.as_ref(), &user.name).await
+        }.await;
+
+        match r {
+            Ok(codes) => Ok(warp::reply::json(&codes)),
+            Err(err) => Err(warp::reject::custom(err)),
+        }
+    }
+}
+
+/// Tests codes, filter, and endpoints and handlers modules, with sign in.
+///
+/// Test plan:
+/// 1. Codes match the RFC 6238 test vectors
+/// 2. Provisioning URIs carry the base32 secret and issuer
+/// 3. Enrolled users sign in with a code, which is accepted once
+/// 4. Recovery codes are accepted once, and can be replaced
+/// 5. The policy limits roles to the read scope until a second factor
+/// 6. Disabling requires a recent second factor
+/// 7. Credentials other than sessions cannot enroll, enrolled users cannot use
+///    basic credentials
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use warp::http::StatusCode;
+
+    use crate::auth::{
+        account::{self, tests::{lifetimes, MemoryAccounts}, Accounts, Tokens},
+        database::Database,
+        jwt::{tests::new_jwt, Algorithm, Jwt},
+        lockout::tests::new_throttle,
+        password,
+        personal_token::tests::MemoryPersonalTokens,
+        session::{self, tests::MemorySessions},
+    };
+
+    use super::*;
+
+    struct Harness {
+        filter: warp::filters::BoxedFilter<(Box<dyn Reply>,)>,
+        accounts: Arc<MemoryAccounts>,
+        sessions: Arc<MemorySessions>,
+        users: Arc<Database<MemoryAccounts, MemoryPersonalTokens, MemorySessions>>,
+        jwt: Arc<Jwt>,
+    }
+
+    impl Harness {
+        async fn new() -> Self {
+            let accounts = Arc::new(MemoryAccounts::default());
+            accounts.create_user("bob", &password::hash("password1").await.unwrap()).await.unwrap();
+            accounts.upsert_external_user("idp root", "root", &[Role::Admin]).await.unwrap();
+            let sessions = Arc::new(MemorySessions::default());
+            let jwt = Arc::new(new_jwt(Algorithm::EdDSA).await);
+            let users = Arc::new(
+                Database::new(accounts.clone(), Arc::new(MemoryPersonalTokens::default()), sessions.clone(), jwt.clone())
+                    .with_policy(Policy { roles: vec![Role::Admin] })
+            );
+            Harness {
+                filter: account::filter(accounts.clone(), sessions.clone(), jwt.clone(), new_throttle().0, lifetimes(), true)
+                    .or(filter(accounts.clone(), sessions.clone(), users.clone(), "wiki".into()))
+                    .map(|r| Box::new(r) as Box<dyn Reply>)
+                    .boxed(),
+                accounts,
+                sessions,
+                users,
+                jwt,
+            }
+        }
+
+        async fn sign_in(&self, code: Option<&str>) -> warp::http::Response<bytes::Bytes> {
+            warp::test::request()
+                .method("POST")
+                .path("/sign-in")
+                .json(&serde_json::json!({"username": "bob", "password": "password1", "code": code}))
+                .reply(&self.filter)
+                .await
+        }
+
+        async fn start(&self, username: &str) -> Tokens {
+            let user = self.accounts.read_user(username).await.unwrap().user();
+            session::start(self.sessions.as_ref(), &self.jwt, &user, lifetimes()).await.unwrap()
+        }
+
+        async fn request(&self, method: &str, path: &str, tokens: &Tokens, body: serde_json::Value) -> warp::http::Response<bytes::Bytes> {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Authorization", format!("Bearer {}", tokens.token))
+                .json(&body)
+                .reply(&self.filter)
+                .await
+        }
+
+        /// Enrolls username, and returns their recovery codes
+        async fn enroll(&self, tokens: &Tokens) -> Vec<String> {
+            let res = self.request("POST", "/2fa/totp", tokens, serde_json::json!({})).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            let res = self.request("POST", "/2fa/totp/confirm", tokens, serde_json::json!({"code": self.code(&tokens.username, 0).await})).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            serde_json::from_slice::<RecoveryCodes>(res.body()).unwrap().recovery_codes
+        }
+
+        /// Computes the current code of username, offset by steps
+        async fn code(&self, username: &str, offset: i64) -> String {
+            let t = self.accounts.read_totp(username).await.unwrap();
+            code(&t.secret, step(SystemTime::now()) + offset)
+        }
+
+        async fn user(&self, tokens: &Tokens) -> User {
+            self.users.authorize(format!("Bearer {}", tokens.token)).await.unwrap()
+        }
+    }
+
+    #[test]
+    fn test_code() {
+        let secret = b"12345678901234567890";
+        for (time, expected) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924"), (2000000000, "279037")] {
+            assert_eq!(code(secret, step(UNIX_EPOCH + Duration::from_secs(time))), expected, "{}", time);
+        }
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        assert_eq!(base32(b"12345678901234567890"), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+
+        let uri = provisioning_uri("My Wiki", "bob", b"12345678901234567890");
+        assert_eq!(uri, "otpauth://totp/My%20Wiki:bob?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+            &issuer=My+Wiki&algorithm=SHA1&digits=6&period=30");
+    }
+
+    #[tokio::test]
+    async fn test_enroll_and_sign_in() {
+        let h = Harness::new().await;
+        let t: Tokens = serde_json::from_slice(h.sign_in(None).await.body()).unwrap();
+
+        let res = h.request("POST", "/2fa/totp", &t, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let enrollment: Enrollment = serde_json::from_slice(res.body()).unwrap();
+        assert!(enrollment.uri.starts_with("otpauth://totp/wiki:bob?secret="), "{}", enrollment.uri);
+        assert!(enrollment.uri.contains(&enrollment.secret));
+
+        // unconfirmed secrets do not apply to sign in
+        let res = h.request("POST", "/2fa/totp/confirm", &t, serde_json::json!({"code": "000000x"})).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(h.sign_in(None).await.status(), StatusCode::OK);
+
+        let code = h.code("bob", 0).await;
+        let res = h.request("POST", "/2fa/totp/confirm", &t, serde_json::json!({"code": code})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let codes: RecoveryCodes = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(codes.recovery_codes.len(), RECOVERY_CODES);
+        assert!(h.user(&t).await.second_factor_at.is_some());
+
+        let res = h.request("POST", "/2fa/totp", &t, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // the confirming code was used, the next step's code is accepted once
+        assert_eq!(h.sign_in(None).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(h.sign_in(Some(&code)).await.status(), StatusCode::UNAUTHORIZED);
+        let next = h.code("bob", 1).await;
+        let res = h.sign_in(Some(&next)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let t: Tokens = serde_json::from_slice(res.body()).unwrap();
+        assert!(h.user(&t).await.second_factor_at.is_some());
+        assert_eq!(h.sign_in(Some(&next)).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_recovery_codes() {
+        let h = Harness::new().await;
+        let t = h.start("bob").await;
+        let codes = h.enroll(&t).await;
+
+        // recovery codes ignore case and dashes
+        let typed = codes[0].replace('-', "").to_uppercase();
+        assert_eq!(h.sign_in(Some(&typed)).await.status(), StatusCode::OK);
+        assert_eq!(h.sign_in(Some(&codes[0])).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(h.sign_in(Some("aaaaa-aaaaa")).await.status(), StatusCode::UNAUTHORIZED);
+
+        let res = h.request("POST", "/2fa/recovery-codes", &t, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let replaced: RecoveryCodes = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(h.sign_in(Some(&codes[1])).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(h.sign_in(Some(&replaced.recovery_codes[1])).await.status(), StatusCode::OK);
+
+        // verifying steps up another session
+        let other = h.start("bob").await;
+        assert!(h.user(&other).await.second_factor_at.is_none());
+        let res = h.request("POST", "/2fa/verify", &other, serde_json::json!({"code": replaced.recovery_codes[2]})).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(h.user(&other).await.second_factor_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let h = Harness::new().await;
+        let root = h.start("root").await;
+        assert_eq!(h.user(&root).await.scopes, vec![Scope::Read]);
+        assert_eq!(h.user(&h.start("bob").await).await.scopes, Scope::ALL.to_vec());
+
+        h.enroll(&root).await;
+        assert_eq!(h.user(&root).await.scopes, Scope::ALL.to_vec());
+
+        // new sessions are limited until they verify
+        let other = h.start("root").await;
+        assert_eq!(h.user(&other).await.scopes, vec![Scope::Read]);
+        let res = h.request("POST", "/2fa/verify", &other, serde_json::json!({"code": h.code("root", 1).await})).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(h.user(&other).await.scopes, Scope::ALL.to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_disable_requires_recent_second_factor() {
+        let h = Harness::new().await;
+        let t = h.start("bob").await;
+        h.enroll(&t).await;
+
+        let stale = h.start("bob").await;
+        let res = h.request("DELETE", "/2fa/totp", &stale, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = h.request("POST", "/2fa/recovery-codes", &stale, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let id = h.jwt.verify(&stale.token).unwrap().sid;
+        h.sessions.record_second_factor(id, SystemTime::now() - RECENT - Duration::from_secs(1)).await.unwrap();
+        let res = h.request("DELETE", "/2fa/totp", &stale, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = h.request("DELETE", "/2fa/totp", &t, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(h.sign_in(None).await.status(), StatusCode::OK);
+        let res = h.request("POST", "/2fa/recovery-codes", &t, serde_json::json!({})).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_other_credentials() {
+        let h = Harness::new().await;
+        let basic = format!("Basic {}", STANDARD.encode("bob:password1"));
+        let res = warp::test::request()
+            .method("POST")
+            .path("/2fa/totp")
+            .header("Authorization", &basic)
+            .reply(&h.filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(h.users.authorize(basic.clone()).await.is_ok());
+
+        h.enroll(&h.start("bob").await).await;
+        let r = h.users.authorize(basic).await;
+        assert!(matches!(r, Err(Error::Unauthorized(_))), "{:?}", r);
+    }
+}
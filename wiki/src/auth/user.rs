@@ -1,7 +1,144 @@
-use std::future::Future;
+use std::{future::Future, net::SocketAddr, sync::Arc, time::{Duration, SystemTime}};
+
+use serde::{Deserialize, Serialize};
+use warp::{http::HeaderMap, reject::Rejection, Filter};
 
 use crate::error::Error;
 
 pub trait Users {
-    fn authorize(&self, header: String) -> impl Future<Output = Result<String, Error>> + Send;
+    fn authorize(&self, header: String) -> impl Future<Output = Result<User, Error>> + Send;
+    /// Authorizes like authorize, for a client connected from remote with
+    /// headers, so that implementations may throttle clients.
+    fn authorize_client(&self, header: String, _remote: Option<SocketAddr>, _headers: &HeaderMap) -> impl Future<Output = Result<User, Error>> + Send {
+        self.authorize(header)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub roles: Vec<Role>,
+    /// Scopes limit what the user may do with the credential they presented.
+    /// Sessions have every scope, personal access tokens have the scopes they
+    /// were minted with.
+    pub scopes: Vec<Scope>,
+    /// Session of the credential, None for other credentials
+    pub session: Option<i64>,
+    /// When the credential last passed a second factor, only sessions do
+    pub second_factor_at: Option<SystemTime>,
+}
+
+impl User {
+    pub fn new(name: &str) -> Self {
+        User {
+            name: name.to_string(),
+            roles: vec![],
+            scopes: Scope::ALL.to_vec(),
+            session: None,
+            second_factor_at: None,
+        }
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), Error> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!("{} lacks scope: {}", self.name, scope.as_str())))
+        }
+    }
+
+    /// Requires that the credential passed a second factor within max_age
+    pub fn require_second_factor(&self, max_age: Duration) -> Result<(), Error> {
+        match self.second_factor_at {
+            Some(at) if at.elapsed().unwrap_or_default() <= max_age => Ok(()),
+            _ => Err(Error::Forbidden(format!("{} lacks a recent second factor", self.name))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "editor" => Ok(Role::Editor),
+            "admin" => Ok(Role::Admin),
+            r => Err(Error::BadRequest(format!("unknown role: {}", r))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::Read, Scope::Write, Scope::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            s => Err(Error::BadRequest(format!("unknown scope: {}", s))),
+        }
+    }
+}
+
+/// Authorizes requests by their Authorization header, and rejects users whose
+/// credential lacks scope.
+pub fn with_authorization<U>(users: Arc<U>, scope: Scope) -> impl Filter<Extract = (User,), Error = Rejection> + Clone
+where
+    U: Users + Send + Sync + 'static
+{
+    warp::header("Authorization")
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || users.clone()))
+        .and_then(move |header: String, remote: Option<SocketAddr>, headers: HeaderMap, users: Arc<U>| async move {
+            match users.authorize_client(header, remote, &headers).await.and_then(|user| user.require(scope).map(|_| user)) {
+                Ok(user) => Ok(user),
+                Err(err) => Err(warp::reject::custom(err)),
+            }
+        })
+}
+
+/// Authorizes like with_authorization, and also rejects users whose
+/// credential did not pass a second factor within max_age. Guards privileged
+/// operations, which a stolen session should not reach.
+pub fn with_recent_second_factor<U>(users: Arc<U>, scope: Scope, max_age: Duration) -> impl Filter<Extract = (User,), Error = Rejection> + Clone
+where
+    U: Users + Send + Sync + 'static
+{
+    with_authorization(users, scope)
+        .and_then(move |user: User| async move {
+            match user.require_second_factor(max_age) {
+                Ok(()) => Ok(user),
+                Err(err) => Err(warp::reject::custom(err)),
+            }
+        })
 }
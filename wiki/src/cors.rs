@@ -0,0 +1,308 @@
+// cors configures which other origins browsers let call the wiki, and protects
+// against cross-site request forgery by checking the origin of requests that
+// change state. Only requests with credentials that browsers attach on their
+// own, cookies and basic auth, can be forged, bearer tokens are always added
+// by scripts that already passed CORS. Requests whose origin is that of the
+// Host they are sent to are the wiki's own, wherever it is served.
+
+use std::{sync::Arc, time::Duration};
+
+use url::Url;
+use warp::{http::{header::{AUTHORIZATION, COOKIE, HOST, ORIGIN, REFERER}, HeaderMap, HeaderName, Method}, reject::Rejection, reply::Reply, Filter};
+
+use crate::error::{self, Error};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origins {
+    Any,
+    /// Origins besides the wiki's own
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Origin the wiki is served from, which is always allowed
+    public: String,
+    origins: Origins,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl Config {
+    /// Origins holding "*" allow any origin, which may not be combined with
+    /// credentials.
+    pub fn new(public_url: &str, origins: &[String], methods: &[String], headers: &[String], credentials: bool, max_age: u64) -> Result<Self, Error> {
+        let public = Url::parse(public_url)
+            .map_err(|e| Error::BadRequest(format!("bad public url: {}", e)))?
+            .origin()
+            .ascii_serialization();
+
+        let origins = if origins.iter().any(|o| o == "*") {
+            if credentials {
+                return Err(Error::BadRequest("credentials cannot be allowed from any origin".into()));
+            }
+            Origins::Any
+        } else {
+            Origins::List(origins.iter().map(|o| parse_origin(o)).collect::<Result<_, _>>()?)
+        };
+
+        Ok(Config {
+            public,
+            origins,
+            methods: methods.iter()
+                .map(|m| Method::from_bytes(m.to_uppercase().as_bytes())
+                    .map_err(|e| Error::BadRequest(format!("bad method {}: {}", m, e))))
+                .collect::<Result<_, _>>()?,
+            headers: headers.iter()
+                .map(|h| HeaderName::from_bytes(h.as_bytes())
+                    .map_err(|e| Error::BadRequest(format!("bad header {}: {}", h, e))))
+                .collect::<Result<_, _>>()?,
+            credentials,
+            max_age,
+        })
+    }
+
+    pub fn cors(&self) -> warp::cors::Builder {
+        let builder = warp::cors()
+            .allow_methods(self.methods.clone())
+            .allow_headers(self.headers.clone())
+            .allow_credentials(self.credentials)
+            // lets scripts replace sections with If-Match
+            .expose_headers(["etag"])
+            .max_age(Duration::from_secs(self.max_age));
+
+        match &self.origins {
+            Origins::Any => builder.allow_any_origin(),
+            Origins::List(origins) => builder.allow_origins(
+                std::iter::once(self.public.as_str()).chain(origins.iter().map(String::as_str))
+            ),
+        }
+    }
+
+    /// Whether requests from origin may carry credentials browsers attach
+    /// on their own
+    fn trusts(&self, origin: &str) -> bool {
+        origin == self.public || match &self.origins {
+            Origins::Any => false,
+            Origins::List(origins) => self.credentials && origins.iter().any(|o| o == origin),
+        }
+    }
+
+    /// Rejects forged requests, that change state with ambient credentials
+    /// from an origin that is not trusted. Requests from clients that send
+    /// neither Origin nor Referer, which browsers do, are not forged.
+    fn check(&self, method: &Method, headers: &HeaderMap) -> Result<(), Error> {
+        if [Method::GET, Method::HEAD, Method::OPTIONS].contains(method) {
+            return Ok(());
+        }
+        let ambient = headers.contains_key(COOKIE) || headers.get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("Basic "));
+        if !ambient {
+            return Ok(());
+        }
+
+        let origin = match headers.get(ORIGIN) {
+            Some(origin) => Some(origin.to_str().unwrap_or_default().to_string()),
+            None => headers.get(REFERER)
+                .and_then(|r| r.to_str().ok())
+                .map(|r| Url::parse(r).map(|u| u.origin().ascii_serialization()).unwrap_or_default()),
+        };
+        match origin {
+            Some(origin) if !self.trusts(&origin) && !same_origin(&origin, headers) =>
+                Err(Error::Forbidden(format!("{} from untrusted origin {:?}", method, origin))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether origin is that of the Host of the request, which browsers set to
+/// where they send it
+fn same_origin(origin: &str, headers: &HeaderMap) -> bool {
+    let host = match headers.get(HOST).and_then(|h| h.to_str().ok()) {
+        Some(host) => host,
+        None => return false,
+    };
+    match Url::parse(origin) {
+        Ok(url) => url.origin().is_tuple() && Url::parse(&format!("{}://{}", url.scheme(), host))
+            .is_ok_and(|h| h.origin() == url.origin()),
+        Err(_) => false,
+    }
+}
+
+fn parse_origin(origin: &str) -> Result<String, Error> {
+    let url = Url::parse(origin)
+        .map_err(|e| Error::BadRequest(format!("bad origin {}: {}", origin, e)))?;
+    let serialized = url.origin().ascii_serialization();
+    if !url.origin().is_tuple() || serialized != origin.trim_end_matches('/') {
+        return Err(Error::BadRequest(format!("bad origin {}: must be scheme://host[:port]", origin)));
+    }
+    Ok(serialized)
+}
+
+/// Replies forbidden to forged requests and rejects all others, so that it
+/// goes first among the wiki's filters.
+pub fn csrf(config: Arc<Config>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+{
+    warp::method()
+        .and(warp::header::headers_cloned())
+        .and_then(move |method: Method, headers: HeaderMap| {
+            let config = config.clone();
+            async move {
+                match config.check(&method, &headers) {
+                    Ok(()) => Err(warp::reject::not_found()),
+                    Err(err) => error::recover(warp::reject::custom(err)).await
+                        .map_err(|never| match never {}),
+                }
+            }
+        })
+}
+
+/// Wraps filter with the CORS of config, except for requests from the origin
+/// of their own Host, which browsers send Origin with but CORS does not apply
+/// to.
+pub fn with_cors<F, R>(config: Arc<Config>, filter: F) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::header::headers_cloned()
+        .and_then(|headers: HeaderMap| async move {
+            match headers.get(ORIGIN).and_then(|o| o.to_str().ok()) {
+                Some(origin) if same_origin(origin, &headers) => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+        .and(filter.clone())
+        .or(filter.with(config.cors()))
+}
+
+/// Tests Config, csrf and with_cors. Preflights of the subject routes are tested with
+/// them.
+///
+/// Test plan:
+/// 1. Bad configs are rejected
+/// 2. Forged requests are forbidden, others pass through
+/// 3. Requests from the origin of their Host pass CORS whatever the config
+#[cfg(test)]
+pub mod tests {
+    use warp::http::StatusCode;
+
+    use super::*;
+
+    pub fn config(origins: &[&str], credentials: bool) -> Config {
+        let origins: Vec<String> = origins.iter().map(|o| o.to_string()).collect();
+        Config::new(
+            "http://wiki.test:8080/",
+            &origins,
+            &["GET".into(), "POST".into(), "PATCH".into()],
+            &["authorization".into(), "content-type".into()],
+            credentials,
+            600,
+        ).unwrap()
+    }
+
+    #[test]
+    fn test_bad_configs() {
+        let methods = ["GET".to_string()];
+        for (public, origins, headers, credentials) in [
+            ("not a url", vec![], vec![], false),
+            ("http://wiki.test", vec!["*".to_string()], vec![], true),
+            ("http://wiki.test", vec!["https://app.test/path".into()], vec![], false),
+            ("http://wiki.test", vec!["app.test".into()], vec![], false),
+            ("http://wiki.test", vec![], vec!["bad header".into()], false),
+        ] {
+            let r = Config::new(public, &origins, &methods, &headers, credentials, 0);
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{} {:?} {:?}: {:?}", public, origins, headers, r);
+        }
+        let r = Config::new("http://wiki.test", &[], &["NOT A METHOD".into()], &[], false, 0);
+        assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}", r);
+
+        let c = config(&["https://app.test/"], true);
+        assert_eq!(c.origins, Origins::List(vec!["https://app.test".into()]));
+        assert_eq!(c.public, "http://wiki.test:8080");
+    }
+
+    #[tokio::test]
+    async fn test_csrf() {
+        let basic = "Basic Ym9iOnBhc3M=";
+        let f = csrf(Arc::new(config(&["https://app.test"], true)))
+            .or(warp::any().map(warp::reply));
+        let any = csrf(Arc::new(config(&["*"], false)))
+            .or(warp::any().map(warp::reply));
+
+        // forged
+        for (method, headers) in [
+            ("POST", vec![("authorization", basic), ("origin", "https://evil.test")]),
+            ("PATCH", vec![("cookie", "session=1"), ("origin", "https://evil.test")]),
+            ("DELETE", vec![("authorization", basic), ("referer", "https://evil.test/page")]),
+            ("POST", vec![("authorization", basic), ("origin", "null")]),
+            ("POST", vec![("cookie", "session=1"), ("origin", "https://evil.test"), ("host", "wiki.example")]),
+            ("POST", vec![("cookie", "session=1"), ("origin", "http://wiki.example"), ("host", "wiki.example:443")]),
+        ] {
+            let mut req = warp::test::request().method(method).path("/api/v1/subject/x");
+            for (k, v) in &headers {
+                req = req.header(*k, *v);
+            }
+            let res = req.reply(&f).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{} {:?}", method, headers);
+        }
+        let res = warp::test::request().method("POST").path("/")
+            .header("authorization", basic)
+            .header("origin", "https://app.test")
+            .reply(&any).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // not forged
+        for (method, headers) in [
+            ("GET", vec![("authorization", basic), ("origin", "https://evil.test")]),
+            ("POST", vec![("authorization", "Bearer token"), ("origin", "https://evil.test")]),
+            ("POST", vec![("authorization", basic), ("origin", "http://wiki.test:8080")]),
+            ("POST", vec![("authorization", basic), ("referer", "http://wiki.test:8080/wiki/x")]),
+            ("POST", vec![("authorization", basic), ("origin", "https://app.test")]),
+            ("POST", vec![("cookie", "session=1"), ("origin", "https://wiki.example"), ("host", "wiki.example")]),
+            ("POST", vec![("cookie", "session=1"), ("origin", "https://wiki.example"), ("host", "WIKI.example:443")]),
+            ("POST", vec![("authorization", basic)]),
+            ("POST", vec![("origin", "https://evil.test")]),
+        ] {
+            let mut req = warp::test::request().method(method).path("/api/v1/subject/x");
+            for (k, v) in &headers {
+                req = req.header(*k, *v);
+            }
+            let res = req.reply(&f).await;
+            assert_eq!(res.status(), StatusCode::OK, "{} {:?}", method, headers);
+        }
+
+        // credentials from listed origins must be allowed to be trusted
+        let f = csrf(Arc::new(config(&["https://app.test"], false)))
+            .or(warp::any().map(warp::reply));
+        let res = warp::test::request().method("POST").path("/")
+            .header("authorization", basic)
+            .header("origin", "https://app.test")
+            .reply(&f).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_with_cors() {
+        let f = with_cors(Arc::new(config(&[], false)), warp::post().map(warp::reply));
+
+        for (origin, host, status) in [
+            // the wiki served at an address other than its public url
+            ("http://10.0.0.5:8080", "10.0.0.5:8080", StatusCode::OK),
+            ("https://wiki.example", "wiki.example", StatusCode::OK),
+            ("http://wiki.test:8080", "localhost:8080", StatusCode::OK),
+            ("https://evil.test", "wiki.example", StatusCode::FORBIDDEN),
+            ("http://10.0.0.5:8081", "10.0.0.5:8080", StatusCode::FORBIDDEN),
+        ] {
+            let res = warp::test::request().method("POST").path("/")
+                .header("origin", origin)
+                .header("host", host)
+                .reply(&f).await;
+            assert_eq!(res.status(), status, "{} {}", origin, host);
+        }
+    }
+}
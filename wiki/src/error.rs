@@ -1,3 +1,9 @@
+use std::{convert::Infallible, time::Duration};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use warp::{http::{header::{HeaderValue, ALLOW, CONTENT_TYPE, RETRY_AFTER}, Method, StatusCode}, reject::{MissingHeader, Rejection}, reply::Reply, Filter};
+
 #[derive(Debug, Clone)]
 pub enum Error {
     Internal(String),
@@ -5,6 +11,179 @@ pub enum Error {
     NotFound(String),
     BadRequest(String),
     Unauthorized(String),
+    Forbidden(String),
+    /// The client must wait for the duration before retrying
+    TooManyRequests(String, Duration),
+    /// The request conflicts with the current state, such as creating what
+    /// already exists
+    Conflict(String),
+    /// A condition the client set on the request does not hold
+    PreconditionFailed(String),
+    PayloadTooLarge(String),
+    /// The request is well-formed, but cannot be processed, such as when it
+    /// reuses an idempotency key of another request
+    UnprocessableEntity(String),
+    /// The path exists, but only supports these methods
+    MethodNotAllowed(Vec<Method>),
 }
 
 impl warp::reject::Reject for Error {}
+
+impl Error {
+    /// Problem the error replies, logging what clients are not told
+    pub fn problem(&self) -> Problem {
+        match self {
+            Error::BadRequest(msg) => Problem::new(StatusCode::BAD_REQUEST, "bad_request", Some(msg.clone())),
+            Error::Internal(msg) => {
+                log::error!(target: "wiki::api", "internal error: {}", msg);
+                Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", None)
+            },
+            Error::NotFound(_) => Problem::new(StatusCode::NOT_FOUND, "not_found", None),
+            Error::Unauthorized(msg) => {
+                log::warn!(target: "wiki::api", "unauthorized: {}", msg);
+                Problem::new(StatusCode::UNAUTHORIZED, "unauthorized", None)
+            },
+            Error::Forbidden(msg) => {
+                log::warn!(target: "wiki::api", "forbidden: {}", msg);
+                Problem::new(StatusCode::FORBIDDEN, "forbidden", None)
+            },
+            Error::TooManyRequests(msg, _) => {
+                log::warn!(target: "wiki::api", "too many requests: {}", msg);
+                Problem::new(StatusCode::TOO_MANY_REQUESTS, "too_many_requests", None)
+            },
+            Error::Conflict(msg) => Problem::new(StatusCode::CONFLICT, "conflict", Some(msg.clone())),
+            Error::PreconditionFailed(msg) => Problem::new(StatusCode::PRECONDITION_FAILED, "precondition_failed", Some(msg.clone())),
+            Error::PayloadTooLarge(msg) => Problem::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", Some(msg.clone())),
+            Error::UnprocessableEntity(msg) => Problem::new(StatusCode::UNPROCESSABLE_ENTITY, "unprocessable_entity", Some(msg.clone())),
+            Error::MethodNotAllowed(_) => Problem::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", None),
+        }
+    }
+}
+
+/// Problem is an RFC 7807 problem details body. Code is stable for clients
+/// to match on, detail is meant for people and may change.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &str, detail: Option<String>) -> Self {
+        Problem {
+            kind: "about:blank".into(),
+            title: status.canonical_reason().unwrap_or_default().into(),
+            status: status.as_u16(),
+            code: code.into(),
+            detail,
+        }
+    }
+}
+
+// recover converts rejections into problem replies, it is shared by all API
+// filters
+pub async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
+    let problem;
+    let mut retry_after = None;
+    let mut allow = None;
+
+    if let Some(e) = err.find::<Error>() {
+        problem = e.problem();
+        match e {
+            Error::TooManyRequests(_, wait) => {
+                // rounded up, so that retrying after it succeeds
+                retry_after = Some(wait.as_secs() + u64::from(wait.subsec_nanos() > 0));
+            },
+            Error::MethodNotAllowed(methods) => {
+                allow = Some(methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", "));
+            },
+            _ => (),
+        }
+    } else if err.is_not_found() {
+        problem = Problem::new(StatusCode::NOT_FOUND, "not_found", None);
+    } else if let Some(e) = err.find::<MissingHeader>() {
+        problem = if e.name() == "Authorization" {
+            Problem::new(StatusCode::UNAUTHORIZED, "unauthorized", Some("unauthorized".into()))
+        } else {
+            Problem::new(StatusCode::BAD_REQUEST, "missing_header", Some(e.to_string()))
+        };
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        problem = Problem::new(StatusCode::BAD_REQUEST, "invalid_body", Some(e.to_string()));
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        problem = Problem::new(StatusCode::BAD_REQUEST, "invalid_query", Some(e.to_string()));
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        problem = Problem::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", None);
+    } else if err.find::<warp::reject::LengthRequired>().is_some() {
+        problem = Problem::new(StatusCode::LENGTH_REQUIRED, "length_required", None);
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        problem = Problem::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", None);
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        problem = Problem::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", None);
+    } else {
+        problem = Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", None);
+    }
+
+    let status = StatusCode::from_u16(problem.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut res = warp::reply::with_status(warp::reply::json(&problem), status).into_response();
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+    if let Some(secs) = retry_after {
+        res.headers_mut().insert(RETRY_AFTER, secs.into());
+    }
+    if let Some(allow) = allow.and_then(|a| HeaderValue::from_str(&a).ok()) {
+        res.headers_mut().insert(ALLOW, allow);
+    }
+    Ok(res)
+}
+
+/// Replies 405 with the allowed methods in Allow. It goes last among the
+/// routes of a path, so that it answers the methods none of them matched.
+pub fn method_not_allowed(methods: &[Method]) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    let methods = methods.to_vec();
+    warp::any()
+        .and_then(move || {
+            let methods = methods.clone();
+            async move {
+                Err::<warp::reply::Response, _>(warp::reject::custom(Error::MethodNotAllowed(methods)))
+            }
+        })
+        .recover(recover)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_problems() {
+        for (err, status, code, detail) in [
+            (Error::Conflict("subject already exists: A".into()), 409, "conflict", Some("subject already exists: A")),
+            (Error::PreconditionFailed("stale".into()), 412, "precondition_failed", Some("stale")),
+            (Error::PayloadTooLarge("value too long".into()), 413, "payload_too_large", Some("value too long")),
+            (Error::UnprocessableEntity("reused".into()), 422, "unprocessable_entity", Some("reused")),
+            (Error::Internal("secret".into()), 500, "internal_error", None),
+            (Error::Forbidden("secret".into()), 403, "forbidden", None),
+        ] {
+            let res = recover(warp::reject::custom(err)).await.unwrap().into_response();
+            assert_eq!(res.status().as_u16(), status);
+            assert_eq!(res.headers()[CONTENT_TYPE], "application/problem+json");
+            let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let problem: Problem = serde_json::from_slice(&body).unwrap();
+            assert_eq!(problem.code, code);
+            assert_eq!(problem.status, status);
+            assert_eq!(problem.kind, "about:blank");
+            assert_eq!(problem.detail.as_deref(), detail);
+        }
+
+        let f = warp::path!("only-get").and(warp::get().map(warp::reply).or(method_not_allowed(&[Method::GET])));
+        let res = warp::test::request().method("DELETE").path("/only-get").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[ALLOW], "GET");
+        let problem: Problem = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(problem.title, "Method Not Allowed");
+    }
+}
@@ -0,0 +1,19 @@
+-- failed attempts per key, such as account:bob or ip:10.0.0.1
+CREATE TABLE lockouts (
+    key             varchar(300) PRIMARY KEY,
+    failures        integer NOT NULL,
+    last_failure_at timestamptz NOT NULL,
+    locked_until    timestamptz
+);
+
+CREATE TABLE audit_log (
+    id     bigserial PRIMARY KEY,
+    at     timestamptz NOT NULL,
+    -- NULL for the wiki itself
+    actor  varchar(256),
+    action varchar(32) NOT NULL,
+    target varchar(300) NOT NULL,
+    detail text NOT NULL
+);
+
+CREATE INDEX audit_log_at ON audit_log (at);
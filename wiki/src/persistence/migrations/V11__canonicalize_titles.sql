@@ -0,0 +1,77 @@
+-- titles were stored as the path segments they arrived in, percent-encoded
+-- by browsers, they become canonical as Title::parse makes them: decoded,
+-- normalized to NFC, with underscores and runs of whitespace folded into
+-- single spaces. normalize() needs a UTF8 server encoding, servers in other
+-- encodings only canonicalize ASCII titles.
+--
+-- Titles that cannot be canonicalized, or that would name the same subject as
+-- another once canonical, fail the migration before any is changed, listing
+-- them to be renamed by hand.
+ALTER TABLE subject_edits
+    DROP CONSTRAINT subject_edits_title_fkey,
+    ADD CONSTRAINT subject_edits_title_fkey FOREIGN KEY (title)
+        REFERENCES subjects (title) ON UPDATE CASCADE ON DELETE CASCADE;
+
+CREATE FUNCTION pg_temp.percent_decode(segment text) RETURNS text AS $$
+    SELECT convert_from(string_agg(
+        CASE WHEN part[1] IS NOT NULL
+            THEN decode(substr(part[1], 2), 'hex')
+            ELSE convert_to(part[2], 'UTF8')
+        END, ''::bytea ORDER BY n), 'UTF8')
+    FROM regexp_matches(segment, '(%[0-9A-Fa-f]{2})|([^%]+|%)', 'g') WITH ORDINALITY AS parts(part, n)
+$$ LANGUAGE sql IMMUTABLE;
+
+-- canonicalize is NULL for titles whose percent-encoding is not UTF-8, and for
+-- titles that cannot be normalized
+CREATE FUNCTION pg_temp.canonicalize(title text) RETURNS text AS $$
+DECLARE
+    decoded text;
+BEGIN
+    decoded := pg_temp.percent_decode(title);
+    IF current_setting('server_encoding') = 'UTF8' THEN
+        decoded := normalize(decoded, NFC);
+    ELSIF decoded ~ '[^\x01-\x7f]' THEN
+        RETURN NULL;
+    END IF;
+    RETURN btrim(regexp_replace(decoded, '[\s_]+', ' ', 'g'));
+EXCEPTION WHEN character_not_in_repertoire THEN
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql STABLE;
+
+CREATE TEMPORARY TABLE canonical_titles AS
+SELECT title, pg_temp.canonicalize(title) AS canonical
+FROM subjects;
+
+DO $$
+DECLARE
+    titles text;
+BEGIN
+    SELECT string_agg(quote_literal(title), ', ' ORDER BY title COLLATE "C") INTO titles
+    FROM canonical_titles
+    WHERE canonical IS NULL OR canonical = '' OR canonical ~ '[/[:cntrl:]]';
+    IF titles IS NOT NULL THEN
+        RAISE EXCEPTION 'titles are not UTF-8 or cannot be normalized, are empty, or hold slashes or control characters once decoded, rename them: %', titles;
+    END IF;
+
+    SELECT string_agg(duplicates, ', ' ORDER BY duplicates COLLATE "C") INTO titles
+    FROM (
+        SELECT '(' || string_agg(quote_literal(title), ', ' ORDER BY title COLLATE "C") || ')' AS duplicates
+        FROM canonical_titles
+        GROUP BY lower(canonical)
+        HAVING count(*) > 1
+    ) d;
+    IF titles IS NOT NULL THEN
+        RAISE EXCEPTION 'titles name the same subject once canonical, merge or rename them: %', titles;
+    END IF;
+END
+$$;
+
+UPDATE subjects s
+SET title = c.canonical
+FROM canonical_titles c
+WHERE s.title = c.title AND c.canonical <> c.title;
+
+DROP TABLE canonical_titles;
+
+CREATE UNIQUE INDEX subjects_title_lower ON subjects (lower(title));
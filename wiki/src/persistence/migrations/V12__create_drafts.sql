@@ -0,0 +1,10 @@
+-- drafts are keyed by username without a foreign key, like profiles, and by
+-- title without one to subjects, since drafts of new subjects have none yet
+CREATE TABLE drafts (
+    username varchar(256) NOT NULL,
+    title    text NOT NULL,
+    content  text NOT NULL,
+    saved_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX drafts_username_title_lower ON drafts (username, lower(title));
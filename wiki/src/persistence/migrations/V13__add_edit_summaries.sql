@@ -0,0 +1,4 @@
+-- edits say why content changed, earlier edits said nothing
+ALTER TABLE subject_edits
+    ADD COLUMN summary text NOT NULL DEFAULT '',
+    ADD COLUMN minor   boolean NOT NULL DEFAULT false;
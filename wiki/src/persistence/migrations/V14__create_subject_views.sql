@@ -0,0 +1,10 @@
+-- views are counted per subject and day, and go with their subject
+CREATE TABLE subject_views (
+    title text NOT NULL REFERENCES subjects (title) ON UPDATE CASCADE ON DELETE CASCADE,
+    day   date NOT NULL,
+    views bigint NOT NULL,
+    PRIMARY KEY (title, day)
+);
+
+CREATE INDEX subject_views_day ON subject_views (day);
+CREATE INDEX subject_edits_title ON subject_edits (title, edited_at);
@@ -0,0 +1,16 @@
+-- links are extracted from content as subjects are created or updated, and go
+-- with the subject linking. Targets are canonical titles that may have no
+-- subject yet.
+CREATE TABLE subject_links (
+    source text NOT NULL REFERENCES subjects (title) ON UPDATE CASCADE ON DELETE CASCADE,
+    target text NOT NULL,
+    PRIMARY KEY (source, target)
+);
+
+CREATE INDEX subject_links_target ON subject_links (lower(target));
+
+-- SQL does not parse Markdown, so links of subjects stored before are
+-- extracted at startup. New rows default to extracted, since creates and
+-- updates extract them.
+ALTER TABLE subjects ADD COLUMN links_extracted boolean NOT NULL DEFAULT false;
+ALTER TABLE subjects ALTER COLUMN links_extracted SET DEFAULT true;
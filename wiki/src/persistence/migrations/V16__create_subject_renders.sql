@@ -0,0 +1,20 @@
+-- transclusions are extracted from content like links, and go with the
+-- subject transcluding. They tell which renders a change makes stale.
+CREATE TABLE subject_transclusions (
+    source text NOT NULL REFERENCES subjects (title) ON UPDATE CASCADE ON DELETE CASCADE,
+    target text NOT NULL,
+    PRIMARY KEY (source, target)
+);
+
+CREATE INDEX subject_transclusions_target ON subject_transclusions (lower(target));
+
+-- renders are cached until a subject they depend on changes
+CREATE TABLE subject_renders (
+    title   text PRIMARY KEY REFERENCES subjects (title) ON UPDATE CASCADE ON DELETE CASCADE,
+    content text NOT NULL
+);
+
+-- transclusions of subjects stored before are extracted at startup, along
+-- with their links again
+ALTER TABLE subjects RENAME COLUMN links_extracted TO extracted;
+UPDATE subjects SET extracted = false;
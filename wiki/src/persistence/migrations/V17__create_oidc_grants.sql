@@ -0,0 +1,6 @@
+-- users signed in with a provider trade a grant's code for their tokens
+CREATE TABLE oidc_grants (
+    code_hash  bytea PRIMARY KEY,
+    username   varchar(256) NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    expires_at timestamptz NOT NULL
+);
@@ -0,0 +1,9 @@
+-- drafts remember the ETag of the subject content they were started from, NULL
+-- for drafts of subjects that did not exist. Drafts saved before are taken to
+-- start from the current content.
+ALTER TABLE drafts ADD COLUMN base text;
+
+UPDATE drafts d
+SET base = '"' || encode(sha256(convert_to(s.content, 'UTF8')), 'hex') || '"'
+FROM subjects s
+WHERE lower(s.title) = lower(d.title);
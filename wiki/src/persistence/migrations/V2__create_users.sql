@@ -0,0 +1,14 @@
+CREATE TABLE users (
+    username      varchar(256) PRIMARY KEY,
+    password_hash text NOT NULL,
+    created_at    timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE user_tokens (
+    token_hash bytea PRIMARY KEY,
+    username   varchar(256) NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    kind       varchar(16) NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX user_tokens_expires_at ON user_tokens (expires_at);
@@ -0,0 +1,12 @@
+CREATE TABLE signing_keys (
+    kid         varchar(64) PRIMARY KEY,
+    algorithm   varchar(16) NOT NULL,
+    private_key bytea NOT NULL,
+    public_key  bytea NOT NULL,
+    created_at  timestamptz NOT NULL,
+    expires_at  timestamptz NOT NULL
+);
+
+-- access tokens are signed JWTs, so only refresh tokens are stored
+DELETE FROM user_tokens WHERE kind <> 'refresh';
+ALTER TABLE user_tokens DROP COLUMN kind;
@@ -0,0 +1,11 @@
+-- users authenticated by an external identity provider have no password
+ALTER TABLE users ALTER COLUMN password_hash DROP NOT NULL;
+ALTER TABLE users ADD COLUMN roles text[] NOT NULL DEFAULT '{}';
+ALTER TABLE users ADD COLUMN external_subject text UNIQUE;
+
+CREATE TABLE oidc_logins (
+    state_hash    bytea PRIMARY KEY,
+    code_verifier text NOT NULL,
+    nonce         text NOT NULL,
+    expires_at    timestamptz NOT NULL
+);
@@ -0,0 +1,12 @@
+CREATE TABLE personal_tokens (
+    id           bigserial PRIMARY KEY,
+    token_hash   bytea NOT NULL UNIQUE,
+    username     varchar(256) NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    name         varchar(128) NOT NULL,
+    scopes       text[] NOT NULL,
+    created_at   timestamptz NOT NULL,
+    expires_at   timestamptz,
+    last_used_at timestamptz
+);
+
+CREATE INDEX personal_tokens_username ON personal_tokens (username);
@@ -0,0 +1,17 @@
+CREATE TABLE sessions (
+    id         bigserial PRIMARY KEY,
+    username   varchar(256) NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX sessions_username ON sessions (username);
+CREATE INDEX sessions_expires_at ON sessions (expires_at);
+
+-- refresh tokens belong to a session, and are kept once used so that reuse
+-- is detected. Tokens issued before sessions existed are dropped.
+DELETE FROM user_tokens;
+ALTER TABLE user_tokens ADD COLUMN session_id bigint NOT NULL REFERENCES sessions (id) ON DELETE CASCADE;
+ALTER TABLE user_tokens ADD COLUMN used_at timestamptz;
+DROP INDEX user_tokens_expires_at;
+CREATE INDEX user_tokens_session_id ON user_tokens (session_id);
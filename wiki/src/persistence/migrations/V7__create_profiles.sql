@@ -0,0 +1,26 @@
+-- profiles are keyed by username without a foreign key, since users of the
+-- static-file backend have no row in users
+CREATE TABLE profiles (
+    username            varchar(256) PRIMARY KEY,
+    display_name        varchar(256) NOT NULL DEFAULT '',
+    bio                 text NOT NULL DEFAULT '',
+    avatar_content_type varchar(128),
+    avatar              bytea,
+    updated_at          timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE subject_edits (
+    id        bigserial PRIMARY KEY,
+    title     text NOT NULL REFERENCES subjects (title) ON DELETE CASCADE,
+    username  varchar(256) NOT NULL,
+    created   boolean NOT NULL,
+    edited_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX subject_edits_username ON subject_edits (username, edited_at);
+
+-- only the latest editor of existing subjects is known
+INSERT INTO subject_edits (title, username, created)
+SELECT title, user_id, false
+FROM subjects
+WHERE user_id IS NOT NULL;
@@ -0,0 +1,15 @@
+ALTER TABLE users ADD COLUMN email varchar(320);
+ALTER TABLE users ADD COLUMN email_verified_at timestamptz;
+
+-- anyone may claim an address, but only one user may verify it
+CREATE UNIQUE INDEX users_verified_email ON users (lower(email)) WHERE email_verified_at IS NOT NULL;
+
+CREATE TABLE email_tokens (
+    token_hash bytea PRIMARY KEY,
+    username   varchar(256) NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    purpose    varchar(16) NOT NULL,
+    email      varchar(320) NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+
+CREATE INDEX email_tokens_username ON email_tokens (username, purpose);
@@ -0,0 +1,12 @@
+ALTER TABLE users ADD COLUMN totp_secret bytea;
+ALTER TABLE users ADD COLUMN totp_enabled_at timestamptz;
+-- step of the last accepted code, so that each code is accepted once
+ALTER TABLE users ADD COLUMN totp_last_step bigint;
+
+ALTER TABLE sessions ADD COLUMN second_factor_at timestamptz;
+
+CREATE TABLE recovery_codes (
+    username  varchar(256) NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+    code_hash bytea NOT NULL,
+    PRIMARY KEY (username, code_hash)
+);
@@ -1,101 +1,1413 @@
-use log::{info, error};
+use std::{sync::Arc, time::{Duration, SystemTime}};
 
-use crate::{api::subject::Subjects, error::Error};
+use log::{debug, info, error};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_postgres::error::SqlState;
+
+use crate::{api::{draft::{Draft, Drafts}, edit::Edit, link::{self, Linked, Links, Report as LinkReport}, profile::{Avatar, Contribution, Profile, ProfileUpdate, Profiles}, stats::{DayStats, Report, Stats, SubjectStats, Usage}, subject::{Subjects, UnitOfWork}, title::Title, transclusion::{self, Renders}}, auth::{account::{Account, Accounts}, audit::{Audit, Entry}, email::{EmailToken, Emails, Purpose}, jwt::{Algorithm, Keys, SigningKey}, lockout::{Counter, Lockouts}, oidc::{Grant, Login, Logins}, personal_token::{PersonalToken, PersonalTokens}, session::{Session, Sessions, Token}, totp::{SecondFactors, Totp}, user::{Role, Scope}}, error::Error};
+
+/// Transactions open at once by default, each on a connection of its own
+pub const MAX_TRANSACTIONS: usize = 10;
+/// How long beginning a transaction waits for others to end
+const TRANSACTION_WAIT: Duration = Duration::from_secs(5);
 
 pub struct Postgres {
     client: tokio_postgres::Client,
+    // to connect again for transactions
+    host: String,
+    user: String,
+    database: String,
+    transactions: Arc<Semaphore>,
 }
 
 impl Postgres {
     pub async fn new(host: &str, user: &str, database: &str) -> Result<Self, Error> {
         match connect(host, user, database).await {
-            Ok(client) => Ok(Postgres{ client }),
+            Ok(client) => {
+                info!(target: "persistence/postgres", "Connected to database");
+                Ok(Postgres{
+                    client,
+                    host: host.to_string(),
+                    user: user.to_string(),
+                    database: database.to_string(),
+                    transactions: Arc::new(Semaphore::new(MAX_TRANSACTIONS)),
+                })
+            },
             Err(e) => Err(e),
         }
     }
 
-    pub async fn migrate(&mut self) -> Result<(), Error> {
-        match embedded::migrations::runner().run_async(&mut self.client).await {
+    /// Limits the transactions open at once, so that they cannot exhaust the
+    /// connections of the database
+    pub fn with_max_transactions(self, max: usize) -> Self {
+        Postgres { transactions: Arc::new(Semaphore::new(max)), ..self }
+    }
+
+    pub async fn migrate(&mut self) -> Result<(), Error> {
+        match embedded::migrations::runner().run_async(&mut self.client).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::Internal(e.to_string())),
+        }
+    }
+
+    /// Extracts the links and transclusions of subjects stored before they
+    /// were, which SQL cannot, and returns how many subjects it extracted them
+    /// of
+    pub async fn extract(&self) -> Result<usize, Error> {
+        let rows = self.client.query(r"
+            SELECT title, content
+            FROM subjects
+            WHERE NOT extracted;
+        ", &[]).await.map_err(sql_error)?;
+
+        for row in &rows {
+            let title: String = row.get(0);
+            let content: String = row.get::<_, Option<String>>(1).unwrap_or_default();
+            self.client.execute(r"
+                WITH linked AS (
+                    INSERT INTO subject_links (source, target)
+                    SELECT $1, target
+                    FROM unnest($2::text[]) AS l (target)
+                    ON CONFLICT DO NOTHING
+                ), transcluded AS (
+                    INSERT INTO subject_transclusions (source, target)
+                    SELECT $1, target
+                    FROM unnest($3::text[]) AS t (target)
+                    ON CONFLICT DO NOTHING
+                )
+                UPDATE subjects
+                SET extracted = true
+                WHERE title = $1;
+            ", &[&title, &link::links(&content), &transclusion::targets(&content)]).await.map_err(sql_error)?;
+        }
+        Ok(rows.len())
+    }
+}
+
+mod embedded {
+    use refinery::embed_migrations;
+    embed_migrations!("./src/persistence/migrations");
+}
+
+impl Subjects for Postgres {
+    type UnitOfWork = PostgresUnitOfWork;
+
+    async fn list(&self) -> Result<Vec<String>, Error> {
+        let r = self.client.query(r"
+            SELECT title
+            FROM subjects;
+        ", &[]).await;
+
+        match r {
+            Ok(rows) => Ok(
+                rows.into_iter()
+                    .map(|r| r.get(0))
+                    .collect::<Vec<String>>()
+            ),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn list_after(&self, after: Option<&str>, limit: i64) -> Result<Vec<String>, Error> {
+        let r = self.client.query(r"
+            SELECT title
+            FROM subjects
+            WHERE $1::text IS NULL OR lower(title) > lower($1)
+            ORDER BY lower(title)
+            LIMIT $2;
+        ", &[&after, &limit]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.into_iter().map(|r| r.get(0)).collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn create(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        create_subject(&self.client, user, title, content, edit).await
+    }
+
+    async fn read(&self, title: &Title) -> Result<String, Error> {
+        let r = self.client.query(r"
+            SELECT content
+            FROM subjects
+            WHERE lower(title) = lower($1);
+        ", &[&title.as_str()]).await;
+
+        match r {
+            Ok(rows) => {
+                if rows.is_empty() {
+                    Err(Error::NotFound(title.to_string()))
+                } else {
+                    let content: String = rows[0].get(0);
+                    Ok(content)
+                }
+            },
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn update(&self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        update_subject(&self.client, user, title, content, edit, None).await
+    }
+
+    async fn update_if(&self, user: &str, title: &Title, previous: &str, content: &str, edit: &Edit) -> Result<(), Error> {
+        update_subject(&self.client, user, title, content, edit, Some(previous)).await
+    }
+
+    async fn begin(&self) -> Result<PostgresUnitOfWork, Error> {
+        let permit = match tokio::time::timeout(TRANSACTION_WAIT, self.transactions.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => permit,
+            Ok(Err(e)) => return Err(Error::Internal(e.to_string())),
+            Err(_) => return Err(Error::TooManyRequests("too many transactions".into(), TRANSACTION_WAIT)),
+        };
+        // the client is shared by every request, so the transaction gets a
+        // connection of its own
+        let client = connect(&self.host, &self.user, &self.database).await?;
+        debug!(target: "persistence/postgres", "Connected to database for a transaction");
+        client.batch_execute("BEGIN;").await.map_err(sql_error)?;
+        Ok(PostgresUnitOfWork { client, _permit: permit })
+    }
+}
+
+/// PostgresUnitOfWork is a transaction on a connection of its own. Dropping it
+/// closes the connection, which rolls the transaction back, and lets another
+/// transaction begin.
+pub struct PostgresUnitOfWork {
+    client: tokio_postgres::Client,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl UnitOfWork for PostgresUnitOfWork {
+    async fn create(&mut self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        create_subject(&self.client, user, title, content, edit).await
+    }
+
+    async fn update(&mut self, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+        update_subject(&self.client, user, title, content, edit, None).await
+    }
+
+    async fn delete(&mut self, title: &Title) -> Result<(), Error> {
+        // edits, links and the render go with the subject
+        let r = self.client.execute(&format!(r"
+            WITH {}
+            DELETE FROM subjects
+            WHERE lower(title) = lower($1);
+        ", INVALIDATE_RENDERS), &[&title.as_str()]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(title.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn commit(self) -> Result<(), Error> {
+        self.client.batch_execute("COMMIT;").await.map_err(sql_error)
+    }
+}
+
+/// Deletes the renders of the subject titled $1 and of every subject that
+/// includes it, however deep, as a WITH query of statements that change it
+const INVALIDATE_RENDERS: &str = r"
+    invalidated AS (
+        DELETE FROM subject_renders
+        WHERE lower(title) IN (
+            WITH RECURSIVE includers (title) AS (
+                SELECT lower($1::text)
+                UNION
+                SELECT lower(t.source)
+                FROM subject_transclusions t
+                JOIN includers i ON lower(t.target) = i.title
+            )
+            SELECT title
+            FROM includers
+        )
+    )";
+
+async fn create_subject(client: &tokio_postgres::Client, user: &str, title: &Title, content: &str, edit: &Edit) -> Result<(), Error> {
+    let r = client.query(&format!(r"
+        WITH {}, subject AS (
+            INSERT INTO subjects
+            VALUES ($1, $2, $3)
+            RETURNING title
+        ), edit AS (
+            INSERT INTO subject_edits (title, username, created, summary, minor)
+            SELECT title, $2, true, $4, $5
+            FROM subject
+        ), transcluded AS (
+            INSERT INTO subject_transclusions (source, target)
+            SELECT s.title, t.target
+            FROM subject s, unnest($7::text[]) AS t (target)
+        )
+        INSERT INTO subject_links (source, target)
+        SELECT s.title, l.target
+        FROM subject s, unnest($6::text[]) AS l (target);
+    ", INVALIDATE_RENDERS), &[&title.as_str(), &user, &content, &edit.summary, &edit.minor, &link::links(content), &transclusion::targets(content)]).await;
+
+    match r {
+        Ok(_) => Ok(()),
+        Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+            Err(Error::Conflict(format!("subject already exists: {}", title))),
+        Err(err) => Err(sql_error(err)),
+    }
+}
+
+/// Updates a subject, if previous is given only while its content still is
+/// previous
+async fn update_subject(client: &tokio_postgres::Client, user: &str, title: &Title, content: &str, edit: &Edit, previous: Option<&str>) -> Result<(), Error> {
+    // links and transclusions still in content are kept rather than deleted
+    // and inserted again, since sub-statements see the same snapshot
+    let r = client.execute(&format!(r"
+        WITH {}, subject AS (
+            UPDATE subjects
+            SET user_id = $2, content = $3, extracted = true
+            WHERE lower(title) = lower($1) AND ($7::text IS NULL OR content = $7)
+            RETURNING title
+        ), edit AS (
+            INSERT INTO subject_edits (title, username, created, summary, minor)
+            SELECT title, $2, false, $4, $5
+            FROM subject
+        ), unlinked AS (
+            DELETE FROM subject_links l
+            USING subject s
+            WHERE l.source = s.title AND l.target <> ALL($6::text[])
+        ), linked AS (
+            INSERT INTO subject_links (source, target)
+            SELECT s.title, l.target
+            FROM subject s, unnest($6::text[]) AS l (target)
+            ON CONFLICT DO NOTHING
+        ), untranscluded AS (
+            DELETE FROM subject_transclusions t
+            USING subject s
+            WHERE t.source = s.title AND t.target <> ALL($8::text[])
+        ), transcluded AS (
+            INSERT INTO subject_transclusions (source, target)
+            SELECT s.title, t.target
+            FROM subject s, unnest($8::text[]) AS t (target)
+            ON CONFLICT DO NOTHING
+        )
+        SELECT title
+        FROM subject;
+    ", INVALIDATE_RENDERS), &[&title.as_str(), &user, &content, &edit.summary, &edit.minor, &link::links(content), &previous, &transclusion::targets(content)]).await;
+
+    match r {
+        Ok(0) if previous.is_some() => {
+            let exists = client.query_opt(r"
+                SELECT 1
+                FROM subjects
+                WHERE lower(title) = lower($1);
+            ", &[&title.as_str()]).await.map_err(sql_error)?;
+            match exists {
+                Some(_) => Err(Error::PreconditionFailed(format!("subject changed: {}", title))),
+                None => Err(Error::NotFound(title.to_string())),
+            }
+        },
+        Ok(0) => Err(Error::NotFound(title.to_string())),
+        Ok(_) => Ok(()),
+        Err(err) => Err(sql_error(err))
+    }
+}
+
+impl Profiles for Postgres {
+    async fn read_profile(&self, username: &str) -> Result<Profile, Error> {
+        let r = self.client.query_opt(r"
+            SELECT p.display_name, p.bio, p.avatar_content_type
+            FROM (SELECT 1) AS one
+            LEFT JOIN profiles p ON p.username = $1
+            WHERE p.username IS NOT NULL
+                OR EXISTS (SELECT 1 FROM users WHERE username = $1)
+                OR EXISTS (SELECT 1 FROM subject_edits WHERE username = $1);
+        ", &[&username]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(Profile {
+                username: username.to_string(),
+                display_name: row.get::<_, Option<String>>(0).unwrap_or_default(),
+                bio: row.get::<_, Option<String>>(1).unwrap_or_default(),
+                avatar_content_type: row.get(2),
+            }),
+            Ok(None) => Err(Error::NotFound(username.to_string())),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn update_profile(&self, username: &str, update: &ProfileUpdate) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO profiles (username, display_name, bio)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (username) DO UPDATE
+            SET display_name = EXCLUDED.display_name, bio = EXCLUDED.bio, updated_at = now();
+        ", &[&username, &update.display_name, &update.bio]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_avatar(&self, username: &str) -> Result<Avatar, Error> {
+        let r = self.client.query_opt(r"
+            SELECT avatar_content_type, avatar
+            FROM profiles
+            WHERE username = $1 AND avatar IS NOT NULL;
+        ", &[&username]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(Avatar {
+                content_type: row.get(0),
+                content: row.get(1),
+            }),
+            Ok(None) => Err(Error::NotFound(format!("avatar of {}", username))),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn update_avatar(&self, username: &str, avatar: &Avatar) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO profiles (username, avatar_content_type, avatar)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (username) DO UPDATE
+            SET avatar_content_type = EXCLUDED.avatar_content_type, avatar = EXCLUDED.avatar, updated_at = now();
+        ", &[&username, &avatar.content_type, &avatar.content]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn list_contributions(&self, username: &str) -> Result<Vec<Contribution>, Error> {
+        let r = self.client.query(r"
+            SELECT title, created, edited_at, summary, minor
+            FROM subject_edits
+            WHERE username = $1
+            ORDER BY edited_at DESC, id DESC;
+        ", &[&username]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.iter()
+                .map(|row| Contribution {
+                    title: row.get(0),
+                    created: row.get(1),
+                    edited_at: row.get(2),
+                    summary: row.get(3),
+                    minor: row.get(4),
+                })
+                .collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Drafts for Postgres {
+    async fn list_drafts(&self, username: &str) -> Result<Vec<Draft>, Error> {
+        let r = self.client.query(r"
+            SELECT title, content, saved_at, base
+            FROM drafts
+            WHERE username = $1
+            ORDER BY saved_at DESC, title;
+        ", &[&username]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.iter().map(draft).collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_draft(&self, username: &str, title: &Title) -> Result<Draft, Error> {
+        let r = self.client.query_opt(r"
+            SELECT title, content, saved_at, base
+            FROM drafts
+            WHERE username = $1 AND lower(title) = lower($2);
+        ", &[&username, &title.as_str()]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(draft(&row)),
+            Ok(None) => Err(Error::NotFound(format!("draft of {} by {}", title, username))),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn save_draft(&self, username: &str, title: &Title, content: &str, base: Option<&str>) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO drafts (username, title, content, base)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (username, lower(title)) DO UPDATE
+            SET title = EXCLUDED.title, content = EXCLUDED.content, saved_at = now();
+        ", &[&username, &title.as_str(), &content, &base]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn delete_draft(&self, username: &str, title: &Title) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            DELETE FROM drafts
+            WHERE username = $1 AND lower(title) = lower($2);
+        ", &[&username, &title.as_str()]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(format!("draft of {} by {}", title, username))),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+fn draft(row: &tokio_postgres::Row) -> Draft {
+    Draft {
+        title: row.get(0),
+        content: row.get(1),
+        saved_at: row.get(2),
+        base: row.get(3),
+    }
+}
+
+impl Stats for Postgres {
+    async fn record_views(&self, views: &[(String, i64)]) -> Result<(), Error> {
+        let (titles, counts): (Vec<&str>, Vec<i64>) = views.iter().map(|(t, n)| (t.as_str(), *n)).unzip();
+        let r = self.client.execute(r"
+            INSERT INTO subject_views (title, day, views)
+            SELECT s.title, current_date, sum(v.views)
+            FROM unnest($1::text[], $2::bigint[]) AS v (title, views)
+            JOIN subjects s ON lower(s.title) = lower(v.title)
+            GROUP BY s.title
+            ON CONFLICT (title, day) DO UPDATE
+            SET views = subject_views.views + EXCLUDED.views;
+        ", &[&titles, &counts]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_stats(&self, title: &Title, days: i64) -> Result<SubjectStats, Error> {
+        let title: String = match self.client.query_opt(r"
+            SELECT title
+            FROM subjects
+            WHERE lower(title) = lower($1);
+        ", &[&title.as_str()]).await {
+            Ok(Some(row)) => row.get(0),
+            Ok(None) => return Err(Error::NotFound(title.to_string())),
+            Err(err) => return Err(sql_error(err)),
+        };
+
+        let r = self.client.query(r"
+            SELECT to_char(day, 'YYYY-MM-DD'), sum(views)::bigint, sum(edits)::bigint
+            FROM (
+                SELECT day, views, 0 AS edits
+                FROM subject_views
+                WHERE title = $1 AND day > current_date - $2::int
+                UNION ALL
+                SELECT edited_at::date, 0, count(*)
+                FROM subject_edits
+                WHERE title = $1 AND edited_at::date > current_date - $2::int
+                GROUP BY 1
+            ) AS activity (day, views, edits)
+            GROUP BY day
+            ORDER BY day;
+        ", &[&title, &(days as i32)]).await;
+
+        match r {
+            Ok(rows) => Ok(SubjectStats {
+                title,
+                days: rows.iter()
+                    .map(|row| DayStats {
+                        day: row.get(0),
+                        views: row.get(1),
+                        edits: row.get(2),
+                    })
+                    .collect(),
+            }),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn report(&self, report: Report, days: i64, limit: i64) -> Result<Vec<Usage>, Error> {
+        let order = match report {
+            Report::MostViewed => "views DESC",
+            Report::LeastViewed => "views ASC",
+            Report::MostEdited => "edits DESC",
+        };
+        let r = self.client.query(&format!(r"
+            SELECT s.title, coalesce(v.views, 0)::bigint AS views, coalesce(e.edits, 0)::bigint AS edits
+            FROM subjects s
+            LEFT JOIN (
+                SELECT title, sum(views) AS views
+                FROM subject_views
+                WHERE day > current_date - $1::int
+                GROUP BY title
+            ) v ON v.title = s.title
+            LEFT JOIN (
+                SELECT title, count(*) AS edits
+                FROM subject_edits
+                WHERE edited_at::date > current_date - $1::int
+                GROUP BY title
+            ) e ON e.title = s.title
+            ORDER BY {}, lower(s.title)
+            LIMIT $2;
+        ", order), &[&(days as i32), &limit]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.iter()
+                .map(|row| Usage {
+                    title: row.get(0),
+                    views: row.get(1),
+                    edits: row.get(2),
+                })
+                .collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Links for Postgres {
+    async fn report_links(&self, report: LinkReport, after: Option<&str>, limit: i64) -> Result<Vec<Linked>, Error> {
+        // links of a subject to itself count for no report
+        let query = match report {
+            LinkReport::Orphans => r"
+                SELECT s.title, 0::bigint
+                FROM subjects s
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM subject_links l
+                    WHERE lower(l.target) = lower(s.title) AND l.source <> s.title
+                )
+                AND ($1::text IS NULL OR lower(s.title) > lower($1))
+                ORDER BY lower(s.title)
+                LIMIT $2;
+            ",
+            LinkReport::Wanted => r"
+                SELECT min(l.target), count(DISTINCT l.source)
+                FROM subject_links l
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM subjects s
+                    WHERE lower(s.title) = lower(l.target)
+                )
+                AND ($1::text IS NULL OR lower(l.target) > lower($1))
+                GROUP BY lower(l.target)
+                ORDER BY lower(l.target)
+                LIMIT $2;
+            ",
+            LinkReport::DeadEnds => r"
+                SELECT s.title, (
+                    SELECT count(*)
+                    FROM subject_links l
+                    WHERE lower(l.target) = lower(s.title) AND l.source <> s.title
+                )
+                FROM subjects s
+                WHERE NOT EXISTS (
+                    SELECT 1
+                    FROM subject_links l
+                    WHERE l.source = s.title AND lower(l.target) <> lower(s.title)
+                )
+                AND ($1::text IS NULL OR lower(s.title) > lower($1))
+                ORDER BY lower(s.title)
+                LIMIT $2;
+            ",
+        };
+        let r = self.client.query(query, &[&after, &limit]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.iter()
+                .map(|row| Linked {
+                    title: row.get(0),
+                    links: row.get(1),
+                })
+                .collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Renders for Postgres {
+    async fn read_render(&self, title: &Title) -> Result<Option<String>, Error> {
+        let r = self.client.query_opt(r"
+            SELECT r.content
+            FROM subject_renders r
+            JOIN subjects s ON s.title = r.title
+            WHERE lower(s.title) = lower($1);
+        ", &[&title.as_str()]).await;
+
+        match r {
+            Ok(row) => Ok(row.map(|row| row.get(0))),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn save_render(&self, title: &Title, render: &str, dependencies: &[(String, Option<String>)]) -> Result<(), Error> {
+        let (titles, contents): (Vec<String>, Vec<Option<&str>>) = dependencies.iter()
+            .map(|(t, c)| (t.to_lowercase(), c.as_deref()))
+            .unzip();
+        // subjects being changed are locked, and skipped as if they changed:
+        // their change may not have invalidated this render yet
+        let r = self.client.execute(r"
+            WITH current AS (
+                SELECT lower(title) AS title, content
+                FROM subjects
+                WHERE lower(title) = ANY($3::text[])
+                FOR SHARE SKIP LOCKED
+            )
+            INSERT INTO subject_renders (title, content)
+            SELECT title, $2
+            FROM subjects
+            WHERE lower(title) = lower($1)
+                AND NOT EXISTS (
+                    SELECT 1
+                    FROM unnest($3::text[], $4::text[]) AS d (title, content)
+                    LEFT JOIN current c ON c.title = d.title
+                    WHERE c.content IS DISTINCT FROM d.content
+                )
+            ON CONFLICT (title) DO UPDATE
+            SET content = EXCLUDED.content;
+        ", &[&title.as_str(), &render, &titles, &contents]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Accounts for Postgres {
+    async fn create_user(&self, username: &str, password_hash: &str) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO users (username, password_hash)
+            VALUES ($1, $2);
+        ", &[&username, &password_hash]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+                Err(Error::Conflict(format!("user already exists: {}", username))),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_user(&self, username: &str) -> Result<Account, Error> {
+        let r = self.client.query_opt(r"
+            SELECT username, password_hash, roles, totp_enabled_at IS NOT NULL
+            FROM users
+            WHERE username = $1;
+        ", &[&username]).await;
+
+        match r {
+            Ok(Some(row)) => account(&row),
+            Ok(None) => Err(Error::NotFound(username.to_string())),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn upsert_external_user(&self, subject: &str, username: &str, roles: &[Role]) -> Result<Account, Error> {
+        let roles: Vec<&str> = roles.iter().map(Role::as_str).collect();
+        let r = self.client.query_one(r"
+            INSERT INTO users (username, external_subject, roles)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (external_subject) DO UPDATE SET roles = EXCLUDED.roles
+            RETURNING username, password_hash, roles, totp_enabled_at IS NOT NULL;
+        ", &[&username, &subject, &roles]).await;
+
+        match r {
+            Ok(row) => account(&row),
+            Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+                Err(Error::Conflict(format!("user already exists: {}", username))),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn update_password(&self, username: &str, password_hash: &str) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            UPDATE users
+            SET password_hash = $2
+            WHERE username = $1;
+        ", &[&username, &password_hash]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(username.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl SecondFactors for Postgres {
+    async fn create_totp(&self, username: &str, secret: &[u8]) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            UPDATE users
+            SET totp_secret = $2, totp_last_step = NULL
+            WHERE username = $1 AND totp_enabled_at IS NULL;
+        ", &[&username, &secret]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(username.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_totp(&self, username: &str) -> Result<Totp, Error> {
+        let r = self.client.query_opt(r"
+            SELECT totp_secret, totp_enabled_at IS NOT NULL, totp_last_step
+            FROM users
+            WHERE username = $1 AND totp_secret IS NOT NULL;
+        ", &[&username]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(Totp {
+                secret: row.get(0),
+                enabled: row.get(1),
+                last_step: row.get(2),
+            }),
+            Ok(None) => Err(Error::NotFound(username.to_string())),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn enable_totp(&self, username: &str, step: i64) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            UPDATE users
+            SET totp_enabled_at = now(), totp_last_step = $2
+            WHERE username = $1 AND totp_secret IS NOT NULL AND totp_enabled_at IS NULL;
+        ", &[&username, &step]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(username.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn use_totp_step(&self, username: &str, step: i64) -> Result<(), Error> {
+        // only one use can advance the step, so concurrent uses are replays
+        let r = self.client.execute(r"
+            UPDATE users
+            SET totp_last_step = $2
+            WHERE username = $1
+                AND totp_enabled_at IS NOT NULL
+                AND (totp_last_step IS NULL OR totp_last_step < $2);
+        ", &[&username, &step]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(format!("step {} of {}", step, username))),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn delete_totp(&self, username: &str) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            WITH codes AS (
+                DELETE FROM recovery_codes
+                WHERE username = $1
+            )
+            UPDATE users
+            SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+            WHERE username = $1 AND totp_secret IS NOT NULL;
+        ", &[&username]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(username.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn replace_recovery_codes(&self, username: &str, hashes: &[Vec<u8>]) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            WITH replaced AS (
+                DELETE FROM recovery_codes
+                WHERE username = $1
+            )
+            INSERT INTO recovery_codes (username, code_hash)
+            SELECT $1, unnest($2::bytea[]);
+        ", &[&username, &hashes]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn use_recovery_code(&self, username: &str, hash: &[u8]) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            DELETE FROM recovery_codes
+            WHERE username = $1 AND code_hash = $2;
+        ", &[&username, &hash]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound("recovery code".into())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Emails for Postgres {
+    async fn set_email(&self, username: &str, email: &str) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            UPDATE users
+            SET email = $2, email_verified_at = NULL
+            WHERE username = $1;
+        ", &[&username, &email]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(username.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn verify_email(&self, username: &str, email: &str) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            UPDATE users
+            SET email_verified_at = now()
+            WHERE username = $1 AND email = $2;
+        ", &[&username, &email]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(format!("email of {}", username))),
+            Ok(_) => Ok(()),
+            Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+                Err(Error::Conflict(format!("email already in use: {}", email))),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_verified_username(&self, email: &str) -> Result<String, Error> {
+        let r = self.client.query_opt(r"
+            SELECT username
+            FROM users
+            WHERE lower(email) = lower($1) AND email_verified_at IS NOT NULL;
+        ", &[&email]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(row.get(0)),
+            Ok(None) => Err(Error::NotFound(email.to_string())),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn create_email_token(&self, token: &EmailToken) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            WITH replaced AS (
+                DELETE FROM email_tokens
+                WHERE username = $2 AND purpose = $3
+            )
+            INSERT INTO email_tokens (token_hash, username, purpose, email, expires_at)
+            VALUES ($1, $2, $3, $4, $5);
+        ", &[&token.hash, &token.username, &token.purpose.as_str(), &token.email, &token.expires_at]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn take_email_token(&self, hash: &[u8]) -> Result<EmailToken, Error> {
+        // expired tokens are cleaned up alongside
+        let now = SystemTime::now();
+        let r = self.client.query(r"
+            DELETE FROM email_tokens
+            WHERE token_hash = $1 OR expires_at < $2
+            RETURNING token_hash, username, purpose, email, expires_at;
+        ", &[&hash, &now]).await;
+
+        match r {
+            Ok(rows) => match rows.into_iter().find(|r| r.get::<_, &[u8]>(0) == hash && r.get::<_, SystemTime>(4) >= now) {
+                Some(row) => Ok(EmailToken {
+                    hash: row.get(0),
+                    username: row.get(1),
+                    purpose: Purpose::parse(row.get(2))
+                        .map_err(|e| Error::Internal(format!("{:?}", e)))?,
+                    email: row.get(3),
+                    expires_at: row.get(4),
+                }),
+                None => Err(Error::NotFound("email token".into())),
+            },
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Sessions for Postgres {
+    async fn create_session(&self, session: &Session) -> Result<i64, Error> {
+        // expired sessions are useless, so they are cleaned up alongside
+        let r = self.client.query_one(r"
+            WITH expired AS (
+                DELETE FROM sessions
+                WHERE expires_at < $3
+            )
+            INSERT INTO sessions (username, created_at, expires_at, second_factor_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id;
+        ", &[&session.username, &session.created_at, &session.expires_at, &session.second_factor_at]).await;
+
+        match r {
+            Ok(row) => Ok(row.get(0)),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_session(&self, id: i64) -> Result<Session, Error> {
+        let r = self.client.query_opt(r"
+            SELECT id, username, created_at, expires_at, second_factor_at
+            FROM sessions
+            WHERE id = $1 AND expires_at > $2;
+        ", &[&id, &SystemTime::now()]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(Session {
+                id: row.get(0),
+                username: row.get(1),
+                created_at: row.get(2),
+                expires_at: row.get(3),
+                second_factor_at: row.get(4),
+            }),
+            Ok(None) => Err(Error::NotFound(format!("session {}", id))),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn record_second_factor(&self, id: i64, at: SystemTime) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            UPDATE sessions
+            SET second_factor_at = $2
+            WHERE id = $1 AND expires_at > $2;
+        ", &[&id, &at]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(format!("session {}", id))),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn revoke_session(&self, id: i64) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            DELETE FROM sessions
+            WHERE id = $1;
+        ", &[&id]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn revoke_sessions(&self, username: &str) -> Result<Vec<i64>, Error> {
+        let r = self.client.query(r"
+            DELETE FROM sessions
+            WHERE username = $1
+            RETURNING id;
+        ", &[&username]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.iter().map(|r| r.get(0)).collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn create_token(&self, token: &Token) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            WITH session AS (
+                UPDATE sessions
+                SET expires_at = GREATEST(expires_at, $4)
+                WHERE id = $2
+            )
+            INSERT INTO user_tokens (token_hash, session_id, username, expires_at)
+            VALUES ($1, $2, $3, $4);
+        ", &[&token.hash, &token.session, &token.username, &token.expires_at]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_token(&self, hash: &[u8]) -> Result<Token, Error> {
+        let r = self.client.query_opt(r"
+            SELECT session_id, username, expires_at, used_at
+            FROM user_tokens
+            WHERE token_hash = $1;
+        ", &[&hash]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(Token {
+                hash: hash.to_vec(),
+                session: row.get(0),
+                username: row.get(1),
+                expires_at: row.get(2),
+                used_at: row.get(3),
+            }),
+            Ok(None) => Err(Error::NotFound("token".into())),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn use_token(&self, hash: &[u8], now: SystemTime) -> Result<Token, Error> {
+        // only one use can mark the token, so concurrent uses are reuses
+        let r = self.client.query_opt(r"
+            UPDATE user_tokens
+            SET used_at = $2
+            WHERE token_hash = $1 AND used_at IS NULL
+            RETURNING session_id, username, expires_at;
+        ", &[&hash, &now]).await;
+
+        match r {
+            Ok(Some(row)) => Ok(Token {
+                hash: hash.to_vec(),
+                session: row.get(0),
+                username: row.get(1),
+                expires_at: row.get(2),
+                used_at: None,
+            }),
+            Ok(None) => self.read_token(hash).await,
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+fn account(row: &tokio_postgres::Row) -> Result<Account, Error> {
+    let roles: Vec<String> = row.get(2);
+    Ok(Account {
+        username: row.get(0),
+        password_hash: row.get(1),
+        roles: roles.iter()
+            .map(|r| Role::parse(r).map_err(|e| Error::Internal(format!("{:?}", e))))
+            .collect::<Result<_, _>>()?,
+        totp_enabled: row.get(3),
+    })
+}
+
+impl Lockouts for Postgres {
+    async fn record_failure(&self, key: &str, now: SystemTime, since: SystemTime) -> Result<Counter, Error> {
+        // counters past the window restart, unlocked
+        let r = self.client.query_one(r"
+            INSERT INTO lockouts (key, failures, last_failure_at)
+            VALUES ($1, 1, $2)
+            ON CONFLICT (key) DO UPDATE
+            SET failures = CASE WHEN lockouts.last_failure_at < $3 THEN 1 ELSE lockouts.failures + 1 END,
+                locked_until = CASE WHEN lockouts.last_failure_at < $3 THEN NULL ELSE lockouts.locked_until END,
+                last_failure_at = $2
+            RETURNING key, failures, last_failure_at, locked_until;
+        ", &[&key, &now, &since]).await;
+
+        match r {
+            Ok(row) => Ok(counter(&row)),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn lock(&self, key: &str, until: SystemTime) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            UPDATE lockouts
+            SET locked_until = $2
+            WHERE key = $1;
+        ", &[&key, &until]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(key.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn read_counters(&self, keys: &[String]) -> Result<Vec<Counter>, Error> {
+        let r = self.client.query(r"
+            SELECT key, failures, last_failure_at, locked_until
+            FROM lockouts
+            WHERE key = ANY($1);
+        ", &[&keys]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.iter().map(counter).collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn list_locked(&self, now: SystemTime) -> Result<Vec<Counter>, Error> {
+        let r = self.client.query(r"
+            SELECT key, failures, last_failure_at, locked_until
+            FROM lockouts
+            WHERE locked_until > $1
+            ORDER BY key;
+        ", &[&now]).await;
+
+        match r {
+            Ok(rows) => Ok(rows.iter().map(counter).collect()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn clear(&self, key: &str) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            DELETE FROM lockouts
+            WHERE key = $1;
+        ", &[&key]).await;
+
+        match r {
+            Ok(0) => Err(Error::NotFound(key.to_string())),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+fn counter(row: &tokio_postgres::Row) -> Counter {
+    Counter {
+        key: row.get(0),
+        failures: row.get(1),
+        last_failure_at: row.get(2),
+        locked_until: row.get(3),
+    }
+}
+
+impl Audit for Postgres {
+    async fn record_audit(&self, entry: &Entry) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO audit_log (at, actor, action, target, detail)
+            VALUES ($1, $2, $3, $4, $5);
+        ", &[&entry.at, &entry.actor, &entry.action, &entry.target, &entry.detail]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Keys for Postgres {
+    async fn create_key(&self, key: &SigningKey) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO signing_keys (kid, algorithm, private_key, public_key, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6);
+        ", &[
+            &key.kid,
+            &key.algorithm.as_str(),
+            &key.private_key,
+            &key.public_key,
+            &key.created_at,
+            &key.expires_at,
+        ]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn list_keys(&self) -> Result<Vec<SigningKey>, Error> {
+        let r = self.client.query(r"
+            SELECT kid, algorithm, private_key, public_key, created_at, expires_at
+            FROM signing_keys
+            WHERE expires_at > $1;
+        ", &[&SystemTime::now()]).await;
+
+        match r {
+            Ok(rows) => rows.into_iter()
+                .map(|r| Ok(SigningKey {
+                    kid: r.get(0),
+                    algorithm: Algorithm::parse(r.get(1))
+                        .map_err(|e| Error::Internal(format!("{:?}", e)))?,
+                    private_key: r.get(2),
+                    public_key: r.get(3),
+                    created_at: r.get(4),
+                    expires_at: r.get(5),
+                }))
+                .collect(),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn delete_expired_keys(&self) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            DELETE FROM signing_keys
+            WHERE expires_at <= $1;
+        ", &[&SystemTime::now()]).await;
+
+        match r {
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl Logins for Postgres {
+    async fn create_login(&self, login: &Login) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO oidc_logins (state_hash, code_verifier, nonce, expires_at)
+            VALUES ($1, $2, $3, $4);
+        ", &[&login.state_hash, &login.code_verifier, &login.nonce, &login.expires_at]).await;
+
+        match r {
             Ok(_) => Ok(()),
-            Err(e) => Err(Error::Internal(e.to_string())),
+            Err(err) => Err(sql_error(err)),
         }
     }
-}
 
-mod embedded {
-    use refinery::embed_migrations;
-    embed_migrations!("./src/persistence/migrations");
-}
-
-impl Subjects for Postgres {
-    async fn list(&self) -> Result<Vec<String>, Error> {
+    async fn take_login(&self, state_hash: &[u8]) -> Result<Login, Error> {
+        // abandoned logins are cleaned up alongside
+        let now = SystemTime::now();
         let r = self.client.query(r"
-            SELECT title
-            FROM subjects;
-        ", &[]).await;
+            DELETE FROM oidc_logins
+            WHERE state_hash = $1 OR expires_at < $2
+            RETURNING state_hash, code_verifier, nonce, expires_at;
+        ", &[&state_hash, &now]).await;
 
         match r {
-            Ok(rows) => Ok(
-                rows.into_iter()
-                    .map(|r| r.get(0))
-                    .collect::<Vec<String>>()
-            ),
-            Err(err) => Err(Error::Internal(err.to_string())),
+            Ok(rows) => rows.into_iter()
+                .find(|r| r.get::<_, &[u8]>(0) == state_hash && r.get::<_, SystemTime>(3) >= now)
+                .map(|r| Login {
+                    state_hash: r.get(0),
+                    code_verifier: r.get(1),
+                    nonce: r.get(2),
+                    expires_at: r.get(3),
+                })
+                .ok_or(Error::NotFound("login".into())),
+            Err(err) => Err(sql_error(err)),
         }
     }
 
-    async fn create(&self, user: &str, title: &str, content: &str) -> Result<(), Error> {
-        let r = self.client.query(r"
-            INSERT INTO subjects
+    async fn create_grant(&self, grant: &Grant) -> Result<(), Error> {
+        let r = self.client.execute(r"
+            INSERT INTO oidc_grants (code_hash, username, expires_at)
             VALUES ($1, $2, $3);
-        ", &[&title, &user, &content]).await;
+        ", &[&grant.code_hash, &grant.username, &grant.expires_at]).await;
 
         match r {
             Ok(_) => Ok(()),
-            Err(err) => Err(Error::Internal(err.to_string())),
+            Err(err) => Err(sql_error(err)),
         }
     }
 
-    async fn read(&self, title: &str) -> Result<String, Error> {
+    async fn take_grant(&self, code_hash: &[u8]) -> Result<Grant, Error> {
+        // unused grants are cleaned up alongside
+        let now = SystemTime::now();
         let r = self.client.query(r"
-            SELECT content
-            FROM subjects
-            WHERE title = $1;
-        ", &[&title]).await;
+            DELETE FROM oidc_grants
+            WHERE code_hash = $1 OR expires_at < $2
+            RETURNING code_hash, username, expires_at;
+        ", &[&code_hash, &now]).await;
 
         match r {
-            Ok(rows) => {
-                if rows.is_empty() {
-                    Err(Error::NotFound(title.to_string()))
-                } else {
-                    let content: String = rows[0].get(0);
-                    Ok(content)
-                }
-            },
-            Err(err) => Err(Error::Internal(err.to_string())),
+            Ok(rows) => rows.into_iter()
+                .find(|r| r.get::<_, &[u8]>(0) == code_hash && r.get::<_, SystemTime>(2) >= now)
+                .map(|r| Grant {
+                    code_hash: r.get(0),
+                    username: r.get(1),
+                    expires_at: r.get(2),
+                })
+                .ok_or(Error::NotFound("grant".into())),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+}
+
+impl PersonalTokens for Postgres {
+    async fn create_personal_token(&self, token: &PersonalToken) -> Result<i64, Error> {
+        let scopes: Vec<&str> = token.scopes.iter().map(Scope::as_str).collect();
+        let r = self.client.query_one(r"
+            INSERT INTO personal_tokens (token_hash, username, name, scopes, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id;
+        ", &[&token.hash, &token.username, &token.name, &scopes, &token.created_at, &token.expires_at]).await;
+
+        match r {
+            Ok(row) => Ok(row.get(0)),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn list_personal_tokens(&self, username: &str) -> Result<Vec<PersonalToken>, Error> {
+        let r = self.client.query(r"
+            SELECT id, token_hash, username, name, scopes, created_at, expires_at, last_used_at
+            FROM personal_tokens
+            WHERE username = $1
+            ORDER BY id;
+        ", &[&username]).await;
+
+        match r {
+            Ok(rows) => rows.iter().map(personal_token).collect(),
+            Err(err) => Err(sql_error(err)),
         }
     }
 
-    async fn update(&self, user: &str, title: &str, content: &str) -> Result<(), Error> {
+    async fn use_personal_token(&self, hash: &[u8], now: SystemTime) -> Result<PersonalToken, Error> {
+        let r = self.client.query_opt(r"
+            UPDATE personal_tokens
+            SET last_used_at = $2
+            WHERE token_hash = $1
+            RETURNING id, token_hash, username, name, scopes, created_at, expires_at, last_used_at;
+        ", &[&hash, &now]).await;
+
+        match r {
+            Ok(Some(row)) => personal_token(&row),
+            Ok(None) => Err(Error::NotFound("personal token".into())),
+            Err(err) => Err(sql_error(err)),
+        }
+    }
+
+    async fn delete_personal_token(&self, username: &str, id: i64) -> Result<(), Error> {
         let r = self.client.execute(r"
-            UPDATE subjects
-            SET user_id = $1, content = $2
-            WHERE title = $3;
-        ", &[&user, &content, &title]).await;
+            DELETE FROM personal_tokens
+            WHERE id = $1 AND username = $2;
+        ", &[&id, &username]).await;
 
         match r {
-            Ok(rows) => {
-                if rows < 1 {
-                    Err(Error::NotFound(title.to_string()))
-                } else {
-                    Ok(())
-                }
-            },
-            Err(err) => Err(Error::Internal(err.to_string()))
+            Ok(0) => Err(Error::NotFound(format!("personal token {}", id))),
+            Ok(_) => Ok(()),
+            Err(err) => Err(sql_error(err)),
         }
     }
 }
 
+fn personal_token(row: &tokio_postgres::Row) -> Result<PersonalToken, Error> {
+    let scopes: Vec<String> = row.get(4);
+    Ok(PersonalToken {
+        id: row.get(0),
+        hash: row.get(1),
+        username: row.get(2),
+        name: row.get(3),
+        scopes: scopes.iter()
+            .map(|s| Scope::parse(s).map_err(|e| Error::Internal(format!("{:?}", e))))
+            .collect::<Result<_, _>>()?,
+        created_at: row.get(5),
+        expires_at: row.get(6),
+        last_used_at: row.get(7),
+    })
+}
+
+/// sql_error maps the SQLSTATE of a failed statement to the error clients can
+/// act on, others are internal.
+fn sql_error(err: tokio_postgres::Error) -> Error {
+    let Some(code) = err.code() else {
+        return Error::Internal(err.to_string());
+    };
+    let constraint = err.as_db_error()
+        .and_then(|e| e.constraint())
+        .unwrap_or_default()
+        .to_string();
+
+    if [SqlState::UNIQUE_VIOLATION, SqlState::EXCLUSION_VIOLATION].contains(code) {
+        Error::Conflict(format!("already exists: {}", constraint))
+    } else if *code == SqlState::FOREIGN_KEY_VIOLATION {
+        Error::Conflict(format!("refers to what does not exist: {}", constraint))
+    } else if [SqlState::T_R_SERIALIZATION_FAILURE, SqlState::T_R_DEADLOCK_DETECTED].contains(code) {
+        Error::Conflict("changed concurrently, retry".into())
+    } else if [SqlState::STRING_DATA_RIGHT_TRUNCATION, SqlState::PROGRAM_LIMIT_EXCEEDED].contains(code) {
+        Error::PayloadTooLarge("value too long".into())
+    } else if [SqlState::CHECK_VIOLATION, SqlState::NOT_NULL_VIOLATION].contains(code) {
+        Error::BadRequest(format!("invalid value: {}", constraint))
+    } else {
+        Error::Internal(err.to_string())
+    }
+}
+
 async fn connect(host: &str, user: &str, database: &str) -> Result<tokio_postgres::Client, Error> {
     let c = tokio_postgres::connect(
         &format!("host={} user={} dbname={}", host, user, database),
@@ -110,7 +1422,6 @@ async fn connect(host: &str, user: &str, database: &str) -> Result<tokio_postgre
                     error!(target: "persistence/postgres", "connection error: {}", e);
                 }
             });
-            info!(target: "persistence/postgres", "Connected to database");
             Ok(client)
         },
     }
@@ -121,6 +1432,7 @@ mod tests {
     use std::{mem::ManuallyDrop, time::Duration};
 
     use rand::{distr::Alphanumeric, Rng};
+    use refinery::Target;
 
     use super::*;
 
@@ -134,7 +1446,7 @@ mod tests {
     }
 
     impl TestDB {
-        async fn new(host: &str, user: &str, database: &str) -> Self {
+        async fn new(host: &str, user: &str, database: &str, target: Target) -> Self {
             let database_name = format!("test_{}", rand::rng()
                 .sample_iter(&Alphanumeric)
                 .take(DATABASE_NAME_LENGTH)
@@ -144,7 +1456,8 @@ mod tests {
 
             let client = connect(host, user, database).await.unwrap();
 
-            client.execute(&format!("CREATE DATABASE {};", database_name), &[]).await.unwrap();
+            // as servers that can normalize titles are
+            client.execute(&format!("CREATE DATABASE {} ENCODING 'UTF8' TEMPLATE template0;", database_name), &[]).await.unwrap();
             println!("Created database: {}", database_name);
 
             while !database_exists(&client, &database_name).await {
@@ -152,7 +1465,7 @@ mod tests {
             }
 
             let mut db = Postgres::new(host, user, &database_name).await.unwrap();
-            db.migrate().await.unwrap();
+            embedded::migrations::runner().set_target(target).run_async(&mut db.client).await.unwrap();
 
             TestDB {
                 database_name,
@@ -162,11 +1475,16 @@ mod tests {
         }
 
         async fn new_from_env() -> Self {
+            Self::new_from_env_at(Target::Latest).await
+        }
+
+        async fn new_from_env_at(target: Target) -> Self {
             use std::env::var;
             Self::new(
                 &var("WIKI_CI_TEST_POSTGRES_HOST").unwrap_or("localhost".into()),
                 &var("WIKI_CI_TEST_POSTGRES_USER").unwrap_or("postgres".into()),
                 &var("WIKI_CI_TEST_POSTGRES_DATABASE").unwrap_or("postgres".into()),
+                target,
             ).await
         }
     }
@@ -200,37 +1518,751 @@ mod tests {
     #[ignore]
     async fn test_subject() {
         let harness = TestDB::new_from_env().await;
+        let title = |raw: &str| Title::parse(raw, 255).unwrap();
 
         // 1. Test subject that does not exist returns error
-        let r = harness.db.read("Does not exist").await;
+        let r = harness.db.read(&title("Does not exist")).await;
         assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
 
         // 2. Create subject
-        let r = harness.db.create("test_user", "Exists", "Some content").await;
+        let r = harness.db.create("test_user", &title("Exists"), "Some content", &Edit::default()).await;
         assert!(r.is_ok());
 
         // 3. Assert subject has old content and not new content
         let new_content = "New content".to_string();
-        let r = harness.db.read("Exists").await;
+        let r = harness.db.read(&title("Exists")).await;
         assert_ne!(r.unwrap(), new_content);
 
         // 4. Update subject and assert has new content
-        let r = harness.db.update("test_user", "Exists", &new_content).await;
+        let r = harness.db.update("test_user", &title("Exists"), &new_content, &Edit::default()).await;
         assert!(r.is_ok());
-        let r = harness.db.read("Exists").await;
+        let r = harness.db.read(&title("Exists")).await;
         assert_eq!(r.unwrap(), new_content);
 
         // 5. List subjects
-        let r = harness.db.create("test_user", "Exists2", "Some content").await;
+        let r = harness.db.create("test_user", &title("Exists 2"), "Some content", &Edit::default()).await;
         assert!(r.is_ok());
         let r = harness.db.list().await;
         let mut actual = r.unwrap();
         actual.sort();
         let mut expected: Vec<String> = vec![
             "Exists".into(),
-            "Exists2".into(),
+            "Exists 2".into(),
         ];
         expected.sort();
         assert_eq!(actual, expected);
+
+        // 6. Titles differing in case name the same subject
+        let r = harness.db.read(&title("EXISTS_2")).await;
+        assert_eq!(r.unwrap(), "Some content");
+        let r = harness.db.update("test_user", &title("exists"), "Newer content", &Edit::default()).await;
+        assert!(r.is_ok());
+        let r = harness.db.create("test_user", &title("exists"), "Other content", &Edit::default()).await;
+        assert!(matches!(r, Err(Error::Conflict(ref msg)) if msg.contains("already exists")), "{:?}", r);
+        let r = harness.db.read(&title("Exists")).await;
+        assert_eq!(r.unwrap(), "Newer content");
+
+        // 7. List pages in case-insensitive order
+        harness.db.create("test_user", &title("apple"), "Some content", &Edit::default()).await.unwrap();
+        let r = harness.db.list_after(None, 2).await.unwrap();
+        assert_eq!(r, vec!["apple".to_string(), "Exists".into()]);
+        let r = harness.db.list_after(Some("EXISTS"), 2).await.unwrap();
+        assert_eq!(r, vec!["Exists 2".to_string()]);
+
+        // 8. Conditional updates apply while the content is unchanged
+        let r = harness.db.update_if("test_user", &title("Exists"), "Newer content", "Newest content", &Edit::default()).await;
+        assert!(r.is_ok(), "{:?}", r);
+        let r = harness.db.update_if("test_user", &title("Exists"), "Newer content", "Stale content", &Edit::default()).await;
+        assert!(matches!(r, Err(Error::PreconditionFailed(_))), "{:?}", r);
+        assert_eq!(harness.db.read(&title("Exists")).await.unwrap(), "Newest content");
+        let r = harness.db.update_if("test_user", &title("Missing"), "", "content", &Edit::default()).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_canonicalize_titles() {
+        async fn canonicalize(titles: &[&str]) -> (TestDB, Result<(), String>) {
+            let mut harness = TestDB::new_from_env_at(Target::Version(10)).await;
+            for title in titles {
+                harness.db.client.execute("INSERT INTO subjects (title, user_id, content) VALUES ($1, 'test_user', '');", &[title]).await.unwrap();
+            }
+            let r = harness.db.migrate().await.map_err(|e| format!("{:?}", e));
+            (harness, r)
+        }
+
+        // 1. Titles are decoded, folded and normalized to NFC
+        let (harness, r) = canonicalize(&["Rust_%20programming", "Cafe\u{301}", "Plain"]).await;
+        r.unwrap();
+        let mut titles = harness.db.list().await.unwrap();
+        titles.sort();
+        assert_eq!(titles, vec!["Café", "Plain", "Rust programming"]);
+
+        // 2. Titles that cannot be canonicalized fail, listing them, and
+        // change nothing
+        let (harness, r) = canonicalize(&["Bad%FF", "a%2Fb", "%20_", "Fine_title"]).await;
+        let err = r.unwrap_err();
+        for title in ["'Bad%FF'", "'a%2Fb'", "'%20_'"] {
+            assert!(err.contains(title), "{}: {}", title, err);
+        }
+        assert!(!err.contains("Fine"), "{}", err);
+        assert!(harness.db.list().await.unwrap().contains(&"Fine_title".to_string()));
+
+        // 3. Titles that name the same subject once canonical fail, listing
+        // them
+        let (_, r) = canonicalize(&["A_b", "A%20b", "Case", "CASE", "Other"]).await;
+        let err = r.unwrap_err();
+        assert!(err.contains("('A%20b', 'A_b')"), "{}", err);
+        assert!(err.contains("('CASE', 'Case')"), "{}", err);
+        assert!(!err.contains("Other"), "{}", err);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_units_of_work() {
+        let harness = TestDB::new_from_env().await;
+        let title = |raw: &str| Title::parse(raw, 255).unwrap();
+        harness.db.create("test_user", &title("Old"), "old", &Edit::default()).await.unwrap();
+        harness.db.create("test_user", &title("Gone"), "soon", &Edit::default()).await.unwrap();
+
+        // 1. Changes are not seen before commit
+        let mut uow = harness.db.begin().await.unwrap();
+        uow.create("test_user", &title("New"), "new", &Edit::default()).await.unwrap();
+        uow.update("test_user", &title("old"), "renewed", &Edit::default()).await.unwrap();
+        uow.delete(&title("GONE")).await.unwrap();
+        let r = harness.db.read(&title("New")).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        assert_eq!(harness.db.read(&title("Old")).await.unwrap(), "old");
+
+        // 2. Changes are seen after commit, deleted subjects take their edits
+        uow.commit().await.unwrap();
+        assert_eq!(harness.db.read(&title("New")).await.unwrap(), "new");
+        assert_eq!(harness.db.read(&title("Old")).await.unwrap(), "renewed");
+        let r = harness.db.read(&title("Gone")).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let contributions = harness.db.list_contributions("test_user").await.unwrap();
+        assert!(contributions.iter().all(|c| c.title != "Gone"), "{:?}", contributions);
+
+        // 3. Failed changes and dropped units of work change nothing
+        let mut uow = harness.db.begin().await.unwrap();
+        uow.update("test_user", &title("Old"), "changed", &Edit::default()).await.unwrap();
+        let r = uow.create("test_user", &title("NEW"), "again", &Edit::default()).await;
+        assert!(matches!(r, Err(Error::Conflict(_))), "{:?}", r);
+        let r = uow.delete(&title("Missing")).await;
+        assert!(r.is_err(), "{:?}", r);
+        drop(uow);
+        assert_eq!(harness.db.read(&title("Old")).await.unwrap(), "renewed");
+
+        // 4. Transactions open at once are limited, ending one lets another
+        // begin
+        let mut open = vec![];
+        for _ in 0..MAX_TRANSACTIONS {
+            open.push(harness.db.begin().await.unwrap());
+        }
+        let r = tokio::time::timeout(Duration::from_millis(200), harness.db.begin()).await;
+        assert!(r.is_err(), "began more than {} transactions", MAX_TRANSACTIONS);
+        open.pop();
+        harness.db.begin().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_profiles() {
+        let harness = TestDB::new_from_env().await;
+
+        // 1. Unknown users have no profile
+        let r = harness.db.read_profile("test_user").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 2. Users with an account have an empty profile
+        harness.db.create_user("test_user", "hash").await.unwrap();
+        let r = harness.db.read_profile("test_user").await.unwrap();
+        assert_eq!(r, Profile { username: "test_user".into(), ..Default::default() });
+
+        // 3. Update profile and avatar
+        let update = ProfileUpdate { display_name: "Test User".into(), bio: "Tests things".into() };
+        harness.db.update_profile("test_user", &update).await.unwrap();
+        let avatar = Avatar { content_type: "image/png".into(), content: b"png".to_vec() };
+        harness.db.update_avatar("test_user", &avatar).await.unwrap();
+        let r = harness.db.read_profile("test_user").await.unwrap();
+        assert_eq!(r.display_name, "Test User");
+        assert_eq!(r.bio, "Tests things");
+        assert_eq!(r.avatar_content_type.unwrap(), "image/png");
+        assert_eq!(harness.db.read_avatar("test_user").await.unwrap(), avatar);
+        let r = harness.db.read_avatar("other_user").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 4. Creates and updates are contributions, newest first, with their edits
+        let minor = Edit { summary: "Fix typo".into(), minor: true };
+        harness.db.create("other_user", &Title::parse("First", 255).unwrap(), "Some content", &Edit::default()).await.unwrap();
+        harness.db.update("other_user", &Title::parse("First", 255).unwrap(), "New content", &minor).await.unwrap();
+        harness.db.create("other_user", &Title::parse("Second", 255).unwrap(), "Some content", &Edit::default()).await.unwrap();
+        harness.db.update("test_user", &Title::parse("Second", 255).unwrap(), "New content", &Edit::default()).await.unwrap();
+        let r = harness.db.list_contributions("other_user").await.unwrap();
+        let actual: Vec<_> = r.iter().map(|c| (c.title.as_str(), c.created)).collect();
+        assert_eq!(actual, vec![("Second", true), ("First", false), ("First", true)]);
+        assert_eq!((r[1].summary.as_str(), r[1].minor), ("Fix typo", true));
+        assert_eq!((r[2].summary.as_str(), r[2].minor), ("", false));
+        assert_eq!(harness.db.list_contributions("test_user").await.unwrap().len(), 1);
+
+        // 5. Contributors are known without an account
+        assert!(harness.db.read_profile("other_user").await.is_ok());
+
+        // 6. Updating a missing subject is no contribution
+        let r = harness.db.update("test_user", &Title::parse("Missing", 255).unwrap(), "Content", &Edit::default()).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        assert_eq!(harness.db.list_contributions("test_user").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_drafts() {
+        let harness = TestDB::new_from_env().await;
+        let title = |t| Title::parse(t, 255).unwrap();
+
+        // 1. Drafts are saved per user, a second save replaces the first
+        // and keeps its base
+        harness.db.save_draft("test_user", &title("First"), "one", Some("\"base\"")).await.unwrap();
+        harness.db.save_draft("test_user", &title("Second"), "two", None).await.unwrap();
+        harness.db.save_draft("test_user", &title("first"), "one again", Some("\"other\"")).await.unwrap();
+        harness.db.save_draft("other_user", &title("First"), "theirs", None).await.unwrap();
+        let r = harness.db.read_draft("test_user", &title("FIRST")).await.unwrap();
+        assert_eq!((r.title.as_str(), r.content.as_str(), r.base.as_deref()), ("first", "one again", Some("\"base\"")));
+        assert_eq!(harness.db.read_draft("test_user", &title("Second")).await.unwrap().base, None);
+
+        // 2. Drafts list newest first, only those of the user
+        let r = harness.db.list_drafts("test_user").await.unwrap();
+        let actual: Vec<_> = r.iter().map(|d| d.title.as_str()).collect();
+        assert_eq!(actual, vec!["first", "Second"]);
+        assert!(harness.db.list_drafts("unknown_user").await.unwrap().is_empty());
+
+        // 3. Deleted drafts are gone, for their author only
+        harness.db.delete_draft("test_user", &title("First")).await.unwrap();
+        let r = harness.db.read_draft("test_user", &title("First")).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let r = harness.db.delete_draft("test_user", &title("First")).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        assert_eq!(harness.db.read_draft("other_user", &title("First")).await.unwrap().content, "theirs");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_stats() {
+        let harness = TestDB::new_from_env().await;
+        let title = |t| Title::parse(t, 255).unwrap();
+        for t in ["Popular", "Stale", "Busy"] {
+            harness.db.create("test_user", &title(t), "content", &Edit::default()).await.unwrap();
+        }
+        for _ in 0..2 {
+            harness.db.update("test_user", &title("Busy"), "more content", &Edit::default()).await.unwrap();
+        }
+
+        // 1. Views add up per day, views of missing subjects are dropped
+        harness.db.record_views(&[("popular".into(), 3), ("Busy".into(), 1), ("Missing".into(), 5)]).await.unwrap();
+        harness.db.record_views(&[("Popular".into(), 2)]).await.unwrap();
+        let r = harness.db.read_stats(&title("POPULAR"), 30).await.unwrap();
+        assert_eq!(r.title, "Popular");
+        assert_eq!(r.days.iter().map(|d| (d.views, d.edits)).collect::<Vec<_>>(), vec![(5, 1)]);
+        let r = harness.db.read_stats(&title("Missing"), 30).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 2. Views of earlier days are outside fewer days
+        harness.db.client.execute("UPDATE subject_views SET day = day - 10 WHERE title = 'Busy';", &[]).await.unwrap();
+        let r = harness.db.read_stats(&title("Busy"), 30).await.unwrap();
+        assert_eq!(r.days.iter().map(|d| (d.views, d.edits)).collect::<Vec<_>>(), vec![(1, 0), (0, 3)]);
+        let r = harness.db.read_stats(&title("Busy"), 7).await.unwrap();
+        assert_eq!(r.days.iter().map(|d| (d.views, d.edits)).collect::<Vec<_>>(), vec![(0, 3)]);
+
+        // 3. Reports rank every subject
+        let ranks = |r: Vec<Usage>| r.into_iter().map(|u| (u.title, u.views, u.edits)).collect::<Vec<_>>();
+        assert_eq!(ranks(harness.db.report(Report::MostViewed, 30, 2).await.unwrap()), vec![
+            ("Popular".to_string(), 5, 1),
+            ("Busy".to_string(), 1, 3),
+        ]);
+        assert_eq!(ranks(harness.db.report(Report::LeastViewed, 7, 10).await.unwrap()), vec![
+            ("Busy".to_string(), 0, 3),
+            ("Stale".to_string(), 0, 1),
+            ("Popular".to_string(), 5, 1),
+        ]);
+        assert_eq!(ranks(harness.db.report(Report::MostEdited, 30, 1).await.unwrap()), vec![
+            ("Busy".to_string(), 1, 3),
+        ]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_links() {
+        let harness = TestDB::new_from_env().await;
+        let title = |t| Title::parse(t, 255).unwrap();
+        let titles = |r: Vec<Linked>| r.into_iter().map(|l| (l.title, l.links)).collect::<Vec<_>>();
+        let db = &harness.db;
+        let report = |report, after| async move { titles(db.report_links(report, after, 10).await.unwrap()) };
+        harness.db.create("test_user", &title("Home"), "[Rust](/wiki/Rust), [the book](/wiki/The_Book)", &Edit::default()).await.unwrap();
+        harness.db.create("test_user", &title("Rust"), "[home](/wiki/home) and [itself](/wiki/Rust)", &Edit::default()).await.unwrap();
+        harness.db.create("test_user", &title("Lonely"), "no links", &Edit::default()).await.unwrap();
+
+        // 1. Creates extract links, which reports count without links to self
+        assert_eq!(report(LinkReport::Orphans, None).await, vec![("Lonely".to_string(), 0)]);
+        assert_eq!(report(LinkReport::Wanted, None).await, vec![("The Book".to_string(), 1)]);
+        assert_eq!(report(LinkReport::DeadEnds, None).await, vec![("Lonely".to_string(), 0)]);
+
+        // 2. Updates replace links, keeping those still in content, in the
+        // spelling of the content
+        harness.db.update("test_user", &title("Home"), "[the book](/wiki/the_book), [lonely](/wiki/Lonely), [Cargo](/wiki/Cargo)", &Edit::default()).await.unwrap();
+        assert_eq!(report(LinkReport::Orphans, None).await, vec![("Rust".to_string(), 0)]);
+        assert_eq!(report(LinkReport::Wanted, None).await, vec![("Cargo".to_string(), 1), ("the book".to_string(), 1)]);
+        assert_eq!(report(LinkReport::Wanted, Some("cargo")).await, vec![("the book".to_string(), 1)]);
+        assert_eq!(report(LinkReport::DeadEnds, None).await, vec![("Lonely".to_string(), 1)]);
+
+        // 3. Links go with their subject, creating a wanted title fulfills it
+        // and deleting a linked one makes it wanted
+        let mut uow = harness.db.begin().await.unwrap();
+        uow.delete(&title("Home")).await.unwrap();
+        uow.create("test_user", &title("Cargo"), "", &Edit::default()).await.unwrap();
+        uow.commit().await.unwrap();
+        assert_eq!(report(LinkReport::Wanted, None).await, vec![("home".to_string(), 1)]);
+        assert_eq!(report(LinkReport::Orphans, None).await, vec![("Cargo".to_string(), 0), ("Lonely".to_string(), 0), ("Rust".to_string(), 0)]);
+
+        // 4. Links of subjects stored before links were are extracted once
+        harness.db.client.execute("INSERT INTO subjects (title, user_id, content, extracted) VALUES ('Old', 'test_user', '[new](/wiki/New)', false);", &[]).await.unwrap();
+        assert_eq!(harness.db.extract().await.unwrap(), 1);
+        assert_eq!(harness.db.extract().await.unwrap(), 0);
+        assert_eq!(report(LinkReport::Wanted, Some("home")).await, vec![("New".to_string(), 1)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_renders() {
+        let harness = TestDB::new_from_env().await;
+        let title = |t| Title::parse(t, 255).unwrap();
+        let db = &harness.db;
+        let render = |t| async move { db.read_render(&title(t)).await.unwrap() };
+        let nested = [("Nested".to_string(), None)];
+        let included = [("Included".to_string(), Some("included {{Nested}}".to_string())), ("Nested".to_string(), None)];
+        harness.db.create("test_user", &title("Page"), "page {{Included}}", &Edit::default()).await.unwrap();
+        harness.db.create("test_user", &title("Included"), "included {{Nested}}", &Edit::default()).await.unwrap();
+        harness.db.create("test_user", &title("Other"), "other", &Edit::default()).await.unwrap();
+
+        // 1. Renders are saved and read whatever the case of the title
+        harness.db.save_render(&title("Page"), "page included", &included).await.unwrap();
+        harness.db.save_render(&title("Included"), "included", &nested).await.unwrap();
+        harness.db.save_render(&title("Other"), "other", &[]).await.unwrap();
+        assert_eq!(render("page").await, Some("page included".to_string()));
+        assert_eq!(render("Included").await, Some("included".to_string()));
+        assert_eq!(render("Missing").await, None);
+
+        // 2. Creating a missing subject invalidates those that include it,
+        // however deep, and no others
+        harness.db.create("test_user", &title("Nested"), "nested", &Edit::default()).await.unwrap();
+        assert_eq!(render("Included").await, None);
+        assert_eq!(render("Page").await, None);
+        assert_eq!(render("Other").await, Some("other".to_string()));
+
+        // 3. Renders of subjects that changed while rendering are not saved
+        harness.db.save_render(&title("Page"), "page included", &included).await.unwrap();
+        assert_eq!(render("Page").await, None);
+        let included = [("Included".to_string(), Some("included {{Nested}}".to_string())), ("Nested".to_string(), Some("nested".to_string()))];
+        harness.db.save_render(&title("Page"), "page included nested", &included).await.unwrap();
+        assert_eq!(render("Page").await, Some("page included nested".to_string()));
+
+        // 4. Updates invalidate renders of the subject and its includers
+        harness.db.update("test_user", &title("Nested"), "changed", &Edit::default()).await.unwrap();
+        assert_eq!(render("Page").await, None);
+        harness.db.save_render(&title("Page"), "page", &[]).await.unwrap();
+        harness.db.update("test_user", &title("Page"), "page", &Edit::default()).await.unwrap();
+        assert_eq!(render("Page").await, None);
+
+        // 5. Deleting a subject invalidates renders of its includers
+        harness.db.save_render(&title("Page"), "page", &[]).await.unwrap();
+        harness.db.update("test_user", &title("Other"), "other {{Page}}", &Edit::default()).await.unwrap();
+        harness.db.save_render(&title("Other"), "other page", &[("Page".to_string(), Some("page".to_string()))]).await.unwrap();
+        let mut uow = harness.db.begin().await.unwrap();
+        uow.delete(&title("Page")).await.unwrap();
+        uow.commit().await.unwrap();
+        assert_eq!(render("Other").await, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_accounts() {
+        let harness = TestDB::new_from_env().await;
+
+        // 1. Unknown user is not found
+        let r = harness.db.read_user("test_user").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 2. Create user, duplicates are rejected
+        let r = harness.db.create_user("test_user", "hash").await;
+        assert!(r.is_ok(), "{:?}", r);
+        let r = harness.db.create_user("test_user", "other hash").await;
+        assert!(matches!(r, Err(Error::Conflict(_))), "{:?}", r);
+        let account = harness.db.read_user("test_user").await.unwrap();
+        assert_eq!(account.password_hash.unwrap(), "hash");
+        assert!(account.roles.is_empty());
+
+        // 3. External users are created once per subject, and roles updated
+        let r = harness.db.upsert_external_user("idp alice", "alice", &[Role::Admin]).await.unwrap();
+        assert_eq!(r.username, "alice");
+        assert_eq!(r.roles, vec![Role::Admin]);
+        assert!(r.password_hash.is_none());
+        let r = harness.db.upsert_external_user("idp alice", "alice2", &[Role::Editor, Role::Admin]).await.unwrap();
+        assert_eq!(r.username, "alice");
+        assert_eq!(r.roles, vec![Role::Editor, Role::Admin]);
+        assert_eq!(harness.db.read_user("alice").await.unwrap().roles, vec![Role::Editor, Role::Admin]);
+
+        // 4. External users cannot take existing usernames
+        let r = harness.db.upsert_external_user("idp mallory", "test_user", &[]).await;
+        assert!(matches!(r, Err(Error::Conflict(_))), "{:?}", r);
+
+        // 5. Update password
+        harness.db.update_password("test_user", "new hash").await.unwrap();
+        assert_eq!(harness.db.read_user("test_user").await.unwrap().password_hash.unwrap(), "new hash");
+        let r = harness.db.update_password("unknown_user", "hash").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_second_factors() {
+        let harness = TestDB::new_from_env().await;
+        harness.db.create_user("test_user", "hash").await.unwrap();
+
+        // 1. Unconfirmed secrets are replaced, and do not enable TOTP
+        let r = harness.db.read_totp("test_user").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        harness.db.create_totp("test_user", b"first").await.unwrap();
+        harness.db.create_totp("test_user", b"second").await.unwrap();
+        let t = harness.db.read_totp("test_user").await.unwrap();
+        assert_eq!(t.secret, b"second");
+        assert!(!t.enabled);
+        assert!(!harness.db.read_user("test_user").await.unwrap().totp_enabled);
+        let r = harness.db.create_totp("unknown_user", b"secret").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 2. Enabling confirms the secret, which is then kept
+        harness.db.enable_totp("test_user", 10).await.unwrap();
+        let t = harness.db.read_totp("test_user").await.unwrap();
+        assert!(t.enabled);
+        assert_eq!(t.last_step, Some(10));
+        assert!(harness.db.read_user("test_user").await.unwrap().totp_enabled);
+        let r = harness.db.create_totp("test_user", b"third").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let r = harness.db.enable_totp("test_user", 11).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 3. Steps are used once, in order
+        harness.db.use_totp_step("test_user", 11).await.unwrap();
+        for step in [11, 10] {
+            let r = harness.db.use_totp_step("test_user", step).await;
+            assert!(matches!(r, Err(Error::NotFound(_))), "{}: {:?}", step, r);
+        }
+
+        // 4. Recovery codes are used once, and replaced together
+        harness.db.replace_recovery_codes("test_user", &[b"a".to_vec(), b"b".to_vec()]).await.unwrap();
+        harness.db.use_recovery_code("test_user", b"a").await.unwrap();
+        let r = harness.db.use_recovery_code("test_user", b"a").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        harness.db.replace_recovery_codes("test_user", &[b"c".to_vec()]).await.unwrap();
+        let r = harness.db.use_recovery_code("test_user", b"b").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 5. Deleting removes the secret and recovery codes
+        harness.db.delete_totp("test_user").await.unwrap();
+        let r = harness.db.read_totp("test_user").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let r = harness.db.use_recovery_code("test_user", b"c").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        assert!(!harness.db.read_user("test_user").await.unwrap().totp_enabled);
+        let r = harness.db.delete_totp("test_user").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_lockouts() {
+        let harness = TestDB::new_from_env().await;
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(60 * 60);
+
+        // 1. Failures count up within the window
+        for failures in 1..=3 {
+            let c = harness.db.record_failure("account:bob", now, now - hour).await.unwrap();
+            assert_eq!((c.key.as_str(), c.failures, c.locked_until), ("account:bob", failures, None));
+        }
+
+        // 2. Locked counters are listed until they unlock
+        harness.db.lock("account:bob", now + hour).await.unwrap();
+        harness.db.record_failure("ip:10.0.0.1", now, now - hour).await.unwrap();
+        let locked = harness.db.list_locked(now).await.unwrap();
+        assert_eq!(locked.iter().map(|c| c.key.as_str()).collect::<Vec<_>>(), vec!["account:bob"]);
+        assert!(harness.db.list_locked(now + hour).await.unwrap().is_empty());
+        let r = harness.db.lock("account:alice", now + hour).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 3. Counters are read by key, unknown keys are skipped
+        let counters = harness.db.read_counters(&["account:bob".into(), "ip:10.0.0.1".into(), "account:alice".into()]).await.unwrap();
+        let mut keys: Vec<_> = counters.iter().map(|c| (c.key.as_str(), c.failures, c.locked_until.is_some())).collect();
+        keys.sort();
+        assert_eq!(keys, vec![("account:bob", 3, true), ("ip:10.0.0.1", 1, false)]);
+
+        // 4. Counters past the window restart unlocked
+        let later = now + 2 * hour;
+        let c = harness.db.record_failure("account:bob", later, later - hour).await.unwrap();
+        assert_eq!((c.failures, c.locked_until), (1, None));
+
+        // 5. Clearing removes the counter
+        harness.db.clear("account:bob").await.unwrap();
+        assert!(harness.db.read_counters(&["account:bob".into()]).await.unwrap().is_empty());
+        let r = harness.db.clear("account:bob").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 6. Audit entries are recorded
+        harness.db.record_audit(&Entry {
+            at: now,
+            actor: Some("admin".into()),
+            action: "unlock".into(),
+            target: "account:bob".into(),
+            detail: "".into(),
+        }).await.unwrap();
+        let row = harness.db.client.query_one("SELECT actor, action, target FROM audit_log;", &[]).await.unwrap();
+        assert_eq!((row.get::<_, String>(0), row.get::<_, String>(1), row.get::<_, String>(2)), ("admin".into(), "unlock".into(), "account:bob".into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_emails() {
+        let harness = TestDB::new_from_env().await;
+        harness.db.create_user("test_user", "hash").await.unwrap();
+        harness.db.create_user("other_user", "hash").await.unwrap();
+
+        // 1. Addresses are unverified until verified
+        harness.db.set_email("test_user", "Test@example.com").await.unwrap();
+        let r = harness.db.read_verified_username("test@example.com").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let r = harness.db.verify_email("test_user", "old@example.com").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        harness.db.verify_email("test_user", "Test@example.com").await.unwrap();
+        assert_eq!(harness.db.read_verified_username("test@example.com").await.unwrap(), "test_user");
+
+        // 2. Only one user may verify an address
+        harness.db.set_email("other_user", "test@EXAMPLE.com").await.unwrap();
+        let r = harness.db.verify_email("other_user", "test@EXAMPLE.com").await;
+        assert!(matches!(r, Err(Error::Conflict(_))), "{:?}", r);
+        let r = harness.db.set_email("unknown_user", "unknown@example.com").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 3. Tokens are single use, and replace tokens of the same purpose
+        let now = SystemTime::now();
+        let new_token = |hash: &[u8], purpose, expires_at| EmailToken {
+            hash: hash.to_vec(),
+            username: "test_user".into(),
+            purpose,
+            email: "test@example.com".into(),
+            expires_at,
+        };
+        harness.db.create_email_token(&new_token(b"reset 1", Purpose::Reset, now + Duration::from_secs(60))).await.unwrap();
+        harness.db.create_email_token(&new_token(b"verify", Purpose::Verify, now + Duration::from_secs(60))).await.unwrap();
+        harness.db.create_email_token(&new_token(b"reset 2", Purpose::Reset, now + Duration::from_secs(60))).await.unwrap();
+        let r = harness.db.take_email_token(b"reset 1").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let t = harness.db.take_email_token(b"reset 2").await.unwrap();
+        assert_eq!(t.purpose, Purpose::Reset);
+        assert_eq!(t.username, "test_user");
+        let r = harness.db.take_email_token(b"reset 2").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        assert_eq!(harness.db.take_email_token(b"verify").await.unwrap().purpose, Purpose::Verify);
+
+        // 4. Expired tokens are not returned
+        harness.db.create_email_token(&new_token(b"expired", Purpose::Reset, now - Duration::from_secs(1))).await.unwrap();
+        let r = harness.db.take_email_token(b"expired").await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_sessions() {
+        let harness = TestDB::new_from_env().await;
+        harness.db.create_user("test_user", "hash").await.unwrap();
+        let now = SystemTime::now();
+        let session = Session {
+            id: 0,
+            username: "test_user".into(),
+            created_at: now,
+            expires_at: now + Duration::from_secs(60),
+            second_factor_at: None,
+        };
+
+        // 1. Create and read sessions, expired sessions are not found
+        let id = harness.db.create_session(&session).await.unwrap();
+        let r = harness.db.read_session(id).await.unwrap();
+        assert_eq!(r.username, "test_user");
+        let expired = harness.db.create_session(&Session {
+            expires_at: now - Duration::from_secs(1),
+            ..session.clone()
+        }).await.unwrap();
+        let r = harness.db.read_session(expired).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 2. Sessions record second factors, expired sessions do not
+        assert!(harness.db.read_session(id).await.unwrap().second_factor_at.is_none());
+        harness.db.record_second_factor(id, now).await.unwrap();
+        let at = harness.db.read_session(id).await.unwrap().second_factor_at.unwrap();
+        assert!(at.duration_since(now).unwrap_or_else(|e| e.duration()) < Duration::from_millis(1));
+        let r = harness.db.record_second_factor(expired, now).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 3. Tokens extend their session, and are used once
+        let expires_at = now + Duration::from_secs(3600);
+        let token = Token {
+            hash: vec![1, 2, 3],
+            session: id,
+            username: "test_user".into(),
+            expires_at,
+            used_at: None,
+        };
+        harness.db.create_token(&token).await.unwrap();
+        assert!(harness.db.read_session(id).await.unwrap().expires_at > now + Duration::from_secs(3000));
+        let r = harness.db.use_token(&token.hash, now).await.unwrap();
+        assert_eq!(r.session, id);
+        assert!(r.used_at.is_none());
+        let r = harness.db.use_token(&token.hash, now).await.unwrap();
+        assert!(r.used_at.is_some());
+        let r = harness.db.use_token(&[9], now).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 4. Revoking sessions revokes their tokens
+        harness.db.revoke_session(id).await.unwrap();
+        let r = harness.db.read_session(id).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        let r = harness.db.read_token(&token.hash).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 5. Revoke all of a user's sessions
+        let a = harness.db.create_session(&session).await.unwrap();
+        let b = harness.db.create_session(&session).await.unwrap();
+        let mut ids = harness.db.revoke_sessions("test_user").await.unwrap();
+        ids.sort();
+        assert_eq!(ids, vec![a, b]);
+        assert!(harness.db.revoke_sessions("test_user").await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_personal_tokens() {
+        let harness = TestDB::new_from_env().await;
+        harness.db.create_user("test_user", "hash").await.unwrap();
+        harness.db.create_user("other_user", "hash").await.unwrap();
+        let now = std::time::UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+        let token = PersonalToken {
+            id: 0,
+            hash: vec![1, 2, 3],
+            username: "test_user".into(),
+            name: "bot".into(),
+            scopes: vec![Scope::Read, Scope::Write],
+            created_at: now,
+            expires_at: Some(now + Duration::from_secs(3600)),
+            last_used_at: None,
+        };
+
+        // 1. Created tokens are listed by owner
+        let id = harness.db.create_personal_token(&token).await.unwrap();
+        let list = harness.db.list_personal_tokens("test_user").await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, id);
+        assert_eq!(list[0].scopes, token.scopes);
+        assert_eq!(list[0].expires_at, token.expires_at);
+        assert!(list[0].last_used_at.is_none());
+        assert!(harness.db.list_personal_tokens("other_user").await.unwrap().is_empty());
+
+        // 2. Use records last used time
+        let used = harness.db.use_personal_token(&token.hash, now).await.unwrap();
+        assert_eq!(used.id, id);
+        assert_eq!(used.last_used_at, Some(now));
+        let r = harness.db.use_personal_token(&[9], now).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 3. Only the owner deletes
+        let r = harness.db.delete_personal_token("other_user", id).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        harness.db.delete_personal_token("test_user", id).await.unwrap();
+        let r = harness.db.use_personal_token(&token.hash, now).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_logins() {
+        let harness = TestDB::new_from_env().await;
+        let login = Login {
+            state_hash: vec![1, 2, 3],
+            code_verifier: "verifier".into(),
+            nonce: "nonce".into(),
+            expires_at: SystemTime::now() + Duration::from_secs(600),
+        };
+
+        // 1. Logins are taken once
+        harness.db.create_login(&login).await.unwrap();
+        let r = harness.db.take_login(&login.state_hash).await.unwrap();
+        assert_eq!(r.code_verifier, login.code_verifier);
+        assert_eq!(r.nonce, login.nonce);
+        let r = harness.db.take_login(&login.state_hash).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 2. Expired logins are not found
+        harness.db.create_login(&Login {
+            expires_at: SystemTime::now() - Duration::from_secs(1),
+            ..login.clone()
+        }).await.unwrap();
+        let r = harness.db.take_login(&login.state_hash).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+
+        // 3. Grants are taken once, and not found once expired
+        harness.db.upsert_external_user("issuer user-1", "alice", &[]).await.unwrap();
+        let grant = Grant {
+            code_hash: vec![4, 5, 6],
+            username: "alice".into(),
+            expires_at: SystemTime::now() + Duration::from_secs(60),
+        };
+        harness.db.create_grant(&grant).await.unwrap();
+        assert_eq!(harness.db.take_grant(&grant.code_hash).await.unwrap().username, "alice");
+        let r = harness.db.take_grant(&grant.code_hash).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        harness.db.create_grant(&Grant {
+            expires_at: SystemTime::now() - Duration::from_secs(1),
+            ..grant.clone()
+        }).await.unwrap();
+        let r = harness.db.take_grant(&grant.code_hash).await;
+        assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_keys() {
+        let harness = TestDB::new_from_env().await;
+        let now = std::time::UNIX_EPOCH + Duration::from_secs(SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH).unwrap().as_secs());
+
+        // 1. Create keys, only unexpired keys are listed
+        let live = SigningKey::generate(Algorithm::EdDSA, now, now + Duration::from_secs(3600)).unwrap();
+        let expired = SigningKey::generate(Algorithm::HS256, now, now - Duration::from_secs(1)).unwrap();
+        harness.db.create_key(&live).await.unwrap();
+        harness.db.create_key(&expired).await.unwrap();
+
+        let keys = harness.db.list_keys().await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].kid, live.kid);
+        assert_eq!(keys[0].algorithm, live.algorithm);
+        assert_eq!(keys[0].private_key, live.private_key);
+        assert_eq!(keys[0].public_key, live.public_key);
+        assert_eq!(keys[0].created_at, live.created_at);
+        assert_eq!(keys[0].expires_at, live.expires_at);
+
+        // 2. Delete expired keys
+        harness.db.delete_expired_keys().await.unwrap();
+        let count: i64 = harness.db.client
+            .query_one("SELECT count(*) FROM signing_keys;", &[])
+            .await.unwrap()
+            .get(0);
+        assert_eq!(count, 1);
     }
 }
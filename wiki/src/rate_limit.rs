@@ -0,0 +1,446 @@
+// rate_limit limits how fast clients make requests, with a token bucket per
+// client and budget. Clients are users when requests carry an access token,
+// which verifies without persistence, and client IPs otherwise. Buckets are
+// kept in memory, or in Redis so that replicas share them.
+
+use std::{collections::HashMap, future::Future, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use redis::aio::ConnectionManager;
+use warp::{http::{header::AUTHORIZATION, HeaderMap, Method}, reject::Rejection, reply::Reply, Filter};
+
+use crate::{auth::jwt::Jwt, error::{self, Error}};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+// memory buckets are pruned of full buckets past this many
+const MAX_MEMORY_BUCKETS: usize = 100_000;
+
+pub trait Buckets {
+    /// Takes a token from the bucket of key at now, or returns how long until
+    /// the bucket holds one.
+    fn take(&self, key: &str, limit: Limit, now: SystemTime) -> impl Future<Output = Result<Option<Duration>, Error>> + Send;
+}
+
+/// Limit allows per_minute requests, in bursts of up to a minute's worth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn rate(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    fn burst(&self) -> f64 {
+        f64::from(self.per_minute)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    Read,
+    Write,
+    Auth,
+}
+
+impl Budget {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Budget::Read => "read",
+            Budget::Write => "write",
+            Budget::Auth => "auth",
+        }
+    }
+}
+
+/// Limits of each budget, None is unlimited
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub read: Option<Limit>,
+    pub write: Option<Limit>,
+    pub auth: Option<Limit>,
+}
+
+impl Limits {
+    fn get(&self, budget: Budget) -> Option<Limit> {
+        match budget {
+            Budget::Read => self.read,
+            Budget::Write => self.write,
+            Budget::Auth => self.auth,
+        }
+    }
+}
+
+/// Cidr matches the addresses of trusted proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    /// Parses addresses, such as 10.0.0.1, and networks, such as 10.0.0.0/8
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let bad = |e: String| Error::BadRequest(format!("bad trusted proxy {}: {}", s, e));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e: std::net::AddrParseError| bad(e.to_string()))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().map_err(|e: std::num::ParseIntError| bad(e.to_string()))?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(bad(format!("prefix is longer than {} bits", bits)));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) =>
+                self.prefix == 0 || (u32::from(net) ^ u32::from(ip)) >> (32 - self.prefix) == 0,
+            (IpAddr::V6(net), IpAddr::V6(ip)) =>
+                self.prefix == 0 || (u128::from(net) ^ u128::from(ip)) >> (128 - self.prefix) == 0,
+            _ => false,
+        }
+    }
+}
+
+/// client_ip is the address of the client, past trusted proxies. Proxies
+/// append the address they received from to X-Forwarded-For, so the client is
+/// the last address that is not a trusted proxy.
+pub fn client_ip(remote: Option<SocketAddr>, headers: &HeaderMap, proxies: &[Cidr]) -> Option<IpAddr> {
+    let mut ip = remote?.ip();
+    let trusted = |ip: &IpAddr| proxies.iter().any(|p| p.contains(ip));
+    if !trusted(&ip) {
+        return Some(ip);
+    }
+    let forwarded = headers.get_all(X_FORWARDED_FOR).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|a| a.trim().parse::<IpAddr>())
+        .collect::<Vec<_>>();
+    for addr in forwarded.into_iter().rev() {
+        match addr {
+            Ok(addr) => {
+                ip = addr;
+                if !trusted(&ip) {
+                    break;
+                }
+            },
+            // past a malformed address nothing can be trusted
+            Err(_) => break,
+        }
+    }
+    Some(ip)
+}
+
+pub struct Limiter<B> {
+    buckets: B,
+    limits: Limits,
+    proxies: Vec<Cidr>,
+    jwt: Arc<Jwt>,
+}
+
+impl<B: Buckets> Limiter<B> {
+    pub fn new(buckets: B, limits: Limits, proxies: Vec<Cidr>, jwt: Arc<Jwt>) -> Self {
+        Limiter { buckets, limits, proxies, jwt }
+    }
+
+    fn key(&self, remote: Option<SocketAddr>, headers: &HeaderMap) -> Option<String> {
+        let user = headers.get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .and_then(|token| self.jwt.verify(token).ok())
+            .map(|claims| format!("user:{}", claims.sub));
+        user.or_else(|| client_ip(remote, headers, &self.proxies).map(|ip| format!("ip:{}", ip)))
+    }
+
+    /// Spends a request from budget, store errors let requests through
+    pub async fn check(&self, budget: Budget, remote: Option<SocketAddr>, headers: &HeaderMap) -> Result<(), Error> {
+        let Some(limit) = self.limits.get(budget) else {
+            return Ok(());
+        };
+        let Some(key) = self.key(remote, headers) else {
+            return Ok(());
+        };
+        let key = format!("{}:{}", budget.as_str(), key);
+        match self.buckets.take(&key, limit, SystemTime::now()).await {
+            Ok(None) => Ok(()),
+            Ok(Some(wait)) => Err(Error::TooManyRequests(format!("{} is rate limited", key), wait)),
+            Err(err) => {
+                log::warn!(target: "wiki::api", "rate limit: {:?}", err);
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Replies too many requests once the client's budget is spent, and rejects
+/// otherwise, so that it goes first among the routes it limits. Budget None
+/// spends reads on safe methods, and writes on others.
+pub fn filter<B>(limiter: Arc<Limiter<B>>, budget: Option<Budget>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    B: Buckets + Send + Sync + 'static
+{
+    warp::method()
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(move |method: Method, remote: Option<SocketAddr>, headers: HeaderMap| {
+            let limiter = limiter.clone();
+            async move {
+                let budget = budget.unwrap_or(match method {
+                    Method::GET | Method::HEAD | Method::OPTIONS => Budget::Read,
+                    _ => Budget::Write,
+                });
+                match limiter.check(budget, remote, &headers).await {
+                    Ok(()) => Err(warp::reject::not_found()),
+                    Err(err) => error::recover(warp::reject::custom(err)).await
+                        .map_err(|never| match never {}),
+                }
+            }
+        })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    at: SystemTime,
+}
+
+impl Bucket {
+    fn refilled(&self, limit: Limit, now: SystemTime) -> f64 {
+        let elapsed = now.duration_since(self.at).unwrap_or_default().as_secs_f64();
+        (self.tokens + elapsed * limit.rate()).min(limit.burst())
+    }
+}
+
+/// MemoryBuckets keeps buckets for a single replica.
+#[derive(Default)]
+pub struct MemoryBuckets {
+    buckets: Mutex<HashMap<String, (Bucket, Limit)>>,
+}
+
+impl Buckets for MemoryBuckets {
+    async fn take(&self, key: &str, limit: Limit, now: SystemTime) -> Result<Option<Duration>, Error> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_MEMORY_BUCKETS {
+            // full buckets are as good as none
+            buckets.retain(|_, (b, l)| b.refilled(*l, now) < l.burst());
+        }
+        let (bucket, _) = buckets.entry(key.to_string())
+            .or_insert((Bucket { tokens: limit.burst(), at: now }, limit));
+        let tokens = bucket.refilled(limit, now);
+        // denied requests take nothing, so that waiting as told is enough
+        if tokens >= 1.0 {
+            *bucket = Bucket { tokens: tokens - 1.0, at: now };
+            Ok(None)
+        } else {
+            *bucket = Bucket { tokens, at: now };
+            Ok(Some(Duration::from_secs_f64((1.0 - tokens) / limit.rate())))
+        }
+    }
+}
+
+/// RedisBuckets shares buckets between replicas. Buckets expire once they
+/// would be full.
+pub struct RedisBuckets {
+    redis: ConnectionManager,
+    script: redis::Script,
+}
+
+const TAKE_SCRIPT: &str = r"
+local burst, rate, now = tonumber(ARGV[1]), tonumber(ARGV[2]), tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens, at = tonumber(bucket[1]) or burst, tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - at) * rate)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = (1 - tokens) / rate
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst / rate * 1000))
+return tostring(wait)
+";
+
+const KEY_PREFIX: &str = "wiki:rate:";
+
+impl RedisBuckets {
+    pub fn new(redis: ConnectionManager) -> Self {
+        RedisBuckets { redis, script: redis::Script::new(TAKE_SCRIPT) }
+    }
+}
+
+impl Buckets for RedisBuckets {
+    async fn take(&self, key: &str, limit: Limit, now: SystemTime) -> Result<Option<Duration>, Error> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let mut redis = self.redis.clone();
+        let r: redis::RedisResult<String> = self.script
+            .key(format!("{}{}", KEY_PREFIX, key))
+            .arg(limit.burst())
+            .arg(limit.rate())
+            .arg(now)
+            .invoke_async(&mut redis)
+            .await;
+
+        let wait: f64 = r.map_err(|e| Error::Internal(e.to_string()))?
+            .parse()
+            .map_err(|e: std::num::ParseFloatError| Error::Internal(e.to_string()))?;
+        if wait > 0.0 {
+            Ok(Some(Duration::from_secs_f64(wait)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+pub enum Store {
+    Memory(MemoryBuckets),
+    Redis(RedisBuckets),
+}
+
+impl Buckets for Store {
+    async fn take(&self, key: &str, limit: Limit, now: SystemTime) -> Result<Option<Duration>, Error> {
+        match self {
+            Store::Memory(buckets) => buckets.take(key, limit, now).await,
+            Store::Redis(buckets) => buckets.take(key, limit, now).await,
+        }
+    }
+}
+
+/// Tests buckets, client IPs and filter.
+///
+/// Test plan:
+/// 1. Buckets allow bursts, then refill at the rate, so that waiting as told
+///    is enough
+/// 2. Client IPs skip trusted proxies only
+/// 3. Requests are limited per budget, by user or IP, with Retry-After
+/// 4. Redis buckets behave like memory buckets
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+
+    use crate::auth::{jwt::{self, tests::new_jwt}, user::User};
+
+    use super::*;
+
+    async fn test_buckets(buckets: &impl Buckets, key: &str) {
+        let limit = Limit { per_minute: 3 };
+        let now = SystemTime::now();
+
+        for _ in 0..3 {
+            assert_eq!(buckets.take(key, limit, now).await.unwrap(), None);
+        }
+        let wait = buckets.take(key, limit, now).await.unwrap().unwrap();
+        assert!(wait > Duration::from_secs(19) && wait <= Duration::from_secs(20), "{:?}", wait);
+
+        // a token every 20s
+        let later = now + Duration::from_secs(20);
+        assert_eq!(buckets.take(key, limit, later).await.unwrap(), None);
+        assert!(buckets.take(key, limit, later).await.unwrap().is_some());
+
+        // denied requests take no part of a token
+        let sooner = later + Duration::from_secs(10);
+        let wait = buckets.take(key, limit, sooner).await.unwrap().unwrap();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10), "{:?}", wait);
+        assert_eq!(buckets.take(key, limit, sooner + wait + Duration::from_millis(1)).await.unwrap(), None);
+
+        // other keys have their own buckets
+        assert_eq!(buckets.take(&format!("{}-other", key), limit, now).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_buckets_in_memory() {
+        test_buckets(&MemoryBuckets::default(), "test").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_buckets_with_redis() {
+        let url = std::env::var("WIKI_CI_TEST_REDIS_URL").unwrap_or("redis://localhost".into());
+        let redis = redis::Client::open(url).unwrap().get_connection_manager().await.unwrap();
+        let key = format!("test-{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos());
+        test_buckets(&RedisBuckets::new(redis), &key).await;
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = vec![Cidr::parse("10.0.0.0/8").unwrap(), Cidr::parse("192.168.1.1").unwrap()];
+        let remote = |ip: &str| Some(SocketAddr::new(ip.parse().unwrap(), 1234));
+        let forwarded = |v: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(X_FORWARDED_FOR, v.parse().unwrap());
+            headers
+        };
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        for (remote, headers, expected) in [
+            // untrusted remotes may forge the header
+            (remote("203.0.113.9"), forwarded("198.51.100.1"), ip("203.0.113.9")),
+            (remote("10.1.2.3"), forwarded("198.51.100.1"), ip("198.51.100.1")),
+            (remote("10.1.2.3"), forwarded("6.6.6.6, 198.51.100.1, 192.168.1.1"), ip("198.51.100.1")),
+            (remote("10.1.2.3"), forwarded("10.0.0.2"), ip("10.0.0.2")),
+            (remote("10.1.2.3"), forwarded("198.51.100.1, not an ip"), ip("10.1.2.3")),
+            (remote("10.1.2.3"), HeaderMap::new(), ip("10.1.2.3")),
+            (remote("::ffff:10.1.2.3"), forwarded("2001:db8::1"), ip("2001:db8::1")),
+            (None, forwarded("198.51.100.1"), None),
+        ] {
+            assert_eq!(client_ip(remote, &headers, &proxies), expected, "{:?} {:?}", remote, headers);
+        }
+
+        for bad in ["10.0.0.0/33", "not an ip", "10.0.0.0/x"] {
+            assert!(Cidr::parse(bad).is_err(), "{}", bad);
+        }
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&"1.2.3.4".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let jwt = Arc::new(new_jwt(jwt::Algorithm::EdDSA).await);
+        let limits = Limits {
+            read: Some(Limit { per_minute: 2 }),
+            write: Some(Limit { per_minute: 1 }),
+            auth: None,
+        };
+        let limiter = Arc::new(Limiter::new(Store::Memory(MemoryBuckets::default()), limits, vec![], jwt.clone()));
+        let f = filter(limiter.clone(), None).or(warp::any().map(warp::reply));
+        let request = |method: &str, ip: &str| warp::test::request()
+            .method(method)
+            .remote_addr(SocketAddr::new(ip.parse().unwrap(), 1234));
+
+        // reads and writes have separate budgets
+        for _ in 0..2 {
+            assert_eq!(request("GET", "10.0.0.1").reply(&f).await.status(), StatusCode::OK);
+        }
+        assert_eq!(request("POST", "10.0.0.1").reply(&f).await.status(), StatusCode::OK);
+        let res = request("GET", "10.0.0.1").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "30");
+        assert_eq!(request("POST", "10.0.0.1").reply(&f).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // other clients are unaffected, users are limited apart from their ip
+        assert_eq!(request("GET", "10.0.0.2").reply(&f).await.status(), StatusCode::OK);
+        let token = jwt.sign(&User::new("bob"), 1, Duration::from_secs(60)).unwrap();
+        for _ in 0..2 {
+            let res = request("GET", "10.0.0.1").header("Authorization", format!("Bearer {}", token)).reply(&f).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = request("GET", "10.0.0.3").header("Authorization", format!("Bearer {}", token)).reply(&f).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // forged tokens are limited by ip
+        let res = request("GET", "10.0.0.1").header("Authorization", "Bearer forged").reply(&f).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // unlimited budgets
+        let auth = filter(limiter, Some(Budget::Auth)).or(warp::any().map(warp::reply));
+        for _ in 0..5 {
+            assert_eq!(request("POST", "10.0.0.1").reply(&auth).await.status(), StatusCode::OK);
+        }
+    }
+}
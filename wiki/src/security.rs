@@ -0,0 +1,121 @@
+// security builds the headers the UI is served with. The Content-Security-Policy
+// allows scripts from the wiki itself, and inline scripts by their hashes, which
+// build.rs computes from the UI's HTML.
+
+use warp::http::{header::{HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS}, HeaderMap};
+
+use crate::error::Error;
+
+const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Sources of style-src, the UI sets inline styles
+    pub style_src: String,
+    pub img_src: String,
+    pub connect_src: String,
+    /// Sources of frame-ancestors, 'none' forbids framing
+    pub frame_ancestors: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    /// HSTS max-age in seconds, None omits HSTS
+    pub hsts_max_age: Option<u64>,
+    /// Whether the UI is served over HTTPS, HSTS is omitted otherwise
+    pub https: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            style_src: "'self' 'unsafe-inline'".into(),
+            img_src: "'self' data:".into(),
+            connect_src: "'self'".into(),
+            frame_ancestors: "'none'".into(),
+            referrer_policy: "same-origin".into(),
+            permissions_policy: "camera=(), microphone=(), geolocation=(), payment=()".into(),
+            hsts_max_age: None,
+            https: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn content_security_policy(&self, script_hashes: &[&str]) -> Result<String, Error> {
+        for (directive, sources) in [
+            ("style-src", &self.style_src),
+            ("img-src", &self.img_src),
+            ("connect-src", &self.connect_src),
+            ("frame-ancestors", &self.frame_ancestors),
+        ] {
+            // separators would smuggle in other directives
+            if sources.contains([';', ',']) || sources.trim().is_empty() {
+                return Err(Error::BadRequest(format!("bad {} sources: {}", directive, sources)));
+            }
+        }
+
+        let script_src = std::iter::once("'self'")
+            .chain(script_hashes.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(format!(
+            "default-src 'self'; script-src {}; style-src {}; img-src {}; connect-src {}; object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors {}",
+            script_src, self.style_src, self.img_src, self.connect_src, self.frame_ancestors,
+        ))
+    }
+
+    pub fn headers(&self, script_hashes: &[&str]) -> Result<HeaderMap, Error> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_SECURITY_POLICY, value(&self.content_security_policy(script_hashes)?)?);
+        headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        headers.insert(REFERRER_POLICY, value(&self.referrer_policy)?);
+        headers.insert(PERMISSIONS_POLICY, value(&self.permissions_policy)?);
+        if let Some(max_age) = self.hsts_max_age
+            && self.https
+        {
+            headers.insert(STRICT_TRANSPORT_SECURITY, value(&format!("max-age={}; includeSubDomains", max_age))?);
+        }
+        Ok(headers)
+    }
+}
+
+fn value(v: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(v).map_err(|e| Error::BadRequest(format!("bad header value {}: {}", v, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let headers = Config::default().headers(&["'sha256-abc='"]).unwrap();
+        let csp = headers[CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(csp.contains("script-src 'self' 'sha256-abc=';"), "{}", csp);
+        assert!(csp.ends_with("frame-ancestors 'none'"), "{}", csp);
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[REFERRER_POLICY], "same-origin");
+        assert!(!headers.contains_key(STRICT_TRANSPORT_SECURITY));
+
+        // HSTS over plain HTTP is ignored by browsers, or pins a host that
+        // serves no HTTPS
+        let config = Config { hsts_max_age: Some(31536000), ..Config::default() };
+        assert!(!config.headers(&[]).unwrap().contains_key(STRICT_TRANSPORT_SECURITY));
+
+        let config = Config { hsts_max_age: Some(31536000), https: true, ..Config::default() };
+        let headers = config.headers(&[]).unwrap();
+        assert_eq!(headers[STRICT_TRANSPORT_SECURITY], "max-age=31536000; includeSubDomains");
+        assert!(headers[CONTENT_SECURITY_POLICY].to_str().unwrap().contains("script-src 'self';"));
+    }
+
+    #[test]
+    fn test_bad_configs() {
+        for config in [
+            Config { style_src: "'self'; script-src *".into(), ..Config::default() },
+            Config { frame_ancestors: "".into(), ..Config::default() },
+            Config { referrer_policy: "bad\nvalue".into(), ..Config::default() },
+        ] {
+            let r = config.headers(&[]);
+            assert!(matches!(r, Err(Error::BadRequest(_))), "{:?}: {:?}", config, r);
+        }
+    }
+}
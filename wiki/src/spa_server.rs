@@ -8,7 +8,7 @@ use std::sync::Arc;
 
 use phf::Map;
 use regex::Regex;
-use warp::{Filter, filters::path::Tail, http::{Response, status::StatusCode}, reject::{self, Rejection}, reply::Reply};
+use warp::{Filter, filters::path::Tail, http::{HeaderMap, Response, status::StatusCode}, reject::{self, Rejection}, reply::Reply};
 
 #[derive(Debug)]
 pub struct Asset {
@@ -20,6 +20,8 @@ pub struct FilterInput {
     pub assets: &'static Map<&'static str, Asset>,
     pub entrypoint: &'static str,
     pub path_validator: Regex,
+    /// Added to every asset, such as security headers
+    pub headers: HeaderMap,
 }
 
 pub fn filter(input: Arc<FilterInput>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
@@ -39,10 +41,10 @@ async fn handler(input: Arc<FilterInput>, path: Tail) -> Result<Response<&'stati
     let path = path.as_str();
 
     match assets.get(path) {
-        Some(asset) => Ok(found(asset)),
+        Some(asset) => Ok(found(asset, &input.headers)),
         None => {
             if path_validator.is_match(path) {
-                Ok(found(assets.get(entrypoint).unwrap()))
+                Ok(found(assets.get(entrypoint).unwrap(), &input.headers))
             } else {
                 Err(reject::not_found())
             }
@@ -50,12 +52,14 @@ async fn handler(input: Arc<FilterInput>, path: Tail) -> Result<Response<&'stati
     }
 }
 
-fn found(asset: &Asset) -> Response<&'static [u8]> {
-    Response::builder()
+fn found(asset: &Asset, headers: &HeaderMap) -> Response<&'static [u8]> {
+    let mut res = Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", asset.content_type)
         .body(asset.content)
-        .unwrap()
+        .unwrap();
+    res.headers_mut().extend(headers.clone());
+    res
 }
 
 #[cfg(test)]
@@ -70,12 +74,16 @@ mod tests {
             content: b"hello, world",
         };
 
-        let res = found(&asset);
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Content-Type-Options", "nosniff".parse().unwrap());
+
+        let res = found(&asset, &headers);
         assert_eq!(asset.content, *res.body());
         assert_eq!(
             res.headers().get("Content-Type").unwrap().to_str().unwrap(),
             asset.content_type,
         );
+        assert_eq!(res.headers()["X-Content-Type-Options"], "nosniff");
     }
 
     #[tokio::test]
@@ -92,6 +100,7 @@ mod tests {
             assets: &ASSETS,
             entrypoint: "index.html",
             path_validator: re,
+            headers: HeaderMap::new(),
         });
         let path = new_tail("/").await;
 
@@ -114,6 +123,7 @@ mod tests {
             assets: &ASSETS,
             entrypoint: "index.html",
             path_validator: re,
+            headers: HeaderMap::new(),
         });
         let path = new_tail("/wiki").await;
 
@@ -136,6 +146,7 @@ mod tests {
             assets: &ASSETS,
             entrypoint: "index.html",
             path_validator: re,
+            headers: HeaderMap::new(),
         });
         let path = new_tail("/index.html").await;
 